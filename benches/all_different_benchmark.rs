@@ -2,7 +2,7 @@ use criterion::{criterion_group, criterion_main, Criterion};
 
 use large_sudoku_solver::solver::all_different;
 use large_sudoku_solver::types::{CellIndex, ValueType};
-use large_sudoku_solver::value_set::{IntBitSet, ValueSet};
+use large_sudoku_solver::value_set::{IntBitSet, SimdValueSet, ValueSet};
 
 type ValueSetType = IntBitSet<i64>;
 
@@ -50,5 +50,29 @@ fn criterion_benchmark(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, criterion_benchmark);
+// Large grids use SimdValueSet.
+fn wide_benchmark(c: &mut Criterion) {
+    const NUM_VALUES: usize = 256;
+
+    let full_set = SimdValueSet::<4>::full(NUM_VALUES as ValueType);
+
+    let mut enforcer = all_different::AllDifferentEnforcer::new(NUM_VALUES as u32);
+
+    let mut grid = vec![full_set; NUM_VALUES];
+    let cells = (0..NUM_VALUES).collect::<Vec<CellIndex>>();
+    let mut candidates = vec![SimdValueSet::<4>::empty(); NUM_VALUES];
+
+    c.bench_function("enforce_all_different partial 256", |b| {
+        grid[5] = SimdValueSet::from_iter([0, 1]);
+        grid[7] = SimdValueSet::from_iter([0, 1, 3]);
+        grid[0] = SimdValueSet::from_iter(0..100);
+
+        b.iter(|| {
+            candidates.fill(SimdValueSet::empty());
+            enforcer.enforce_all_different_internal(&grid, &cells, &mut candidates)
+        });
+    });
+}
+
+criterion_group!(benches, criterion_benchmark, wide_benchmark);
 criterion_main!(benches);
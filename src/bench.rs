@@ -0,0 +1,119 @@
+// The built-in benchmark suite for the bench action: a few representative
+// puzzles, from a hard 9x9 to a prefix of the search of an empty 256x256 grid,
+// each solved with the config being evaluated.
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+use crate::io::parser;
+use crate::solver::{self, Config, Counters, OutputType};
+
+pub struct Benchmark {
+    pub name: &'static str,
+    puzzle: &'static str,
+    // Count all the solutions, rather than stopping after two as solve does.
+    count: bool,
+    // Stop after this many guesses, for grids which take too long to finish.
+    max_guesses: Option<u64>,
+}
+
+pub static SUITE: [Benchmark; 4] = [
+    Benchmark {
+        name: "9x9-hard",
+        puzzle: include_str!("../puzzles/9x9_1.txt"),
+        count: false,
+        max_guesses: None,
+    },
+    Benchmark {
+        name: "16x16",
+        puzzle: include_str!("../puzzles/16x16_2.txt"),
+        count: false,
+        max_guesses: None,
+    },
+    Benchmark {
+        name: "64x64-x",
+        puzzle: include_str!("../puzzles/64x64_X_1.txt"),
+        count: false,
+        max_guesses: None,
+    },
+    Benchmark {
+        name: "256x256-empty",
+        puzzle: "256x256",
+        count: true,
+        max_guesses: Some(100),
+    },
+];
+
+// Quick benchmarks are repeated until they have run for this long (up to
+// `MAX_RUNS` times), and the fastest run is reported, as a single run is too
+// short to time reliably.
+const MIN_TIME: Duration = Duration::from_secs(1);
+const MAX_RUNS: u32 = 100;
+
+pub struct BenchmarkResult {
+    pub runs: u32,
+    // The time of the fastest run.
+    pub secs: f64,
+    // The counters of the first run. Every run does the same work unless the
+    // config is random.
+    pub counters: Counters,
+}
+
+impl Benchmark {
+    // Run the benchmark with configs from `make_config`, which may set limits
+    // other than the number of guesses.
+    pub fn run(&self, make_config: &dyn Fn() -> Config) -> Result<BenchmarkResult, Error> {
+        let constraint = parser::parse_text(self.puzzle, false)?;
+        let max_solutions = if self.count { usize::MAX } else { 2 };
+
+        let mut result: Option<BenchmarkResult> = None;
+        let start = Instant::now();
+        while start.elapsed() < MIN_TIME && result.as_ref().is_none_or(|r| r.runs < MAX_RUNS) {
+            let config = make_config();
+            let config = Config {
+                output_type: OutputType::Empty,
+                max_guesses: self.max_guesses.or(config.max_guesses),
+                ..config
+            };
+            let run_start = Instant::now();
+            let mut solutions = solver::solution_iter(&constraint, config);
+            solutions.by_ref().take(max_solutions).for_each(drop);
+            let secs = run_start.elapsed().as_secs_f64();
+
+            match &mut result {
+                Some(result) => {
+                    result.runs += 1;
+                    result.secs = result.secs.min(secs);
+                }
+                None => {
+                    result = Some(BenchmarkResult {
+                        runs: 1,
+                        secs,
+                        counters: solutions.counters(),
+                    })
+                }
+            }
+        }
+        Ok(result.unwrap())
+    }
+}
+
+// The benchmarks named in `names` (a comma-separated list), or the whole suite
+// for "all".
+pub fn select(names: &str) -> Result<Vec<&'static Benchmark>, Error> {
+    if names == "all" {
+        return Ok(SUITE.iter().collect());
+    }
+    names
+        .split(',')
+        .map(|name| {
+            SUITE.iter().find(|b| b.name == name.trim()).ok_or_else(|| {
+                let known = SUITE.iter().map(|b| b.name).collect::<Vec<_>>();
+                Error::Options(format!(
+                    "Unknown benchmark: {} (expected all or {}).",
+                    name,
+                    known.join(", ")
+                ))
+            })
+        })
+        .collect()
+}
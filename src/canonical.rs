@@ -0,0 +1,463 @@
+// Canonical forms of puzzles, for finding puzzles which are the same up to
+// relabeling the values, permuting the bands and stacks (and the rows and
+// columns within them), and transposing the grid.
+//
+// The canonical form is the smallest grid (in row-major order, with empty cells
+// first) over all of these transformations, after relabeling the values in the
+// order they first appear. Rows and columns are first sorted by properties
+// which don't change under the transformations (such as how many givens they
+// have), so only the orders of rows and columns which can't be told apart this
+// way are searched. This makes most puzzles fast to canonicalize, but grids with
+// many symmetries can take a long time.
+use std::cmp::Ordering;
+
+use crate::types::{CellValue, Constraint, FixedValues, Solution, ValueType};
+
+// The canonical givens of the puzzle. Solutions can be canonicalized as puzzles
+// with every cell given.
+pub fn canonicalize(constraint: &Constraint) -> Result<FixedValues, String> {
+    check_supported(constraint)?;
+    let shape = &constraint.shape;
+    let side_len = shape.side_len as usize;
+    let band_size = shape.box_size.unwrap_or(shape.side_len) as usize;
+
+    let mut grid = vec![0; shape.num_cells];
+    for &(cell, value) in &constraint.fixed_values {
+        grid[cell] = value.display_value();
+    }
+
+    let mut best: Option<Vec<ValueType>> = None;
+    for transposed in [false, true] {
+        let grid = if transposed {
+            transpose(&grid, side_len)
+        } else {
+            grid.clone()
+        };
+        let mut search = Search::new(&grid, side_len, band_size, best.take());
+        search.search_columns();
+        best = search.best;
+    }
+
+    Ok(best
+        .unwrap()
+        .iter()
+        .enumerate()
+        .filter(|(_, &value)| value != 0)
+        .map(|(cell, &value)| (cell, CellValue::from_display_value(value)))
+        .collect())
+}
+
+// Whether the puzzles are the same up to the transformations above.
+pub fn is_isomorphic(a: &Constraint, b: &Constraint) -> Result<bool, String> {
+    if a.shape != b.shape || a.fixed_values.len() != b.fixed_values.len() {
+        check_supported(a)?;
+        check_supported(b)?;
+        return Ok(false);
+    }
+    let sorted = |mut fixed_values: FixedValues| {
+        fixed_values.sort_by_key(|&(cell, _)| cell);
+        fixed_values
+            .into_iter()
+            .map(|(cell, value)| (cell, value.index()))
+            .collect::<Vec<_>>()
+    };
+    Ok(sorted(canonicalize(a)?) == sorted(canonicalize(b)?))
+}
+
+// A hash of the canonical form, which is the same for isomorphic puzzles and
+// doesn't change between versions or platforms. This is FNV-1a over the shape
+// and the canonical grid.
+pub fn fingerprint(constraint: &Constraint) -> Result<u64, String> {
+    let canonical = canonicalize(constraint)?;
+    let shape = &constraint.shape;
+    let mut grid = vec![0; shape.num_cells];
+    for (cell, value) in canonical {
+        grid[cell] = value.display_value();
+    }
+
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let header = [shape.box_size.unwrap_or(0), shape.side_len];
+    for word in header.into_iter().chain(grid.into_iter().map(u32::from)) {
+        for byte in word.to_le_bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    Ok(hash)
+}
+
+// The fingerprint of a solution to the puzzle, as a grid with every cell given.
+pub fn solution_fingerprint(constraint: &Constraint, solution: &Solution) -> Result<u64, String> {
+    fingerprint(&Constraint {
+        fixed_values: solution.iter().copied().enumerate().collect(),
+        candidates: Vec::new(),
+        ..constraint.clone()
+    })
+}
+
+// Variant constraints don't stay in place under the transformations.
+fn check_supported(constraint: &Constraint) -> Result<(), String> {
+    if has_variants(constraint) || !constraint.candidates.is_empty() {
+        return Err(
+            "Canonical forms are only supported for puzzles without variant constraints or \
+             candidates."
+                .to_string(),
+        );
+    }
+    Ok(())
+}
+
+pub(crate) fn has_variants(constraint: &Constraint) -> bool {
+    constraint.x_sudoku
+        || constraint.main_diagonal
+        || constraint.anti_diagonal
+        || constraint.argyle
+        || constraint.anti_king
+        || constraint.windoku
+        || constraint.kropki_negative
+        || constraint.xv_negative
+        || constraint.consecutive_negative
+        || !constraint.variants.is_empty()
+        || !constraint.propagators.is_empty()
+}
+
+fn transpose(grid: &[ValueType], side_len: usize) -> Vec<ValueType> {
+    (0..grid.len())
+        .map(|i| grid[(i % side_len) * side_len + i / side_len])
+        .collect()
+}
+
+// The lines (rows or columns) which may fill each position, in order. Bands
+// (or stacks) are sorted by their key, then the lines within each band, and
+// only lines with the same key may be swapped.
+struct LineGroups {
+    // Groups of bands with the same key, in order.
+    band_groups: Vec<Vec<usize>>,
+    // For each band, the groups of its lines with the same key, in order.
+    line_groups: Vec<Vec<Vec<usize>>>,
+}
+
+impl LineGroups {
+    // `line_keys[line]` must not change under the transformations.
+    fn new(line_keys: &[LineKey], band_size: usize) -> LineGroups {
+        let num_bands = line_keys.len() / band_size;
+        let mut band_keys = Vec::new();
+        let mut line_groups = Vec::new();
+        for band in 0..num_bands {
+            let lines = (band * band_size..(band + 1) * band_size).collect::<Vec<_>>();
+            let mut keys = lines.iter().map(|&l| &line_keys[l]).collect::<Vec<_>>();
+            keys.sort();
+            band_keys.push(keys);
+            line_groups.push(group_by_key(lines, |&line| &line_keys[line]));
+        }
+        LineGroups {
+            band_groups: group_by_key((0..num_bands).collect(), |&band| &band_keys[band]),
+            line_groups,
+        }
+    }
+
+    // The group of bands which may fill each band position.
+    fn band_group_at(&self, position: usize) -> &[usize] {
+        let mut position = position;
+        for group in &self.band_groups {
+            if position < group.len() {
+                return group;
+            }
+            position -= group.len();
+        }
+        unreachable!()
+    }
+
+    // The group of lines in `band` which may fill each position in the band.
+    fn line_group_at(&self, band: usize, position: usize) -> &[usize] {
+        let mut position = position;
+        for group in &self.line_groups[band] {
+            if position < group.len() {
+                return group;
+            }
+            position -= group.len();
+        }
+        unreachable!()
+    }
+}
+
+// Sort the items by key, and group those with equal keys.
+fn group_by_key<K: Ord, F: Fn(&usize) -> K>(mut items: Vec<usize>, key: F) -> Vec<Vec<usize>> {
+    items.sort_by_key(&key);
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for item in items {
+        match groups.last_mut() {
+            Some(group) if key(&group[0]) == key(&item) => group.push(item),
+            _ => groups.push(vec![item]),
+        }
+    }
+    groups
+}
+
+// A key for each line which doesn't change under the transformations.
+type LineKey = (Vec<usize>, Vec<Vec<usize>>);
+
+// For each row, the number of givens, the givens in each stack, and how often
+// each of its values appears in the grid, followed by how it relates to each
+// other row (see `row_pair_key`). Columns use the transposed grid.
+fn line_keys(grid: &[ValueType], side_len: usize, band_size: usize) -> Vec<LineKey> {
+    let mut value_counts = vec![0; side_len + 1];
+    for &value in grid {
+        value_counts[value as usize] += 1;
+    }
+    let rows = grid.chunks(side_len).collect::<Vec<_>>();
+
+    (0..side_len)
+        .map(|row| {
+            let line = rows[row];
+            let mut stack_counts = line
+                .chunks(band_size)
+                .map(|stack| stack.iter().filter(|&&v| v != 0).count())
+                .collect::<Vec<_>>();
+            stack_counts.sort();
+            let mut frequencies = line
+                .iter()
+                .filter(|&&v| v != 0)
+                .map(|&v| value_counts[v as usize])
+                .collect::<Vec<_>>();
+            frequencies.sort();
+
+            let mut key = vec![stack_counts.iter().sum()];
+            key.extend(stack_counts);
+            key.extend(frequencies);
+
+            let mut pair_keys = (0..side_len)
+                .filter(|&other| other != row)
+                .map(|other| {
+                    let same_band = row / band_size == other / band_size;
+                    row_pair_key(line, rows[other], same_band, band_size)
+                })
+                .collect::<Vec<_>>();
+            pair_keys.sort();
+            (key, pair_keys)
+        })
+        .collect()
+}
+
+// How two rows relate: whether they are in the same band, how many values they
+// share, how many of those are in the same stack in both, and the lengths of
+// the paths and cycles made by following each shared value from its column in
+// `a` to its column in `b`. This is what lets the rows of complete grids be
+// told apart.
+fn row_pair_key(a: &[ValueType], b: &[ValueType], same_band: bool, band_size: usize) -> Vec<usize> {
+    let mut b_columns = vec![None; a.len() + 1];
+    for (column, &value) in b.iter().enumerate() {
+        if value != 0 {
+            b_columns[value as usize] = Some(column);
+        }
+    }
+    let next = |column: usize| match a[column] {
+        0 => None,
+        value => b_columns[value as usize],
+    };
+
+    let mut shared = 0;
+    let mut same_stack = 0;
+    let mut has_previous = vec![false; a.len()];
+    for column in 0..a.len() {
+        if let Some(b_column) = next(column) {
+            shared += 1;
+            if column / band_size == b_column / band_size {
+                same_stack += 1;
+            }
+            has_previous[b_column] = true;
+        }
+    }
+
+    // Paths start at columns which no shared value maps to, and everything
+    // else is on a cycle.
+    let mut visited = vec![false; a.len()];
+    let mut paths = Vec::new();
+    for start in (0..a.len()).filter(|&c| !has_previous[c] && next(c).is_some()) {
+        let (mut column, mut len) = (Some(start), 0);
+        while let Some(c) = column {
+            visited[c] = true;
+            len += 1;
+            column = next(c);
+        }
+        paths.push(len);
+    }
+    let mut cycles = Vec::new();
+    for start in 0..a.len() {
+        if visited[start] || next(start).is_none() {
+            continue;
+        }
+        let (mut column, mut len) = (start, 0);
+        while !visited[column] {
+            visited[column] = true;
+            len += 1;
+            column = next(column).unwrap();
+        }
+        cycles.push(len);
+    }
+    paths.sort();
+    cycles.sort();
+
+    let mut key = vec![same_band as usize, shared, same_stack, paths.len()];
+    key.extend(paths);
+    key.extend(cycles);
+    key
+}
+
+struct Search<'a> {
+    grid: &'a [ValueType],
+    side_len: usize,
+    band_size: usize,
+    rows: LineGroups,
+    columns: LineGroups,
+    column_order: Vec<usize>,
+    used_columns: Vec<bool>,
+    used_stacks: Vec<bool>,
+    // The band at each band position so far.
+    row_bands: Vec<usize>,
+    used_rows: Vec<bool>,
+    // The relabeled grid so far, and the label of each value (0 if it hasn't
+    // appeared yet).
+    current: Vec<ValueType>,
+    labels: Vec<ValueType>,
+    next_label: ValueType,
+    best: Option<Vec<ValueType>>,
+}
+
+impl<'a> Search<'a> {
+    fn new(
+        grid: &'a [ValueType],
+        side_len: usize,
+        band_size: usize,
+        best: Option<Vec<ValueType>>,
+    ) -> Search<'a> {
+        let row_keys = line_keys(grid, side_len, band_size);
+        let column_keys = line_keys(&transpose(grid, side_len), side_len, band_size);
+        Search {
+            grid,
+            side_len,
+            band_size,
+            rows: LineGroups::new(&row_keys, band_size),
+            columns: LineGroups::new(&column_keys, band_size),
+            column_order: Vec::with_capacity(side_len),
+            used_columns: vec![false; side_len],
+            used_stacks: vec![false; side_len / band_size],
+            row_bands: vec![0; side_len / band_size],
+            used_rows: vec![false; side_len],
+            current: vec![0; grid.len()],
+            labels: vec![0; side_len + 1],
+            next_label: 1,
+            best,
+        }
+    }
+
+    // Try each order of the columns, then search the rows for each.
+    fn search_columns(&mut self) {
+        let position = self.column_order.len();
+        if position == self.side_len {
+            self.search_rows(0);
+            return;
+        }
+
+        let (stack_position, position_in_stack) =
+            (position / self.band_size, position % self.band_size);
+        if position_in_stack == 0 {
+            for stack in self.columns.band_group_at(stack_position).to_vec() {
+                if self.used_stacks[stack] {
+                    continue;
+                }
+                self.used_stacks[stack] = true;
+                self.search_columns_in_stack(stack);
+                self.used_stacks[stack] = false;
+            }
+        } else {
+            let stack = self.column_order[position - 1] / self.band_size;
+            self.search_columns_in_stack(stack);
+        }
+    }
+
+    fn search_columns_in_stack(&mut self, stack: usize) {
+        let position_in_stack = self.column_order.len() % self.band_size;
+        for column in self
+            .columns
+            .line_group_at(stack, position_in_stack)
+            .to_vec()
+        {
+            if self.used_columns[column] {
+                continue;
+            }
+            self.used_columns[column] = true;
+            self.column_order.push(column);
+            self.search_columns();
+            self.column_order.pop();
+            self.used_columns[column] = false;
+        }
+    }
+
+    fn search_rows(&mut self, position: usize) {
+        if position == self.side_len {
+            if self.compare_with_best(self.current.len()) == Ordering::Less {
+                self.best = Some(self.current.clone());
+            }
+            return;
+        }
+
+        let (band_position, position_in_band) =
+            (position / self.band_size, position % self.band_size);
+        if position_in_band == 0 {
+            for band in self.rows.band_group_at(band_position).to_vec() {
+                if self.row_bands[..band_position].contains(&band) {
+                    continue;
+                }
+                self.row_bands[band_position] = band;
+                self.search_rows_in_band(position, band);
+            }
+        } else {
+            self.search_rows_in_band(position, self.row_bands[band_position]);
+        }
+    }
+
+    fn search_rows_in_band(&mut self, position: usize, band: usize) {
+        let position_in_band = position % self.band_size;
+        for row in self.rows.line_group_at(band, position_in_band).to_vec() {
+            if self.used_rows[row] {
+                continue;
+            }
+            let new_labels = self.place_row(position, row);
+            // Only orders which could still be smaller than the best are
+            // continued.
+            if self.compare_with_best((position + 1) * self.side_len) != Ordering::Greater {
+                self.used_rows[row] = true;
+                self.search_rows(position + 1);
+                self.used_rows[row] = false;
+            }
+            for value in new_labels {
+                self.labels[value as usize] = 0;
+                self.next_label -= 1;
+            }
+        }
+    }
+
+    // Write the relabeled row at `position`, and return the values which were
+    // given labels.
+    fn place_row(&mut self, position: usize, row: usize) -> Vec<ValueType> {
+        let mut new_labels = Vec::new();
+        for (i, &column) in self.column_order.iter().enumerate() {
+            let value = self.grid[row * self.side_len + column];
+            if value != 0 && self.labels[value as usize] == 0 {
+                self.labels[value as usize] = self.next_label;
+                self.next_label += 1;
+                new_labels.push(value);
+            }
+            self.current[position * self.side_len + i] = self.labels[value as usize];
+        }
+        new_labels
+    }
+
+    fn compare_with_best(&self, len: usize) -> Ordering {
+        match &self.best {
+            Some(best) => self.current[..len].cmp(&best[..len]),
+            None => Ordering::Less,
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use rusqlite::{params, Connection};
+
+use crate::canonical;
+use crate::io::output;
+use crate::solver::{self, Config, Counters};
+use crate::types::{Constraint, FixedValues};
+
+// A puzzle stored in the database, along with how it was produced.
+#[derive(Debug, Clone)]
+pub struct PuzzleRecord {
+    // Puzzle in grid format, in its canonical form so that relabeled, permuted
+    // and transposed copies are the same. Puzzles with variant constraints
+    // have no canonical form, and are stored as they are. This is used with
+    // `x_sudoku` as the key for deduplication.
+    pub puzzle: String,
+    pub side_len: u32,
+    pub x_sudoku: bool,
+    pub num_clues: usize,
+    pub source: String,
+    pub seed: Option<u64>,
+    // The score from rating the puzzle.
+    pub difficulty: Option<f64>,
+    pub guesses: u64,
+    pub backtracks: u64,
+}
+
+impl PuzzleRecord {
+    pub fn new(
+        constraint: &Constraint,
+        fixed_values: &FixedValues,
+        source: &str,
+        seed: Option<u64>,
+        counters: &Counters,
+    ) -> PuzzleRecord {
+        let puzzle = Constraint {
+            fixed_values: fixed_values.clone(),
+            ..constraint.clone()
+        };
+        let canonical = canonical::canonicalize(&puzzle).unwrap_or_else(|_| fixed_values.clone());
+        let rating = solver::rate(&puzzle, Config::default());
+        PuzzleRecord {
+            puzzle: output::fixed_values_as_grid(constraint, &canonical),
+            side_len: constraint.shape.side_len,
+            x_sudoku: constraint.x_sudoku,
+            num_clues: fixed_values.len(),
+            source: source.to_string(),
+            seed,
+            difficulty: Some(rating.score),
+            guesses: counters.guesses,
+            backtracks: counters.backtracks,
+        }
+    }
+}
+
+pub struct PuzzleDb {
+    conn: Connection,
+}
+
+impl PuzzleDb {
+    pub fn open(path: &str) -> Result<PuzzleDb, String> {
+        let conn = Connection::open(path)
+            .map_err(|e| format!("Could not open database {}: {}", path, e))?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS puzzles (
+                id INTEGER PRIMARY KEY,
+                puzzle TEXT NOT NULL,
+                side_len INTEGER NOT NULL,
+                x_sudoku INTEGER NOT NULL,
+                num_clues INTEGER NOT NULL,
+                source TEXT NOT NULL,
+                seed INTEGER,
+                difficulty REAL,
+                guesses INTEGER NOT NULL,
+                backtracks INTEGER NOT NULL,
+                created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                UNIQUE (puzzle, x_sudoku)
+            );
+            CREATE INDEX IF NOT EXISTS puzzles_by_shape ON puzzles (side_len, num_clues);",
+        )
+        .map_err(db_error)?;
+        Ok(PuzzleDb { conn })
+    }
+
+    // Returns false if the puzzle, or a copy of it with the values relabeled or
+    // the grid permuted or transposed, was already in the database.
+    pub fn insert(&self, record: &PuzzleRecord) -> Result<bool, String> {
+        let num_inserted = self
+            .conn
+            .execute(
+                "INSERT OR IGNORE INTO puzzles
+                (puzzle, side_len, x_sudoku, num_clues, source, seed, difficulty, guesses, backtracks)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    record.puzzle,
+                    record.side_len,
+                    record.x_sudoku,
+                    record.num_clues as u64,
+                    record.source,
+                    record.seed.map(|s| s as i64),
+                    record.difficulty,
+                    record.guesses as i64,
+                    record.backtracks as i64,
+                ],
+            )
+            .map_err(db_error)?;
+        Ok(num_inserted > 0)
+    }
+
+    // List puzzles with the given side length, fewest clues first.
+    pub fn list(&self, side_len: u32) -> Result<Vec<PuzzleRecord>, String> {
+        let mut statement = self
+            .conn
+            .prepare(
+                "SELECT puzzle, side_len, x_sudoku, num_clues, source, seed, difficulty, guesses, backtracks
+                FROM puzzles WHERE side_len = ?1 ORDER BY num_clues, id",
+            )
+            .map_err(db_error)?;
+        let rows = statement
+            .query_map(params![side_len], |row| {
+                Ok(PuzzleRecord {
+                    puzzle: row.get(0)?,
+                    side_len: row.get(1)?,
+                    x_sudoku: row.get(2)?,
+                    num_clues: row.get::<_, u64>(3)? as usize,
+                    source: row.get(4)?,
+                    seed: row.get::<_, Option<i64>>(5)?.map(|s| s as u64),
+                    difficulty: row.get(6)?,
+                    guesses: row.get::<_, i64>(7)? as u64,
+                    backtracks: row.get::<_, i64>(8)? as u64,
+                })
+            })
+            .map_err(db_error)?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(db_error)
+    }
+}
+
+fn db_error(e: rusqlite::Error) -> String {
+    format!("Database error: {}", e)
+}
@@ -0,0 +1,69 @@
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    // The puzzle couldn't be parsed. Lines and columns count from 1, and are
+    // given when the error can be tied to a place in the input.
+    Parse {
+        message: String,
+        line: Option<usize>,
+        column: Option<usize>,
+    },
+    // The grid size isn't supported, or doesn't match the rest of the puzzle.
+    Shape(String),
+    // The input couldn't be read.
+    Io {
+        path: String,
+        source: io::Error,
+    },
+    // An output, checkpoint or database file couldn't be used.
+    Storage(String),
+    // Options which can't be used together, or with this puzzle.
+    Options(String),
+    // The solver couldn't complete the action, e.g. the puzzle has no
+    // solution.
+    Solver(String),
+}
+
+impl Error {
+    pub fn parse<S: Into<String>>(message: S) -> Error {
+        Error::Parse {
+            message: message.into(),
+            line: None,
+            column: None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse {
+                message,
+                line: Some(line),
+                column: Some(column),
+            } => write!(f, "Line {}, column {}: {}", line, column, message),
+            Error::Parse {
+                message,
+                line: Some(line),
+                column: None,
+            } => write!(f, "Line {}: {}", line, message),
+            Error::Parse { message, .. } => write!(f, "{}", message),
+            Error::Io { path, source } => write!(f, "Could not read file {}: {}", path, source),
+            Error::Shape(message)
+            | Error::Storage(message)
+            | Error::Options(message)
+            | Error::Solver(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::solver::Checkpoint;
+
+// A checkpoint file records the puzzle and action along with the search state,
+// so that a search can't be resumed against a different puzzle.
+#[derive(Serialize, Deserialize)]
+struct CheckpointFile {
+    puzzle: String,
+    action: String,
+    checkpoint: Checkpoint,
+}
+
+pub fn save(
+    path: &Path,
+    puzzle: &str,
+    action: &str,
+    checkpoint: &Checkpoint,
+) -> Result<(), String> {
+    let file = CheckpointFile {
+        puzzle: puzzle.to_string(),
+        action: action.to_string(),
+        checkpoint: checkpoint.clone(),
+    };
+    let json = serde_json::to_string(&file).map_err(|e| e.to_string())?;
+
+    // Write to a temporary file first, so that an existing checkpoint is not
+    // lost if we are interrupted while writing.
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, json)
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .map_err(|e| format!("Could not write checkpoint {}: {}", path.display(), e))
+}
+
+pub fn load(path: &Path, puzzle: &str, action: &str) -> Result<Checkpoint, String> {
+    let json = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read checkpoint {}: {}", path.display(), e))?;
+    let file: CheckpointFile = serde_json::from_str(&json)
+        .map_err(|e| format!("Invalid checkpoint {}: {}", path.display(), e))?;
+
+    if file.puzzle != puzzle {
+        return Err("Checkpoint was saved for a different puzzle.".to_string());
+    }
+    if file.action != action {
+        return Err(format!(
+            "Checkpoint was saved for the '{}' action.",
+            file.action
+        ));
+    }
+    Ok(file.checkpoint)
+}
@@ -0,0 +1,383 @@
+// Import of puzzles from f-puzzles.com and SudokuPad.
+// Both share puzzles as URLs containing the f-puzzles JSON, compressed with
+// lz-string. The JSON is converted into the solver's own JSON format.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde_json::{json, Map, Value};
+
+pub fn is_url(input: &str) -> bool {
+    input.starts_with("https://") || input.starts_with("http://")
+}
+
+// Extract the f-puzzles JSON from a URL.
+// Supports f-puzzles.com `?load=` URLs and SudokuPad `fpuzzles` URLs.
+pub fn decode_url(url: &str) -> Result<String, String> {
+    lazy_static! {
+        static ref FPUZZLES_URL_REGEX: Regex =
+            Regex::new("[?&]load=([^&#]+)|/(?:\\?puzzleid=)?fpuzzles([^?&#]+)").unwrap();
+    }
+
+    let data = FPUZZLES_URL_REGEX
+        .captures(url)
+        .and_then(|cap| cap.get(1).or_else(|| cap.get(2)))
+        .ok_or("Unsupported URL - only f-puzzles encoded puzzles are supported.")?
+        .as_str();
+
+    // The data is base64, but '+' may have been mangled by URL encoding.
+    let data = percent_decode(data)?.replace(' ', "+");
+    decompress_from_base64(&data).ok_or_else(|| "Could not decode puzzle URL.".to_string())
+}
+
+fn percent_decode(input: &str) -> Result<String, String> {
+    let mut bytes = Vec::new();
+    let mut iter = input.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next(), iter.next()]
+                .into_iter()
+                .collect::<Option<Vec<_>>>()
+                .and_then(|h| String::from_utf8(h).ok())
+                .and_then(|h| u8::from_str_radix(&h, 16).ok())
+                .ok_or("Invalid URL encoding.")?;
+            bytes.push(hex);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).map_err(|e| e.to_string())
+}
+
+// Port of lz-string's decompressFromBase64.
+fn decompress_from_base64(input: &str) -> Option<String> {
+    const KEY: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/=";
+    let values = input
+        .bytes()
+        .map(|c| KEY.iter().position(|&k| k == c).map(|p| p as u32))
+        .collect::<Option<Vec<_>>>()?;
+    decompress(&values, 32)
+}
+
+fn decompress(values: &[u32], reset_value: u32) -> Option<String> {
+    let mut reader = BitReader {
+        values,
+        reset_value,
+        val: values.first().copied().unwrap_or(0),
+        position: reset_value,
+        index: 1,
+    };
+
+    let mut dictionary: Vec<Vec<u16>> = vec![Vec::new(); 3];
+    let mut enlarge_in = 4u32;
+    let mut num_bits = 3;
+
+    let c = match reader.read_bits(2) {
+        0 => reader.read_bits(8) as u16,
+        1 => reader.read_bits(16) as u16,
+        _ => return Some(String::new()),
+    };
+    dictionary.push(vec![c]);
+    let mut w = vec![c];
+    let mut result = w.clone();
+
+    loop {
+        if reader.index > values.len() {
+            return Some(String::new());
+        }
+
+        let mut cc = reader.read_bits(num_bits) as usize;
+        match cc {
+            0 | 1 => {
+                let bits = if cc == 0 { 8 } else { 16 };
+                dictionary.push(vec![reader.read_bits(bits) as u16]);
+                cc = dictionary.len() - 1;
+                enlarge_in -= 1;
+            }
+            2 => return String::from_utf16(&result).ok(),
+            _ => {}
+        }
+
+        if enlarge_in == 0 {
+            enlarge_in = 1 << num_bits;
+            num_bits += 1;
+        }
+
+        let entry = if cc < dictionary.len() {
+            dictionary[cc].clone()
+        } else if cc == dictionary.len() {
+            let mut entry = w.clone();
+            entry.push(w[0]);
+            entry
+        } else {
+            return None;
+        };
+        result.extend(&entry);
+
+        let mut new_entry = w;
+        new_entry.push(entry[0]);
+        dictionary.push(new_entry);
+        enlarge_in -= 1;
+
+        w = entry;
+
+        if enlarge_in == 0 {
+            enlarge_in = 1 << num_bits;
+            num_bits += 1;
+        }
+    }
+}
+
+struct BitReader<'a> {
+    values: &'a [u32],
+    reset_value: u32,
+    val: u32,
+    position: u32,
+    index: usize,
+}
+
+impl BitReader<'_> {
+    fn read_bits(&mut self, num_bits: u32) -> u32 {
+        let mut bits = 0;
+        for i in 0..num_bits {
+            let bit = self.val & self.position;
+            self.position >>= 1;
+            if self.position == 0 {
+                self.position = self.reset_value;
+                self.val = self.values.get(self.index).copied().unwrap_or(0);
+                self.index += 1;
+            }
+            if bit > 0 {
+                bits |= 1 << i;
+            }
+        }
+        bits
+    }
+}
+
+pub fn is_fpuzzles_json(json: &Map<String, Value>) -> bool {
+    json.get("grid").is_some_and(Value::is_array)
+}
+
+// Convert f-puzzles JSON into the solver's JSON format.
+pub fn convert(fpuzzles: &Map<String, Value>) -> Result<Value, String> {
+    // Keys which don't affect the solution.
+    const IGNORED_KEYS: [&str; 10] = [
+        "title",
+        "author",
+        "ruleset",
+        "solution",
+        "highlightConflicts",
+        "text",
+        "line",
+        "rectangle",
+        "circle",
+        "cage",
+    ];
+
+    let mut givens = Vec::new();
+    let mut flags = Vec::new();
+    let mut constraints = Vec::new();
+    let mut negative = Vec::new();
+
+    for (key, value) in fpuzzles {
+        match key.as_str() {
+            "size" => {}
+            "grid" => givens = convert_grid(value)?,
+            "diagonal+" if value == true => flags.push("Anti-Diagonal"),
+            "diagonal-" if value == true => flags.push("Main-Diagonal"),
+            "antiking" if value == true => flags.push("Anti-King"),
+            "nonconsecutive" if value == true => flags.push("Non-Consecutive"),
+            "negative" => {
+                for item in as_array(value)? {
+                    negative.push(item.as_str().ok_or("Invalid negative constraint.")?);
+                }
+            }
+            "killercage" => {
+                for item in as_array(value)? {
+                    let cells = field(item, "cells")?.clone();
+                    match item.get("value") {
+                        Some(sum) => constraints.push(json!({
+                            "type": "cage",
+                            "sum": as_number(sum)?,
+                            "cells": cells,
+                        })),
+                        None => constraints.push(json!({ "type": "region", "cells": cells })),
+                    }
+                }
+            }
+            "thermometer" | "renban" | "whispers" | "regionsumline" | "entropicline" => {
+                let kind = match key.as_str() {
+                    "thermometer" => "thermo",
+                    "whispers" => "whisper",
+                    "regionsumline" => "region-sum",
+                    "entropicline" => "entropic",
+                    _ => "renban",
+                };
+                for item in as_array(value)? {
+                    for line in as_array(field(item, "lines")?)? {
+                        constraints.push(json!({ "type": kind, "cells": line }));
+                    }
+                }
+            }
+            "arrow" => {
+                for item in as_array(value)? {
+                    constraints.push(convert_arrow(item)?);
+                }
+            }
+            "difference" | "ratio" => {
+                let (kind, default_value) = if key == "difference" {
+                    ("white", 1)
+                } else {
+                    ("black", 2)
+                };
+                for item in as_array(value)? {
+                    if let Some(v) = item.get("value") {
+                        if as_number(v)? != default_value {
+                            return Err(format!("Unsupported {} value: {}", key, v));
+                        }
+                    }
+                    constraints.push(json!({ "type": kind, "cells": field(item, "cells")? }));
+                }
+            }
+            "xv" => {
+                for item in as_array(value)? {
+                    let kind = match field(item, "value")?.as_str() {
+                        Some("X") | Some("x") => "x",
+                        Some("V") | Some("v") => "v",
+                        _ => return Err("Invalid XV value.".to_string()),
+                    };
+                    constraints.push(json!({ "type": kind, "cells": field(item, "cells")? }));
+                }
+            }
+            "odd" | "even" => {
+                for item in as_array(value)? {
+                    constraints.push(json!({ "type": key, "cells": [field(item, "cell")?] }));
+                }
+            }
+            "extraregion" => {
+                for item in as_array(value)? {
+                    constraints.push(json!({ "type": "region", "cells": field(item, "cells")? }));
+                }
+            }
+            "clone" => {
+                for item in as_array(value)? {
+                    constraints.push(json!({
+                        "type": "clone",
+                        "cells": field(item, "cells")?,
+                        "clone": field(item, "cloneCells")?,
+                    }));
+                }
+            }
+            "quadruple" => {
+                for item in as_array(value)? {
+                    constraints.push(convert_quadruple(item)?);
+                }
+            }
+            _ if IGNORED_KEYS.contains(&key.as_str()) => {}
+            // Empty or disabled constraints don't matter.
+            _ if value == false || value.as_array().is_some_and(|a| a.is_empty()) => {}
+            _ => return Err(format!("Unsupported f-puzzles constraint: {}", key)),
+        }
+    }
+
+    let nonconsecutive = flags.contains(&"Non-Consecutive");
+    for kind in negative {
+        match kind {
+            "xv" => flags.push("XV-Negative"),
+            // Negative ratio dots are only supported along with negative
+            // difference dots, which is what Kropki-Negative means.
+            "ratio" if nonconsecutive => {
+                flags.retain(|&f| f != "Non-Consecutive");
+                flags.push("Kropki-Negative");
+            }
+            _ => return Err(format!("Unsupported negative constraint: {}", kind)),
+        }
+    }
+
+    Ok(json!({
+        "size": as_number(fpuzzles.get("size").ok_or("Missing size.")?)?,
+        "givens": givens,
+        "flags": flags,
+        "constraints": constraints,
+    }))
+}
+
+fn convert_grid(grid: &Value) -> Result<Vec<Value>, String> {
+    let mut givens = Vec::new();
+    for (r, row) in as_array(grid)?.iter().enumerate() {
+        for (c, cell) in as_array(row)?.iter().enumerate() {
+            if cell.get("region").is_some() {
+                return Err("Irregular regions are not supported.".to_string());
+            }
+            if cell.get("given") == Some(&Value::Bool(true)) {
+                let value = as_number(field(cell, "value")?)?;
+                givens.push(json!([format!("R{}C{}", r + 1, c + 1), value]));
+            }
+        }
+    }
+    Ok(givens)
+}
+
+// Each line of an arrow starts at the circle, and all the lines together sum to
+// the circle.
+fn convert_arrow(arrow: &Value) -> Result<Value, String> {
+    let circle = as_array(field(arrow, "cells")?)?;
+    let circle = match circle.as_slice() {
+        [cell] => cell,
+        _ => return Err("Arrows with multi-cell circles are not supported.".to_string()),
+    };
+
+    let mut cells = vec![circle.clone()];
+    for line in as_array(field(arrow, "lines")?)? {
+        for cell in as_array(line)?.iter().skip(1) {
+            if !cells.contains(cell) {
+                cells.push(cell.clone());
+            }
+        }
+    }
+    Ok(json!({ "type": "arrow", "cells": cells }))
+}
+
+// Quadruples are given by their four cells, rather than the top-left cell.
+fn convert_quadruple(quadruple: &Value) -> Result<Value, String> {
+    lazy_static! {
+        static ref CELL_REGEX: Regex = Regex::new("^(?i)r(\\d+)c(\\d+)$").unwrap();
+    }
+
+    let top_left = as_array(field(quadruple, "cells")?)?
+        .iter()
+        .filter_map(|cell| {
+            let cap = CELL_REGEX.captures(cell.as_str()?)?;
+            Some((cap[1].parse::<u32>().ok()?, cap[2].parse::<u32>().ok()?))
+        })
+        .min()
+        .ok_or("Invalid quadruple.")?;
+
+    let values = as_array(field(quadruple, "values")?)?
+        .iter()
+        .map(as_number)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(json!({
+        "type": "quad",
+        "cell": format!("R{}C{}", top_left.0, top_left.1),
+        "values": values,
+    }))
+}
+
+fn field<'a>(json: &'a Value, key: &str) -> Result<&'a Value, String> {
+    json.get(key)
+        .ok_or_else(|| format!("Missing field: {}", key))
+}
+
+fn as_array(json: &Value) -> Result<&Vec<Value>, String> {
+    json.as_array()
+        .ok_or_else(|| format!("Expected an array: {}", json))
+}
+
+// f-puzzles stores some numbers as strings.
+fn as_number(json: &Value) -> Result<u64, String> {
+    json.as_u64()
+        .or_else(|| json.as_str().and_then(|s| s.parse().ok()))
+        .ok_or_else(|| format!("Expected a number: {}", json))
+}
@@ -0,0 +1,97 @@
+use std::fmt::Write;
+
+use crate::solver::HandlerDescription;
+use crate::types::{CellIndex, Shape};
+
+// Exports the incidence structure between cells and constraint handlers.
+// Each handler is a hyperedge over its cells, which is represented as a
+// bipartite graph with a node for every cell and every handler.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default)]
+pub enum GraphFormat {
+    #[default]
+    Dot,
+    Graphml,
+    EdgeList,
+}
+
+pub fn render_graph(shape: &Shape, handlers: &[HandlerDescription], format: GraphFormat) -> String {
+    match format {
+        GraphFormat::Dot => render_dot(shape, handlers),
+        GraphFormat::Graphml => render_graphml(shape, handlers),
+        GraphFormat::EdgeList => render_edge_list(handlers),
+    }
+}
+
+fn cell_label(shape: &Shape, cell: CellIndex) -> String {
+    let side_len = shape.side_len as usize;
+    format!("R{}C{}", cell / side_len + 1, cell % side_len + 1)
+}
+
+fn render_dot(shape: &Shape, handlers: &[HandlerDescription]) -> String {
+    let mut output = String::new();
+
+    output.push_str("graph constraints {\n");
+    for cell in 0..shape.num_cells {
+        let label = cell_label(shape, cell);
+        writeln!(output, "  c{cell} [label=\"{label}\" shape=ellipse];").unwrap();
+    }
+    for (i, handler) in handlers.iter().enumerate() {
+        writeln!(output, "  h{i} [label=\"{}\" shape=box];", handler.kind).unwrap();
+    }
+    for (i, handler) in handlers.iter().enumerate() {
+        for cell in &handler.cells {
+            writeln!(output, "  h{i} -- c{cell};").unwrap();
+        }
+    }
+    output.push_str("}\n");
+
+    output
+}
+
+fn render_graphml(shape: &Shape, handlers: &[HandlerDescription]) -> String {
+    let mut output = String::new();
+
+    output.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    output.push_str("<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n");
+    output.push_str("  <key id=\"type\" for=\"node\" attr.name=\"type\" attr.type=\"string\"/>\n");
+    output
+        .push_str("  <key id=\"label\" for=\"node\" attr.name=\"label\" attr.type=\"string\"/>\n");
+    output.push_str("  <graph id=\"constraints\" edgedefault=\"undirected\">\n");
+    for cell in 0..shape.num_cells {
+        let label = cell_label(shape, cell);
+        writeln!(
+            output,
+            "    <node id=\"c{cell}\"><data key=\"type\">cell</data><data key=\"label\">{label}</data></node>"
+        )
+        .unwrap();
+    }
+    for (i, handler) in handlers.iter().enumerate() {
+        writeln!(
+            output,
+            "    <node id=\"h{i}\"><data key=\"type\">handler</data><data key=\"label\">{}</data></node>",
+            handler.kind
+        )
+        .unwrap();
+    }
+    for (i, handler) in handlers.iter().enumerate() {
+        for cell in &handler.cells {
+            writeln!(output, "    <edge source=\"h{i}\" target=\"c{cell}\"/>").unwrap();
+        }
+    }
+    output.push_str("  </graph>\n");
+    output.push_str("</graphml>\n");
+
+    output
+}
+
+fn render_edge_list(handlers: &[HandlerDescription]) -> String {
+    let mut output = String::new();
+
+    for (i, handler) in handlers.iter().enumerate() {
+        for cell in &handler.cells {
+            writeln!(output, "h{i} c{cell}").unwrap();
+        }
+    }
+
+    output
+}
@@ -1,18 +1,34 @@
 use std::io::Read;
 use std::{fs, io};
 
-use super::parser;
+use super::{fpuzzles, parser};
+use crate::error::Error;
+
+// Load the input, split into separate puzzles.
+pub fn load_puzzles(input: &str) -> Result<Vec<String>, Error> {
+    Ok(parser::split_puzzles(&load(input)?))
+}
+
+pub fn load(input: &str) -> Result<String, Error> {
+    let io_error = |source| Error::Io {
+        path: input.to_string(),
+        source,
+    };
 
-pub fn load(input: &str) -> Result<String, io::Error> {
     if input == "-" {
         let mut content = String::new();
-        io::stdin().read_to_string(&mut content)?;
+        io::stdin().read_to_string(&mut content).map_err(io_error)?;
         return Ok(content);
     }
 
-    if parser::parse_shape_spec(input).is_some() {
+    if fpuzzles::is_url(input) {
+        return Ok(input.to_string());
+    }
+
+    // Accept any side length here, as the puzzle may be a latin square.
+    if parser::parse_shape_spec(input, true).is_some() {
         return Ok(input.to_string());
     }
 
-    fs::read_to_string(input)
+    fs::read_to_string(input).map_err(io_error)
 }
@@ -0,0 +1,128 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::io::output;
+use crate::solver::Counters;
+
+// How the snapshots are written: as CSV rows under a header, or as JSON lines.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default)]
+pub enum MetricsFormat {
+    #[default]
+    Csv,
+    Json,
+}
+
+// The columns of the CSV format, after the timestamp. These are the fields of
+// the counters in the JSON output, with the progress bounds split in two.
+const COLUMNS: &[&str] = &[
+    "elapsed_secs",
+    "solutions",
+    "guesses",
+    "constraints_processed",
+    "values_tried",
+    "cells_searched",
+    "backtracks",
+    "restarts",
+    "nogoods_learned",
+    "nogood_prunes",
+    "progress_ratio",
+    "progress_lower",
+    "progress_upper",
+    "peak_memory",
+    "nodes_per_sec",
+    "eta_secs",
+    "limit_reached",
+    "cancelled",
+];
+
+// Appends snapshots of the counters to a metrics file. Each snapshot has the
+// time it was taken, in seconds since the Unix epoch. Errors can't be returned
+// from the metrics callback, so the first one is kept and returned by `finish`.
+pub struct MetricsWriter {
+    path: PathBuf,
+    format: MetricsFormat,
+    writer: BufWriter<File>,
+    error: Option<io::Error>,
+}
+
+impl MetricsWriter {
+    pub fn create(path: &Path, format: MetricsFormat) -> Result<MetricsWriter, String> {
+        let error = |e: io::Error| format!("Could not write metrics {}: {}", path.display(), e);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(error)?;
+        // The header is only written at the start of the file, so that runs
+        // can be appended to the same file.
+        let is_empty = file.metadata().map_err(error)?.len() == 0;
+        let mut new = MetricsWriter {
+            path: path.to_path_buf(),
+            format,
+            writer: BufWriter::new(file),
+            error: None,
+        };
+        if is_empty && matches!(format, MetricsFormat::Csv) {
+            new.write_line(&format!("timestamp,{}", COLUMNS.join(",")));
+        }
+        Ok(new)
+    }
+
+    pub fn write(&mut self, counters: &Counters) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |time| time.as_secs_f64());
+        let mut json = output::counters_json(counters);
+        let line = match self.format {
+            MetricsFormat::Csv => {
+                let (lower, upper) = json["progress_bounds"]
+                    .as_array()
+                    .map_or((serde_json::Value::Null, serde_json::Value::Null), |b| {
+                        (b[0].clone(), b[1].clone())
+                    });
+                json["progress_lower"] = lower;
+                json["progress_upper"] = upper;
+                let mut line = format!("{:.3}", timestamp);
+                for column in COLUMNS {
+                    line.push(',');
+                    if !json[column].is_null() {
+                        line.push_str(&json[column].to_string());
+                    }
+                }
+                line
+            }
+            MetricsFormat::Json => {
+                json["timestamp"] = serde_json::json!(timestamp);
+                json.to_string()
+            }
+        };
+        self.write_line(&line);
+        // Flush each snapshot, so that the file can be followed while the
+        // search runs.
+        if self.error.is_none() {
+            self.error = self.writer.flush().err();
+        }
+    }
+
+    pub fn finish(&mut self) -> Result<(), String> {
+        if self.error.is_none() {
+            self.error = self.writer.flush().err();
+        }
+        match self.error.take() {
+            Some(e) => Err(format!(
+                "Could not write metrics {}: {}",
+                self.path.display(),
+                e
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if self.error.is_none() {
+            self.error = writeln!(self.writer, "{}", line).err();
+        }
+    }
+}
@@ -1,3 +1,9 @@
+pub mod checkpoint;
+pub mod fpuzzles;
+pub mod graph;
 pub mod input;
+pub mod metrics;
 pub mod output;
 pub mod parser;
+pub mod search_tree;
+pub mod trace;
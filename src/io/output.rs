@@ -1,38 +1,130 @@
+use std::cell::{Cell, RefCell};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use indicatif::ProgressBar;
 use indicatif::ProgressStyle;
 use lazy_static::lazy_static;
 
+use super::parser;
+use crate::bench;
 use crate::solver;
 use crate::types;
 
-pub fn solver_item_as_grid(constraint: &types::Constraint, item: &solver::Output) -> String {
+// How solutions and puzzles are serialized.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default)]
+pub enum OutputFormat {
+    // Aligned rows of values.
+    #[default]
+    Grid,
+    // The single line short format.
+    Short,
+    // One line of comma-separated values per row.
+    Csv,
+    // A JSON puzzle description, which can be read back as input.
+    Json,
+}
+
+impl OutputFormat {
+    fn render(&self, constraint: &types::Constraint, grid: &[Option<types::CellValue>]) -> String {
+        match self {
+            OutputFormat::Grid => render_grid(constraint, grid),
+            OutputFormat::Short => render_short_text(constraint, grid),
+            OutputFormat::Csv => render_csv(constraint, grid),
+            OutputFormat::Json => render_json(constraint, grid),
+        }
+    }
+}
+
+// Options controlling how results are written.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OutputOptions {
+    pub format: OutputFormat,
+    // Write each result as a JSON object on its own line instead, followed by
+    // a summary of the counters.
+    pub json_lines: bool,
+}
+
+impl OutputOptions {
+    pub fn solver_item(&self, constraint: &types::Constraint, item: &solver::Output) -> String {
+        if self.json_lines {
+            solver_item_as_json_line(constraint, item)
+        } else {
+            solver_item_as_text(constraint, item, self.format)
+        }
+    }
+
+    // The file extension for files containing the output.
+    pub fn file_extension(&self) -> &'static str {
+        match (self.json_lines, self.format) {
+            (true, _) => "jsonl",
+            (false, OutputFormat::Grid | OutputFormat::Short) => "txt",
+            (false, OutputFormat::Csv) => "csv",
+            (false, OutputFormat::Json) => "json",
+        }
+    }
+
+    pub fn fixed_values(
+        &self,
+        constraint: &types::Constraint,
+        fixed_values: &types::FixedValues,
+    ) -> String {
+        if self.json_lines {
+            fixed_values_as_json_line(constraint, fixed_values)
+        } else {
+            fixed_values_as_text(constraint, fixed_values, self.format)
+        }
+    }
+}
+
+pub fn solver_item_as_text(
+    constraint: &types::Constraint,
+    item: &solver::Output,
+    format: OutputFormat,
+) -> String {
     match item {
-        solver::Output::Solution(solution) => solution_as_grid(constraint, solution),
-        solver::Output::Guesses(fixed_values) => fixed_values_as_grid(constraint, fixed_values),
+        solver::Output::Solution(solution) => solution_as_text(constraint, solution, format),
+        solver::Output::Guesses(fixed_values) => {
+            fixed_values_as_text(constraint, fixed_values, format)
+        }
         solver::Output::Empty => String::new(),
     }
 }
 
-fn solution_as_grid(constraint: &types::Constraint, solution: &types::Solution) -> String {
-    render_grid(
+pub fn solution_as_text(
+    constraint: &types::Constraint,
+    solution: &types::Solution,
+    format: OutputFormat,
+) -> String {
+    format.render(
         constraint,
         &solution.iter().map(|&v| Some(v)).collect::<Vec<_>>(),
     )
 }
 
-pub fn fixed_values_as_grid(
+pub fn fixed_values_as_text(
     constraint: &types::Constraint,
     fixed_values: &types::FixedValues,
+    format: OutputFormat,
 ) -> String {
     let shape = &constraint.shape;
     let mut grid = vec![None; shape.num_cells];
     for (cell, value) in fixed_values {
         grid[*cell] = Some(*value);
     }
-    render_grid(constraint, &grid)
+    format.render(constraint, &grid)
+}
+
+pub fn fixed_values_as_grid(
+    constraint: &types::Constraint,
+    fixed_values: &types::FixedValues,
+) -> String {
+    fixed_values_as_text(constraint, fixed_values, OutputFormat::Grid)
 }
 
 fn render_grid(constraint: &types::Constraint, grid: &[Option<types::CellValue>]) -> String {
@@ -59,6 +151,594 @@ fn render_grid(constraint: &types::Constraint, grid: &[Option<types::CellValue>]
     output
 }
 
+// Render the grid on a single line, with each cell as a fixed-width base-36
+// number. This is the inverse of the short input format.
+fn render_short_text(constraint: &types::Constraint, grid: &[Option<types::CellValue>]) -> String {
+    let width = parser::short_cell_width(constraint.shape.num_values);
+    let mut output = String::with_capacity(grid.len() * width + 1);
+
+    for cell in grid {
+        match cell {
+            None => (0..width).for_each(|_| output.push('.')),
+            Some(v) => {
+                let mut digits = vec!['0'; width];
+                let mut value = v.display_value() as u32;
+                for digit in digits.iter_mut().rev() {
+                    *digit = char::from_digit(
+                        value % parser::SHORT_FORMAT_RADIX,
+                        parser::SHORT_FORMAT_RADIX,
+                    )
+                    .unwrap();
+                    value /= parser::SHORT_FORMAT_RADIX;
+                }
+                output.extend(digits);
+            }
+        }
+    }
+    output.push('\n');
+
+    output
+}
+
+fn render_csv(constraint: &types::Constraint, grid: &[Option<types::CellValue>]) -> String {
+    let mut output = String::new();
+
+    for row in grid.chunks(constraint.shape.side_len as usize) {
+        let row = row
+            .iter()
+            .map(|v| v.map_or(String::new(), |v| v.to_string()))
+            .collect::<Vec<_>>();
+        output.push_str(&row.join(","));
+        output.push('\n');
+    }
+
+    output
+}
+
+// The grid is written as givens, in the same form as the JSON input.
+fn render_json(constraint: &types::Constraint, grid: &[Option<types::CellValue>]) -> String {
+    let side_len = constraint.shape.side_len as usize;
+    let givens = grid
+        .iter()
+        .enumerate()
+        .filter_map(|(cell, v)| {
+            let name = format!("R{}C{}", cell / side_len + 1, cell % side_len + 1);
+            v.map(|v| serde_json::json!([name, v.display_value()]))
+        })
+        .collect::<Vec<_>>();
+
+    let mut json = serde_json::json!({
+        "size": side_len,
+        "givens": givens,
+    });
+    if constraint.shape.box_size.is_none() {
+        json["flags"] = serde_json::json!(["Latin-Square"]);
+    }
+    let mut output = json.to_string();
+    output.push('\n');
+
+    output
+}
+
+fn solver_item_as_json_line(constraint: &types::Constraint, item: &solver::Output) -> String {
+    match item {
+        solver::Output::Solution(solution) => json_line(serde_json::json!({
+            "type": "solution",
+            "values": solution.iter().map(|v| v.display_value()).collect::<Vec<_>>(),
+        })),
+        solver::Output::Guesses(fixed_values) => {
+            fixed_values_as_json_line(constraint, fixed_values)
+        }
+        solver::Output::Empty => String::new(),
+    }
+}
+
+// Puzzles are written as the values of each cell, with null for empty cells.
+fn fixed_values_as_json_line(
+    constraint: &types::Constraint,
+    fixed_values: &types::FixedValues,
+) -> String {
+    let mut values = vec![None; constraint.shape.num_cells];
+    for (cell, value) in fixed_values {
+        values[*cell] = Some(value.display_value());
+    }
+    json_line(serde_json::json!({
+        "type": "puzzle",
+        "values": values,
+    }))
+}
+
+fn cell_name(cell: types::CellIndex, shape: &types::Shape) -> String {
+    let side_len = shape.side_len as usize;
+    format!("R{}C{}", cell / side_len + 1, cell % side_len + 1)
+}
+
+// A deduction as a sentence, e.g. "R3C5=7 because hidden single in box 2".
+pub fn deduction_as_text(constraint: &types::Constraint, deduction: &solver::Deduction) -> String {
+    let shape = &constraint.shape;
+    let changes = deduction
+        .placements
+        .iter()
+        .map(|(cell, value)| format!("{}={}", cell_name(*cell, shape), value))
+        .chain(
+            deduction
+                .eliminations
+                .iter()
+                .map(|(cell, value)| format!("{}<>{}", cell_name(*cell, shape), value)),
+        )
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut reason = deduction.technique.name(deduction.size);
+    if !deduction.houses.is_empty() {
+        reason = format!("{} in {}", reason, deduction.houses.join(", "));
+    }
+    format!("{} because {}", changes, reason)
+}
+
+pub fn deduction_as_json_line(
+    constraint: &types::Constraint,
+    deduction: &solver::Deduction,
+) -> String {
+    let shape = &constraint.shape;
+    let cell_values = |values: &types::FixedValues| {
+        values
+            .iter()
+            .map(|(cell, value)| {
+                serde_json::json!([cell_name(*cell, shape), value.display_value()])
+            })
+            .collect::<Vec<_>>()
+    };
+    json_line(serde_json::json!({
+        "type": "deduction",
+        "technique": deduction.technique.name(deduction.size),
+        "houses": deduction.houses,
+        "placements": cell_values(&deduction.placements),
+        "eliminations": cell_values(&deduction.eliminations),
+    }))
+}
+
+pub fn violation_as_text(constraint: &types::Constraint, violation: &solver::Violation) -> String {
+    let cells = violation
+        .cells
+        .iter()
+        .map(|cell| cell_name(*cell, &constraint.shape))
+        .collect::<Vec<_>>()
+        .join(", ");
+    match &violation.house {
+        Some(house) => format!("Violated {}: {}", house, cells),
+        None => format!("Violated {}: {}", violation.kind, cells),
+    }
+}
+
+pub fn violation_as_json_line(
+    constraint: &types::Constraint,
+    violation: &solver::Violation,
+) -> String {
+    json_line(serde_json::json!({
+        "type": "violation",
+        "kind": violation.kind,
+        "house": violation.house,
+        "cells": violation
+            .cells
+            .iter()
+            .map(|cell| cell_name(*cell, &constraint.shape))
+            .collect::<Vec<_>>(),
+    }))
+}
+
+// One clue per line, e.g. "R1C1=5" or "cage R1C2 R1C3".
+pub fn diagnosis_as_text(constraint: &types::Constraint, diagnosis: &solver::Diagnosis) -> String {
+    let shape = &constraint.shape;
+    let mut output = String::new();
+    for (cell, value) in &diagnosis.fixed_values {
+        output.push_str(&format!("{}={}\n", cell_name(*cell, shape), value));
+    }
+    for variant in &diagnosis.variants {
+        let cells = variant.cells().iter().map(|cell| cell_name(*cell, shape));
+        output.push_str(&format!(
+            "{} {}\n",
+            variant.name(),
+            cells.collect::<Vec<_>>().join(" ")
+        ));
+    }
+    output
+}
+
+// One removable given per line, e.g. "R1C1=5", after a summary line.
+pub fn minimality_as_text(
+    constraint: &types::Constraint,
+    minimality: &solver::Minimality,
+) -> String {
+    if !minimality.unique {
+        return "Not minimal: the puzzle doesn't have a unique solution.\n".to_string();
+    }
+    if minimality.is_minimal() {
+        return "Minimal: removing any given gives more solutions.\n".to_string();
+    }
+    let mut output = format!(
+        "Not minimal: {} givens can each be removed:\n",
+        minimality.removable.len()
+    );
+    for (cell, value) in &minimality.removable {
+        output.push_str(&format!(
+            "{}={}\n",
+            cell_name(*cell, &constraint.shape),
+            value
+        ));
+    }
+    output
+}
+
+pub fn minimality_as_json_line(
+    constraint: &types::Constraint,
+    minimality: &solver::Minimality,
+) -> String {
+    json_line(serde_json::json!({
+        "type": "minimality",
+        "minimal": minimality.is_minimal(),
+        "unique": minimality.unique,
+        "removable": minimality
+            .removable
+            .iter()
+            .map(|(cell, value)| serde_json::json!([cell_name(*cell, &constraint.shape), value.display_value()]))
+            .collect::<Vec<_>>(),
+        "counters": counters_json(&minimality.counters),
+    }))
+}
+
+// Fingerprints are written as 16 hex digits.
+pub fn fingerprint_as_text(fingerprint: u64) -> String {
+    format!("{:016x}", fingerprint)
+}
+
+pub fn fingerprint_as_json_line(fingerprint: u64) -> String {
+    json_line(serde_json::json!({
+        "type": "fingerprint",
+        "fingerprint": fingerprint_as_text(fingerprint),
+    }))
+}
+
+pub fn diagnosis_as_json_line(
+    constraint: &types::Constraint,
+    diagnosis: &solver::Diagnosis,
+) -> String {
+    let shape = &constraint.shape;
+    json_line(serde_json::json!({
+        "type": "diagnosis",
+        "givens": diagnosis
+            .fixed_values
+            .iter()
+            .map(|(cell, value)| serde_json::json!([cell_name(*cell, shape), value.display_value()]))
+            .collect::<Vec<_>>(),
+        "constraints": diagnosis
+            .variants
+            .iter()
+            .map(|variant| serde_json::json!({
+                "type": variant.name(),
+                "cells": variant
+                    .cells()
+                    .iter()
+                    .map(|cell| cell_name(*cell, shape))
+                    .collect::<Vec<_>>(),
+            }))
+            .collect::<Vec<_>>(),
+        "limit_reached": diagnosis.limit_reached,
+    }))
+}
+
+// Trace files only record the side length, which is all that cell names need.
+fn trace_cell_name(cell: types::CellIndex, side_len: u32) -> String {
+    cell_name(cell, &types::Shape::new_latin_square(side_len))
+}
+
+// An event from a trace file, after its depth, e.g. "   3 guess R1C2=5".
+pub fn trace_event_as_text(side_len: u32, event: &solver::TraceEvent) -> String {
+    match *event {
+        solver::TraceEvent::Guess {
+            depth, cell, value, ..
+        } => format!(
+            "{:>4} guess {}={}",
+            depth,
+            trace_cell_name(cell, side_len),
+            value
+        ),
+        solver::TraceEvent::LastValue {
+            depth, cell, value, ..
+        } => format!(
+            "{:>4} last value {}={}",
+            depth,
+            trace_cell_name(cell, side_len),
+            value
+        ),
+        solver::TraceEvent::Propagation {
+            depth,
+            handlers,
+            eliminations,
+            contradiction,
+        } => format!(
+            "{:>4} propagate {} handlers, {} eliminations{}",
+            depth,
+            handlers,
+            eliminations,
+            if contradiction {
+                " - contradiction"
+            } else {
+                ""
+            }
+        ),
+        solver::TraceEvent::Backtrack { depth, cell } => {
+            format!("{:>4} backtrack {}", depth, trace_cell_name(cell, side_len))
+        }
+        solver::TraceEvent::Solution { depth } => format!("{:>4} solution", depth),
+        solver::TraceEvent::Restart => "   0 restart".to_string(),
+    }
+}
+
+pub fn trace_event_as_json_line(side_len: u32, event: &solver::TraceEvent) -> String {
+    json_line(match *event {
+        solver::TraceEvent::Guess {
+            depth,
+            cell,
+            value,
+            progress,
+        } => serde_json::json!({
+            "type": "guess",
+            "depth": depth,
+            "cell": trace_cell_name(cell, side_len),
+            "value": value.display_value(),
+            "progress": progress,
+        }),
+        solver::TraceEvent::LastValue {
+            depth,
+            cell,
+            value,
+            progress,
+        } => serde_json::json!({
+            "type": "last_value",
+            "depth": depth,
+            "cell": trace_cell_name(cell, side_len),
+            "value": value.display_value(),
+            "progress": progress,
+        }),
+        solver::TraceEvent::Propagation {
+            depth,
+            handlers,
+            eliminations,
+            contradiction,
+        } => serde_json::json!({
+            "type": "propagation",
+            "depth": depth,
+            "handlers": handlers,
+            "eliminations": eliminations,
+            "contradiction": contradiction,
+        }),
+        solver::TraceEvent::Backtrack { depth, cell } => serde_json::json!({
+            "type": "backtrack",
+            "depth": depth,
+            "cell": trace_cell_name(cell, side_len),
+        }),
+        solver::TraceEvent::Solution { depth } => serde_json::json!({
+            "type": "solution",
+            "depth": depth,
+        }),
+        solver::TraceEvent::Restart => serde_json::json!({
+            "type": "restart",
+            "depth": 0,
+        }),
+    })
+}
+
+// With `set_deterministic`, the timings and memory use are left out of the
+// counters, so that the output is the same on every run.
+static DETERMINISTIC: AtomicBool = AtomicBool::new(false);
+
+pub fn set_deterministic(deterministic: bool) {
+    DETERMINISTIC.store(deterministic, Ordering::Relaxed);
+}
+
+pub fn counters_json(counters: &solver::Counters) -> serde_json::Value {
+    let mut counters = *counters;
+    if DETERMINISTIC.load(Ordering::Relaxed) {
+        counters.clear_measurements();
+    }
+    let mut json = serde_json::json!({
+        "solutions": counters.solutions,
+        "guesses": counters.guesses,
+        "constraints_processed": counters.constraints_processed,
+        "values_tried": counters.values_tried,
+        "cells_searched": counters.cells_searched,
+        "backtracks": counters.backtracks,
+        "restarts": counters.restarts,
+        "nogoods_learned": counters.nogoods_learned,
+        "nogood_prunes": counters.nogood_prunes,
+        "progress_ratio": counters.progress_ratio,
+        "progress_bounds": counters.progress_bounds,
+        "peak_memory": counters.peak_memory,
+        "elapsed_secs": counters.elapsed_secs,
+        "nodes_per_sec": counters.nodes_per_sec,
+        "eta_secs": counters.eta_secs,
+        "limit_reached": counters.limit_reached,
+        "cancelled": counters.cancelled,
+    });
+    if let Some(profile) = &counters.handler_profile {
+        json["handler_profile"] = handler_profile_json(profile);
+    }
+    json
+}
+
+// Only the kinds of handlers which were run are included.
+fn handler_profile_json(profile: &solver::HandlerProfile) -> serde_json::Value {
+    profile
+        .kinds()
+        .filter(|(_, stats)| stats.invocations > 0)
+        .map(|(kind, stats)| {
+            let stats = serde_json::json!({
+                "invocations": stats.invocations,
+                "eliminations": stats.eliminations,
+                "elapsed_secs": stats.elapsed_secs,
+            });
+            (kind.to_string(), stats)
+        })
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+pub fn handler_profile_as_text(profile: &solver::HandlerProfile) -> String {
+    let mut profile = *profile;
+    if DETERMINISTIC.load(Ordering::Relaxed) {
+        profile.clear_times();
+    }
+    let total_secs = profile.stats.iter().map(|s| s.elapsed_secs).sum::<f64>();
+    let mut output = format!(
+        "{:<14} {:>12} {:>12} {:>10} {:>6}\n",
+        "handler", "invocations", "eliminated", "time (s)", "time"
+    );
+    for (kind, stats) in profile.kinds().filter(|(_, s)| s.invocations > 0) {
+        let percent = if total_secs > 0.0 {
+            100.0 * stats.elapsed_secs / total_secs
+        } else {
+            0.0
+        };
+        output += &format!(
+            "{:<14} {:>12} {:>12} {:>10.3} {:>5.1}%\n",
+            kind, stats.invocations, stats.eliminations, stats.elapsed_secs, percent
+        );
+    }
+    output
+}
+
+pub fn benchmark_table_header() -> String {
+    format!(
+        "{:<14} {:>5} {:>10} {:>9} {:>10} {:>10} {:>12} {:>10}\n",
+        "benchmark",
+        "runs",
+        "time (s)",
+        "solutions",
+        "guesses",
+        "backtracks",
+        "constraints",
+        "memory"
+    )
+}
+
+pub fn benchmark_as_text(name: &str, result: &bench::BenchmarkResult) -> String {
+    let counters = &result.counters;
+    format!(
+        "{:<14} {:>5} {:>10.4} {:>9} {:>10} {:>10} {:>12} {:>10}\n",
+        name,
+        result.runs,
+        result.secs,
+        counters.solutions,
+        counters.guesses,
+        counters.backtracks,
+        counters.constraints_processed,
+        format_bytes(counters.peak_memory)
+    )
+}
+
+pub fn benchmark_as_json_line(name: &str, result: &bench::BenchmarkResult) -> String {
+    json_line(serde_json::json!({
+        "type": "benchmark",
+        "name": name,
+        "runs": result.runs,
+        "secs": result.secs,
+        "counters": counters_json(&result.counters),
+    }))
+}
+
+pub fn counters_as_json_line(counters: &solver::Counters) -> String {
+    json_line(serde_json::json!({
+        "type": "summary",
+        "counters": counters_json(counters),
+    }))
+}
+
+pub fn estimate_json(estimate: &solver::Estimate) -> serde_json::Value {
+    serde_json::json!({
+        "samples": estimate.samples,
+        "log10_solutions": estimate.log10_solutions,
+        "log10_std_error": estimate.log10_std_error,
+        "log10_lower": estimate.log10_lower,
+        "log10_upper": estimate.log10_upper,
+        "dead_ends": estimate.dead_ends,
+        "limit_reached": estimate.limit_reached,
+        "cancelled": estimate.cancelled,
+    })
+}
+
+// Format a number given as log10 in scientific notation, e.g. "6.6709e21".
+pub fn format_log10(log10: f64) -> String {
+    if log10 == f64::NEG_INFINITY {
+        return "0".to_string();
+    }
+    let exponent = log10.floor();
+    let mantissa = 10f64.powf(log10 - exponent);
+    // Rounding may give a mantissa of 10.
+    let (mantissa, exponent) = if format!("{:.4}", mantissa) == "10.0000" {
+        (1.0, exponent + 1.0)
+    } else {
+        (mantissa, exponent)
+    };
+    format!("{:.4}e{}", mantissa, exponent)
+}
+
+pub fn estimate_as_json_line(estimate: &solver::Estimate) -> String {
+    json_line(serde_json::json!({
+        "type": "estimate",
+        "estimate": estimate_json(estimate),
+    }))
+}
+
+pub fn rating_as_text(rating: &solver::Rating) -> String {
+    let reason = match (&rating.search_counters, rating.hardest_technique) {
+        (Some(counters), _) if counters.solutions == 0 => "no solution".to_string(),
+        (Some(counters), _) => format!(
+            "needs search: {} guesses, {} backtracks",
+            counters.guesses, counters.backtracks
+        ),
+        (None, Some((technique, size))) => technique.name(size),
+        (None, None) => "no deductions needed".to_string(),
+    };
+    format!(
+        "{} {:.2} ({})",
+        rating.difficulty().name(),
+        rating.score,
+        reason
+    )
+}
+
+pub fn rating_as_json_line(rating: &solver::Rating) -> String {
+    json_line(serde_json::json!({
+        "type": "rating",
+        "score": rating.score,
+        "difficulty": rating.difficulty().name(),
+        "hardest_technique": rating.hardest_technique.map(|(t, size)| t.name(size)),
+        "solved_by_techniques": rating.solved_by_techniques,
+        "counters": rating.search_counters.as_ref().map(counters_json),
+    }))
+}
+
+pub fn minimizer_counters_json(counters: &solver::MinimizerCounters) -> serde_json::Value {
+    serde_json::json!({
+        "cells_tried": counters.cells_tried,
+        "cells_removed": counters.cells_removed,
+        "target_reached": counters.target_reached,
+        "counters": counters_json(&counters.solver_counters),
+    })
+}
+
+pub fn minimizer_counters_as_json_line(counters: &solver::MinimizerCounters) -> String {
+    let mut json = minimizer_counters_json(counters);
+    json["type"] = "summary".into();
+    json_line(json)
+}
+
+fn json_line(json: serde_json::Value) -> String {
+    let mut output = json.to_string();
+    output.push('\n');
+    output
+}
+
 pub fn solution_compact(solution: &types::Solution) -> String {
     format!(
         "[{}]",
@@ -70,20 +750,153 @@ pub fn solution_compact(solution: &types::Solution) -> String {
     )
 }
 
-pub fn with_progress_bar<F: FnOnce(Rc<ProgressBar>)>(scale: u64, f: F) {
-    let bar = Rc::new(ProgressBar::new(scale));
-    bar.set_style(
-        ProgressStyle::default_bar()
-            .template("[{elapsed_precise}] {wide_bar:cyan/blue} {percent}%\n{wide_msg}"),
-    );
-    bar.enable_steady_tick(1000);
-    bar.set_position(0);
-    bar.set_message("Initializing...");
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", value, UNITS[unit])
+}
+
+// How progress is reported on stderr.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProgressMode {
+    #[default]
+    Bar,
+    // Periodic JSON progress records, one per line.
+    Json,
+}
+
+static JSON_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_progress_mode(mode: ProgressMode) {
+    JSON_PROGRESS.store(mode == ProgressMode::Json, Ordering::Relaxed);
+}
+
+fn progress_mode() -> ProgressMode {
+    if JSON_PROGRESS.load(Ordering::Relaxed) {
+        ProgressMode::Json
+    } else {
+        ProgressMode::Bar
+    }
+}
+
+// Reports progress as either a progress bar or JSON records.
+pub struct Progress {
+    bar: Option<ProgressBar>,
+    scale: u64,
+    start: Instant,
+    last_report: Cell<Option<Instant>>,
+    // The latest position and record, which is reported when finished.
+    latest: RefCell<Option<(u64, serde_json::Value)>>,
+}
+
+impl Progress {
+    // Minimum time between JSON progress records.
+    const JSON_INTERVAL: Duration = Duration::from_secs(1);
+
+    fn new(scale: u64, mode: ProgressMode) -> Progress {
+        let bar = (mode == ProgressMode::Bar).then(|| {
+            let bar = ProgressBar::new(scale);
+            bar.set_style(
+                ProgressStyle::default_bar()
+                    .template("[{elapsed_precise}] {wide_bar:cyan/blue} {percent}%\n{wide_msg}"),
+            );
+            bar.enable_steady_tick(1000);
+            bar.set_position(0);
+            bar.set_message("Initializing...");
+            bar
+        });
+
+        Progress {
+            bar,
+            scale,
+            start: Instant::now(),
+            last_report: Cell::new(None),
+            latest: RefCell::new(None),
+        }
+    }
+
+    // `message` is shown on the progress bar, while `record` contains the
+    // counters for JSON progress records.
+    pub fn update<M, R>(&self, position: u64, message: M, record: R)
+    where
+        M: FnOnce() -> String,
+        R: FnOnce() -> serde_json::Value,
+    {
+        if let Some(bar) = &self.bar {
+            bar.set_position(position);
+            bar.set_message(message());
+            return;
+        }
+
+        let record = record();
+        let due = self
+            .last_report
+            .get()
+            .is_none_or(|last| last.elapsed() >= Self::JSON_INTERVAL);
+        if due {
+            self.last_report.set(Some(Instant::now()));
+            self.report(position, &record, false);
+        }
+        *self.latest.borrow_mut() = Some((position, record));
+    }
 
-    f(bar.clone());
+    fn report(&self, position: u64, record: &serde_json::Value, done: bool) {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let ratio = if self.scale == 0 {
+            1.0
+        } else {
+            (position as f64 / self.scale as f64).min(1.0)
+        };
+        // The solver's counters have their own ETA, from the recent search
+        // rate, so the same one is reported.
+        let eta = match record.get("eta_secs") {
+            Some(eta) => eta.clone(),
+            None => serde_json::json!((ratio > 0.0).then(|| elapsed * (1.0 - ratio) / ratio)),
+        };
+        eprintln!(
+            "{}",
+            serde_json::json!({
+                "type": "progress",
+                "done": done,
+                "elapsed": elapsed,
+                "progress": ratio,
+                "eta": eta,
+                "counters": record,
+            })
+        );
+    }
 
-    bar.set_style(ProgressStyle::default_bar().template("[{elapsed_precise}] {msg}"));
-    bar.finish();
+    fn finish(&self) {
+        match &self.bar {
+            Some(bar) => {
+                bar.set_style(ProgressStyle::default_bar().template("[{elapsed_precise}] {msg}"));
+                bar.finish();
+            }
+            None => {
+                if let Some((position, record)) = self.latest.borrow().as_ref() {
+                    self.report(*position, record, true);
+                }
+            }
+        }
+    }
+}
+
+pub fn format_duration(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+pub fn with_progress_bar<F: FnOnce(Rc<Progress>)>(scale: u64, f: F) {
+    let progress = Rc::new(Progress::new(scale, progress_mode()));
+
+    f(progress.clone());
+
+    progress.finish();
 }
 
 pub fn print_above_progress_bar(output: &str) {
@@ -91,7 +904,14 @@ pub fn print_above_progress_bar(output: &str) {
         return;
     }
 
-    if atty::is(atty::Stream::Stdout) {
+    write_above_progress_bar(output);
+
+    // Print another line between solutions.
+    println!();
+}
+
+fn write_above_progress_bar(output: &str) {
+    if atty::is(atty::Stream::Stdout) && progress_mode() == ProgressMode::Bar {
         // We only need to worry about the bar if stdout is going to a tty.
 
         // Erase the bar (two lines).
@@ -108,9 +928,6 @@ pub fn print_above_progress_bar(output: &str) {
     } else {
         print!("{}", output);
     }
-
-    // Print another line between solutions.
-    println!();
 }
 
 pub trait Writer {
@@ -119,12 +936,30 @@ pub trait Writer {
 
 pub type ProgressWriter = Box<dyn Writer>;
 
-pub fn get_writer(output_last: bool) -> ProgressWriter {
-    let mut writer: ProgressWriter = Box::new(ProgressBarWriter {});
+// If `output_file` is given then results are written there instead of stdout.
+pub fn get_writer(
+    output_last: bool,
+    json_lines: bool,
+    output_file: Option<&Path>,
+    append: bool,
+) -> Result<ProgressWriter, String> {
+    let mut writer: ProgressWriter = match output_file {
+        Some(path) => Box::new(
+            FileWriter::new(path, append, !json_lines)
+                .map_err(|e| format!("Could not open output file {}: {}", path.display(), e))?,
+        ),
+        None if json_lines => Box::new(JsonLinesWriter {}),
+        None => Box::new(ProgressBarWriter {}),
+    };
     if output_last {
-        writer = Box::new(LastItemWriter::new(writer));
+        writer = last_item_writer(writer);
     }
-    writer
+    Ok(writer)
+}
+
+// Only write the last item, once the writer is dropped.
+pub fn last_item_writer(writer: ProgressWriter) -> ProgressWriter {
+    Box::new(LastItemWriter::new(writer))
 }
 
 struct ProgressBarWriter {}
@@ -134,6 +969,92 @@ impl Writer for ProgressBarWriter {
     }
 }
 
+// JSON lines must not be separated by blank lines.
+struct JsonLinesWriter {}
+impl Writer for JsonLinesWriter {
+    fn write(&mut self, s: &str) {
+        if !s.is_empty() {
+            write_above_progress_bar(s);
+        }
+    }
+}
+
+// Writes results to a file. By default the results are written to a temporary
+// file which replaces the output file once the run completes, so the output
+// file is never left partially written. In append mode results are instead
+// appended to the output file as they are found.
+struct FileWriter {
+    path: PathBuf,
+    temp_path: Option<PathBuf>,
+    file: BufWriter<File>,
+    // Whether to separate results with a blank line, as on stdout.
+    separate: bool,
+    error: Option<io::Error>,
+}
+
+impl FileWriter {
+    fn new(path: &Path, append: bool, separate: bool) -> io::Result<FileWriter> {
+        let (file, temp_path) = if append {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            (file, None)
+        } else {
+            let mut temp_path = path.as_os_str().to_owned();
+            temp_path.push(".tmp");
+            let temp_path = PathBuf::from(temp_path);
+            (File::create(&temp_path)?, Some(temp_path))
+        };
+
+        Ok(FileWriter {
+            path: path.to_path_buf(),
+            temp_path,
+            file: BufWriter::new(file),
+            separate,
+            error: None,
+        })
+    }
+
+    fn try_write(&mut self, s: &str) -> io::Result<()> {
+        self.file.write_all(s.as_bytes())?;
+        if self.separate {
+            self.file.write_all(b"\n")?;
+        }
+        if self.temp_path.is_none() {
+            // Stream results in append mode.
+            self.file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Writer for FileWriter {
+    fn write(&mut self, s: &str) {
+        if s.is_empty() || self.error.is_some() {
+            return;
+        }
+        self.error = self.try_write(s).err();
+    }
+}
+
+impl Drop for FileWriter {
+    fn drop(&mut self) {
+        let result = match self.error.take() {
+            Some(e) => Err(e),
+            None => self.file.flush(),
+        };
+        let result = match (&self.temp_path, result) {
+            (Some(temp_path), Ok(())) => fs::rename(temp_path, &self.path),
+            (Some(temp_path), Err(e)) => {
+                let _ = fs::remove_file(temp_path);
+                Err(e)
+            }
+            (None, result) => result,
+        };
+        if let Err(e) = result {
+            eprintln!("Error: Could not write {}: {}", self.path.display(), e);
+        }
+    }
+}
+
 pub struct EmptyWriter {}
 impl Writer for EmptyWriter {
     fn write(&mut self, _s: &str) {}
@@ -141,12 +1062,19 @@ impl Writer for EmptyWriter {
 
 lazy_static! {
     static ref LAST_ITEM: Mutex<String> = Mutex::new(String::new());
+    static ref CTRLC_CANCELLATION: Mutex<Option<solver::CancellationToken>> = Mutex::new(None);
 }
 
 pub fn set_ctrlc_handler() {
     LastItemWriter::set_ctrlc_handler();
 }
 
+// Make the first ctrl-c cancel the search with this token (so that it can stop
+// cleanly), rather than exiting immediately.
+pub fn set_ctrlc_cancellation(token: Option<solver::CancellationToken>) {
+    *CTRLC_CANCELLATION.lock().unwrap() = token;
+}
+
 struct LastItemWriter {
     last_item: String,
     wrapped_writer: ProgressWriter,
@@ -160,10 +1088,19 @@ impl LastItemWriter {
         }
     }
 
+    // There are no signals in a browser.
+    #[cfg(target_arch = "wasm32")]
+    pub fn set_ctrlc_handler() {}
+
+    #[cfg(not(target_arch = "wasm32"))]
     pub fn set_ctrlc_handler() {
         ctrlc::set_handler(|| {
             // Print a new line so that we aren't on the same line as the '^C'
             eprintln!();
+            if let Some(token) = CTRLC_CANCELLATION.lock().unwrap().take() {
+                token.cancel();
+                return;
+            }
             // Write the last line.
             print!("{}", *LAST_ITEM.lock().unwrap());
             // Exit the process.
@@ -1,14 +1,26 @@
-use std::collections::HashMap;
-
 use lazy_static::lazy_static;
 use regex::Regex;
 
+use super::fpuzzles;
+use crate::error::Error;
 use crate::solver;
-use crate::types::{CellValue, Constraint, FixedValues, Shape, ValueType};
+use crate::types::{
+    default_xv_sums, Candidates, CellIndex, CellValue, Constraint, FixedValues, KropkiKind, Shape,
+    ValueType, VariantConstraint,
+};
+
+pub type ParserResult = Result<Constraint, Error>;
 
-pub type ParserResult = Result<Constraint, String>;
+// A `name: args` line, which declares a variant constraint.
+struct Directive {
+    name: String,
+    args: String,
+    // Where the args start. Unknown for JSON constraints.
+    line: Option<usize>,
+    column: Option<usize>,
+}
 
-pub fn parse_shape_spec(input: &str) -> Option<Shape> {
+pub fn parse_shape_spec(input: &str, latin_square: bool) -> Option<Shape> {
     lazy_static! {
         static ref SHAPE_REGEX: Regex = Regex::new("^(\\d+)x(\\d+)$").unwrap();
     }
@@ -18,59 +30,830 @@ pub fn parse_shape_spec(input: &str) -> Option<Shape> {
         .filter(|cap| cap[1] == cap[2])
         .and_then(|cap| cap[1].parse::<usize>().ok())?;
 
-    let dim = guess_dimension(side_len * side_len).ok()?;
-    Some(Shape::new(dim))
+    guess_shape(side_len * side_len, latin_square).ok()
+}
+
+// Flags which are set by keywords anywhere in the puzzle text.
+#[derive(Default)]
+struct Flags {
+    latin_square: bool,
+    x_sudoku: bool,
+    main_diagonal: bool,
+    anti_diagonal: bool,
+    argyle: bool,
+    anti_king: bool,
+    windoku: bool,
+    kropki_negative: bool,
+    xv_negative: bool,
+    consecutive_negative: bool,
+    killer_only: bool,
+}
+
+impl Flags {
+    fn extract(input: &mut String) -> Flags {
+        Flags {
+            latin_square: extract_latin_square(input),
+            x_sudoku: extract_sodoku_x(input),
+            main_diagonal: extract_main_diagonal(input),
+            anti_diagonal: extract_anti_diagonal(input),
+            argyle: extract_argyle(input),
+            anti_king: extract_anti_king(input),
+            windoku: extract_windoku(input),
+            kropki_negative: extract_kropki_negative(input),
+            xv_negative: extract_xv_negative(input),
+            consecutive_negative: extract_consecutive_negative(input),
+            killer_only: extract_killer_only(input),
+        }
+    }
+
+    fn apply(&self, constraint: &mut Constraint) {
+        constraint.x_sudoku = self.x_sudoku;
+        constraint.main_diagonal = self.main_diagonal;
+        constraint.anti_diagonal = self.anti_diagonal;
+        constraint.argyle = self.argyle;
+        constraint.anti_king = self.anti_king;
+        constraint.windoku = self.windoku;
+        constraint.kropki_negative = self.kropki_negative;
+        constraint.xv_negative = self.xv_negative;
+        constraint.consecutive_negative = self.consecutive_negative;
+    }
+}
+
+// Split input containing multiple puzzles. Puzzles are either separated by
+// `---` lines, or given one per line in the short format.
+pub fn split_puzzles(input: &str) -> Vec<String> {
+    lazy_static! {
+        static ref SEPARATOR_REGEX: Regex = Regex::new("(?m)^[ \t]*---+[ \t]*$").unwrap();
+    }
+
+    // Lines shorter than a 9x9 puzzle may be the rows of a single grid.
+    const MIN_LINE_LEN: usize = 81;
+
+    if SEPARATOR_REGEX.is_match(input) {
+        return SEPARATOR_REGEX
+            .split(input)
+            .filter(|puzzle| !puzzle.trim().is_empty())
+            .map(str::to_string)
+            .collect();
+    }
+
+    let lines = input
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect::<Vec<_>>();
+    let is_puzzle_line = |line: &&str| {
+        line.len() >= MIN_LINE_LEN
+            && !line.contains(char::is_whitespace)
+            && short_text_shape(line.len(), None, false).is_ok()
+    };
+    if lines.len() > 1 && lines.iter().all(is_puzzle_line) {
+        return lines.into_iter().map(str::to_string).collect();
+    }
+
+    vec![input.to_string()]
 }
 
-pub fn parse_text(input: &str) -> ParserResult {
+// If `latin_square` is set then the grid has no boxes, regardless of whether
+// the puzzle text specifies it.
+// JSON puzzle descriptions are detected by the leading `{`, and f-puzzles or
+// SudokuPad URLs by the leading `http`.
+pub fn parse_text(input: &str, latin_square: bool) -> ParserResult {
+    if input.trim_start().starts_with('{') {
+        return parse_json(input, latin_square);
+    }
+    if fpuzzles::is_url(input.trim()) {
+        return parse_json(
+            &fpuzzles::decode_url(input.trim()).map_err(Error::parse)?,
+            latin_square,
+        );
+    }
+
     let mut input = String::from(input);
 
     remove_comments(&mut input);
-    let x_sudoku = extract_sodoku_x(&mut input);
+    extract_puzzle_section(&mut input);
+    let mut flags = Flags::extract(&mut input);
+    flags.latin_square |= latin_square;
+    let directives = extract_directives(&mut input);
+
+    build_constraint(input.trim(), &flags, directives)
+}
+
+// `grid` is either a grid, a shape spec, or empty for killer-only puzzles or
+// puzzles with a shape header.
+fn build_constraint(grid: &str, flags: &Flags, directives: Vec<Directive>) -> ParserResult {
+    let latin_square = flags.latin_square;
+    let (headers, directives): (Vec<_>, Vec<_>) = directives
+        .into_iter()
+        .partition(|directive| directive.name == "shape");
+    let (locked, directives): (Vec<_>, Vec<_>) = directives
+        .into_iter()
+        .partition(|directive| directive.name == "locked");
+    let explicit_shape = match headers.as_slice() {
+        [] => None,
+        [header] => Some(
+            parse_shape_header(&header.args, latin_square)
+                .map_err(|e| Error::Shape(format!("[shape] {}", e)))?,
+        ),
+        _ => return Err(Error::parse("Multiple shape headers.")),
+    };
+
+    let mut constraint = match (parse_shape_spec(grid, latin_square), explicit_shape) {
+        // If the input is a pure shape spec, then there are no fixed values.
+        (Some(shape), None) => Constraint::new(shape, Vec::new()),
+        (Some(shape), Some(explicit)) if shape.num_cells == explicit.num_cells => {
+            Constraint::new(explicit, Vec::new())
+        }
+        (Some(_), Some(_)) => {
+            return Err(Error::Shape(
+                "Grid size does not match the shape header.".to_string(),
+            ))
+        }
+        (None, Some(shape)) if grid.is_empty() => Constraint::new(shape, Vec::new()),
+        // Killer-only puzzles can leave out the grid entirely.
+        (None, None) if flags.killer_only && grid.is_empty() => {
+            Constraint::new(infer_shape(&directives, latin_square)?, Vec::new())
+        }
+        (None, _) if flags.killer_only => {
+            return Err(Error::parse("Killer-only puzzles can't have givens."))
+        }
+        (None, shape) => parse_grid(grid, latin_square, shape)?,
+    };
+
+    flags.apply(&mut constraint);
+    for directive in directives {
+        let variant = parse_directive(&directive.name, &directive.args, &constraint.shape)
+            .map_err(|msg| Error::Parse {
+                message: format!("[{}] {}", directive.name, msg),
+                line: directive.line,
+                column: directive.column,
+            })?;
+        constraint.variants.push(variant);
+    }
+    // `locked: <cells>` marks givens which the minimizer keeps.
+    for directive in locked {
+        let cells =
+            parse_cell_list(&directive.args, &constraint.shape).map_err(|msg| Error::Parse {
+                message: format!("[locked] {}", msg),
+                line: directive.line,
+                column: directive.column,
+            })?;
+        constraint.locked_cells.extend(cells);
+    }
+
+    if flags.killer_only {
+        check_cages_cover_grid(&constraint)?;
+    }
+
+    Ok(constraint)
+}
+
+// A JSON puzzle description has the form:
+//   {
+//     "size": 9,                  (or "grid": "<grid in any text format>")
+//     "givens": [["R1C1", 5]],
+//     "candidates": [["R1C2", [1, 4, 7]]],
+//     "locked": ["R1C1"],
+//     "flags": ["X-Sudoku"],
+//     "constraints": [{"type": "cage", "sum": 10, "cells": ["R1C2", "R1C3"]}]
+//   }
+// All fields are optional, except that the size must be known.
+// Constraints map onto the directives of the text format, so they support the
+// same types. Their fields are:
+//   "value", "count", "sum", "difference", "modulus": numbers
+//   "cell": a cell, "cells": a list of cells, "values": a list of numbers
+//   "clone": the cells of the second region of a clone
+// Inequalities are written with "cells": [a, b], meaning a < b.
+// f-puzzles JSON is also accepted, and is detected by its "grid" array.
+fn parse_json(input: &str, latin_square: bool) -> ParserResult {
+    let json = serde_json::from_str::<serde_json::Value>(input).map_err(|e| {
+        // The message ends with the position, which is given separately.
+        let message = e.to_string();
+        let message = message.split(" at line ").next().unwrap_or_default();
+        Error::Parse {
+            message: format!("Invalid JSON: {}", message),
+            line: Some(e.line()),
+            column: Some(e.column()),
+        }
+    })?;
+    let json = json
+        .as_object()
+        .ok_or_else(|| Error::parse("Expected a JSON object."))?;
+    if fpuzzles::is_fpuzzles_json(json) {
+        let json = fpuzzles::convert(json).map_err(Error::parse)?;
+        return parse_json_object(json.as_object().unwrap(), latin_square);
+    }
+    parse_json_object(json, latin_square)
+}
+
+fn parse_json_object(
+    json: &serde_json::Map<String, serde_json::Value>,
+    latin_square: bool,
+) -> ParserResult {
+    let mut grid = String::new();
+    let mut givens = Vec::new();
+    let mut candidates = Vec::new();
+    let mut locked = Vec::new();
+    let mut flags = Flags::default();
+    let mut directives = Vec::new();
+    for (key, value) in json {
+        match key.as_str() {
+            "size" => {
+                let size = json_number(value)?;
+                grid = format!("{size}x{size}");
+            }
+            "grid" => grid = json_str(value)?.to_string(),
+            "givens" => givens = json_array(value)?.iter().collect(),
+            "candidates" => candidates = json_array(value)?.iter().collect(),
+            "locked" => locked = json_array(value)?.iter().collect(),
+            "flags" => {
+                let mut text = json_array(value)?
+                    .iter()
+                    .map(|v| json_str(v))
+                    .collect::<Result<Vec<_>, _>>()?
+                    .join("\n");
+                flags = Flags::extract(&mut text);
+                if !text.trim().is_empty() {
+                    return Err(Error::parse(format!("Unknown flags: {}", text.trim())));
+                }
+            }
+            "constraints" => {
+                for constraint in json_array(value)? {
+                    directives.push(json_directive(constraint)?);
+                }
+            }
+            _ => return Err(Error::parse(format!("Unknown field: {}", key))),
+        }
+    }
+    flags.latin_square |= latin_square;
+
+    if flags.killer_only && !(givens.is_empty() && candidates.is_empty()) {
+        return Err(Error::parse("Killer-only puzzles can't have givens."));
+    }
+
+    let mut constraint = build_constraint(grid.trim(), &flags, directives)?;
+    for given in givens {
+        let (cell, value) = match json_array(given)?.as_slice() {
+            [cell, value] => (json_str(cell)?, json_number(value)?),
+            _ => return Err(Error::parse("Givens must be [cell, value] pairs.")),
+        };
+        let cell = parse_cell(cell, &constraint.shape).map_err(Error::parse)?;
+        if value == 0 || value > constraint.shape.num_values {
+            return Err(Error::parse(format!("Value out of range: {value}.")));
+        }
+        let value = CellValue::from_display_value(value as ValueType);
+        constraint.fixed_values.push((cell, value));
+    }
+    for entry in candidates {
+        let (cell, values) = match json_array(entry)?.as_slice() {
+            [cell, values] => (json_str(cell)?, json_array(values)?),
+            _ => {
+                return Err(Error::parse(
+                    "Candidates must be [cell, [values...]] pairs.",
+                ))
+            }
+        };
+        let cell = parse_cell(cell, &constraint.shape).map_err(Error::parse)?;
+        let values = values
+            .iter()
+            .map(|value| match json_number(value)? {
+                v if v == 0 || v > constraint.shape.num_values => {
+                    Err(Error::parse(format!("Value out of range: {v}.")))
+                }
+                v => Ok(CellValue::from_display_value(v as ValueType)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        constraint.candidates.push((cell, values));
+    }
+    for cell in locked {
+        let cell = parse_cell(json_str(cell)?, &constraint.shape).map_err(Error::parse)?;
+        constraint.locked_cells.push(cell);
+    }
+
+    Ok(constraint)
+}
+
+// Convert a JSON constraint into the equivalent text directive.
+fn json_directive(json: &serde_json::Value) -> Result<Directive, Error> {
+    const NUMBER_FIELDS: [&str; 5] = ["value", "count", "sum", "difference", "modulus"];
+
+    let json = json
+        .as_object()
+        .ok_or_else(|| Error::parse("Constraints must be JSON objects."))?;
+    let name = json
+        .get("type")
+        .ok_or_else(|| Error::parse("Constraint is missing a type."))?;
+    let name = json_str(name)?;
+    let name = name.to_lowercase();
+
+    let mut args = Vec::new();
+    for field in NUMBER_FIELDS {
+        if let Some(value) = json.get(field) {
+            args.push(json_number(value)?.to_string());
+        }
+    }
+    if let Some(cell) = json.get("cell") {
+        args.push(json_str(cell)?.to_string());
+    }
+    if let Some(cells) = json.get("cells") {
+        let cells = json_array(cells)?
+            .iter()
+            .map(|c| json_str(c).map(str::to_string))
+            .collect::<Result<Vec<_>, _>>()?;
+        match (name.as_str(), cells.as_slice()) {
+            ("inequality", [a, b]) => args.extend([a.clone(), "<".to_string(), b.clone()]),
+            _ => args.extend(cells),
+        }
+    }
+    if let Some(cells) = json.get("clone") {
+        args.push("=".to_string());
+        for cell in json_array(cells)? {
+            args.push(json_str(cell)?.to_string());
+        }
+    }
+    if let Some(values) = json.get("values") {
+        for value in json_array(values)? {
+            args.push(json_number(value)?.to_string());
+        }
+    }
+
+    let known_fields = ["type", "cell", "cells", "clone", "values"];
+    if let Some(field) = json
+        .keys()
+        .find(|k| !known_fields.contains(&k.as_str()) && !NUMBER_FIELDS.contains(&k.as_str()))
+    {
+        return Err(Error::parse(format!("[{}] Unknown field: {}", name, field)));
+    }
+
+    Ok(Directive {
+        name,
+        args: args.join(" "),
+        line: None,
+        column: None,
+    })
+}
+
+fn json_str(json: &serde_json::Value) -> Result<&str, Error> {
+    json.as_str()
+        .ok_or_else(|| Error::parse(format!("Expected a string: {}", json)))
+}
 
-    if let Some(shape) = parse_shape_spec(&input) {
-        // If the input is a pure shape spec, then just return it.
-        return Ok(Constraint {
-            shape,
-            x_sudoku,
-            fixed_values: Vec::new(),
-        });
+fn json_number(json: &serde_json::Value) -> Result<u32, Error> {
+    json.as_u64()
+        .and_then(|n| u32::try_from(n).ok())
+        .ok_or_else(|| Error::parse(format!("Expected a number: {}", json)))
+}
+
+fn json_array(json: &serde_json::Value) -> Result<&Vec<serde_json::Value>, Error> {
+    json.as_array()
+        .ok_or_else(|| Error::parse(format!("Expected an array: {}", json)))
+}
+
+// The shape of a grid which just fits every cell referenced by the directives.
+fn infer_shape(directives: &[Directive], latin_square: bool) -> Result<Shape, Error> {
+    lazy_static! {
+        static ref CELL_REF_REGEX: Regex = Regex::new("(?i)r(\\d+)c(\\d+)").unwrap();
+    }
+
+    let side_len = directives
+        .iter()
+        .flat_map(|directive| CELL_REF_REGEX.captures_iter(&directive.args))
+        .flat_map(|cap| [cap[1].parse::<usize>(), cap[2].parse::<usize>()])
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| Error::parse(e.to_string()))?
+        .into_iter()
+        .max()
+        .ok_or_else(|| {
+            Error::Shape("Could not infer grid size - no cells specified.".to_string())
+        })?;
+
+    guess_shape(side_len * side_len, latin_square)
+}
+
+fn check_cages_cover_grid(constraint: &Constraint) -> Result<(), Error> {
+    let mut covered = vec![false; constraint.shape.num_cells];
+    for variant in &constraint.variants {
+        if let VariantConstraint::Cage { cells, .. } = variant {
+            for &cell in cells {
+                if covered[cell] {
+                    return Err(Error::parse(format!(
+                        "Cell is in multiple cages: {}",
+                        cell_name(cell, &constraint.shape)
+                    )));
+                }
+                covered[cell] = true;
+            }
+        }
     }
 
-    let parse_fns = HashMap::from([
-        ("short-format", parse_short_text as fn(_) -> _),
+    match covered.iter().position(|&c| !c) {
+        Some(cell) => Err(Error::parse(format!(
+            "Cell is not in a cage: {}",
+            cell_name(cell, &constraint.shape)
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn cell_name(cell: CellIndex, shape: &Shape) -> String {
+    let side_len = shape.side_len as usize;
+    format!("R{}C{}", cell / side_len + 1, cell % side_len + 1)
+}
+
+// A shape header has the form `shape: 25x25, box: 5x5`. The box is optional,
+// and can be `none` for latin squares.
+fn parse_shape_header(args: &str, latin_square: bool) -> Result<Shape, Error> {
+    lazy_static! {
+        static ref HEADER_REGEX: Regex =
+            Regex::new("(?i)^(\\d+)x(\\d+)(?:\\s*,\\s*box\\s*:\\s*(none|(\\d+)x(\\d+)))?$")
+                .unwrap();
+    }
+
+    let cap = HEADER_REGEX
+        .captures(args.trim())
+        .ok_or_else(|| Error::Shape("Expected a shape of the form: 9x9, box: 3x3".to_string()))?;
+    if cap[1] != cap[2] {
+        return Err(Error::Shape("Only square grids are supported.".to_string()));
+    }
+    let side_len = parse_number(&cap[1]).map_err(Error::Shape)?;
+    if !solver::VALID_NUM_VALUE_RANGE.contains(&side_len) {
+        return Err(Error::Shape(format!(
+            "Grid size not supported - side length: {side_len}."
+        )));
+    }
+
+    match cap.get(3) {
+        _ if latin_square => Ok(Shape::new_latin_square(side_len)),
+        None => guess_shape((side_len * side_len) as usize, false),
+        Some(spec) if spec.as_str().eq_ignore_ascii_case("none") => {
+            Ok(Shape::new_latin_square(side_len))
+        }
+        Some(_) => {
+            let box_size = parse_number(&cap[4]).map_err(Error::Shape)?;
+            if cap[4] != cap[5] || box_size * box_size != side_len {
+                return Err(Error::Shape(format!(
+                    "Boxes must be square and fit the grid: {}x{}",
+                    &cap[4], &cap[5]
+                )));
+            }
+            Ok(Shape::new(box_size))
+        }
+    }
+}
+
+// The shape of a grid with `num_cells` cells. If an explicit shape is given
+// then the grid can leave out trailing cells, which are treated as empty.
+fn grid_shape(num_cells: usize, shape: Option<Shape>, latin_square: bool) -> Result<Shape, Error> {
+    match shape {
+        Some(shape) if num_cells > shape.num_cells => Err(Error::Shape(format!(
+            "Too many cells for the shape header: {num_cells}."
+        ))),
+        Some(shape) => Ok(shape),
+        None => guess_shape(num_cells, latin_square),
+    }
+}
+
+fn parse_grid(input: &str, latin_square: bool, shape: Option<Shape>) -> ParserResult {
+    // The formats are tried in order, so that the same one is used on every
+    // run when both could parse the input.
+    let parse_fns = [
+        ("short-format", parse_short_text as fn(_, _, _) -> _),
         ("grid-format", parse_grid_layout),
-    ]);
+    ];
 
-    let mut constraint = None;
     let mut errors = vec!["Could not parse grid:".to_string()];
+    // The grid is the wrong size if neither format could find a shape.
+    let mut all_shape_errors = true;
     for (name, parse_fn) in parse_fns {
-        match (parse_fn)(&input) {
-            Ok(parsed) => {
-                constraint = Some(parsed);
-                break;
+        match (parse_fn)(input, latin_square, shape) {
+            Ok(parsed) => return Ok(parsed),
+            Err(e) => {
+                all_shape_errors &= matches!(e, Error::Shape(_));
+                errors.push(format!("[{}] {}", name, e));
+            }
+        }
+    }
+
+    if all_shape_errors {
+        Err(Error::Shape(errors.join("\n")))
+    } else {
+        Err(Error::parse(errors.join("\n")))
+    }
+}
+
+// Directives are lines of the form `name: args`, which declare variant
+// constraints. They are removed from the input so that they don't interfere
+// with parsing the grid.
+fn extract_directives(input: &mut String) -> Vec<Directive> {
+    lazy_static! {
+        static ref DIRECTIVE_REGEX: Regex =
+            Regex::new("(?m)^[ \t]*([A-Za-z][A-Za-z-]*)[ \t]*:(.*)$").unwrap();
+    }
+
+    let directives = DIRECTIVE_REGEX
+        .captures_iter(input)
+        .map(|cap| {
+            let start = cap.get(2).unwrap().start();
+            let line_start = input[..start].rfind('\n').map_or(0, |i| i + 1);
+            Directive {
+                name: cap[1].to_lowercase(),
+                args: cap[2].trim().to_string(),
+                line: Some(input[..start].matches('\n').count() + 1),
+                column: Some(input[line_start..start].chars().count() + 1),
+            }
+        })
+        .collect();
+
+    *input = DIRECTIVE_REGEX.replace_all(input, "").to_string();
+    directives
+}
+
+fn parse_directive(name: &str, args: &str, shape: &Shape) -> Result<VariantConstraint, String> {
+    let args = args
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+
+    match name {
+        "cage" | "sum" => {
+            let (sum, cells) = args.split_first().ok_or("Missing cage sum.")?;
+            let sum = parse_number(sum)?;
+            let cells = parse_cells(cells, shape)?;
+            Ok(VariantConstraint::Cage {
+                cells,
+                sum,
+                allow_repeats: name == "sum",
+            })
+        }
+        "thermo" => Ok(VariantConstraint::Thermo {
+            cells: parse_cells(&args, shape)?,
+        }),
+        "arrow" => {
+            let cells = parse_cells(&args, shape)?;
+            if cells.len() < 2 {
+                return Err("Arrow must have a circle and at least one cell.".to_string());
+            }
+            Ok(VariantConstraint::Arrow { cells })
+        }
+        "renban" => {
+            let cells = parse_cells(&args, shape)?;
+            if cells.len() > shape.num_values as usize {
+                return Err("Renban line is longer than the number of values.".to_string());
+            }
+            Ok(VariantConstraint::Renban { cells })
+        }
+        "whisper" => {
+            // The difference is optional, and defaults to 5 for 9x9 grids.
+            let (difference, cells) = split_optional_number(&args)?;
+            Ok(VariantConstraint::Whisper {
+                cells: parse_cells(cells, shape)?,
+                difference: difference.unwrap_or(shape.num_values.div_ceil(2)),
+            })
+        }
+        "white" => Ok(VariantConstraint::Kropki {
+            cells: parse_cell_pair(&args, shape)?,
+            kind: KropkiKind::White,
+        }),
+        "black" => Ok(VariantConstraint::Kropki {
+            cells: parse_cell_pair(&args, shape)?,
+            kind: KropkiKind::Black,
+        }),
+        "consecutive" => Ok(VariantConstraint::Consecutive {
+            cells: parse_cell_pair(&args, shape)?,
+        }),
+        "x" | "v" => {
+            // The sum is optional, and defaults to 10 (X) or 5 (V) for 9x9 grids.
+            let (sum, cells) = split_optional_number(&args)?;
+            let (x_sum, v_sum) = default_xv_sums(shape);
+            Ok(VariantConstraint::Xv {
+                cells: parse_cell_pair(cells, shape)?,
+                sum: sum.unwrap_or(if name == "x" { x_sum } else { v_sum }),
+            })
+        }
+        "entropic" => Ok(VariantConstraint::Entropic {
+            cells: parse_cells(&args, shape)?,
+        }),
+        "modular" => {
+            // The modulus is optional, and defaults to 3.
+            let (modulus, cells) = split_optional_number(&args)?;
+            let modulus = modulus.unwrap_or(3);
+            let max_modulus = shape.num_values.min(solver::MAX_LINE_CLASSES as u32);
+            if !(2..=max_modulus).contains(&modulus) {
+                return Err(format!("Modulus must be between 2 and {}.", max_modulus));
+            }
+            Ok(VariantConstraint::Modular {
+                cells: parse_cells(cells, shape)?,
+                modulus,
+            })
+        }
+        "region-sum" => {
+            if shape.box_size.is_none() {
+                return Err("Region sum lines require boxes.".to_string());
+            }
+            Ok(VariantConstraint::RegionSum {
+                cells: parse_cells(&args, shape)?,
+            })
+        }
+        "quad" => {
+            // The cell is the top-left of the 2x2 square.
+            let (cell, values) = args.split_first().ok_or("No cells specified.")?;
+            let cell = parse_cell(cell, shape)?;
+            let (row, col) = (cell as u32 / shape.side_len, cell as u32 % shape.side_len);
+            if row + 1 >= shape.side_len || col + 1 >= shape.side_len {
+                return Err("Quadruple must be inside the grid.".to_string());
+            }
+            if values.is_empty() || values.len() > 4 {
+                return Err("Quadruple must have between 1 and 4 values.".to_string());
+            }
+            let values = values
+                .iter()
+                .map(|v| match parse_number(v)? {
+                    v if v == 0 || v > shape.num_values => Err(format!("Value out of range: {v}.")),
+                    v => Ok(CellValue::from_display_value(v as ValueType)),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(VariantConstraint::Quadruple {
+                cells: vec![
+                    shape.make_cell_index(row, col),
+                    shape.make_cell_index(row, col + 1),
+                    shape.make_cell_index(row + 1, col),
+                    shape.make_cell_index(row + 1, col + 1),
+                ],
+                values,
+            })
+        }
+        "count" => {
+            // If no cells are given, then the count is over the whole grid.
+            let (value, count, cells) = match &args[..] {
+                [value, count, cells @ ..] => (parse_number(value)?, parse_number(count)?, cells),
+                _ => return Err("Expected a value and a count.".to_string()),
+            };
+            if value == 0 || value > shape.num_values {
+                return Err(format!("Value out of range: {value}."));
             }
-            Err(msg) => {
-                errors.push(format!("[{}] {}", name, msg));
+            let cells = if cells.is_empty() {
+                (0..shape.num_cells).collect()
+            } else {
+                parse_cells(cells, shape)?
+            };
+            if count as usize > cells.len() {
+                return Err("Count is larger than the number of cells.".to_string());
             }
+            Ok(VariantConstraint::ValueCount {
+                cells,
+                value: CellValue::from_display_value(value as ValueType),
+                count,
+            })
         }
+        "region" => {
+            let cells = parse_cells(&args, shape)?;
+            if cells.len() > shape.num_values as usize {
+                return Err("Region is larger than the number of values.".to_string());
+            }
+            Ok(VariantConstraint::Region { cells })
+        }
+        "inequality" => {
+            // Whitespace around the comparison is optional.
+            let text = args.concat();
+            let (cells, op) = match (text.split_once('<'), text.split_once('>')) {
+                (Some((a, b)), None) => ([a, b], '<'),
+                (None, Some((a, b))) => ([a, b], '>'),
+                _ => return Err("Expected exactly one of '<' or '>'.".to_string()),
+            };
+            let mut cells = parse_cell_pair(&cells, shape)?;
+            if op == '>' {
+                cells.reverse();
+            }
+            Ok(VariantConstraint::Inequality { cells })
+        }
+        "even" | "odd" => Ok(VariantConstraint::Parity {
+            cells: parse_cells(&args, shape)?,
+            even: name == "even",
+        }),
+        "clone" => {
+            let split = args
+                .iter()
+                .position(|&arg| arg == "=")
+                .ok_or("Expected '=' between the two regions.")?;
+            let region0 = parse_cells(&args[..split], shape)?;
+            let region1 = parse_cells(&args[split + 1..], shape)?;
+            if region0.len() != region1.len() {
+                return Err("Regions must have the same number of cells.".to_string());
+            }
+            Ok(VariantConstraint::CloneRegion {
+                cells: [region0, region1].concat(),
+            })
+        }
+        _ => Err("Unknown constraint type.".to_string()),
     }
+}
+
+// Cells separated by whitespace or commas, as in the directives.
+pub fn parse_cell_list(input: &str, shape: &Shape) -> Result<Vec<CellIndex>, String> {
+    let tokens = input
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+    parse_cells(&tokens, shape)
+}
 
-    match constraint {
-        None => Err(errors.join("\n")),
-        Some(mut constraint) => {
-            constraint.x_sudoku = x_sudoku;
-            Ok(constraint)
+// Split off the first argument if it is a number.
+fn split_optional_number<'a, 'b>(
+    args: &'a [&'b str],
+) -> Result<(Option<u32>, &'a [&'b str]), String> {
+    match args.split_first() {
+        Some((first, rest)) if first.starts_with(|c: char| c.is_ascii_digit()) => {
+            Ok((Some(parse_number(first)?), rest))
         }
+        _ => Ok((None, args)),
     }
 }
 
+fn parse_number(token: &str) -> Result<u32, String> {
+    token
+        .parse::<u32>()
+        .map_err(|_| format!("Expected a number: {}", token))
+}
+
+// Cells are specified as `RxCy` with 1-indexed rows and columns.
+fn parse_cell(token: &str, shape: &Shape) -> Result<CellIndex, String> {
+    lazy_static! {
+        static ref CELL_REF_REGEX: Regex = Regex::new("^(?i)r(\\d+)c(\\d+)$").unwrap();
+    }
+
+    let (row, col) = CELL_REF_REGEX
+        .captures(token)
+        .and_then(|cap| Some((cap[1].parse::<u32>().ok()?, cap[2].parse::<u32>().ok()?)))
+        .ok_or_else(|| format!("Invalid cell: {}", token))?;
+
+    if row == 0 || col == 0 || row > shape.side_len || col > shape.side_len {
+        return Err(format!("Cell out of range: {}", token));
+    }
+
+    Ok(shape.make_cell_index(row - 1, col - 1))
+}
+
+fn parse_cell_pair(tokens: &[&str], shape: &Shape) -> Result<Vec<CellIndex>, String> {
+    if tokens.len() != 2 {
+        return Err("Expected exactly two cells.".to_string());
+    }
+    parse_cells(tokens, shape)
+}
+
+fn parse_cells(tokens: &[&str], shape: &Shape) -> Result<Vec<CellIndex>, String> {
+    if tokens.is_empty() {
+        return Err("No cells specified.".to_string());
+    }
+
+    let cells = tokens
+        .iter()
+        .map(|token| parse_cell(token, shape))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (i, cell) in cells.iter().enumerate() {
+        if cells[..i].contains(cell) {
+            return Err(format!("Repeated cell: {}", tokens[i]));
+        }
+    }
+
+    Ok(cells)
+}
+
+// Also removes the `#X...` metadata headers of SadMan (.sdk) files.
 fn remove_comments(input: &mut String) {
     lazy_static! {
         static ref COMMENT_REGEX: Regex = Regex::new("(?m)#.*$").unwrap();
     }
 
-    *input = COMMENT_REGEX.replace(input, "").to_string();
+    *input = COMMENT_REGEX.replace_all(input, "").to_string();
+}
+
+// SadMan files split the puzzle into sections such as `[Puzzle]` and `[State]`
+// (which holds the solving progress and pencilmarks). Only the puzzle section
+// is kept.
+fn extract_puzzle_section(input: &mut String) {
+    lazy_static! {
+        static ref SECTION_REGEX: Regex =
+            Regex::new("(?m)^[ \t]*\\[([A-Za-z ]+)\\][ \t]*$").unwrap();
+    }
+
+    let headers = SECTION_REGEX.captures_iter(input).collect::<Vec<_>>();
+    let Some(first) = headers.first() else {
+        return;
+    };
+
+    let mut puzzle = input[..first.get(0).unwrap().start()].to_string();
+    for (i, header) in headers.iter().enumerate() {
+        if header[1].eq_ignore_ascii_case("puzzle") {
+            let start = header.get(0).unwrap().end();
+            let end = headers
+                .get(i + 1)
+                .map_or(input.len(), |next| next.get(0).unwrap().start());
+            puzzle.push_str(&input[start..end]);
+        }
+    }
+    *input = puzzle;
 }
 
 fn extract_sodoku_x(input: &mut String) -> bool {
@@ -78,11 +861,98 @@ fn extract_sodoku_x(input: &mut String) -> bool {
         static ref SUDOKU_X_REGEX: Regex = Regex::new("(?i)x[- ]sudoku|sudoku[ -]x").unwrap();
     }
 
-    if !SUDOKU_X_REGEX.is_match(input) {
+    extract_flag(input, &SUDOKU_X_REGEX)
+}
+
+fn extract_latin_square(input: &mut String) -> bool {
+    lazy_static! {
+        static ref LATIN_SQUARE_REGEX: Regex = Regex::new("(?i)latin[- ]square").unwrap();
+    }
+
+    extract_flag(input, &LATIN_SQUARE_REGEX)
+}
+
+fn extract_killer_only(input: &mut String) -> bool {
+    lazy_static! {
+        static ref KILLER_ONLY_REGEX: Regex = Regex::new("(?i)killer[- ]only").unwrap();
+    }
+
+    extract_flag(input, &KILLER_ONLY_REGEX)
+}
+
+fn extract_main_diagonal(input: &mut String) -> bool {
+    lazy_static! {
+        static ref MAIN_DIAGONAL_REGEX: Regex = Regex::new("(?i)main[- ]diagonal").unwrap();
+    }
+
+    extract_flag(input, &MAIN_DIAGONAL_REGEX)
+}
+
+fn extract_anti_diagonal(input: &mut String) -> bool {
+    lazy_static! {
+        static ref ANTI_DIAGONAL_REGEX: Regex = Regex::new("(?i)anti[- ]diagonal").unwrap();
+    }
+
+    extract_flag(input, &ANTI_DIAGONAL_REGEX)
+}
+
+fn extract_argyle(input: &mut String) -> bool {
+    lazy_static! {
+        static ref ARGYLE_REGEX: Regex = Regex::new("(?i)argyle").unwrap();
+    }
+
+    extract_flag(input, &ARGYLE_REGEX)
+}
+
+fn extract_kropki_negative(input: &mut String) -> bool {
+    lazy_static! {
+        static ref KROPKI_NEGATIVE_REGEX: Regex = Regex::new("(?i)kropki[- ]negative").unwrap();
+    }
+
+    extract_flag(input, &KROPKI_NEGATIVE_REGEX)
+}
+
+fn extract_xv_negative(input: &mut String) -> bool {
+    lazy_static! {
+        static ref XV_NEGATIVE_REGEX: Regex = Regex::new("(?i)xv[- ]negative").unwrap();
+    }
+
+    extract_flag(input, &XV_NEGATIVE_REGEX)
+}
+
+fn extract_consecutive_negative(input: &mut String) -> bool {
+    lazy_static! {
+        static ref CONSECUTIVE_NEGATIVE_REGEX: Regex =
+            Regex::new("(?i)consecutive[- ]negative|non[- ]consecutive").unwrap();
+    }
+
+    extract_flag(input, &CONSECUTIVE_NEGATIVE_REGEX)
+}
+
+fn extract_anti_king(input: &mut String) -> bool {
+    lazy_static! {
+        static ref ANTI_KING_REGEX: Regex = Regex::new("(?i)anti[- ]?king").unwrap();
+    }
+
+    extract_flag(input, &ANTI_KING_REGEX)
+}
+
+fn extract_windoku(input: &mut String) -> bool {
+    lazy_static! {
+        static ref WINDOKU_REGEX: Regex = Regex::new("(?i)windoku|hyper[- ]sudoku").unwrap();
+    }
+
+    extract_flag(input, &WINDOKU_REGEX)
+}
+
+// Remove all matches of `regex` from the input, returning whether there were
+// any.
+fn extract_flag(input: &mut String, regex: &Regex) -> bool {
+    if !regex.is_match(input) {
         return false;
     }
 
-    *input = SUDOKU_X_REGEX.replace(input, "").to_string();
+    *input = regex.replace_all(input, "").to_string();
     true
 }
 
@@ -90,82 +960,151 @@ fn remove_whitespace(s: &mut String) {
     s.retain(|c| !c.is_whitespace());
 }
 
-fn guess_dimension(num_cells: usize) -> Result<u32, String> {
-    let dim = (num_cells as f64).sqrt().sqrt() as u32;
-    let num_values = dim * dim;
-    if num_values * num_values != (num_cells as u32) {
-        return Err(format!(
+fn guess_shape(num_cells: usize, latin_square: bool) -> Result<Shape, Error> {
+    let side_len = (num_cells as f64).sqrt() as u32;
+    // Latin squares can have any side length, otherwise the side length must
+    // be a square so that the grid can be divided into boxes.
+    let dim = (side_len as f64).sqrt() as u32;
+    if side_len * side_len != (num_cells as u32) || (!latin_square && dim * dim != side_len) {
+        return Err(Error::Shape(format!(
             "Cell count does not make a valid grid size: {num_cells}."
-        ));
+        )));
     }
 
-    if !solver::VALID_NUM_VALUE_RANGE.contains(&num_values) {
-        return Err(format!(
-            "Grid size not supported - side length: {num_values}."
-        ));
+    if !solver::VALID_NUM_VALUE_RANGE.contains(&side_len) {
+        return Err(Error::Shape(format!(
+            "Grid size not supported - side length: {side_len}."
+        )));
     }
 
-    Ok(dim)
+    if latin_square {
+        Ok(Shape::new_latin_square(side_len))
+    } else {
+        Ok(Shape::new(dim))
+    }
 }
 
-fn parse_short_text(input: &str) -> ParserResult {
+// The short format writes each value as a base-36 number.
+pub(crate) const SHORT_FORMAT_RADIX: u32 = 36;
+
+// The number of characters per cell in the short format. Grids with more
+// values than there are digits use fixed-width cells (e.g. `0a` for 10).
+pub(crate) fn short_cell_width(num_values: u32) -> usize {
+    let mut width = 1;
+    let mut max_value = SHORT_FORMAT_RADIX - 1;
+    while num_values > max_value {
+        width += 1;
+        max_value = max_value * SHORT_FORMAT_RADIX + SHORT_FORMAT_RADIX - 1;
+    }
+    width
+}
+
+// Find the shape and cell width of a short format grid with `len` characters.
+fn short_text_shape(
+    len: usize,
+    shape: Option<Shape>,
+    latin_square: bool,
+) -> Result<(Shape, usize), Error> {
+    if let Some(shape) = shape {
+        let width = short_cell_width(shape.num_values);
+        if !len.is_multiple_of(width) {
+            return Err(Error::Shape(format!(
+                "Length is not a multiple of the cell width: {width}."
+            )));
+        }
+        return Ok((grid_shape(len / width, Some(shape), latin_square)?, width));
+    }
+
+    let max_width = short_cell_width(*solver::VALID_NUM_VALUE_RANGE.end());
+    (1..=max_width)
+        .filter(|width| len.is_multiple_of(*width))
+        .filter_map(|width| Some((guess_shape(len / width, latin_square).ok()?, width)))
+        .find(|(shape, width)| short_cell_width(shape.num_values) == *width)
+        .ok_or_else(|| match guess_shape(len, latin_square) {
+            Ok(shape) => Error::Shape(format!(
+                "Too many values for short input: {}.",
+                shape.num_values
+            )),
+            Err(e) => e,
+        })
+}
+
+fn parse_short_text(input: &str, latin_square: bool, shape: Option<Shape>) -> ParserResult {
     let mut input = String::from(input);
     remove_whitespace(&mut input);
-
-    let dim = guess_dimension(input.len())?;
-    let num_values = dim * dim;
-    let radix = num_values + 1;
-    if radix > 36 {
-        return Err(format!("Too many values for short input: {num_values}."));
+    if !input.is_ascii() {
+        return Err(Error::parse("Short input must be ASCII."));
     }
 
+    let (shape, width) = short_text_shape(input.len(), shape, latin_square)?;
+    let num_values = shape.num_values;
+
     let mut fixed_values = FixedValues::new();
 
-    for (i, c) in input.chars().enumerate() {
-        if c.is_digit(radix) {
-            fixed_values.push((
-                i,
-                CellValue::from_display_value(c.to_digit(radix).unwrap().try_into().unwrap()),
-            ));
-        } else if c != '.' && c != '0' {
-            return Err(format!("Unrecognized character: {}", c));
+    for (i, cell) in input.as_bytes().chunks(width).enumerate() {
+        let cell = std::str::from_utf8(cell).unwrap();
+        if cell.bytes().all(|c| c == b'.') {
+            continue;
+        }
+        let value = u32::from_str_radix(cell, SHORT_FORMAT_RADIX)
+            .ok()
+            .filter(|_| cell.bytes().all(|c| c.is_ascii_alphanumeric()))
+            .ok_or_else(|| Error::parse(format!("Unrecognized cell: {}", cell)))?;
+        if value > num_values {
+            return Err(Error::parse(format!("Value out of range: {value}.")));
+        }
+        if value != 0 {
+            fixed_values.push((i, CellValue::from_display_value(value as ValueType)));
         }
     }
 
-    Ok(Constraint {
-        shape: Shape::new(dim),
-        fixed_values,
-        x_sudoku: false,
-    })
+    Ok(Constraint::new(shape, fixed_values))
 }
 
-fn parse_grid_layout(input: &str) -> ParserResult {
+fn parse_grid_layout(input: &str, latin_square: bool, shape: Option<Shape>) -> ParserResult {
     lazy_static! {
-        static ref CELL_REGEX: Regex = Regex::new("[.]|\\d+").unwrap();
+        static ref CELL_REGEX: Regex = Regex::new("\\[[^\\]]*\\]|[.]|\\d+").unwrap();
     }
 
     let parts = CELL_REGEX
         .find_iter(input)
         .map(|mat| mat.as_str())
         .collect::<Vec<_>>();
-    let dim = guess_dimension(parts.len())?;
-    let num_values = dim * dim;
+    let shape = grid_shape(parts.len(), shape, latin_square)?;
+    let num_values = shape.num_values;
 
     let mut fixed_values = FixedValues::new();
+    let mut candidates = Candidates::new();
+
+    let parse_value = |part: &str| {
+        let value = part
+            .parse::<ValueType>()
+            .map_err(|_| Error::parse(format!("Value out of range: {part}.")))?;
+        if value == 0 || value > num_values as ValueType {
+            return Err(Error::parse(format!("Value out of range: {value}.")));
+        }
+        Ok(CellValue::from_display_value(value))
+    };
 
     for (i, part) in parts.iter().enumerate() {
-        if *part != "." {
-            let value = part.parse::<ValueType>().expect("Unparsable number.");
-            if value == 0 || value > num_values as ValueType {
-                return Err(format!("Value out of range: {value}."));
+        if let Some(list) = part.strip_prefix('[') {
+            // A list of candidates, such as `[1,4,7]`.
+            let values = list
+                .split(|c: char| !c.is_ascii_digit())
+                .filter(|s| !s.is_empty())
+                .map(parse_value)
+                .collect::<Result<Vec<_>, _>>()?;
+            match values.as_slice() {
+                [] => return Err(Error::parse(format!("No candidates for cell: {part}"))),
+                [value] => fixed_values.push((i, *value)),
+                _ => candidates.push((i, values)),
             }
-            fixed_values.push((i, CellValue::from_display_value(value)));
+        } else if *part != "." {
+            fixed_values.push((i, parse_value(part)?));
         }
     }
 
-    Ok(Constraint {
-        shape: Shape::new(dim),
-        fixed_values,
-        x_sudoku: false,
-    })
+    let mut constraint = Constraint::new(shape, fixed_values);
+    constraint.candidates = candidates;
+    Ok(constraint)
 }
@@ -0,0 +1,231 @@
+use std::fmt::Write;
+
+use crate::solver::TraceEvent;
+use crate::types::{CellIndex, CellValue};
+
+// How the search tree is written: as a Graphviz digraph, or a JSON list of
+// nodes with the index of their parents.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default)]
+pub enum SearchTreeFormat {
+    #[default]
+    Dot,
+    Json,
+}
+
+// Larger trees can't be drawn, so deeper nodes are only counted in their
+// deepest kept ancestor.
+const MAX_NODES: usize = 10000;
+
+struct Node {
+    parent: Option<usize>,
+    // None for the root, which is the grid before any guesses.
+    value: Option<(CellIndex, CellValue)>,
+    last_value: bool,
+    // The share of the search space below the node.
+    progress: f64,
+    // The values removed by propagating the node's value.
+    eliminations: u64,
+    contradiction: bool,
+    // The solutions found at the node, and at the nodes below it which weren't
+    // kept.
+    solutions: u64,
+    // The nodes below this one which weren't kept.
+    hidden: u64,
+}
+
+// The search tree built from the trace of a search, for --search-tree. Each
+// node is a value tried in a cell (a guess, or the last value after the
+// guesses failed), below the values which were fixed when it was tried.
+pub struct SearchTree {
+    side_len: u32,
+    nodes: Vec<Node>,
+    // The depth of each node on the path to the current node, and its index in
+    // `nodes` if it was kept.
+    path: Vec<(usize, Option<usize>)>,
+}
+
+impl SearchTree {
+    pub fn new(side_len: u32) -> SearchTree {
+        let root = Node {
+            parent: None,
+            value: None,
+            last_value: false,
+            progress: 1.0,
+            eliminations: 0,
+            contradiction: false,
+            solutions: 0,
+            hidden: 0,
+        };
+        SearchTree {
+            side_len,
+            nodes: vec![root],
+            path: vec![(0, Some(0))],
+        }
+    }
+
+    pub fn add(&mut self, event: &TraceEvent) {
+        match *event {
+            // A guess is below the values fixed at the depth before it, while
+            // a last value takes the place of the guesses at its depth.
+            TraceEvent::Guess {
+                depth,
+                cell,
+                value,
+                progress,
+            } => {
+                self.pop_to(|node_depth| node_depth < depth);
+                self.push(depth, cell, value, progress, false);
+            }
+            TraceEvent::LastValue {
+                depth,
+                cell,
+                value,
+                progress,
+            } => {
+                self.pop_to(|node_depth| node_depth <= depth);
+                self.push(depth, cell, value, progress, true);
+            }
+            TraceEvent::Propagation {
+                eliminations,
+                contradiction,
+                ..
+            } => {
+                if let (_, Some(index)) = self.path[self.path.len() - 1] {
+                    let node = &mut self.nodes[index];
+                    node.eliminations += eliminations;
+                    node.contradiction |= contradiction;
+                }
+            }
+            TraceEvent::Solution { .. } => {
+                let index = self.kept_ancestor();
+                self.nodes[index].solutions += 1;
+            }
+            TraceEvent::Restart => self.path.truncate(1),
+            TraceEvent::Backtrack { .. } => {}
+        }
+    }
+
+    pub fn render(&self, format: SearchTreeFormat) -> String {
+        match format {
+            SearchTreeFormat::Dot => self.render_dot(),
+            SearchTreeFormat::Json => self.render_json(),
+        }
+    }
+
+    // Pop the path until the current node's depth matches, keeping the root.
+    fn pop_to(&mut self, keep: impl Fn(usize) -> bool) {
+        while self.path.len() > 1 && !keep(self.path[self.path.len() - 1].0) {
+            self.path.pop();
+        }
+    }
+
+    fn push(
+        &mut self,
+        depth: usize,
+        cell: CellIndex,
+        value: CellValue,
+        progress: f64,
+        last_value: bool,
+    ) {
+        let parent = self.kept_ancestor();
+        let parent_kept = self.path[self.path.len() - 1].1.is_some();
+        let index = if self.nodes.len() < MAX_NODES && parent_kept {
+            self.nodes.push(Node {
+                parent: Some(parent),
+                value: Some((cell, value)),
+                last_value,
+                progress,
+                eliminations: 0,
+                contradiction: false,
+                solutions: 0,
+                hidden: 0,
+            });
+            Some(self.nodes.len() - 1)
+        } else {
+            self.nodes[parent].hidden += 1;
+            None
+        };
+        self.path.push((depth, index));
+    }
+
+    fn kept_ancestor(&self) -> usize {
+        self.path
+            .iter()
+            .rev()
+            .find_map(|(_, index)| *index)
+            .unwrap()
+    }
+
+    fn label(&self, node: &Node) -> String {
+        let Some((cell, value)) = node.value else {
+            return "root".to_string();
+        };
+        let side_len = self.side_len as usize;
+        let mut label = format!("R{}C{}={}", cell / side_len + 1, cell % side_len + 1, value);
+        if node.last_value {
+            label.push_str(" (last)");
+        }
+        label
+    }
+
+    fn render_dot(&self) -> String {
+        let mut output = String::new();
+
+        output.push_str("digraph search_tree {\n");
+        for (i, node) in self.nodes.iter().enumerate() {
+            let mut label = self.label(node);
+            if node.solutions > 0 {
+                write!(label, "\\n{} solutions", node.solutions).unwrap();
+            }
+            if node.hidden > 0 {
+                write!(label, "\\n+{} nodes", node.hidden).unwrap();
+            }
+            let color = if node.solutions > 0 {
+                "green"
+            } else if node.contradiction {
+                "red"
+            } else {
+                "black"
+            };
+            writeln!(output, "  n{i} [label=\"{label}\" color={color}];").unwrap();
+        }
+        for (i, node) in self.nodes.iter().enumerate() {
+            if let Some(parent) = node.parent {
+                writeln!(
+                    output,
+                    "  n{parent} -> n{i} [label=\"-{} ({:.3e})\"];",
+                    node.eliminations, node.progress
+                )
+                .unwrap();
+            }
+        }
+        output.push_str("}\n");
+
+        output
+    }
+
+    fn render_json(&self) -> String {
+        let nodes = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| {
+                serde_json::json!({
+                    "id": i,
+                    "parent": node.parent,
+                    "label": self.label(node),
+                    "last_value": node.last_value,
+                    "progress": node.progress,
+                    "eliminations": node.eliminations,
+                    "contradiction": node.contradiction,
+                    "solutions": node.solutions,
+                    "hidden": node.hidden,
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut output = serde_json::json!({ "nodes": nodes }).to_string();
+        output.push('\n');
+
+        output
+    }
+}
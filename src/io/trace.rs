@@ -0,0 +1,232 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::solver::TraceEvent;
+use crate::types::{CellIndex, CellValue, ValueType};
+
+// A trace file starts with this, then the version and the side length of the
+// grid. Each event follows as a tag byte and its fields as LEB128 varints, so
+// most events take 3 to 6 bytes. The progress of a value is stored as the
+// 8 bytes of the float.
+const MAGIC: &[u8; 8] = b"LSSTRACE";
+const VERSION: u8 = 2;
+
+const GUESS: u8 = 0;
+const PROPAGATION: u8 = 1;
+const CONTRADICTION: u8 = 2;
+const BACKTRACK: u8 = 3;
+const SOLUTION: u8 = 4;
+const RESTART: u8 = 5;
+const LAST_VALUE: u8 = 6;
+
+// Writes the events of a search to a trace file. Errors can't be returned from
+// the trace callback, so the first one is kept and returned by `finish`.
+pub struct TraceWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    error: Option<io::Error>,
+}
+
+impl TraceWriter {
+    pub fn create(path: &Path, side_len: u32) -> Result<TraceWriter, String> {
+        let file = File::create(path)
+            .map_err(|e| format!("Could not write trace {}: {}", path.display(), e))?;
+        let mut new = TraceWriter {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(file),
+            error: None,
+        };
+        new.write_bytes(MAGIC);
+        new.write_bytes(&[VERSION]);
+        new.write_varint(side_len as u64);
+        Ok(new)
+    }
+
+    pub fn write(&mut self, event: &TraceEvent) {
+        match *event {
+            TraceEvent::Guess {
+                depth,
+                cell,
+                value,
+                progress,
+            } => {
+                self.write_bytes(&[GUESS]);
+                self.write_value(depth, cell, value, progress);
+            }
+            TraceEvent::LastValue {
+                depth,
+                cell,
+                value,
+                progress,
+            } => {
+                self.write_bytes(&[LAST_VALUE]);
+                self.write_value(depth, cell, value, progress);
+            }
+            TraceEvent::Propagation {
+                depth,
+                handlers,
+                eliminations,
+                contradiction,
+            } => {
+                self.write_bytes(&[if contradiction {
+                    CONTRADICTION
+                } else {
+                    PROPAGATION
+                }]);
+                self.write_varint(depth as u64);
+                self.write_varint(handlers);
+                self.write_varint(eliminations);
+            }
+            TraceEvent::Backtrack { depth, cell } => {
+                self.write_bytes(&[BACKTRACK]);
+                self.write_varint(depth as u64);
+                self.write_varint(cell as u64);
+            }
+            TraceEvent::Solution { depth } => {
+                self.write_bytes(&[SOLUTION]);
+                self.write_varint(depth as u64);
+            }
+            TraceEvent::Restart => self.write_bytes(&[RESTART]),
+        }
+    }
+
+    pub fn finish(&mut self) -> Result<(), String> {
+        if self.error.is_none() {
+            self.error = self.writer.flush().err();
+        }
+        match self.error.take() {
+            Some(e) => Err(format!(
+                "Could not write trace {}: {}",
+                self.path.display(),
+                e
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn write_value(&mut self, depth: usize, cell: CellIndex, value: CellValue, progress: f64) {
+        self.write_varint(depth as u64);
+        self.write_varint(cell as u64);
+        self.write_varint(value.index() as u64);
+        self.write_bytes(&progress.to_le_bytes());
+    }
+
+    fn write_varint(&mut self, mut value: u64) {
+        let mut bytes = [0; 10];
+        let mut len = 0;
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes[len] = byte;
+                len += 1;
+                break;
+            }
+            bytes[len] = byte | 0x80;
+            len += 1;
+        }
+        self.write_bytes(&bytes[..len]);
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        if self.error.is_none() {
+            self.error = self.writer.write_all(bytes).err();
+        }
+    }
+}
+
+pub struct Trace {
+    pub side_len: u32,
+    pub events: Vec<TraceEvent>,
+}
+
+pub fn load(path: &Path) -> Result<Trace, String> {
+    let mut bytes = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut bytes))
+        .map_err(|e| format!("Could not read trace {}: {}", path.display(), e))?;
+    parse(&bytes).map_err(|e| format!("Invalid trace {}: {}", path.display(), e))
+}
+
+fn parse(bytes: &[u8]) -> Result<Trace, String> {
+    let Some(rest) = bytes.strip_prefix(MAGIC) else {
+        return Err("not a trace file".to_string());
+    };
+    let mut reader = Reader { bytes: rest };
+    let version = reader.byte()?;
+    if version != VERSION {
+        return Err(format!("unsupported version {}", version));
+    }
+    let side_len = reader.varint()? as u32;
+
+    let mut events = Vec::new();
+    while !reader.bytes.is_empty() {
+        let event = match reader.byte()? {
+            GUESS => TraceEvent::Guess {
+                depth: reader.varint()? as usize,
+                cell: reader.varint()? as usize,
+                value: CellValue::from_index(reader.varint()? as ValueType),
+                progress: reader.float()?,
+            },
+            LAST_VALUE => TraceEvent::LastValue {
+                depth: reader.varint()? as usize,
+                cell: reader.varint()? as usize,
+                value: CellValue::from_index(reader.varint()? as ValueType),
+                progress: reader.float()?,
+            },
+            tag @ (PROPAGATION | CONTRADICTION) => TraceEvent::Propagation {
+                depth: reader.varint()? as usize,
+                handlers: reader.varint()?,
+                eliminations: reader.varint()?,
+                contradiction: tag == CONTRADICTION,
+            },
+            BACKTRACK => TraceEvent::Backtrack {
+                depth: reader.varint()? as usize,
+                cell: reader.varint()? as usize,
+            },
+            SOLUTION => TraceEvent::Solution {
+                depth: reader.varint()? as usize,
+            },
+            RESTART => TraceEvent::Restart,
+            tag => return Err(format!("unknown event {}", tag)),
+        };
+        events.push(event);
+    }
+    Ok(Trace { side_len, events })
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8, String> {
+        let (&byte, rest) = self
+            .bytes
+            .split_first()
+            .ok_or_else(|| "unexpected end of file".to_string())?;
+        self.bytes = rest;
+        Ok(byte)
+    }
+
+    fn float(&mut self) -> Result<f64, String> {
+        let mut bytes = [0; 8];
+        for byte in &mut bytes {
+            *byte = self.byte()?;
+        }
+        Ok(f64::from_le_bytes(bytes))
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("invalid number".to_string())
+    }
+}
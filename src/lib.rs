@@ -1,4 +1,14 @@
+pub mod bench;
+pub mod canonical;
+#[cfg(feature = "db")]
+pub mod db;
+pub mod error;
 pub mod io;
+#[cfg(feature = "serve")]
+pub mod server;
 pub mod solver;
+pub mod transform;
 pub mod types;
 pub mod value_set;
+#[cfg(feature = "wasm")]
+pub mod wasm;
@@ -1,213 +1,2041 @@
+use std::cell::{Cell, RefCell};
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
+use std::rc::Rc;
+use std::time::Duration;
 
 use clap::Parser as _;
 use rand::prelude::SliceRandom;
 use rand::SeedableRng;
 
-use large_sudoku_solver::io::{input, output, parser};
+use large_sudoku_solver::canonical;
+#[cfg(feature = "db")]
+use large_sudoku_solver::db;
+use large_sudoku_solver::error::Error;
+use large_sudoku_solver::io::{
+    checkpoint, graph, input, metrics, output, parser, search_tree, trace,
+};
+#[cfg(feature = "serve")]
+use large_sudoku_solver::server;
 use large_sudoku_solver::solver;
+use large_sudoku_solver::transform::{self, Transform};
 use large_sudoku_solver::types::Constraint;
+use large_sudoku_solver::types::FixedValues;
 use large_sudoku_solver::types::RngType;
 
+// Receives the final puzzle produced by the generator/minimizer.
+type PuzzleSink<'a> = &'a dyn Fn(&Constraint, &FixedValues, &solver::Counters) -> Result<(), Error>;
+
+// Whether an action ran to completion, or stopped early at a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Completion {
+    Finished,
+    LimitReached,
+}
+
+type ActionResult = Result<Completion, Error>;
+
+// Growth of the restart limit for --restarts geometric.
+const GEOMETRIC_RESTART_FACTOR: f64 = 1.5;
+
+// Exit code when the search stopped at --time-limit or --max-guesses.
+const LIMIT_REACHED_EXIT_CODE: u8 = 2;
+
+// Saves the search state for --checkpoint, and restores it for --resume.
+struct Checkpointing<'a> {
+    path: Option<&'a Path>,
+    interval: Duration,
+    resume: Option<solver::Checkpoint>,
+    puzzle: &'a str,
+    action: &'static str,
+}
+
+impl Checkpointing<'_> {
+    fn save(&self, checkpoint: &solver::Checkpoint) -> Result<(), Error> {
+        match self.path {
+            Some(path) => {
+                checkpoint::save(path, self.puzzle, self.action, checkpoint).map_err(Error::Storage)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+const SOLVER_PROGRESS_SCALE: u64 = 10000;
+
+// Updates the progress bar from the solver counters, and remembers the latest
+// counters.
+fn solver_progress_callback(
+    progress: Rc<output::Progress>,
+    last_counters: Rc<Cell<solver::Counters>>,
+) -> Box<solver::ProgressCallback> {
+    Box::new(move |counters: &solver::Counters| {
+        last_counters.set(*counters);
+        progress.update(
+            (counters.progress_ratio * (SOLVER_PROGRESS_SCALE as f64)) as u64,
+            || format!(
+                "{{ eta: {} rate: {:.0} nodes/s }} {{ solutions: {} guesses: {} values_tried: {} constraints_processed: {} progress_ratio: {}{} peak_memory: {} }}",
+                counters.eta_secs.map_or("unknown".to_string(), output::format_duration),
+                counters.nodes_per_sec,
+                counters.solutions,
+                counters.guesses,
+                counters.values_tried,
+                counters.constraints_processed,
+                counters.progress_ratio,
+                counters.progress_bounds.map_or(String::new(), |(lower, upper)| format!(
+                    " ({:.4} to {:.4})",
+                    lower, upper
+                )),
+                output::format_bytes(counters.peak_memory)
+            ),
+            || output::counters_json(counters),
+        );
+    })
+}
+
+struct SolverResult {
+    num_solutions: usize,
+    last_output: Option<solver::Output>,
+    counters: solver::Counters,
+}
+
 fn run_solver(
     constraint: &Constraint,
     mut writer: output::ProgressWriter,
     mut config: solver::Config,
-    num_solutions: usize,
-) -> Result<usize, String> {
+    mut num_solutions: usize,
+    output_options: output::OutputOptions,
+    checkpointing: Option<Checkpointing>,
+) -> Result<SolverResult, Error> {
     let mut solutions_found = 0;
+    let mut last_output = None;
+    let last_counters = Rc::new(Cell::new(solver::Counters::default()));
+    let mut result = Ok(());
 
-    const SCALE: u64 = 10000;
-    output::with_progress_bar(SCALE, |bar| {
-        config.progress_callback = Some(Box::new(move |counters: &solver::Counters| {
-            bar.set_position((counters.progress_ratio * (SCALE as f64)) as u64);
-            bar.set_message(format!(
-                "{{ solutions: {} guesses: {} values_tried: {} constraints_processed: {} progress_ratio: {} }}",
-                counters.solutions,
-                counters.guesses,
-                counters.values_tried,
-                counters.constraints_processed,
-                counters.progress_ratio
-            ));
-        }));
+    // Stop cleanly on ctrl-c, so that the search state can be saved.
+    let output_partial = config.record_partial_assignment;
+    if output_partial || checkpointing.as_ref().is_some_and(|c| c.path.is_some()) {
+        let token = solver::CancellationToken::new();
+        output::set_ctrlc_cancellation(Some(token.clone()));
+        config.cancellation = Some(token);
+    }
+
+    if let Some(checkpointing) = &checkpointing {
+        if checkpointing.path.is_some() {
+            let (path, puzzle, action) = (
+                checkpointing.path.map(Path::to_path_buf),
+                checkpointing.puzzle.to_string(),
+                checkpointing.action,
+            );
+            config.checkpoint_interval = Some(checkpointing.interval);
+            config.checkpoint_callback = Some(Box::new(move |checkpoint| {
+                if let Some(path) = &path {
+                    if let Err(e) = checkpoint::save(path, &puzzle, action, checkpoint) {
+                        eprintln!("Error: {}", e);
+                    }
+                }
+            }));
+        }
+    }
 
-        for solution in solver::solution_iter(constraint, config).take(num_solutions) {
-            writer.write(&output::solver_item_as_grid(constraint, &solution));
+    output::with_progress_bar(SOLVER_PROGRESS_SCALE, |progress| {
+        config.progress_callback = Some(solver_progress_callback(progress, last_counters.clone()));
+
+        let mut solutions = solver::solution_iter(constraint, config);
+        if let Some(checkpoint) = checkpointing.as_ref().and_then(|c| c.resume.as_ref()) {
+            if let Err(e) = solutions.restore(checkpoint) {
+                result = Err(Error::Solver(e));
+                return;
+            }
+            // Solutions found before the checkpoint count towards the limit.
+            num_solutions = num_solutions.saturating_sub(checkpoint.counters.solutions as usize);
+        }
+
+        for solution in solutions.by_ref().take(num_solutions) {
+            if !matches!(solution, solver::Output::Empty) {
+                writer.write(&output_options.solver_item(constraint, &solution));
+            }
 
             solutions_found += 1;
+            last_output = Some(solution);
+        }
+
+        if let Some(checkpointing) = &checkpointing {
+            if let (true, Some(path)) = (solutions.stopped_early(), checkpointing.path) {
+                result = solutions
+                    .checkpoint()
+                    .map_err(Error::Solver)
+                    .and_then(|checkpoint| checkpointing.save(&checkpoint));
+                if result.is_ok() {
+                    eprintln!("Checkpoint saved to {}", path.display());
+                }
+            }
+        }
+
+        if output_partial && solutions.stopped_early() {
+            let partial_assignment = solutions.partial_assignment();
+            eprintln!(
+                "Deepest partial assignment: {} of {} cells",
+                partial_assignment.len(),
+                constraint.shape.num_cells
+            );
+            writer.write(&output_options.fixed_values(constraint, &partial_assignment));
         }
 
         drop(writer);
     });
+    output::set_ctrlc_cancellation(None);
+    result?;
+
+    print_handler_profile(&last_counters.get());
+    if output_options.json_lines {
+        print!("{}", output::counters_as_json_line(&last_counters.get()));
+    }
+
+    Ok(SolverResult {
+        num_solutions: solutions_found,
+        last_output,
+        counters: last_counters.get(),
+    })
+}
+
+// With --profile, the table goes to stderr so that it doesn't mix with the
+// solutions.
+fn print_handler_profile(counters: &solver::Counters) {
+    if let Some(profile) = &counters.handler_profile {
+        eprint!("{}", output::handler_profile_as_text(profile));
+    }
+}
+
+// Report partial statistics if the search was stopped early by a limit.
+fn check_limit_reached(counters: &solver::Counters) -> Completion {
+    if !counters.limit_reached && !counters.cancelled {
+        return Completion::Finished;
+    }
 
-    Ok(solutions_found)
+    eprintln!(
+        "{} - stopped after {} guesses ({}) with {} solutions found.",
+        if counters.cancelled {
+            "Interrupted"
+        } else {
+            "Limit reached"
+        },
+        counters.guesses,
+        output::format_duration(counters.elapsed_secs),
+        counters.solutions
+    );
+    Completion::LimitReached
+}
+
+// Options for minimizing, from the command line.
+struct MinimizerOptions {
+    no_guesses: bool,
+    // With more than one pass, only the puzzle with the fewest clues is written.
+    passes: usize,
+    num_threads: Option<usize>,
 }
 
 fn run_minimizer(
     mut constraint: Constraint,
     mut writer: output::ProgressWriter,
-    no_guesses: bool,
+    make_config: &(dyn Fn() -> solver::Config + Sync),
+    options: &MinimizerOptions,
     mut rng: RngType,
-) -> Result<(), String> {
-    constraint.fixed_values.shuffle(&mut rng);
+    puzzle_sink: PuzzleSink,
+    output_options: output::OutputOptions,
+) -> ActionResult {
+    let config = make_config();
+    let (target_clues, target_difficulty) = (config.target_clues, config.target_difficulty);
+    let make_config = || solver::Config {
+        no_guesses: options.no_guesses,
+        ..make_config()
+    };
+
+    let mut last_fixed_values = None;
+    let last_counters = Rc::new(Cell::new(solver::MinimizerCounters::default()));
 
-    let num_fixed_values = constraint.fixed_values.len();
-    output::with_progress_bar(num_fixed_values as u64, |bar| {
+    let num_cells_to_try = constraint.fixed_values.len() * options.passes;
+    output::with_progress_bar(num_cells_to_try as u64, |progress| {
+        let last_counters = last_counters.clone();
         let progress_callback = Box::new(move |counters: &solver::MinimizerCounters| {
-            bar.set_position(counters.cells_tried);
-            bar.set_message(format!(
-                "{{ progress: {}/{} cells cells_removed: {} total_guesses: {} }} {{ solver_progress: {} }}",
+            last_counters.set(*counters);
+            progress.update(
                 counters.cells_tried,
-                num_fixed_values,
-                counters.cells_removed,
-                counters.solver_counters.guesses,
-                counters.solver_counters.progress_ratio
-            ));
+                || format!(
+                    "{{ progress: {}/{} cells cells_removed: {} total_guesses: {} }} {{ solver_progress: {} peak_memory: {} }}",
+                    counters.cells_tried,
+                    num_cells_to_try,
+                    counters.cells_removed,
+                    counters.solver_counters.guesses,
+                    counters.solver_counters.progress_ratio,
+                    output::format_bytes(counters.solver_counters.peak_memory)
+                ),
+                || output::minimizer_counters_json(counters),
+            );
         });
 
-        let config = solver::Config {
-            no_guesses,
-            ..solver::Config::default()
-        };
-
-        for fixed_values in solver::minimize(&constraint, config, Some(progress_callback)) {
-            writer.write(&output::fixed_values_as_grid(&constraint, &fixed_values));
+        if options.passes > 1 {
+            last_fixed_values = solver::minimize_best_of(
+                &constraint,
+                &make_config,
+                options.passes,
+                options.num_threads.unwrap_or(1),
+                &mut rng,
+                Some(progress_callback),
+            );
+            if let Some(fixed_values) = &last_fixed_values {
+                writer.write(&output_options.fixed_values(&constraint, fixed_values));
+            }
+        } else {
+            constraint.fixed_values.shuffle(&mut rng);
+            for fixed_values in
+                solver::minimize(&constraint, make_config(), Some(progress_callback))
+            {
+                writer.write(&output_options.fixed_values(&constraint, &fixed_values));
+                last_fixed_values = Some(fixed_values);
+            }
         }
 
         drop(writer);
     });
 
-    Ok(())
+    if output_options.json_lines {
+        print!(
+            "{}",
+            output::minimizer_counters_as_json_line(&last_counters.get())
+        );
+    }
+
+    let counters = last_counters.get();
+    let stopped_early =
+        counters.solver_counters.limit_reached || counters.solver_counters.cancelled;
+    if !counters.target_reached && !stopped_early {
+        let num_clues = last_fixed_values
+            .as_ref()
+            .unwrap_or(&constraint.fixed_values)
+            .len();
+        if let Some(target_clues) = target_clues {
+            eprintln!(
+                "Could not reach {} clues - the smallest puzzle found has {} clues.",
+                target_clues, num_clues
+            );
+        } else if let Some(difficulty) = target_difficulty {
+            eprintln!(
+                "Could not reach {} difficulty - the puzzle found has {} clues.",
+                difficulty.name(),
+                num_clues
+            );
+        }
+    }
+
+    if let Some(fixed_values) = last_fixed_values {
+        puzzle_sink(
+            &constraint,
+            &fixed_values,
+            &last_counters.get().solver_counters,
+        )?;
+    }
+
+    Ok(check_limit_reached(&last_counters.get().solver_counters))
 }
 
+// Fill the template with a random solution, then carve clues from it with the
+// minimizer, so that the puzzle has a unique solution.
+#[allow(clippy::too_many_arguments)]
 fn run_generator(
     constraint: Constraint,
     writer: output::ProgressWriter,
-    _rng: RngType,
-) -> Result<(), String> {
-    let config = solver::Config {
-        output_type: solver::OutputType::Guesses,
-        ..solver::Config::default()
+    solver_config: solver::Config,
+    make_minimizer_config: &(dyn Fn() -> solver::Config + Sync),
+    minimizer_options: &MinimizerOptions,
+    mut rng: RngType,
+    puzzle_sink: PuzzleSink,
+    output_options: output::OutputOptions,
+) -> ActionResult {
+    let solver_config = solver::Config {
+        search_randomizer: solver_config
+            .search_randomizer
+            .or_else(|| Some(RngType::from_rng(&mut rng).unwrap())),
+        ..solver_config
     };
-    let num_results = run_solver(&constraint, writer, config, 1)?;
-    if num_results == 0 {
-        return Err("Input has no solution - puzzle could not be generated.".to_string());
+    // The summary is only written for the minimizer.
+    let result = run_solver(
+        &constraint,
+        Box::new(output::EmptyWriter {}),
+        solver_config,
+        1,
+        output::OutputOptions {
+            json_lines: false,
+            ..output_options
+        },
+        None,
+    )?;
+    if result.counters.limit_reached || result.counters.cancelled {
+        return Ok(check_limit_reached(&result.counters));
     }
+    let Some(solver::Output::Solution(solution)) = result.last_output else {
+        return Err(Error::Solver(
+            "Input has no solution - puzzle could not be generated.".to_string(),
+        ));
+    };
 
-    Ok(())
+    let constraint = Constraint {
+        fixed_values: solution.into_iter().enumerate().collect(),
+        ..constraint
+    };
+    // Only the final puzzle is written, not each step of the carving.
+    run_minimizer(
+        constraint,
+        output::last_item_writer(writer),
+        make_minimizer_config,
+        minimizer_options,
+        rng,
+        puzzle_sink,
+        output_options,
+    )
 }
 
-fn run_count(constraint: Constraint) -> Result<(), String> {
-    let config = solver::Config {
+fn run_count(
+    constraint: Constraint,
+    writer: output::ProgressWriter,
+    config: solver::Config,
+    output_options: output::OutputOptions,
+    checkpointing: Checkpointing,
+    num_threads: Option<usize>,
+) -> ActionResult {
+    let mut config = solver::Config {
         output_type: solver::OutputType::Empty,
-        ..solver::Config::default()
+        ..config
     };
 
+    if let Some(num_threads) = num_threads {
+        if checkpointing.path.is_some() || checkpointing.resume.is_some() {
+            return Err(Error::Options(
+                "--checkpoint and --resume can't be used with --threads.".to_string(),
+            ));
+        }
+
+        let last_counters = Rc::new(Cell::new(solver::Counters::default()));
+        output::with_progress_bar(SOLVER_PROGRESS_SCALE, |progress| {
+            config.progress_callback =
+                Some(solver_progress_callback(progress, last_counters.clone()));
+            // The final counters are passed to the progress callback.
+            solver::count_parallel(&constraint, config, num_threads);
+        });
+
+        print_handler_profile(&last_counters.get());
+        if output_options.json_lines {
+            print!("{}", output::counters_as_json_line(&last_counters.get()));
+        }
+        return Ok(check_limit_reached(&last_counters.get()));
+    }
+
+    // Only the partial assignment is written, with --output-partial.
     run_solver(
         &constraint,
-        Box::new(output::EmptyWriter {}),
+        writer,
         config,
         usize::MAX,
+        output_options,
+        Some(checkpointing),
     )
-    .map(|_| ())
+    .map(|result| check_limit_reached(&result.counters))
+}
+
+fn run_explain(constraint: Constraint, output_options: output::OutputOptions) -> ActionResult {
+    let explanation = solver::explain(&constraint);
+    for deduction in &explanation.deductions {
+        if output_options.json_lines {
+            print!("{}", output::deduction_as_json_line(&constraint, deduction));
+        } else {
+            println!("{}", output::deduction_as_text(&constraint, deduction));
+        }
+    }
+
+    match &explanation.solution {
+        Some(solution) => print!(
+            "{}",
+            output_options.solver_item(&constraint, &solver::Output::Solution(solution.clone()))
+        ),
+        None => print!(
+            "{}",
+            output_options.fixed_values(&constraint, &explanation.fixed_values)
+        ),
+    }
+    if explanation.contradiction {
+        return Err(Error::Solver("The puzzle has no solution.".to_string()));
+    }
+    if explanation.solution.is_none() {
+        eprintln!("The techniques were not enough to solve the puzzle.");
+    }
+    Ok(Completion::Finished)
+}
+
+fn run_rate(
+    constraint: Constraint,
+    config: solver::Config,
+    output_options: output::OutputOptions,
+) -> ActionResult {
+    let rating = solver::rate(&constraint, config);
+    if output_options.json_lines {
+        print!("{}", output::rating_as_json_line(&rating));
+    } else {
+        println!("Difficulty: {}", output::rating_as_text(&rating));
+    }
+    match rating.search_counters {
+        Some(counters) => Ok(check_limit_reached(&counters)),
+        None => Ok(Completion::Finished),
+    }
+}
+
+fn run_verify(constraint: Constraint, output_options: output::OutputOptions) -> ActionResult {
+    let violations = solver::verify(&constraint);
+    for violation in &violations {
+        if output_options.json_lines {
+            print!("{}", output::violation_as_json_line(&constraint, violation));
+        } else {
+            println!("{}", output::violation_as_text(&constraint, violation));
+        }
+    }
+
+    if !violations.is_empty() {
+        return Err(Error::Solver(format!(
+            "{} constraints are violated.",
+            violations.len()
+        )));
+    }
+    if !output_options.json_lines {
+        let complete = constraint.fixed_values.len() == constraint.shape.num_cells;
+        println!(
+            "The {} grid satisfies all constraints.",
+            if complete {
+                "complete"
+            } else {
+                "partly filled"
+            }
+        );
+    }
+    Ok(Completion::Finished)
+}
+
+fn run_check_minimal(
+    constraint: Constraint,
+    config: solver::Config,
+    output_options: output::OutputOptions,
+) -> ActionResult {
+    let minimality = solver::check_minimal(&constraint, config);
+    if output_options.json_lines {
+        print!(
+            "{}",
+            output::minimality_as_json_line(&constraint, &minimality)
+        );
+    } else {
+        print!("{}", output::minimality_as_text(&constraint, &minimality));
+    }
+    let completion = check_limit_reached(&minimality.counters);
+    if completion == Completion::Finished && !minimality.is_minimal() {
+        return Err(Error::Solver("The puzzle is not minimal.".to_string()));
+    }
+    Ok(completion)
+}
+
+fn run_canonicalize(constraint: Constraint, output_options: output::OutputOptions) -> ActionResult {
+    let fixed_values = canonical::canonicalize(&constraint).map_err(Error::Options)?;
+    print!(
+        "{}",
+        output_options.fixed_values(&constraint, &fixed_values)
+    );
+    Ok(Completion::Finished)
+}
+
+fn run_fingerprint(constraint: Constraint, output_options: output::OutputOptions) -> ActionResult {
+    let fingerprint = canonical::fingerprint(&constraint).map_err(Error::Options)?;
+    if output_options.json_lines {
+        print!("{}", output::fingerprint_as_json_line(fingerprint));
+    } else {
+        println!("{}", output::fingerprint_as_text(fingerprint));
+    }
+    Ok(Completion::Finished)
+}
+
+fn run_transform(
+    constraint: Constraint,
+    specs: &[String],
+    mut rng: RngType,
+    output_options: output::OutputOptions,
+) -> ActionResult {
+    let transforms = if specs.is_empty() {
+        Transform::random(&constraint.shape, &mut rng)
+    } else {
+        specs
+            .iter()
+            .map(|spec| Transform::parse(spec, &constraint.shape, &mut rng))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Error::Options)?
+    };
+    let transformed = transform::apply_all(&constraint, &transforms).map_err(Error::Options)?;
+    print!(
+        "{}",
+        output_options.fixed_values(&transformed, &transformed.fixed_values)
+    );
+    Ok(Completion::Finished)
+}
+
+fn run_diagnose(
+    constraint: &Constraint,
+    config: solver::Config,
+    output_options: output::OutputOptions,
+) -> Result<(), Error> {
+    let Some(diagnosis) = solver::diagnose(constraint, config) else {
+        return Ok(());
+    };
+    if output_options.json_lines {
+        print!("{}", output::diagnosis_as_json_line(constraint, &diagnosis));
+    } else {
+        println!("No solution. These clues conflict:");
+        print!("{}", output::diagnosis_as_text(constraint, &diagnosis));
+    }
+    if diagnosis.limit_reached {
+        eprintln!("A limit was reached, so some of these clues may not be needed.");
+    }
+    Ok(())
+}
+
+fn run_estimate(
+    constraint: Constraint,
+    config: solver::Config,
+    rng: RngType,
+    num_samples: u64,
+    output_options: output::OutputOptions,
+) -> ActionResult {
+    let config = solver::Config {
+        search_randomizer: Some(rng),
+        ..config
+    };
+
+    let mut estimate = solver::Estimate::default();
+    output::with_progress_bar(num_samples, |progress| {
+        let progress_callback = Box::new(move |estimate: &solver::Estimate| {
+            progress.update(
+                estimate.samples,
+                || {
+                    format!(
+                        "{{ samples: {} solutions: {} std_error: {} dead_ends: {} }}",
+                        estimate.samples,
+                        output::format_log10(estimate.log10_solutions),
+                        output::format_log10(estimate.log10_std_error),
+                        estimate.dead_ends
+                    )
+                },
+                || output::estimate_json(estimate),
+            );
+        });
+        estimate = solver::estimate(&constraint, config, num_samples, Some(progress_callback));
+    });
+
+    if output_options.json_lines {
+        print!("{}", output::estimate_as_json_line(&estimate));
+    } else {
+        println!(
+            "Estimated solutions: {} (95% confidence interval: {} to {})",
+            output::format_log10(estimate.log10_solutions),
+            output::format_log10(estimate.log10_lower),
+            output::format_log10(estimate.log10_upper)
+        );
+        println!(
+            "Samples: {} (dead ends: {})",
+            estimate.samples, estimate.dead_ends
+        );
+    }
+
+    if estimate.limit_reached || estimate.cancelled {
+        eprintln!(
+            "Stopped after {} of {} samples.",
+            estimate.samples, num_samples
+        );
+        return Ok(Completion::LimitReached);
+    }
+    Ok(Completion::Finished)
+}
+
+fn run_export_graph(constraint: Constraint, format: graph::GraphFormat) -> ActionResult {
+    let handlers = solver::describe_handlers(&constraint);
+    print!(
+        "{}",
+        graph::render_graph(&constraint.shape, &handlers, format)
+    );
+    Ok(Completion::Finished)
 }
 
 fn get_rng(args: &CliArgs) -> RngType {
     match args.seed {
         Some(seed) => RngType::seed_from_u64(seed),
+        None if args.deterministic => RngType::seed_from_u64(0),
         None => RngType::from_entropy(),
     }
 }
 
-fn main_with_result(args: CliArgs) -> Result<(), String> {
-    let input = input::load(&args.input)
-        .map_err(|e| format!("Could not read file {}: {}", args.input, e))?;
+#[cfg(feature = "db")]
+fn run_db_list(constraint: Constraint, db: Option<&db::PuzzleDb>) -> ActionResult {
+    let db = db.ok_or_else(|| Error::Options("db-list requires --db".to_string()))?;
+    for record in db.list(constraint.shape.side_len).map_err(Error::Storage)? {
+        println!(
+            "# clues: {} source: {} seed: {} difficulty: {} guesses: {} backtracks: {}",
+            record.num_clues,
+            record.source,
+            record.seed.map_or("none".to_string(), |s| s.to_string()),
+            record
+                .difficulty
+                .map_or("none".to_string(), |d| format!("{:.2}", d)),
+            record.guesses,
+            record.backtracks
+        );
+        println!("{}", record.puzzle);
+    }
+    Ok(Completion::Finished)
+}
+
+#[cfg(feature = "db")]
+fn store_puzzle(
+    db: Option<&db::PuzzleDb>,
+    args: &CliArgs,
+    constraint: &Constraint,
+    fixed_values: &FixedValues,
+    counters: &solver::Counters,
+) -> Result<(), Error> {
+    let db = match db {
+        Some(db) => db,
+        None => return Ok(()),
+    };
+
+    let source = match args.action {
+        CliAction::Generate => "generate",
+        _ => "minimize",
+    };
+    let record = db::PuzzleRecord::new(constraint, fixed_values, source, args.seed, counters);
+    if !db.insert(&record).map_err(Error::Storage)? {
+        eprintln!("Puzzle is already in the database - not stored.");
+    }
+    Ok(())
+}
 
-    let mut constraint = parser::parse_text(&input)?;
+fn parse_puzzle(input: &str, args: &CliArgs) -> Result<Constraint, Error> {
+    let mut constraint = parser::parse_text(input, args.latin_square)?;
     if args.x_sudoku {
         constraint.x_sudoku = true;
     }
+    if args.main_diagonal {
+        constraint.main_diagonal = true;
+    }
+    if args.anti_diagonal {
+        constraint.anti_diagonal = true;
+    }
+    if args.argyle {
+        constraint.argyle = true;
+    }
+    if args.anti_king {
+        constraint.anti_king = true;
+    }
+    if args.windoku {
+        constraint.windoku = true;
+    }
+    if let Some(locked) = &args.lock {
+        let cells = parser::parse_cell_list(locked, &constraint.shape)
+            .map_err(|e| Error::Options(format!("Invalid --lock cells: {}", e)))?;
+        constraint.locked_cells.extend(cells);
+    }
+    Ok(constraint)
+}
+
+fn get_output_options(args: &CliArgs) -> output::OutputOptions {
+    output::OutputOptions {
+        format: args.format,
+        json_lines: args.json,
+    }
+}
 
-    let rng = get_rng(&args);
+fn get_checkpointing<'a>(input: &'a str, args: &'a CliArgs) -> Result<Checkpointing<'a>, Error> {
+    let action = match args.action {
+        CliAction::Solve => "solve",
+        CliAction::Count => "count",
+        _ if args.checkpoint.is_some() || args.resume.is_some() => {
+            return Err(Error::Options(
+                "--checkpoint and --resume only support solve and count.".to_string(),
+            ))
+        }
+        _ => "",
+    };
+    let interval = Duration::try_from_secs_f64(args.checkpoint_interval)
+        .map_err(|e| Error::Options(format!("Invalid checkpoint interval: {}", e)))?;
+    let resume = args
+        .resume
+        .as_deref()
+        .map(|path| checkpoint::load(Path::new(path), input, action))
+        .transpose()
+        .map_err(Error::Storage)?;
 
-    let writer = output::get_writer(args.output_last);
+    Ok(Checkpointing {
+        path: args.checkpoint.as_deref().map(Path::new),
+        interval,
+        resume,
+        puzzle: input,
+        action,
+    })
+}
 
-    match args.action {
-        CliAction::Solve => {
-            run_solver(&constraint, writer, solver::Config::default(), 2).map(|_| ())
+// Records the search for --trace and --search-tree, which are written out
+// once the search is finished.
+#[derive(Default)]
+struct Tracing {
+    writer: Option<trace::TraceWriter>,
+    search_tree: Option<search_tree::SearchTree>,
+}
+
+impl Tracing {
+    fn add(&mut self, event: &solver::TraceEvent) {
+        if let Some(writer) = &mut self.writer {
+            writer.write(event);
+        }
+        if let Some(search_tree) = &mut self.search_tree {
+            search_tree.add(event);
+        }
+    }
+
+    fn finish(&mut self, args: &CliArgs) -> Result<(), Error> {
+        if let Some(writer) = &mut self.writer {
+            writer.finish().map_err(Error::Storage)?;
+        }
+        if let (Some(search_tree), Some(path)) = (&self.search_tree, &args.search_tree) {
+            fs::write(path, search_tree.render(args.search_tree_format)).map_err(|e| {
+                Error::Storage(format!("Could not write search tree {}: {}", path, e))
+            })?;
         }
-        CliAction::Minimize => run_minimizer(constraint, writer, args.no_guesses, rng),
-        CliAction::Generate => run_generator(constraint, writer, rng),
-        CliAction::Count => run_count(constraint),
+        Ok(())
     }
 }
 
-#[derive(clap::Parser, Debug)]
-#[clap(
-    arg_required_else_help = true,
-    about = "Solves and generates sudoku puzzles with large grids (up to 512x512)"
-)]
-struct CliArgs {
-    #[clap(
-        value_enum,
-        hide_possible_values = true,
-        help = "Supported actions:
+// The number of solutions for solve to find. Two are enough to show whether the
+// solution is unique.
+fn get_max_solutions(args: &CliArgs) -> Result<usize, Error> {
+    if args.max_solutions.is_none() && !args.all {
+        return Ok(2);
+    }
+    if !matches!(args.action, CliAction::Solve) || args.explain {
+        return Err(Error::Options(
+            "--max-solutions and --all only support solve.".to_string(),
+        ));
+    }
+    match args.max_solutions {
+        Some(0) => Err(Error::Options(
+            "--max-solutions must be at least 1.".to_string(),
+        )),
+        Some(max_solutions) => Ok(max_solutions),
+        None => Ok(usize::MAX),
+    }
+}
 
-  solve:    Solve the input and prove uniqueness
-  minimize: Attempt to remove as many set values from the puzzle as possible
-            while keeping the solution unique
-  generate: Generate a new puzzle using the input as a template (not efficient)
-  count:    Count the number of solutions without printing them"
-    )]
-    action: CliAction,
+// With --trace or --search-tree, record each step of the search.
+fn start_tracing(
+    args: &CliArgs,
+    constraint: &Constraint,
+    config: &mut solver::Config,
+) -> Result<Option<Rc<RefCell<Tracing>>>, Error> {
+    if args.trace.is_none() && args.search_tree.is_none() {
+        return Ok(None);
+    }
+    let searches = match args.action {
+        CliAction::Solve => !args.explain,
+        CliAction::Count => args.threads.is_none(),
+        _ => false,
+    };
+    if !searches {
+        return Err(Error::Options(
+            "--trace and --search-tree only support solve and count without --threads.".to_string(),
+        ));
+    }
+    if config.engine != solver::EngineKind::Propagation {
+        return Err(Error::Options(
+            "--trace and --search-tree are only supported by the propagation engine.".to_string(),
+        ));
+    }
 
-    #[clap(
-        value_parser,
-        help = "One of:
-  Filename to read puzzle from
-  '-' to read from stdin
-  'NxN' size specification for empty grid"
-    )]
-    input: String,
+    let side_len = constraint.shape.side_len;
+    let tracing = Tracing {
+        writer: args
+            .trace
+            .as_deref()
+            .map(|path| trace::TraceWriter::create(Path::new(path), side_len))
+            .transpose()
+            .map_err(Error::Storage)?,
+        search_tree: args
+            .search_tree
+            .as_ref()
+            .map(|_| search_tree::SearchTree::new(side_len)),
+    };
+    let tracing = Rc::new(RefCell::new(tracing));
+    config.trace_callback = Some(Box::new({
+        let tracing = tracing.clone();
+        move |event| tracing.borrow_mut().add(event)
+    }));
+    Ok(Some(tracing))
+}
 
-    #[clap(
-        short,
-        long,
-        help = "Add x-sudoku constraints
-(This can also be specified by adding 'X-Sudoku' inside the puzzle file)"
-    )]
-    x_sudoku: bool,
+// With --metrics-file, write snapshots of the counters while solving. The
+// last snapshot is written when the search is dropped.
+fn start_metrics(
+    args: &CliArgs,
+    config: &mut solver::Config,
+) -> Result<Option<Rc<RefCell<metrics::MetricsWriter>>>, Error> {
+    let Some(path) = &args.metrics_file else {
+        return Ok(None);
+    };
+    if !matches!(args.action, CliAction::Solve | CliAction::Count) || args.explain {
+        return Err(Error::Options(
+            "--metrics-file only supports solve and count.".to_string(),
+        ));
+    }
+    let interval = Duration::try_from_secs_f64(args.metrics_interval)
+        .map_err(|e| Error::Options(format!("Invalid metrics interval: {}", e)))?;
 
-    #[clap(
-        long,
-        help = "Only output the last solution/puzzle
-(Works even if program is aborted with ctrl-c)"
-    )]
-    output_last: bool,
+    let writer = metrics::MetricsWriter::create(Path::new(path), args.metrics_format)
+        .map_err(Error::Storage)?;
+    let writer = Rc::new(RefCell::new(writer));
+    config.metrics_interval = Some(interval);
+    config.metrics_callback = Some(Box::new({
+        let writer = writer.clone();
+        move |counters| writer.borrow_mut().write(counters)
+    }));
+    Ok(Some(writer))
+}
 
-    #[clap(long, help = "Don't allow guessing when generating/minimizing")]
-    no_guesses: bool,
+fn get_handler_priorities(args: &CliArgs) -> Result<Option<solver::HandlerPriorities>, Error> {
+    if !args.handler_priorities && args.handler_priority.is_empty() {
+        return Ok(None);
+    }
+    let mut priorities = solver::HandlerPriorities::default();
+    for spec in &args.handler_priority {
+        let invalid = || Error::Options(format!("Invalid handler priority: {}", spec));
+        let (kind, priority) = spec.split_once('=').ok_or_else(invalid)?;
+        if !solver::HANDLER_KINDS.contains(&kind) {
+            return Err(invalid());
+        }
+        priorities.set(kind, priority.parse().map_err(|_| invalid())?);
+    }
+    Ok(Some(priorities))
+}
 
-    #[clap(long, help = "RNG seed for generator/minimizer")]
-    seed: Option<u64>,
+fn get_config(args: &CliArgs) -> Result<solver::Config, Error> {
+    let time_limit = args
+        .time_limit
+        .map(Duration::try_from_secs_f64)
+        .transpose()
+        .map_err(|e| Error::Options(format!("Invalid time limit: {}", e)))?;
+    // Where the search stops at a time limit depends on the speed of the run,
+    // and so does a guess limit shared by the counting threads.
+    let shared_guess_limit = args.max_guesses.is_some()
+        && args.threads.is_some()
+        && matches!(args.action, CliAction::Count);
+    if args.deterministic && (args.time_limit.is_some() || shared_guess_limit) {
+        return Err(Error::Options(
+            "--deterministic can't be used with --time-limit, or --max-guesses with count --threads."
+                .to_string(),
+        ));
+    }
+    if args.max_grid_memory.is_some() && (args.nogoods || args.early_exit_houses) {
+        return Err(Error::Options(
+            "--max-grid-memory can't be used with --nogoods or --early-exit-houses.".to_string(),
+        ));
+    }
+    let restarts = match args.restarts {
+        CliRestarts::None => None,
+        CliRestarts::Luby => Some(solver::RestartSchedule::Luby(args.restart_base)),
+        CliRestarts::Geometric => Some(solver::RestartSchedule::Geometric(
+            args.restart_base,
+            GEOMETRIC_RESTART_FACTOR,
+        )),
+    };
+    Ok(solver::Config {
+        time_limit,
+        max_guesses: args.max_guesses,
+        restarts,
+        nogoods: args.nogoods.then(|| solver::NogoodConfig {
+            max_size: args.max_nogood_size,
+            ..solver::NogoodConfig::default()
+        }),
+        search_randomizer: restarts.map(|_| get_rng(args)),
+        value_order: match args.value_order {
+            Some(CliValueOrder::Min) => solver::ValueOrder::Min,
+            Some(CliValueOrder::Max) => solver::ValueOrder::Max,
+            Some(CliValueOrder::Random) => solver::ValueOrder::Random,
+            Some(CliValueOrder::LeastConstraining) => solver::ValueOrder::LeastConstraining,
+            // Random values give more varied puzzles.
+            None if matches!(args.action, CliAction::Generate) => solver::ValueOrder::Random,
+            None => solver::ValueOrder::Min,
+        },
+        cell_ordering: match args.cell_order {
+            CliCellOrder::MinValues => solver::CellOrdering::MinValues,
+            CliCellOrder::BacktrackWeighted => solver::CellOrdering::BacktrackWeighted,
+            CliCellOrder::DomWdeg => solver::CellOrdering::DomWdeg,
+            CliCellOrder::Sequential => solver::CellOrdering::Sequential,
+        },
+        grid_strategy: match args.grid_strategy {
+            CliGridStrategy::Trail => solver::GridStrategy::Trail,
+            CliGridStrategy::CopyOnWrite => solver::GridStrategy::CopyOnWrite,
+        },
+        max_grid_memory: args.max_grid_memory.map(|mib| mib << 20),
+        gather_houses: args.gather_houses,
+        incremental_scc: args.incremental_scc,
+        early_exit_houses: args.early_exit_houses,
+        #[cfg(feature = "batch-houses")]
+        batch_houses: args.batch_houses,
+        propagation_level: match args.propagation {
+            CliPropagation::Singles => solver::PropagationLevel::Singles,
+            CliPropagation::Intersections => solver::PropagationLevel::Intersections,
+            CliPropagation::Full => solver::PropagationLevel::Full,
+        },
+        tiered_propagation: args.tiered_propagation,
+        handler_priorities: get_handler_priorities(args)?,
+        profile: args.profile,
+        deterministic: args.deterministic,
+        max_fish_size: args.max_fish_size,
+        symmetry: match args.symmetry {
+            CliSymmetry::None => solver::Symmetry::None,
+            CliSymmetry::Rotational => solver::Symmetry::Rotational,
+            CliSymmetry::Mirror => solver::Symmetry::Mirror,
+            CliSymmetry::Diagonal => solver::Symmetry::Diagonal,
+        },
+        target_clues: args.target_clues,
+        max_removals: args.max_removals,
+        target_difficulty: args.difficulty.map(|difficulty| match difficulty {
+            CliDifficulty::Easy => solver::Difficulty::Easy,
+            CliDifficulty::Medium => solver::Difficulty::Medium,
+            CliDifficulty::Hard => solver::Difficulty::Hard,
+            CliDifficulty::Expert => solver::Difficulty::Expert,
+        }),
+        record_partial_assignment: args.output_partial,
+        engine: match args.engine {
+            CliEngine::Propagation => solver::EngineKind::Propagation,
+            CliEngine::Dlx => solver::EngineKind::Dlx,
+            #[cfg(feature = "sat")]
+            CliEngine::Sat => solver::EngineKind::Sat,
+        },
+        ..solver::Config::default()
+    })
 }
 
-#[derive(clap::ValueEnum, Debug, Clone)]
-enum CliAction {
-    Solve,
-    Minimize,
-    Generate,
-    Count,
+fn run_action(
+    input: &str,
+    constraint: Constraint,
+    args: &CliArgs,
+    puzzle_sink: PuzzleSink,
+    output_file: Option<&Path>,
+    #[cfg(feature = "db")] db: Option<&db::PuzzleDb>,
+) -> ActionResult {
+    let rng = get_rng(args);
+    let mut config = get_config(args)?;
+
+    let checkpointing = get_checkpointing(input, args)?;
+    solver::check_engine(&constraint, config.engine).map_err(Error::Options)?;
+    if args.verbose && config.engine == solver::EngineKind::Propagation {
+        let num_values = constraint.shape.num_values;
+        eprintln!(
+            "Value set: {} for {} values",
+            solver::ValueSetKind::for_num_values(num_values),
+            num_values
+        );
+    }
+    if config.engine != solver::EngineKind::Propagation
+        && (args.checkpoint.is_some() || args.resume.is_some())
+    {
+        return Err(Error::Options(
+            "Checkpoints are only supported by the propagation engine.".to_string(),
+        ));
+    }
+    if args.output_partial {
+        if !matches!(args.action, CliAction::Solve | CliAction::Count) || args.threads.is_some() {
+            return Err(Error::Options(
+                "--output-partial only supports solve and count without --threads.".to_string(),
+            ));
+        }
+        if config.engine != solver::EngineKind::Propagation {
+            return Err(Error::Options(
+                "--output-partial is only supported by the propagation engine.".to_string(),
+            ));
+        }
+    }
+    if args.threads.is_some() && !matches!(args.action, CliAction::Count) && args.passes == 1 {
+        return Err(Error::Options(
+            "--threads only supports count, and generate or minimize with --passes.".to_string(),
+        ));
+    }
+    if args.passes == 0 {
+        return Err(Error::Options("--passes must be at least 1.".to_string()));
+    }
+    // The config has already been checked, and a new one is needed for each
+    // minimizer pass.
+    let make_config = || get_config(args).unwrap();
+    let minimizer_options = MinimizerOptions {
+        no_guesses: args.no_guesses,
+        passes: args.passes,
+        num_threads: args.threads,
+    };
+
+    let max_solutions = get_max_solutions(args)?;
+    let tracing = start_tracing(args, &constraint, &mut config)?;
+    let metrics = start_metrics(args, &mut config)?;
+
+    let output_options = get_output_options(args);
+    let writer = output::get_writer(args.output_last, args.json, output_file, args.append)
+        .map_err(Error::Storage)?;
+
+    let result = match args.action {
+        CliAction::Solve if args.explain => run_explain(constraint, output_options),
+        CliAction::Solve => {
+            let result = run_solver(
+                &constraint,
+                writer,
+                config,
+                max_solutions,
+                output_options,
+                Some(checkpointing),
+            )?;
+            let completion = check_limit_reached(&result.counters);
+            if args.diagnose && result.num_solutions == 0 && completion == Completion::Finished {
+                run_diagnose(&constraint, get_config(args)?, output_options)?;
+            }
+            Ok(completion)
+        }
+        CliAction::Minimize => run_minimizer(
+            constraint,
+            writer,
+            &make_config,
+            &minimizer_options,
+            rng,
+            puzzle_sink,
+            output_options,
+        ),
+        CliAction::Generate => run_generator(
+            constraint,
+            writer,
+            config,
+            &make_config,
+            &minimizer_options,
+            rng,
+            puzzle_sink,
+            output_options,
+        ),
+        CliAction::Count => run_count(
+            constraint,
+            writer,
+            config,
+            output_options,
+            checkpointing,
+            args.threads,
+        ),
+        CliAction::Estimate => run_estimate(constraint, config, rng, args.samples, output_options),
+        CliAction::Rate => run_rate(constraint, config, output_options),
+        CliAction::Verify => run_verify(constraint, output_options),
+        CliAction::CheckMinimal => run_check_minimal(constraint, config, output_options),
+        CliAction::Canonicalize => run_canonicalize(constraint, output_options),
+        CliAction::Fingerprint => run_fingerprint(constraint, output_options),
+        CliAction::Transform => run_transform(constraint, &args.transform, rng, output_options),
+        CliAction::ExportGraph => run_export_graph(constraint, args.graph_format),
+        CliAction::Bench => unreachable!("bench doesn't take a puzzle"),
+        CliAction::Replay => unreachable!("replay doesn't take a puzzle"),
+        #[cfg(feature = "db")]
+        CliAction::DbList => run_db_list(constraint, db),
+        #[cfg(feature = "serve")]
+        CliAction::Serve => unreachable!("serve doesn't take a puzzle"),
+    };
+    if let Some(tracing) = tracing {
+        tracing.borrow_mut().finish(args)?;
+    }
+    if let Some(metrics) = metrics {
+        metrics.borrow_mut().finish().map_err(Error::Storage)?;
+    }
+    result
+}
+
+#[cfg(feature = "serve")]
+fn run_serve(args: CliArgs) -> ActionResult {
+    // Check the options once, rather than failing each job.
+    get_config(&args)?;
+    let args = std::sync::Arc::new(args);
+    // The metrics are served on the same host as the jobs.
+    let metrics_address = args.metrics_port.map(|port| {
+        let host = args
+            .input
+            .rsplit_once(':')
+            .map_or(args.input.as_str(), |(host, _)| host);
+        format!("{}:{}", host, port)
+    });
+    let options = server::ServerOptions {
+        num_workers: args.workers,
+        max_queued: args.max_queued,
+        parse: Box::new({
+            let args = args.clone();
+            move |input| parse_puzzle(input, &args)
+        }),
+        make_config: Box::new({
+            let args = args.clone();
+            move || get_config(&args).unwrap()
+        }),
+        metrics_address: metrics_address.clone(),
+    };
+    eprintln!("Listening on http://{}", args.input);
+    if let Some(metrics_address) = &metrics_address {
+        eprintln!("Serving metrics on http://{}/metrics", metrics_address);
+    }
+    server::serve(&args.input, options)?;
+    Ok(Completion::Finished)
+}
+
+// Each benchmark is run with a new config from the options, so the options
+// which don't apply to solving (e.g. --threads) are ignored.
+fn run_bench(args: &CliArgs) -> ActionResult {
+    let benchmarks = large_sudoku_solver::bench::select(&args.input)?;
+    // Check the options once, rather than failing each run.
+    get_config(args)?;
+    let make_config = || get_config(args).unwrap();
+
+    let output_options = get_output_options(args);
+    if !output_options.json_lines {
+        print!("{}", output::benchmark_table_header());
+    }
+    for benchmark in benchmarks {
+        let result = benchmark.run(&make_config)?;
+        if output_options.json_lines {
+            print!(
+                "{}",
+                output::benchmark_as_json_line(benchmark.name, &result)
+            );
+        } else {
+            print!("{}", output::benchmark_as_text(benchmark.name, &result));
+        }
+    }
+    Ok(Completion::Finished)
+}
+
+// Print each event of a trace file written with --trace, and build the
+// search tree from them for --search-tree.
+fn run_replay(args: &CliArgs) -> ActionResult {
+    let trace = trace::load(Path::new(&args.input)).map_err(Error::Storage)?;
+    let mut tracing = Tracing {
+        search_tree: args
+            .search_tree
+            .as_ref()
+            .map(|_| search_tree::SearchTree::new(trace.side_len)),
+        ..Tracing::default()
+    };
+    let output_options = get_output_options(args);
+    for event in &trace.events {
+        tracing.add(event);
+        if output_options.json_lines {
+            print!(
+                "{}",
+                output::trace_event_as_json_line(trace.side_len, event)
+            );
+        } else {
+            println!("{}", output::trace_event_as_text(trace.side_len, event));
+        }
+    }
+    tracing.finish(args)?;
+    Ok(Completion::Finished)
+}
+
+fn main_with_result(args: CliArgs) -> ActionResult {
+    #[cfg(feature = "serve")]
+    if matches!(args.action, CliAction::Serve) {
+        return run_serve(args);
+    }
+    if matches!(args.action, CliAction::Bench) {
+        return run_bench(&args);
+    }
+    if matches!(args.action, CliAction::Replay) {
+        return run_replay(&args);
+    }
+
+    let puzzles = input::load_puzzles(&args.input)?;
+
+    #[cfg(feature = "db")]
+    let db = args
+        .db
+        .as_deref()
+        .map(db::PuzzleDb::open)
+        .transpose()
+        .map_err(Error::Storage)?;
+    #[cfg(feature = "db")]
+    let puzzle_sink =
+        |constraint: &Constraint, fixed_values: &FixedValues, counters: &solver::Counters| {
+            store_puzzle(db.as_ref(), &args, constraint, fixed_values, counters)
+        };
+    #[cfg(not(feature = "db"))]
+    let puzzle_sink = |_: &Constraint, _: &FixedValues, _: &solver::Counters| Ok(());
+
+    if puzzles.len() > 1 && args.output.is_some() {
+        return Err(Error::Options(
+            "--output can't be used with multiple puzzles, use --output-dir.".to_string(),
+        ));
+    }
+    if puzzles.len() > 1 && (args.checkpoint.is_some() || args.resume.is_some()) {
+        return Err(Error::Options(
+            "--checkpoint and --resume can't be used with multiple puzzles.".to_string(),
+        ));
+    }
+    if puzzles.len() > 1 && (args.trace.is_some() || args.search_tree.is_some()) {
+        return Err(Error::Options(
+            "--trace and --search-tree can't be used with multiple puzzles.".to_string(),
+        ));
+    }
+    if let Some(dir) = &args.output_dir {
+        fs::create_dir_all(dir).map_err(|e| {
+            Error::Storage(format!("Could not create output directory {}: {}", dir, e))
+        })?;
+    }
+    // Each puzzle is written to its own file in the output directory.
+    let output_file = |i: usize| match (&args.output, &args.output_dir) {
+        (Some(path), _) => Some(PathBuf::from(path)),
+        (None, Some(dir)) => Some(Path::new(dir).join(format!(
+            "puzzle-{}.{}",
+            i + 1,
+            get_output_options(&args).file_extension()
+        ))),
+        (None, None) => None,
+    };
+
+    let run_puzzle = |i: usize, input: &str| {
+        let constraint = parse_puzzle(input, &args)?;
+        run_action(
+            input,
+            constraint,
+            &args,
+            &puzzle_sink,
+            output_file(i).as_deref(),
+            #[cfg(feature = "db")]
+            db.as_ref(),
+        )
+    };
+
+    if let [puzzle] = puzzles.as_slice() {
+        return run_puzzle(0, puzzle);
+    }
+
+    // For multiple puzzles, report errors for each puzzle and keep going.
+    let mut num_failed = 0;
+    let mut completion = Completion::Finished;
+    for (i, puzzle) in puzzles.iter().enumerate() {
+        println!("# Puzzle {}/{}", i + 1, puzzles.len());
+        match run_puzzle(i, puzzle) {
+            Ok(Completion::Finished) => {}
+            Ok(Completion::LimitReached) => completion = Completion::LimitReached,
+            Err(e) => {
+                println!("# Error: {}", e);
+                num_failed += 1;
+            }
+        }
+    }
+
+    if num_failed > 0 {
+        return Err(Error::Solver(format!(
+            "{} of {} puzzles failed.",
+            num_failed,
+            puzzles.len()
+        )));
+    }
+    Ok(completion)
+}
+
+#[derive(clap::Parser, Debug)]
+#[clap(
+    arg_required_else_help = true,
+    about = "Solves and generates sudoku puzzles with large grids (up to 4096x4096)"
+)]
+struct CliArgs {
+    #[clap(
+        value_enum,
+        hide_possible_values = true,
+        help = "Supported actions:
+
+  solve:    Solve the input and prove uniqueness
+  minimize: Attempt to remove as many set values from the puzzle as possible
+            while keeping the solution unique
+  generate: Generate a new puzzle by filling the input (a template) with a
+            random solution, then removing values while the solution stays
+            unique
+  count:    Count the number of solutions without printing them
+  estimate: Estimate the number of solutions by sampling random paths through
+            the search (see --samples)
+  verify:   Check that the filled values (of a complete or partly filled grid)
+            don't break any constraints, and list the ones they break
+  check-minimal:
+            Check that the puzzle has a unique solution, and that removing any
+            one given would give more solutions, listing the ones which could
+            be removed
+  canonicalize:
+            Print the canonical form of the puzzle (or grid), which is the same
+            for puzzles which differ only by relabeling the values, permuting
+            bands, stacks, rows or columns, or transposing
+  fingerprint:
+            Print a hash of the canonical form, which is the same for puzzles
+            with the same canonical form, to find duplicates
+  transform:
+            Print the puzzle after applying each --transform in turn, or a
+            random relabeling and permutation of the bands and stacks
+  rate:     Grade the difficulty by the hardest technique needed to solve the
+            puzzle, or the amount of search if the techniques aren't enough
+  export-graph:
+            Print the cell/constraint incidence graph (see --graph-format)
+  bench:    Time a built-in suite of puzzles (9x9-hard, 16x16, 64x64-x and the
+            first 100 guesses of counting 256x256-empty) with the given
+            options, and print a table of the counters and times
+  replay:   Print each guess, propagation and backtrack recorded in a trace
+            file written with --trace (and write its --search-tree)
+  db-list:  List puzzles of the input's shape stored in --db (requires the
+            'db' feature)
+  serve:    Run an HTTP server which solves, counts or minimizes submitted
+            puzzles as background jobs, listening on the input address
+            (requires the 'serve' feature)"
+    )]
+    action: CliAction,
+
+    #[clap(
+        value_parser,
+        help = "One of:
+  Filename to read puzzle from
+  '-' to read from stdin
+  'NxN' size specification for empty grid
+  Address to listen on for serve (e.g. 127.0.0.1:8080)
+  'all' or a comma-separated list of benchmarks for bench
+  Trace file for replay"
+    )]
+    input: String,
+
+    #[clap(
+        short,
+        long,
+        help = "Add x-sudoku constraints
+(This can also be specified by adding 'X-Sudoku' inside the puzzle file)"
+    )]
+    x_sudoku: bool,
+
+    #[clap(
+        long,
+        help = "Solve a latin square (no box constraints, any side length)
+(This can also be specified by adding 'Latin-Square' inside the puzzle file)"
+    )]
+    latin_square: bool,
+
+    #[clap(
+        long,
+        help = "Add only the main diagonal (top-left to bottom-right) of x-sudoku
+(This can also be specified by adding 'Main-Diagonal' inside the puzzle file)"
+    )]
+    main_diagonal: bool,
+
+    #[clap(
+        long,
+        help = "Add only the anti-diagonal (top-right to bottom-left) of x-sudoku
+(This can also be specified by adding 'Anti-Diagonal' inside the puzzle file)"
+    )]
+    anti_diagonal: bool,
+
+    #[clap(
+        long,
+        help = "Add argyle constraints (diagonals offset from the main diagonals)
+(This can also be specified by adding 'Argyle' inside the puzzle file)"
+    )]
+    argyle: bool,
+
+    #[clap(
+        long,
+        help = "Add anti-king constraints (diagonally touching cells differ)
+(This can also be specified by adding 'Anti-King' inside the puzzle file)"
+    )]
+    anti_king: bool,
+
+    #[clap(
+        long,
+        help = "Add windoku constraints (extra boxes offset inside the grid)
+(This can also be specified by adding 'Windoku' inside the puzzle file)"
+    )]
+    windoku: bool,
+
+    #[clap(
+        long,
+        help = "Only output the last solution/puzzle
+(Works even if program is aborted with ctrl-c)"
+    )]
+    output_last: bool,
+
+    #[clap(
+        long,
+        conflicts_with = "output-dir",
+        help = "Write solutions/puzzles to this file instead of stdout
+(The file is only replaced once the run completes, unless --append is given)"
+    )]
+    output: Option<String>,
+
+    #[clap(
+        long,
+        help = "Write the solutions/puzzles for each input puzzle to a separate file
+in this directory (puzzle-1.txt, puzzle-2.txt, ...)"
+    )]
+    output_dir: Option<String>,
+
+    #[clap(
+        long,
+        help = "Append to the output files as results are found, instead of
+replacing them on completion"
+    )]
+    append: bool,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value = "bar",
+        help = "How progress is reported on stderr: a progress bar, or periodic JSON
+records with the counters, progress ratio and ETA"
+    )]
+    progress: output::ProgressMode,
+
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        help = "Stop the search after this many seconds (per puzzle)
+(Exits with code 2 and prints partial statistics)"
+    )]
+    time_limit: Option<f64>,
+
+    #[clap(
+        long,
+        help = "Stop the search after this many guesses (per puzzle)
+(Exits with code 2 and prints partial statistics)"
+    )]
+    max_guesses: Option<u64>,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Save the search state to this file periodically, and when the search is
+stopped by ctrl-c or a limit (solve and count only)"
+    )]
+    checkpoint: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        default_value = "600",
+        help = "How often to save the search state for --checkpoint"
+    )]
+    checkpoint_interval: f64,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Record each guess, propagation and backtrack of the search in this
+binary file, which can be printed with replay (solve and count only)"
+    )]
+    trace: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Write the tree of values tried by the search to this file, with the
+values each one eliminated and its share of the search space (solve, count
+and replay only, up to 10000 nodes)"
+    )]
+    search_tree: Option<String>,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value = "dot",
+        help = "Format for --search-tree (dot or json)"
+    )]
+    search_tree_format: search_tree::SearchTreeFormat,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Append snapshots of the solver counters to this file while
+solving (solve and count only)"
+    )]
+    metrics_file: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        default_value = "1",
+        help = "How often to write a snapshot for --metrics-file"
+    )]
+    metrics_interval: f64,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value = "csv",
+        help = "Format for --metrics-file (csv or json)"
+    )]
+    metrics_format: metrics::MetricsFormat,
+
+    #[clap(
+        long,
+        help = "When the search is stopped by ctrl-c or a limit, output the cells fixed
+at the deepest point of the search so far (solve and count only)"
+    )]
+    output_partial: bool,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Continue the search from a checkpoint saved with --checkpoint
+(The puzzle and action must be the same)"
+    )]
+    resume: Option<String>,
+
+    #[clap(
+        long,
+        help = "Count solutions, or run the --passes of generate and minimize, using
+this many threads (The result is the same for any number of threads)"
+    )]
+    threads: Option<usize>,
+
+    #[clap(
+        long,
+        default_value = "1000",
+        help = "Number of random samples for estimate"
+    )]
+    samples: u64,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value = "backtrack-weighted",
+        help = "How to choose the next cell to guess:
+  min-values:         the cell with the fewest values
+  backtrack-weighted: fewest values, weighted by recent backtracks at the cell
+  dom-wdeg:           fewest values, weighted by failures of the cell's
+                      constraints
+  sequential:         the first unfixed cell in row order"
+    )]
+    cell_order: CliCellOrder,
+
+    #[clap(
+        long,
+        value_enum,
+        help = "Which value to guess first: min, max, random (see --seed) or
+least-constraining (the value in the fewest cells that share a constraint)
+[default: random for generate, otherwise min]"
+    )]
+    value_order: Option<CliValueOrder>,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value = "trail",
+        help = "How the grid is saved before each guess:
+  trail:         record the old value of each cell as it changes
+  copy-on-write: copy each block of cells the first time it changes"
+    )]
+    grid_strategy: CliGridStrategy,
+
+    #[clap(
+        long,
+        value_name = "MIB",
+        help = "Stop saving the grid for each guess once it takes this many MiB, and
+rebuild the grids past that point by propagating the guesses again"
+    )]
+    max_grid_memory: Option<usize>,
+
+    #[clap(
+        long,
+        help = "Copy the values of each row, column, box and region into one place
+before removing values from it, and write back the changes"
+    )]
+    gather_houses: bool,
+
+    #[clap(
+        long,
+        help = "Keep the components found by the all-different algorithm for each
+house, and only search again those whose cells changed"
+    )]
+    incremental_scc: bool,
+
+    #[clap(
+        long,
+        help = "Only find singles in a house when one or two of its cells changed
+since it was last checked, unless a cell has two values left"
+    )]
+    early_exit_houses: bool,
+
+    #[cfg(feature = "batch-houses")]
+    #[clap(
+        long,
+        help = "Run the all-different algorithm for the houses waiting to be
+checked in batches, searching the houses in each batch in parallel"
+    )]
+    batch_houses: bool,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value = "full",
+        help = "How values are removed from rows, columns, boxes and regions:
+  singles:       naked and hidden singles
+  intersections: singles, plus pointing pairs and box-line reduction
+  full:          the all-different algorithm, which finds everything"
+    )]
+    propagation: CliPropagation,
+
+    #[clap(
+        long,
+        help = "Run the cheaper --propagation levels until nothing changes before
+running each more expensive level"
+    )]
+    tiered_propagation: bool,
+
+    #[clap(
+        long,
+        help = "Run cheap handlers (e.g. same-value and binary) before the houses,
+and the houses before fish, until nothing changes at each step"
+    )]
+    handler_priorities: bool,
+
+    #[clap(
+        long,
+        value_name = "KIND=N",
+        help = "Set the priority of a kind of handler for --handler-priorities (which
+it implies), where lower priorities run first. Can be given more than once.
+The kinds are house, same-value, unique-values, cage, thermo, arrow, renban,
+clone, parity, class-line, region-sum, quadruple, value-count, binary and
+fish (default: 0 for same-value, binary, parity and clone, 2 for house, 3
+for fish and 1 for the rest)"
+    )]
+    handler_priority: Vec<String>,
+
+    #[clap(
+        long,
+        help = "Count the invocations, eliminated values and time of each kind of
+handler, and print them as a table after solving"
+    )]
+    profile: bool,
+
+    #[clap(
+        long,
+        value_name = "LINES",
+        help = "Also remove values using fish patterns across rows and columns with
+up to this many lines (2: X-wing, 3: swordfish, 4: jellyfish)"
+    )]
+    max_fish_size: Option<usize>,
+
+    #[clap(
+        long,
+        help = "For solve, only use human-style techniques (singles, locked
+candidates, tuples and fish) and list each deduction"
+    )]
+    explain: bool,
+
+    #[clap(
+        long,
+        help = "For solve, if there is no solution, find a smallest set of givens and
+constraints which conflict, by removing each one in turn"
+    )]
+    diagnose: bool,
+
+    #[clap(
+        long,
+        value_name = "N",
+        help = "For solve, stop after finding this many solutions (default: 2, which
+proves whether the solution is unique)"
+    )]
+    max_solutions: Option<usize>,
+
+    #[clap(
+        long,
+        conflicts_with = "max-solutions",
+        help = "For solve, find all the solutions"
+    )]
+    all: bool,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value = "propagation",
+        help = "The search algorithm:
+  propagation: constraint propagation with backtracking
+  dlx:         dancing links exact cover, for puzzles where every region
+               contains each value (no other variants)
+  sat:         a CDCL SAT solver (requires the sat feature)"
+    )]
+    engine: CliEngine,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value = "none",
+        help = "Restart the search with a shuffled cell order after a number of
+backtracks, until the first solution is found (luby, geometric or none)"
+    )]
+    restarts: CliRestarts,
+
+    #[clap(
+        long,
+        value_name = "BACKTRACKS",
+        default_value = "100",
+        help = "Backtracks before the first restart (the Luby sequence is scaled by
+this, and the geometric schedule grows from it)"
+    )]
+    restart_base: u64,
+
+    #[clap(
+        long,
+        help = "Learn nogoods (sets of guesses which lead to a contradiction) and
+use them to prune the search"
+    )]
+    nogoods: bool,
+
+    #[clap(
+        long,
+        value_name = "CELLS",
+        default_value = "4",
+        help = "The largest nogood to learn with --nogoods"
+    )]
+    max_nogood_size: usize,
+
+    #[clap(long, help = "Don't allow guessing when generating/minimizing")]
+    no_guesses: bool,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value = "none",
+        help = "Keep the clues symmetric when generating/minimizing:
+  rotational: the same after rotating the grid by 180 degrees
+  mirror:     the same after reflecting the grid left to right
+  diagonal:   the same after reflecting the grid in the main diagonal"
+    )]
+    symmetry: CliSymmetry,
+
+    #[clap(
+        long,
+        value_name = "N",
+        help = "Stop generating/minimizing once the puzzle has at most this many clues"
+    )]
+    target_clues: Option<usize>,
+
+    #[clap(
+        long,
+        value_name = "N",
+        help = "Stop generating/minimizing once this many clues have been removed"
+    )]
+    max_removals: Option<u64>,
+
+    #[clap(
+        long,
+        value_name = "K",
+        default_value = "1",
+        help = "Run this many generator/minimizer passes over different random orders
+of the clues, and keep the puzzle with the fewest clues"
+    )]
+    passes: usize,
+
+    #[clap(
+        long,
+        value_enum,
+        help = "Stop generating/minimizing once the puzzle has this difficulty (see rate),
+and keep clues whose removal would make it harder"
+    )]
+    difficulty: Option<CliDifficulty>,
+
+    #[clap(
+        long,
+        value_name = "CELLS",
+        help = "Never remove the givens in these cells when generating/minimizing,
+e.g. \"R1C1,R5C5\" (This can also be specified with a 'locked:' line)"
+    )]
+    lock: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "TRANSFORM",
+        help = "A transformation for transform, which can be given more than once:
+  rotate, reflect-horizontal, reflect-vertical, transpose, relabel,
+  permute-bands or permute-stacks. relabel and the permutations take a list,
+  e.g. \"permute-bands=3,1,2\", or are chosen at random (see --seed)"
+    )]
+    transform: Vec<String>,
+
+    #[clap(long, help = "RNG seed for generator/minimizer")]
+    seed: Option<u64>,
+
+    #[clap(
+        long,
+        help = "Give the same search order, counters and output on every run and
+platform: the seed defaults to 0, timings and memory use are left out of
+the counters, and count --threads gives each thread a fixed share of the
+subproblems (Can't be used with --time-limit, or --max-guesses with count
+--threads)"
+    )]
+    deterministic: bool,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value = "dot",
+        help = "Graph format for export-graph"
+    )]
+    graph_format: graph::GraphFormat,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value = "grid",
+        help = "Output format for solutions and puzzles (grid, short, csv, json)"
+    )]
+    format: output::OutputFormat,
+
+    #[clap(
+        long,
+        help = "Write each solution/puzzle and a final summary of the counters as
+JSON objects, one per line (overrides --format)"
+    )]
+    json: bool,
+
+    #[clap(
+        long,
+        help = "Print details of how the puzzle will be solved to stderr, such as
+the value set chosen for the grid size"
+    )]
+    verbose: bool,
+
+    #[cfg(feature = "db")]
+    #[clap(
+        long,
+        help = "SQLite database to store generated/minimized puzzles in
+(Puzzles already in the database are not stored again)"
+    )]
+    db: Option<String>,
+
+    #[cfg(feature = "serve")]
+    #[clap(
+        long,
+        default_value = "1",
+        help = "For serve, the number of jobs to run at once"
+    )]
+    workers: usize,
+
+    #[cfg(feature = "serve")]
+    #[clap(
+        long,
+        default_value = "100",
+        help = "For serve, reject new jobs while this many are waiting to run"
+    )]
+    max_queued: usize,
+
+    #[cfg(feature = "serve")]
+    #[clap(
+        long,
+        value_name = "PORT",
+        help = "For serve, serve Prometheus metrics of the jobs at /metrics on
+this port, on the same host"
+    )]
+    metrics_port: Option<u16>,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone)]
+enum CliAction {
+    Solve,
+    Minimize,
+    Generate,
+    Count,
+    Estimate,
+    Rate,
+    Verify,
+    CheckMinimal,
+    Canonicalize,
+    Fingerprint,
+    Transform,
+    ExportGraph,
+    Bench,
+    Replay,
+    #[cfg(feature = "db")]
+    DbList,
+    #[cfg(feature = "serve")]
+    Serve,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum CliDifficulty {
+    Easy,
+    Medium,
+    Hard,
+    Expert,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum CliSymmetry {
+    None,
+    Rotational,
+    Mirror,
+    Diagonal,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum CliCellOrder {
+    MinValues,
+    BacktrackWeighted,
+    DomWdeg,
+    Sequential,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum CliValueOrder {
+    Min,
+    Max,
+    Random,
+    LeastConstraining,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum CliGridStrategy {
+    Trail,
+    CopyOnWrite,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum CliPropagation {
+    Singles,
+    Intersections,
+    Full,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum CliEngine {
+    Propagation,
+    Dlx,
+    #[cfg(feature = "sat")]
+    Sat,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum CliRestarts {
+    None,
+    Luby,
+    Geometric,
 }
 
 fn main() -> ExitCode {
     let args = CliArgs::parse();
     output::set_ctrlc_handler();
+    output::set_progress_mode(args.progress);
+    output::set_deterministic(args.deterministic);
     match main_with_result(args) {
         Err(e) => {
             eprintln!("Error: {}", e);
             ExitCode::FAILURE
         }
-        Ok(_) => ExitCode::SUCCESS,
+        Ok(Completion::LimitReached) => ExitCode::from(LIMIT_REACHED_EXIT_CODE),
+        Ok(Completion::Finished) => ExitCode::SUCCESS,
     }
 }
@@ -0,0 +1,443 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use crate::io::output;
+use crate::solver::{self, CancellationToken, Config, Counters, MinimizerCounters};
+use crate::types::{Constraint, FixedValues, Solution, ValueType};
+
+use super::metrics::MetricsPage;
+
+pub type JobId = u64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobAction {
+    // Find up to two solutions, to prove uniqueness.
+    Solve,
+    Count,
+    Minimize,
+}
+
+impl JobAction {
+    pub fn from_name(name: &str) -> Option<JobAction> {
+        match name {
+            "solve" => Some(JobAction::Solve),
+            "count" => Some(JobAction::Count),
+            "minimize" => Some(JobAction::Minimize),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobAction::Solve => "solve",
+            JobAction::Count => "count",
+            JobAction::Minimize => "minimize",
+        }
+    }
+}
+
+// The solver counters exported for each job, with their metric type.
+type CounterMetric = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&Counters) -> Option<f64>,
+);
+
+const COUNTER_METRICS: &[CounterMetric] = &[
+    ("job_solutions_total", "counter", "Solutions found.", |c| {
+        Some(c.solutions as f64)
+    }),
+    ("job_guesses_total", "counter", "Guesses made.", |c| {
+        Some(c.guesses as f64)
+    }),
+    ("job_values_tried_total", "counter", "Values tried.", |c| {
+        Some(c.values_tried as f64)
+    }),
+    ("job_backtracks_total", "counter", "Backtracks.", |c| {
+        Some(c.backtracks as f64)
+    }),
+    (
+        "job_constraints_processed_total",
+        "counter",
+        "Constraint handlers run.",
+        |c| Some(c.constraints_processed as f64),
+    ),
+    (
+        "job_progress_ratio",
+        "gauge",
+        "Share of the search which is done.",
+        |c| Some(c.progress_ratio),
+    ),
+    (
+        "job_elapsed_seconds",
+        "gauge",
+        "Time spent searching.",
+        |c| Some(c.elapsed_secs),
+    ),
+    (
+        "job_nodes_per_second",
+        "gauge",
+        "Recent rate of values tried.",
+        |c| Some(c.nodes_per_sec),
+    ),
+    (
+        "job_eta_seconds",
+        "gauge",
+        "Estimated time remaining.",
+        |c| c.eta_secs,
+    ),
+    (
+        "job_peak_memory_bytes",
+        "gauge",
+        "Peak memory used by the solver.",
+        |c| Some(c.peak_memory as f64),
+    ),
+];
+
+// The minimizer counters exported for minimize jobs.
+type MinimizerMetric = (&'static str, &'static str, fn(&MinimizerCounters) -> u64);
+
+const MINIMIZER_METRICS: &[MinimizerMetric] = &[
+    (
+        "job_cells_tried_total",
+        "Givens the minimizer tried to remove.",
+        |c| c.cells_tried,
+    ),
+    (
+        "job_cells_removed_total",
+        "Givens the minimizer removed.",
+        |c| c.cells_removed,
+    ),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobState {
+    Queued,
+    Running,
+    Finished,
+    Cancelled,
+}
+
+impl JobState {
+    const ALL: [JobState; 4] = [
+        JobState::Queued,
+        JobState::Running,
+        JobState::Finished,
+        JobState::Cancelled,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Finished => "finished",
+            JobState::Cancelled => "cancelled",
+        }
+    }
+}
+
+struct Job {
+    action: JobAction,
+    state: JobState,
+    // Taken by the worker when the job starts.
+    constraint: Option<Constraint>,
+    cancellation: CancellationToken,
+    // The latest counters, updated while the job runs.
+    counters: Counters,
+    // For minimize, the number of givens tried and removed so far.
+    minimizer_counters: Option<MinimizerCounters>,
+    // The solutions found so far, or the smallest puzzle found so far for
+    // minimize.
+    results: Vec<Vec<Option<ValueType>>>,
+}
+
+impl Job {
+    fn as_json(&self, id: JobId, with_results: bool) -> serde_json::Value {
+        let mut json = serde_json::json!({
+            "id": id,
+            "action": self.action.name(),
+            "state": self.state.name(),
+            "counters": output::counters_json(&self.counters),
+        });
+        if let Some(counters) = &self.minimizer_counters {
+            json["minimizer"] = serde_json::json!({
+                "cells_tried": counters.cells_tried,
+                "cells_removed": counters.cells_removed,
+            });
+        }
+        if with_results {
+            json["results"] = serde_json::json!(self.results);
+        }
+        json
+    }
+}
+
+#[derive(Debug)]
+pub enum SubmitError {
+    // The queue already has `max_queued` jobs waiting.
+    QueueFull,
+}
+
+struct State {
+    jobs: BTreeMap<JobId, Job>,
+    queue: VecDeque<JobId>,
+    next_id: JobId,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    job_available: Condvar,
+    make_config: Box<dyn Fn() -> Config + Send + Sync>,
+    max_queued: usize,
+}
+
+// Runs submitted jobs on a fixed number of worker threads, in the order they
+// were submitted. Jobs are kept (with their results) until they are removed.
+#[derive(Clone)]
+pub struct JobQueue {
+    shared: Arc<Shared>,
+}
+
+impl JobQueue {
+    // `make_config` is called for each job, as a config can only be used once.
+    pub fn new(
+        num_workers: usize,
+        max_queued: usize,
+        make_config: Box<dyn Fn() -> Config + Send + Sync>,
+    ) -> JobQueue {
+        let queue = JobQueue {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    jobs: BTreeMap::new(),
+                    queue: VecDeque::new(),
+                    next_id: 1,
+                }),
+                job_available: Condvar::new(),
+                make_config,
+                max_queued,
+            }),
+        };
+        for _ in 0..num_workers.max(1) {
+            let queue = queue.clone();
+            thread::spawn(move || queue.run_worker());
+        }
+        queue
+    }
+
+    pub fn submit(&self, constraint: Constraint, action: JobAction) -> Result<JobId, SubmitError> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.queue.len() >= self.shared.max_queued {
+            return Err(SubmitError::QueueFull);
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.jobs.insert(
+            id,
+            Job {
+                action,
+                state: JobState::Queued,
+                constraint: Some(constraint),
+                cancellation: CancellationToken::new(),
+                counters: Counters::default(),
+                minimizer_counters: None,
+                results: Vec::new(),
+            },
+        );
+        state.queue.push_back(id);
+        self.shared.job_available.notify_one();
+        Ok(id)
+    }
+
+    // The job's state, counters and results so far.
+    pub fn get(&self, id: JobId) -> Option<serde_json::Value> {
+        let state = self.shared.state.lock().unwrap();
+        state.jobs.get(&id).map(|job| job.as_json(id, true))
+    }
+
+    // All jobs, without their results.
+    pub fn list(&self) -> serde_json::Value {
+        let state = self.shared.state.lock().unwrap();
+        serde_json::json!(state
+            .jobs
+            .iter()
+            .map(|(&id, job)| job.as_json(id, false))
+            .collect::<Vec<_>>())
+    }
+
+    // The number of jobs in each state, and the counters of each job, in the
+    // Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let state = self.shared.state.lock().unwrap();
+        let mut page = MetricsPage::default();
+
+        page.metric(
+            "jobs",
+            "gauge",
+            "Jobs which haven't been removed, by state.",
+        );
+        for job_state in JobState::ALL {
+            let count = state
+                .jobs
+                .values()
+                .filter(|job| job.state == job_state)
+                .count();
+            page.sample("jobs", &[("state", job_state.name())], count as f64);
+        }
+        page.metric("jobs_submitted_total", "counter", "Jobs submitted.");
+        page.sample("jobs_submitted_total", &[], (state.next_id - 1) as f64);
+
+        let jobs = state
+            .jobs
+            .iter()
+            .map(|(id, job)| (id.to_string(), job))
+            .collect::<Vec<_>>();
+        page.metric("job_state", "gauge", "The state of each job, as a label.");
+        for (id, job) in &jobs {
+            let labels = [
+                ("id", id.as_str()),
+                ("action", job.action.name()),
+                ("state", job.state.name()),
+            ];
+            page.sample("job_state", &labels, 1.0);
+        }
+        for &(name, kind, help, value) in COUNTER_METRICS {
+            page.metric(name, kind, help);
+            for (id, job) in &jobs {
+                if let Some(value) = value(&job.counters) {
+                    page.sample(name, &[("id", id), ("action", job.action.name())], value);
+                }
+            }
+        }
+        for &(name, help, value) in MINIMIZER_METRICS {
+            page.metric(name, "counter", help);
+            for (id, job) in &jobs {
+                if let Some(counters) = &job.minimizer_counters {
+                    let labels = [("id", id.as_str()), ("action", job.action.name())];
+                    page.sample(name, &labels, value(counters) as f64);
+                }
+            }
+        }
+        page.finish()
+    }
+
+    // Cancel a queued or running job, keeping its results so far. A job which
+    // has already stopped is removed.
+    pub fn cancel(&self, id: JobId) -> Option<serde_json::Value> {
+        let mut state = self.shared.state.lock().unwrap();
+        let job = state.jobs.get_mut(&id)?;
+        match job.state {
+            JobState::Queued => {
+                job.state = JobState::Cancelled;
+                job.constraint = None;
+                state.queue.retain(|&queued| queued != id);
+            }
+            // The worker marks the job as cancelled once the search stops.
+            JobState::Running => job.cancellation.cancel(),
+            JobState::Finished | JobState::Cancelled => {
+                return state.jobs.remove(&id).map(|job| job.as_json(id, true));
+            }
+        }
+        state.jobs.get(&id).map(|job| job.as_json(id, true))
+    }
+
+    fn run_worker(&self) {
+        loop {
+            let (id, constraint, action, cancellation) = {
+                let mut state = self.shared.state.lock().unwrap();
+                let id = loop {
+                    match state.queue.pop_front() {
+                        Some(id) => break id,
+                        None => state = self.shared.job_available.wait(state).unwrap(),
+                    }
+                };
+                let job = state.jobs.get_mut(&id).unwrap();
+                job.state = JobState::Running;
+                (
+                    id,
+                    job.constraint.take().unwrap(),
+                    job.action,
+                    job.cancellation.clone(),
+                )
+            };
+
+            let config = Config {
+                cancellation: Some(cancellation.clone()),
+                ..(self.shared.make_config)()
+            };
+            match action {
+                JobAction::Solve => self.run_solver(id, &constraint, config, 2),
+                JobAction::Count => self.run_solver(
+                    id,
+                    &constraint,
+                    Config {
+                        output_type: solver::OutputType::Empty,
+                        ..config
+                    },
+                    usize::MAX,
+                ),
+                JobAction::Minimize => self.run_minimizer(id, &constraint, config),
+            }
+
+            self.update(id, |job| {
+                job.state = if cancellation.is_cancelled() {
+                    JobState::Cancelled
+                } else {
+                    JobState::Finished
+                };
+            });
+        }
+    }
+
+    fn run_solver(&self, id: JobId, constraint: &Constraint, mut config: Config, limit: usize) {
+        let queue = self.clone();
+        config.progress_callback = Some(Box::new(move |counters: &Counters| {
+            queue.update(id, |job| job.counters = *counters);
+        }));
+
+        for output in solver::solution_iter(constraint, config).take(limit) {
+            if let solver::Output::Solution(solution) = output {
+                self.update(id, |job| job.results.push(solution_values(&solution)));
+            }
+        }
+    }
+
+    fn run_minimizer(&self, id: JobId, constraint: &Constraint, config: Config) {
+        let queue = self.clone();
+        let progress_callback = Box::new(move |counters: &MinimizerCounters| {
+            queue.update(id, |job| {
+                job.counters = counters.solver_counters;
+                job.minimizer_counters = Some(*counters);
+            });
+        });
+
+        // Only the smallest puzzle so far is kept, starting with the input.
+        let num_cells = constraint.shape.num_cells;
+        let values = puzzle_values(num_cells, &constraint.fixed_values);
+        self.update(id, |job| job.results = vec![values]);
+        for fixed_values in solver::minimize(constraint, config, Some(progress_callback)) {
+            let values = puzzle_values(num_cells, &fixed_values);
+            self.update(id, |job| job.results = vec![values]);
+        }
+    }
+
+    fn update<F: FnOnce(&mut Job)>(&self, id: JobId, f: F) {
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(job) = state.jobs.get_mut(&id) {
+            f(job);
+        }
+    }
+}
+
+fn solution_values(solution: &Solution) -> Vec<Option<ValueType>> {
+    solution.iter().map(|v| Some(v.display_value())).collect()
+}
+
+fn puzzle_values(num_cells: usize, fixed_values: &FixedValues) -> Vec<Option<ValueType>> {
+    let mut values = vec![None; num_cells];
+    for &(cell, value) in fixed_values {
+        values[cell] = Some(value.display_value());
+    }
+    values
+}
@@ -0,0 +1,60 @@
+use std::fmt::Write;
+use std::thread;
+
+use tiny_http::{Header, Method, Response};
+
+use crate::error::Error;
+
+use super::jobs::JobQueue;
+
+// Serve the jobs' states and counters as Prometheus metrics at /metrics, on a
+// separate address from the jobs, so that it can be scraped without exposing
+// the API.
+pub fn serve(address: &str, queue: JobQueue) -> Result<(), Error> {
+    let server = tiny_http::Server::http(address)
+        .map_err(|e| Error::Options(format!("Could not listen on {}: {}", address, e)))?;
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let path = request.url().split('?').next().unwrap_or_default();
+            let response = match (request.method(), path) {
+                (Method::Get, "/metrics") => Response::from_string(queue.metrics()).with_header(
+                    Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap(),
+                ),
+                _ => Response::from_string("Not found.\n").with_status_code(404),
+            };
+            // The client may have gone away.
+            let _ = request.respond(response);
+        }
+    });
+    Ok(())
+}
+
+// Builds a page of metrics in the Prometheus text format. Each metric is
+// declared once, followed by its samples.
+#[derive(Default)]
+pub(super) struct MetricsPage {
+    text: String,
+}
+
+impl MetricsPage {
+    pub(super) fn metric(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.text, "# HELP lss_{} {}", name, help).unwrap();
+        writeln!(self.text, "# TYPE lss_{} {}", name, kind).unwrap();
+    }
+
+    pub(super) fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        write!(self.text, "lss_{}", name).unwrap();
+        if !labels.is_empty() {
+            let labels = labels
+                .iter()
+                .map(|(label, value)| format!("{}=\"{}\"", label, value))
+                .collect::<Vec<_>>();
+            write!(self.text, "{{{}}}", labels.join(",")).unwrap();
+        }
+        writeln!(self.text, " {}", value).unwrap();
+    }
+
+    pub(super) fn finish(self) -> String {
+        self.text
+    }
+}
@@ -0,0 +1,112 @@
+// An HTTP server which runs solver jobs in the background. The puzzle is sent
+// as the request body, and the job is then polled until it finishes:
+//
+//   POST   /jobs?action=count  Submit a job (solve, count or minimize).
+//   GET    /jobs               List the jobs.
+//   GET    /jobs/<id>          The job's state, counters and results so far.
+//   DELETE /jobs/<id>          Cancel the job, or remove it once it has stopped.
+//
+// Responses are JSON objects, with an "error" field if the request failed.
+// With a metrics address, the jobs' states and counters are also served there
+// as Prometheus metrics at /metrics.
+mod jobs;
+mod metrics;
+
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::error::Error;
+use crate::solver::Config;
+use crate::types::Constraint;
+
+pub use jobs::{JobAction, JobId, JobQueue, JobState, SubmitError};
+
+pub type PuzzleParser = dyn Fn(&str) -> Result<Constraint, Error> + Send + Sync;
+
+pub struct ServerOptions {
+    // The number of jobs which run at once.
+    pub num_workers: usize,
+    // Jobs are rejected while this many are waiting for a worker.
+    pub max_queued: usize,
+    pub parse: Box<PuzzleParser>,
+    // Called for each job.
+    pub make_config: Box<dyn Fn() -> Config + Send + Sync>,
+    // Where to serve the Prometheus metrics, if anywhere.
+    pub metrics_address: Option<String>,
+}
+
+// Serve requests until the server is stopped.
+pub fn serve(address: &str, options: ServerOptions) -> Result<(), Error> {
+    let server = tiny_http::Server::http(address)
+        .map_err(|e| Error::Options(format!("Could not listen on {}: {}", address, e)))?;
+    let queue = JobQueue::new(options.num_workers, options.max_queued, options.make_config);
+    if let Some(metrics_address) = &options.metrics_address {
+        metrics::serve(metrics_address, queue.clone())?;
+    }
+
+    for mut request in server.incoming_requests() {
+        let (status, json) = handle_request(&mut request, &queue, &options.parse);
+        let response = Response::from_string(format!("{}\n", json))
+            .with_status_code(status)
+            .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+        // The client may have gone away.
+        let _ = request.respond(response);
+    }
+    Ok(())
+}
+
+fn handle_request(
+    request: &mut Request,
+    queue: &JobQueue,
+    parse: &PuzzleParser,
+) -> (u16, serde_json::Value) {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+
+    match (request.method(), segments.as_slice()) {
+        (Method::Post, ["jobs"]) => submit_job(request, query, queue, parse),
+        (Method::Get, ["jobs"]) => (200, queue.list()),
+        (Method::Get, ["jobs", id]) => match id.parse().ok().and_then(|id| queue.get(id)) {
+            Some(job) => (200, job),
+            None => error_response(404, "No such job."),
+        },
+        (Method::Delete, ["jobs", id]) => match id.parse().ok().and_then(|id| queue.cancel(id)) {
+            Some(job) => (200, job),
+            None => error_response(404, "No such job."),
+        },
+        _ => error_response(404, "Not found."),
+    }
+}
+
+fn submit_job(
+    request: &mut Request,
+    query: &str,
+    queue: &JobQueue,
+    parse: &PuzzleParser,
+) -> (u16, serde_json::Value) {
+    let action_name = query
+        .split('&')
+        .find_map(|param| param.strip_prefix("action="))
+        .unwrap_or("solve");
+    let Some(action) = JobAction::from_name(action_name) else {
+        return error_response(400, &format!("Unknown action: {}", action_name));
+    };
+
+    let mut body = String::new();
+    if let Err(e) = request.as_reader().read_to_string(&mut body) {
+        return error_response(400, &format!("Could not read the puzzle: {}", e));
+    }
+    let constraint = match parse(&body) {
+        Ok(constraint) => constraint,
+        Err(e) => return error_response(400, &e.to_string()),
+    };
+
+    match queue.submit(constraint, action) {
+        Ok(id) => (202, queue.get(id).unwrap()),
+        Err(SubmitError::QueueFull) => error_response(503, "Too many jobs are queued."),
+    }
+}
+
+fn error_response(status: u16, message: &str) -> (u16, serde_json::Value) {
+    (status, serde_json::json!({ "error": message }))
+}
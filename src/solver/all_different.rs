@@ -5,7 +5,8 @@ use crate::value_set::ValueSet;
 
 use super::cell_accumulator::CellAccumulator;
 use super::handlers;
-use super::handlers::Contradition;
+use super::handlers::Contradiction;
+use super::trail::TrailGrid;
 
 pub struct AllDifferentEnforcer<VS: ValueSet> {
     assignees: Vec<usize>,
@@ -14,9 +15,12 @@ pub struct AllDifferentEnforcer<VS: ValueSet> {
     rec_stack: Vec<usize>,
     data_stack: Vec<usize>,
     cell_nodes: Vec<VS>,
+    // The components which changed since the last call with a cache. Each
+    // component is identified by one of its cells.
+    changed_components: Vec<bool>,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug)]
 struct SccSet<VS: ValueSet> {
     low: VS,
     values: VS,
@@ -33,6 +37,76 @@ impl<VS: ValueSet> SccSet<VS> {
     }
 }
 
+// The cells whose remaining edges are impossible after `find_edges`.
+pub enum Edges<VS> {
+    // Nothing changed since the cached result.
+    Unchanged,
+    All,
+    Cells(VS),
+}
+
+// The result of the last call for a house. The components can only split as
+// values are removed, so the next call only needs to search the components
+// containing a cell which changed since.
+pub struct SccCache<VS> {
+    // The cell values after the last call, or empty if there is no valid
+    // result.
+    values: Vec<VS>,
+    // The component of each cell.
+    components: Vec<ValueType>,
+}
+
+impl<VS> Default for SccCache<VS> {
+    fn default() -> Self {
+        Self {
+            values: Vec::new(),
+            components: Vec::new(),
+        }
+    }
+}
+
+impl<VS: ValueSet> SccCache<VS> {
+    // Update the cached values, and return the cells in components containing
+    // a cell which changed, or None if the components don't apply (e.g. a
+    // value was restored by backtracking).
+    fn update(&mut self, values: &[VS], changed_components: &mut [bool]) -> Option<VS> {
+        if self.values.len() != values.len() {
+            // Keep the capacity, so that this doesn't allocate after a
+            // contradiction.
+            self.values.clear();
+            self.values.extend_from_slice(values);
+            self.components.clear();
+            self.components.resize(values.len(), 0);
+            return None;
+        }
+        changed_components.fill(false);
+        let mut valid = true;
+        let mut any_changed = false;
+        for (i, (value, old)) in zip(values, self.values.iter_mut()).enumerate() {
+            if value != old {
+                valid &= value.without(old).is_empty();
+                changed_components[self.components[i] as usize] = true;
+                any_changed = true;
+                old.clone_from(value);
+            }
+        }
+        if !valid || !any_changed {
+            return valid.then(VS::empty);
+        }
+        let mut cells = VS::empty();
+        for (i, &component) in self.components.iter().enumerate() {
+            if changed_components[component as usize] {
+                cells.add_set(&VS::from_value(i as ValueType));
+            }
+        }
+        Some(cells)
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        super::vec_memory(&self.values) + super::vec_memory(&self.components)
+    }
+}
+
 impl<VS: ValueSet> AllDifferentEnforcer<VS> {
     pub fn new(num_values: u32) -> Self {
         let num_values = num_values as usize;
@@ -49,28 +123,121 @@ impl<VS: ValueSet> AllDifferentEnforcer<VS> {
             rec_stack: Vec::with_capacity(num_values),
             data_stack: Vec::with_capacity(num_values),
             cell_nodes: vec![VS::empty(); num_values],
+            changed_components: vec![false; num_values],
         }
     }
 
+    pub fn memory_usage(&self) -> usize {
+        super::vec_memory(&self.assignees)
+            + super::vec_memory(&self.ids)
+            + super::vec_memory(&self.scc_set)
+            + super::vec_memory(&self.rec_stack)
+            + super::vec_memory(&self.data_stack)
+            + super::vec_memory(&self.cell_nodes)
+            + super::vec_memory(&self.changed_components)
+    }
+
     // Algorithm: http://www.constraint-programming.com/people/regin/papers/alldiff.pdf
+    // `values` are the values of `cells`, if the caller has already gathered
+    // them. With a cache, only the components which changed since the last
+    // call with it are searched.
     pub fn enforce_all_different(
         &mut self,
-        grid: &mut [VS],
+        grid: &mut TrailGrid<VS>,
         cells: &[CellIndex],
+        values: Option<&[VS]>,
         candidate_matching: &mut [VS],
+        mut cache: Option<&mut SccCache<VS>>,
         cell_accumulator: &mut CellAccumulator,
     ) -> handlers::Result {
-        self.enforce_all_different_internal(grid, cells, candidate_matching)?;
+        let edges = self.find_edges(
+            grid,
+            cells,
+            values,
+            candidate_matching,
+            cache.as_deref_mut(),
+        )?;
+        self.remove_edges(grid, cells, values, &edges, cache, cell_accumulator);
+        Ok(())
+    }
+
+    // Find the impossible values of `cells` without changing the grid, so that
+    // several houses can be searched at once. They are removed by
+    // `remove_edges`.
+    pub fn find_edges(
+        &mut self,
+        grid: &[VS],
+        cells: &[CellIndex],
+        values: Option<&[VS]>,
+        candidate_matching: &mut [VS],
+        cache: Option<&mut SccCache<VS>>,
+    ) -> Result<Edges<VS>, Contradiction> {
+        match values {
+            Some(values) => self.cell_nodes[..values.len()].clone_from_slice(values),
+            None => self.gather(grid, cells),
+        }
 
-        // Remove the remaining edges as they are impossible assignments.
-        for (i, cell_node) in self.cell_nodes.iter().enumerate() {
+        let Some(cache) = cache else {
+            self.enforce_cell_nodes(candidate_matching, None, None)?;
+            return Ok(Edges::All);
+        };
+
+        let cells_to_search = cache.update(
+            &self.cell_nodes[..cells.len()],
+            &mut self.changed_components,
+        );
+        if cells_to_search.as_ref().is_some_and(VS::is_empty) {
+            return Ok(Edges::Unchanged);
+        }
+        let result = self.enforce_cell_nodes(
+            candidate_matching,
+            cells_to_search.as_ref(),
+            Some(&mut cache.components),
+        );
+        if result.is_err() {
+            cache.values.clear();
+        }
+        result?;
+        Ok(cells_to_search.map_or(Edges::All, Edges::Cells))
+    }
+
+    // Remove the remaining edges of the searched cells, as they are impossible
+    // assignments. The values are removed from the current values of the cells
+    // if `values` isn't given.
+    pub fn remove_edges(
+        &self,
+        grid: &mut TrailGrid<VS>,
+        cells: &[CellIndex],
+        values: Option<&[VS]>,
+        edges: &Edges<VS>,
+        cache: Option<&mut SccCache<VS>>,
+        cell_accumulator: &mut CellAccumulator,
+    ) {
+        let mut cached_values = cache.map(|cache| &mut cache.values);
+        let mut remove = |i: usize| {
+            let cell_node = &self.cell_nodes[i];
             if !cell_node.is_empty() {
-                cell_accumulator.add(cells[i]);
-                grid[cells[i]].remove_set(cell_node);
+                let cell = cells[i];
+                let old = values.map_or(&grid[cell], |values| &values[i]);
+                let new = old.without(cell_node);
+                if let Some(cached_values) = cached_values.as_deref_mut() {
+                    cached_values[i] = new.clone();
+                }
+                #[cfg(feature = "logging")]
+                tracing::trace!(
+                    cell,
+                    removed = cell_node.count(),
+                    "all-different removed values"
+                );
+                cell_accumulator.add(cell);
+                grid.set(cell, new);
             }
+        };
+        match edges {
+            Edges::Unchanged => {}
+            Edges::Cells(searched) => searched.iter().for_each(|i| remove(i as usize)),
+            Edges::All => (0..self.cell_nodes.len()).for_each(remove),
         }
-
-        Ok(())
     }
 
     // Internal section for benchmarking.
@@ -80,15 +247,38 @@ impl<VS: ValueSet> AllDifferentEnforcer<VS> {
         cells: &[CellIndex],
         candidate_matching: &mut [VS],
     ) -> handlers::Result {
-        // Copy over the cell values.
+        self.gather(grid, cells);
+        self.enforce_cell_nodes(candidate_matching, None, None)
+    }
+
+    // Copy over the cell values.
+    fn gather(&mut self, grid: &[VS], cells: &[CellIndex]) {
         for (i, &cell) in cells.iter().enumerate() {
-            self.cell_nodes[i] = grid[cell];
+            self.cell_nodes[i] = grid[cell].clone();
         }
+    }
 
+    // Find the edges to remove from the cell nodes, searching only the
+    // components of `cells` if given. The components found are recorded in
+    // `components` if given.
+    fn enforce_cell_nodes(
+        &mut self,
+        candidate_matching: &mut [VS],
+        cells: Option<&VS>,
+        components: Option<&mut [ValueType]>,
+    ) -> handlers::Result {
         // Find a maximum matching.
         // A candidate mapping is taken in as a hint. The updated mapping is
         // returned to the caller so that we can use the hint next iteration.
-        self.max_matching(candidate_matching)?;
+        let result = self.max_matching(candidate_matching);
+        #[cfg(feature = "logging")]
+        if result.is_err() {
+            tracing::trace!(
+                cells = self.cell_nodes.len(),
+                "all-different found no matching of values to cells"
+            );
+        }
+        result?;
 
         // Remove the forward edges in the maximum matching.
         for (cell_node, candidate) in zip(self.cell_nodes.iter_mut(), candidate_matching.iter()) {
@@ -97,14 +287,19 @@ impl<VS: ValueSet> AllDifferentEnforcer<VS> {
 
         // Find and remove strongly-connected components in the
         // implicit directed graph.
-        self.remove_scc(candidate_matching);
+        self.remove_scc(candidate_matching, cells, components);
 
         Ok(())
     }
 
     // https://en.wikipedia.org/wiki/Tarjan%27s_strongly_connected_components_algorithm
     // With simplifications as per https://www.cs.cmu.edu/~15451-f18/lectures/lec19-DFS-strong-components.pdf
-    fn remove_scc(&mut self, assignees_inv: &[VS]) {
+    fn remove_scc(
+        &mut self,
+        assignees_inv: &[VS],
+        cells: Option<&VS>,
+        mut components: Option<&mut [ValueType]>,
+    ) {
         let rec_stack = &mut self.rec_stack;
         let scc_stack = &mut self.data_stack;
         let cell_nodes = &mut self.cell_nodes;
@@ -119,14 +314,28 @@ impl<VS: ValueSet> AllDifferentEnforcer<VS> {
         let mut index = 0;
 
         let full_set = VS::full(cell_nodes.len() as ValueType);
-        let mut unseen_cells = full_set;
-        let mut unseen_values = full_set;
+        let (mut unseen_cells, mut unseen_values) = match cells {
+            // The other cells are in components which haven't changed, and
+            // their values are matched to their own cells, so they are never
+            // reached.
+            Some(cells) => {
+                let mut values = VS::empty();
+                for i in cells.iter() {
+                    values.add_set(&assignees_inv[i as usize]);
+                }
+                (cells.clone(), values)
+            }
+            None => (full_set.clone(), full_set),
+        };
 
         while let Some(i) = unseen_cells.pop() {
             // Try the next unseen node.
 
             // If it has no edges, ignore it (it's a fixed value).
             if cell_nodes[i as usize].is_empty() {
+                if let Some(components) = components.as_deref_mut() {
+                    components[i as usize] = i;
+                }
                 continue;
             }
 
@@ -144,7 +353,7 @@ impl<VS: ValueSet> AllDifferentEnforcer<VS> {
                         // First time we've seen u.
                         let u_set = VS::from_value(u as ValueType);
                         unseen_cells.remove_set(&u_set);
-                        let u_inv = assignees_inv[u];
+                        let u_inv = assignees_inv[u].clone();
                         stack_cell_values.add_set(&u_inv);
                         unseen_values.remove_set(&u_inv);
                         scc_stack.push(u);
@@ -166,7 +375,7 @@ impl<VS: ValueSet> AllDifferentEnforcer<VS> {
                         // adjacency which will be handled below).
                         // However it is vital for performance to skip over
                         // the seen values. ~2x performance increase.
-                        let scc_set_n = scc_set[n];
+                        let scc_set_n = scc_set[n].clone();
                         scc_set[u].union_update(&scc_set_n);
                     }
                 }
@@ -183,12 +392,12 @@ impl<VS: ValueSet> AllDifferentEnforcer<VS> {
                 // Handle any adjacent nodes already in the stack.
                 // Ignore any that we already know are in the same scc set as u,
                 // as they add no new information.
-                let mut scc_set_u = scc_set[u];
-                let mut stack_adj = cell_nodes[u]
+                let mut scc_set_u = scc_set[u].clone();
+                let stack_adj = cell_nodes[u]
                     .intersection(&stack_cell_values)
                     .without(&scc_set_u.values);
                 scc_set_u.values.add_set(&stack_adj);
-                while let Some(value) = stack_adj.pop() {
+                for value in stack_adj.iter() {
                     let n = assignees[value as usize];
                     // We preserve the invariant that
                     // `low_set[u].value0() = lowlinks[u]`. This is because
@@ -206,7 +415,7 @@ impl<VS: ValueSet> AllDifferentEnforcer<VS> {
                 // If u is a root node, pop the scc_stack and generate an SCC.
                 if scc_set_u.low_id() == Some(ids[u]) {
                     // Remove the edges and truncate the stack.
-                    let mask = scc_set_u.values;
+                    let mask = scc_set_u.values.clone();
                     stack_cell_values.remove_set(&mask);
 
                     // We know exactly how many cells are in this scc.
@@ -217,6 +426,9 @@ impl<VS: ValueSet> AllDifferentEnforcer<VS> {
 
                     for w in scc_stack.drain(remaining_size..) {
                         cell_nodes[w].remove_set(&mask);
+                        if let Some(components) = components.as_deref_mut() {
+                            components[w] = u as ValueType;
+                        }
                     }
                     stack_state = StackState::NoResult;
                 } else {
@@ -239,13 +451,13 @@ impl<VS: ValueSet> AllDifferentEnforcer<VS> {
         let mut assigned_values = VS::empty();
 
         // Prefill using the candidate mapping.
-        for (i, (&candidate, &cell_node)) in candidate_matching
+        for (i, (candidate, cell_node)) in candidate_matching
             .iter()
             .zip(self.cell_nodes.iter())
             .enumerate()
         {
-            if let Some(candidate_value) = candidate.intersection(&cell_node).min() {
-                assigned_values.add_set(&candidate);
+            if let Some(candidate_value) = candidate.intersection(cell_node).min() {
+                assigned_values.add_set(candidate);
                 self.assignees[candidate_value as usize] = i;
             }
         }
@@ -284,7 +496,7 @@ impl<VS: ValueSet> AllDifferentEnforcer<VS> {
         Ok(())
     }
 
-    fn update_matching(&mut self, cell: CellIndex, assigned: &VS) -> Result<VS, Contradition> {
+    fn update_matching(&mut self, cell: CellIndex, assigned: &VS) -> Result<VS, Contradiction> {
         let c_stack = &mut self.rec_stack;
         let v_stack = &mut self.data_stack;
         c_stack.clear();
@@ -330,6 +542,6 @@ impl<VS: ValueSet> AllDifferentEnforcer<VS> {
             c_stack.push(next_c);
         }
 
-        Err(Contradition)
+        Err(Contradiction)
     }
 }
@@ -0,0 +1,66 @@
+// With the `alloc-check` feature, the global allocator panics if memory is
+// allocated on a thread while it holds a `NoAllocGuard`. The engine holds one
+// while propagating during the search, to check that the hot path doesn't
+// allocate.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+thread_local! {
+    static FORBIDDEN: Cell<bool> = const { Cell::new(false) };
+}
+
+struct CheckingAllocator;
+
+#[global_allocator]
+static ALLOCATOR: CheckingAllocator = CheckingAllocator;
+
+impl CheckingAllocator {
+    fn check(&self, layout: Layout) {
+        // Allow allocations again before panicking, as the panic allocates. The
+        // thread local can't be read while the thread is being torn down, but
+        // nothing is forbidden then.
+        if FORBIDDEN.try_with(|f| f.replace(false)).unwrap_or(false) {
+            panic!("Allocated {} bytes while searching", layout.size());
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for CheckingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.check(layout);
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.check(layout);
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.check(layout);
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+// Forbids allocations on this thread until it is dropped.
+pub struct NoAllocGuard {
+    previous: bool,
+}
+
+impl NoAllocGuard {
+    pub fn new() -> NoAllocGuard {
+        NoAllocGuard {
+            previous: FORBIDDEN.with(|f| f.replace(true)),
+        }
+    }
+}
+
+impl Drop for NoAllocGuard {
+    fn drop(&mut self) {
+        FORBIDDEN.with(|f| f.set(self.previous));
+    }
+}
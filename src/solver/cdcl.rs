@@ -0,0 +1,505 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+// A small CDCL SAT solver: two watched literals, first-UIP clause learning,
+// VSIDS branching with phase saving, Luby restarts, and learnt clause
+// deletion by LBD.
+
+pub type Var = u32;
+
+// A variable, or its negation, packed as `var * 2 + negated`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Lit(u32);
+
+impl Lit {
+    pub fn pos(var: Var) -> Lit {
+        Lit(var * 2)
+    }
+
+    pub fn neg(var: Var) -> Lit {
+        Lit(var * 2 + 1)
+    }
+
+    pub fn var(self) -> Var {
+        self.0 / 2
+    }
+
+    fn is_neg(self) -> bool {
+        self.0 & 1 == 1
+    }
+
+    fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl std::ops::Not for Lit {
+    type Output = Lit;
+
+    fn not(self) -> Lit {
+        Lit(self.0 ^ 1)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Value {
+    True,
+    False,
+    Unassigned,
+}
+
+struct Clause {
+    lits: Vec<Lit>,
+    learnt: bool,
+    // The number of distinct decision levels in the clause when it was learnt.
+    lbd: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub decisions: u64,
+    pub conflicts: u64,
+    pub propagations: u64,
+    pub restarts: u64,
+}
+
+// Variable activity, ordered so that the heap pops the most active variable.
+#[derive(PartialEq)]
+struct Activity(f64, Var);
+
+impl Eq for Activity {}
+
+impl PartialOrd for Activity {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Activity {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(other.1.cmp(&self.1))
+    }
+}
+
+pub struct SatSolver {
+    clauses: Vec<Clause>,
+    // The clauses watching each literal.
+    watches: Vec<Vec<usize>>,
+    values: Vec<Value>,
+    levels: Vec<u32>,
+    // The clause which implied each variable, with the implied literal first.
+    reasons: Vec<Option<usize>>,
+    trail: Vec<Lit>,
+    // The start of each decision level in the trail.
+    trail_lims: Vec<usize>,
+    propagated: usize,
+    activity: Vec<f64>,
+    activity_inc: f64,
+    // May contain stale entries, which are skipped when popped.
+    order: BinaryHeap<Activity>,
+    phases: Vec<bool>,
+    seen: Vec<bool>,
+    num_learnts: usize,
+    max_learnts: usize,
+    // False once the clauses are known to be unsatisfiable.
+    ok: bool,
+    pub stats: Stats,
+}
+
+const ACTIVITY_DECAY: f64 = 0.95;
+const RESTART_BASE: u64 = 100;
+// Learnt clauses with this LBD or less are never deleted.
+const KEEP_LBD: u32 = 2;
+
+impl SatSolver {
+    pub fn new(num_vars: usize) -> SatSolver {
+        SatSolver {
+            clauses: Vec::new(),
+            watches: vec![Vec::new(); num_vars * 2],
+            values: vec![Value::Unassigned; num_vars],
+            levels: vec![0; num_vars],
+            reasons: vec![None; num_vars],
+            trail: Vec::new(),
+            trail_lims: Vec::new(),
+            propagated: 0,
+            activity: vec![0.0; num_vars],
+            activity_inc: 1.0,
+            order: (0..num_vars as Var).map(|v| Activity(0.0, v)).collect(),
+            phases: vec![false; num_vars],
+            seen: vec![false; num_vars],
+            num_learnts: 0,
+            max_learnts: 1000,
+            ok: true,
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn num_vars(&self) -> usize {
+        self.values.len()
+    }
+
+    // The value of the variable in the model found by `solve`, or at the root
+    // level after `propagate_root`.
+    pub fn value(&self, var: Var) -> Option<bool> {
+        match self.values[var as usize] {
+            Value::True => Some(true),
+            Value::False => Some(false),
+            Value::Unassigned => None,
+        }
+    }
+
+    fn lit_value(&self, lit: Lit) -> Value {
+        lit_value(&self.values, lit)
+    }
+
+    fn decision_level(&self) -> u32 {
+        self.trail_lims.len() as u32
+    }
+
+    // Add a clause, returning false if the clauses are now unsatisfiable. This
+    // undoes any search, so can be called after `solve` (e.g. to block a
+    // solution).
+    pub fn add_clause(&mut self, lits: &[Lit]) -> bool {
+        self.cancel_until(0);
+        if !self.ok {
+            return false;
+        }
+
+        let mut clause = Vec::with_capacity(lits.len());
+        for &lit in lits {
+            match self.lit_value(lit) {
+                Value::True => return true,
+                Value::False => {}
+                Value::Unassigned => {
+                    if clause.contains(&!lit) {
+                        return true;
+                    }
+                    if !clause.contains(&lit) {
+                        clause.push(lit);
+                    }
+                }
+            }
+        }
+
+        match clause.len() {
+            0 => self.ok = false,
+            1 => {
+                self.enqueue(clause[0], None);
+                self.ok = self.propagate().is_none();
+            }
+            _ => {
+                self.attach(clause, false, 0);
+            }
+        }
+        self.ok
+    }
+
+    fn attach(&mut self, lits: Vec<Lit>, learnt: bool, lbd: u32) -> usize {
+        let index = self.clauses.len();
+        self.watches[lits[0].index()].push(index);
+        self.watches[lits[1].index()].push(index);
+        self.clauses.push(Clause { lits, learnt, lbd });
+        if learnt {
+            self.num_learnts += 1;
+        }
+        index
+    }
+
+    fn enqueue(&mut self, lit: Lit, reason: Option<usize>) {
+        let var = lit.var() as usize;
+        self.values[var] = if lit.is_neg() {
+            Value::False
+        } else {
+            Value::True
+        };
+        self.levels[var] = self.decision_level();
+        self.reasons[var] = reason;
+        self.trail.push(lit);
+    }
+
+    // Propagate the units at the root level, returning false on a
+    // contradiction.
+    pub fn propagate_root(&mut self) -> bool {
+        self.cancel_until(0);
+        if self.ok && self.propagate().is_some() {
+            self.ok = false;
+        }
+        self.ok
+    }
+
+    // Unit propagation, returning the conflicting clause if there is one.
+    fn propagate(&mut self) -> Option<usize> {
+        while self.propagated < self.trail.len() {
+            let false_lit = !self.trail[self.propagated];
+            self.propagated += 1;
+            self.stats.propagations += 1;
+
+            let mut watches = std::mem::take(&mut self.watches[false_lit.index()]);
+            let mut conflict = None;
+            let (mut i, mut j) = (0, 0);
+            while i < watches.len() {
+                let index = watches[i];
+                i += 1;
+                let lits = &mut self.clauses[index].lits;
+                if lits[0] == false_lit {
+                    lits.swap(0, 1);
+                }
+                let first = lits[0];
+                if lit_value(&self.values, first) == Value::True {
+                    watches[j] = index;
+                    j += 1;
+                    continue;
+                }
+
+                // Look for a new literal to watch.
+                if let Some(k) =
+                    (2..lits.len()).find(|&k| lit_value(&self.values, lits[k]) != Value::False)
+                {
+                    lits.swap(1, k);
+                    self.watches[lits[1].index()].push(index);
+                    continue;
+                }
+
+                watches[j] = index;
+                j += 1;
+                if lit_value(&self.values, first) == Value::False {
+                    conflict = Some(index);
+                    while i < watches.len() {
+                        watches[j] = watches[i];
+                        i += 1;
+                        j += 1;
+                    }
+                } else {
+                    self.enqueue(first, Some(index));
+                }
+            }
+            watches.truncate(j);
+            self.watches[false_lit.index()] = watches;
+
+            if conflict.is_some() {
+                return conflict;
+            }
+        }
+        None
+    }
+
+    fn cancel_until(&mut self, level: u32) {
+        if self.decision_level() <= level {
+            return;
+        }
+        let start = self.trail_lims[level as usize];
+        for lit in self.trail.drain(start..).rev() {
+            let var = lit.var() as usize;
+            self.phases[var] = !lit.is_neg();
+            self.values[var] = Value::Unassigned;
+            self.reasons[var] = None;
+            self.order.push(Activity(self.activity[var], var as Var));
+        }
+        self.trail_lims.truncate(level as usize);
+        self.propagated = self.trail.len();
+
+        // Drop the stale entries if there are too many.
+        if self.order.len() > 4 * self.num_vars() {
+            self.order = (0..self.num_vars())
+                .filter(|&v| self.values[v] == Value::Unassigned)
+                .map(|v| Activity(self.activity[v], v as Var))
+                .collect();
+        }
+    }
+
+    // Find the first-UIP clause for the conflict, with the asserting literal
+    // first and a literal from the backtrack level second.
+    fn analyze(&mut self, mut conflict: usize) -> (Vec<Lit>, u32) {
+        let mut learnt = vec![Lit(0)];
+        let mut num_at_level = 0;
+        let mut implied = None;
+        let mut index = self.trail.len();
+        let level = self.decision_level();
+
+        loop {
+            let skip = implied.is_some() as usize;
+            for k in skip..self.clauses[conflict].lits.len() {
+                let lit = self.clauses[conflict].lits[k];
+                let var = lit.var() as usize;
+                if self.seen[var] || self.levels[var] == 0 {
+                    continue;
+                }
+                self.seen[var] = true;
+                self.bump(var);
+                if self.levels[var] >= level {
+                    num_at_level += 1;
+                } else {
+                    learnt.push(lit);
+                }
+            }
+
+            // The next literal on the trail in the conflict.
+            let lit = loop {
+                index -= 1;
+                if self.seen[self.trail[index].var() as usize] {
+                    break self.trail[index];
+                }
+            };
+            self.seen[lit.var() as usize] = false;
+            implied = Some(lit);
+            num_at_level -= 1;
+            if num_at_level == 0 {
+                break;
+            }
+            conflict = self.reasons[lit.var() as usize].expect("Implied literal without reason");
+        }
+        learnt[0] = !implied.unwrap();
+
+        for lit in &learnt[1..] {
+            self.seen[lit.var() as usize] = false;
+        }
+
+        let mut backtrack_level = 0;
+        if learnt.len() > 1 {
+            let max_index = (1..learnt.len())
+                .max_by_key(|&k| self.levels[learnt[k].var() as usize])
+                .unwrap();
+            learnt.swap(1, max_index);
+            backtrack_level = self.levels[learnt[1].var() as usize];
+        }
+        (learnt, backtrack_level)
+    }
+
+    fn bump(&mut self, var: usize) {
+        self.activity[var] += self.activity_inc;
+        if self.activity[var] > 1e100 {
+            for a in &mut self.activity {
+                *a *= 1e-100;
+            }
+            self.activity_inc *= 1e-100;
+            self.order = (0..self.num_vars())
+                .filter(|&v| self.values[v] == Value::Unassigned)
+                .map(|v| Activity(self.activity[v], v as Var))
+                .collect();
+        } else if self.values[var] == Value::Unassigned {
+            self.order.push(Activity(self.activity[var], var as Var));
+        }
+    }
+
+    fn pick_branch(&mut self) -> Option<Lit> {
+        while let Some(Activity(activity, var)) = self.order.pop() {
+            let v = var as usize;
+            if self.values[v] == Value::Unassigned && activity == self.activity[v] {
+                return Some(if self.phases[v] {
+                    Lit::pos(var)
+                } else {
+                    Lit::neg(var)
+                });
+            }
+        }
+        // Stale entries may have hidden a variable.
+        (0..self.num_vars())
+            .find(|&v| self.values[v] == Value::Unassigned)
+            .map(|v| Lit::neg(v as Var))
+    }
+
+    fn lbd(&mut self, lits: &[Lit]) -> u32 {
+        let mut levels = lits
+            .iter()
+            .map(|lit| self.levels[lit.var() as usize])
+            .collect::<Vec<_>>();
+        levels.sort_unstable();
+        levels.dedup();
+        levels.len() as u32
+    }
+
+    // Delete the worse half of the learnt clauses. Only called at the root
+    // level, where no learnt clause is a reason that will be needed.
+    fn reduce_learnts(&mut self) {
+        let mut lbds = self
+            .clauses
+            .iter()
+            .filter(|c| c.learnt && c.lbd > KEEP_LBD)
+            .map(|c| c.lbd)
+            .collect::<Vec<_>>();
+        if lbds.is_empty() {
+            return;
+        }
+        lbds.sort_unstable();
+        let cutoff = lbds[lbds.len() / 2];
+
+        self.clauses
+            .retain(|c| !c.learnt || c.lbd <= KEEP_LBD || c.lbd < cutoff);
+        self.num_learnts = self.clauses.iter().filter(|c| c.learnt).count();
+        for reason in &mut self.reasons {
+            *reason = None;
+        }
+        for watches in &mut self.watches {
+            watches.clear();
+        }
+        for (index, clause) in self.clauses.iter().enumerate() {
+            self.watches[clause.lits[0].index()].push(index);
+            self.watches[clause.lits[1].index()].push(index);
+        }
+    }
+
+    // Search for an assignment satisfying all the clauses. Returns None if
+    // `should_stop` returned true first. `should_stop` is called before each
+    // decision.
+    pub fn solve(&mut self, should_stop: &mut dyn FnMut(&Stats) -> bool) -> Option<bool> {
+        if !self.propagate_root() {
+            return Some(false);
+        }
+
+        let mut restart_conflicts = 0;
+        let mut restart_limit = RESTART_BASE * super::luby(self.stats.restarts + 1);
+        loop {
+            if let Some(conflict) = self.propagate() {
+                self.stats.conflicts += 1;
+                restart_conflicts += 1;
+                if self.decision_level() == 0 {
+                    self.ok = false;
+                    return Some(false);
+                }
+                let (learnt, backtrack_level) = self.analyze(conflict);
+                self.cancel_until(backtrack_level);
+                if learnt.len() == 1 {
+                    self.enqueue(learnt[0], None);
+                } else {
+                    let lbd = self.lbd(&learnt);
+                    let asserting = learnt[0];
+                    let index = self.attach(learnt, true, lbd);
+                    self.enqueue(asserting, Some(index));
+                }
+                self.activity_inc /= ACTIVITY_DECAY;
+                continue;
+            }
+
+            if restart_conflicts >= restart_limit {
+                self.cancel_until(0);
+                self.stats.restarts += 1;
+                restart_conflicts = 0;
+                restart_limit = RESTART_BASE * super::luby(self.stats.restarts + 1);
+                if self.num_learnts > self.max_learnts {
+                    self.reduce_learnts();
+                    self.max_learnts += self.max_learnts / 10;
+                }
+                continue;
+            }
+
+            if should_stop(&self.stats) {
+                self.cancel_until(0);
+                return None;
+            }
+            let Some(lit) = self.pick_branch() else {
+                return Some(true);
+            };
+            self.stats.decisions += 1;
+            self.trail_lims.push(self.trail.len());
+            self.enqueue(lit, None);
+        }
+    }
+}
+
+fn lit_value(values: &[Value], lit: Lit) -> Value {
+    match (values[lit.var() as usize], lit.is_neg()) {
+        (Value::Unassigned, _) => Value::Unassigned,
+        (Value::True, false) | (Value::False, true) => Value::True,
+        _ => Value::False,
+    }
+}
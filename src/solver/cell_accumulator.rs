@@ -11,7 +11,12 @@ pub struct CellAccumulator {
 }
 
 impl CellAccumulator {
-    pub fn new<H: CellContainer>(num_cells: usize, handlers: &[H]) -> CellAccumulator {
+    // Handlers are popped from the lowest tier first.
+    pub fn new<H: CellContainer>(
+        num_cells: usize,
+        handlers: &[H],
+        tiers: &[usize],
+    ) -> CellAccumulator {
         let mut cell_to_handlers = vec![Vec::new(); num_cells];
         for (index, handler) in handlers.iter().enumerate() {
             for cell in handler.cells() {
@@ -21,7 +26,7 @@ impl CellAccumulator {
 
         CellAccumulator {
             cell_to_handlers,
-            linked_list: IndexLinkedList::new(handlers.len()),
+            linked_list: IndexLinkedList::new(tiers),
         }
     }
 
@@ -31,6 +36,10 @@ impl CellAccumulator {
         }
     }
 
+    pub fn handlers(&self, cell: CellIndex) -> &[HandlerIndex] {
+        &self.cell_to_handlers[cell]
+    }
+
     pub fn clear(&mut self) {
         self.linked_list.clear();
     }
@@ -39,6 +48,12 @@ impl CellAccumulator {
         self.linked_list.pop()
     }
 
+    // The handler which `pop` would return next.
+    #[cfg(feature = "batch-houses")]
+    pub fn peek(&self) -> Option<usize> {
+        self.linked_list.peek()
+    }
+
     pub fn hold(&mut self, index: usize) {
         self.linked_list.hold(index)
     }
@@ -46,11 +61,24 @@ impl CellAccumulator {
     pub fn clear_hold(&mut self) {
         self.linked_list.clear_hold()
     }
+
+    pub fn memory_usage(&self) -> usize {
+        super::vec_memory(&self.cell_to_handlers)
+            + self
+                .cell_to_handlers
+                .iter()
+                .map(super::vec_memory)
+                .sum::<usize>()
+            + super::vec_memory(&self.linked_list.linked_list)
+            + super::vec_memory(&self.linked_list.tiers)
+    }
 }
 
 struct IndexLinkedList {
     linked_list: Vec<usize>,
-    head: usize,
+    tiers: Vec<usize>,
+    // The head of the list for each tier.
+    heads: Vec<usize>,
     hold: usize,
 }
 
@@ -58,26 +86,31 @@ impl IndexLinkedList {
     const NOT_IN_LIST: usize = usize::MAX;
     const NIL: usize = usize::MAX - 1;
 
-    fn new(size: usize) -> IndexLinkedList {
+    fn new(tiers: &[usize]) -> IndexLinkedList {
+        let num_tiers = tiers.iter().max().map_or(1, |max| max + 1);
         IndexLinkedList {
-            linked_list: vec![Self::NOT_IN_LIST; size],
-            head: Self::NIL,
+            linked_list: vec![Self::NOT_IN_LIST; tiers.len()],
+            tiers: tiers.to_vec(),
+            heads: vec![Self::NIL; num_tiers],
             hold: Self::NIL,
         }
     }
 
     fn add(&mut self, index: usize) {
         if self.linked_list[index] == Self::NOT_IN_LIST {
-            self.linked_list[index] = self.head;
-            self.head = index;
+            let head = &mut self.heads[self.tiers[index]];
+            self.linked_list[index] = *head;
+            *head = index;
         }
     }
 
     fn clear(&mut self) {
-        while self.head != Self::NIL {
-            let new_head = self.linked_list[self.head];
-            self.linked_list[self.head] = Self::NOT_IN_LIST;
-            self.head = new_head;
+        for head in &mut self.heads {
+            while *head != Self::NIL {
+                let new_head = self.linked_list[*head];
+                self.linked_list[*head] = Self::NOT_IN_LIST;
+                *head = new_head;
+            }
         }
         self.clear_hold();
     }
@@ -91,14 +124,16 @@ impl IndexLinkedList {
     }
 
     fn pop(&mut self) -> Option<usize> {
-        match self.head {
-            Self::NIL => None,
-            index => {
-                self.head = self.linked_list[index];
-                self.linked_list[index] = Self::NOT_IN_LIST;
-                Some(index)
-            }
-        }
+        let head = self.heads.iter_mut().find(|head| **head != Self::NIL)?;
+        let index = *head;
+        *head = self.linked_list[index];
+        self.linked_list[index] = Self::NOT_IN_LIST;
+        Some(index)
+    }
+
+    #[cfg(feature = "batch-houses")]
+    fn peek(&self) -> Option<usize> {
+        self.heads.iter().copied().find(|&head| head != Self::NIL)
     }
 
     fn hold(&mut self, index: usize) {
@@ -0,0 +1,72 @@
+use crate::types::{Constraint, FixedValues, VariantConstraint};
+
+use super::engine;
+use super::{Config, OutputType};
+
+// A smallest set of clues which has no solution on its own: removing any one of
+// them would give a solution.
+pub struct Diagnosis {
+    pub fixed_values: FixedValues,
+    pub variants: Vec<VariantConstraint>,
+    // Whether a limit stopped one of the searches, in which case the clue being
+    // tried was kept and the set may not be minimal.
+    pub limit_reached: bool,
+}
+
+// Find out why a puzzle has no solution, by removing each clue in turn and
+// keeping it only if the rest of the clues have a solution without it. Returns
+// None if the puzzle has a solution.
+pub fn diagnose(constraint: &Constraint, config: Config) -> Option<Diagnosis> {
+    let mut limit_reached = false;
+    let mut has_solution = |constraint: &Constraint, fixed_values: &FixedValues| {
+        let mut runner = engine::make_runner(constraint, check_config(&config));
+        runner.reset_fixed_values(fixed_values);
+        let found = runner.next().is_some();
+        limit_reached |= runner.stopped_early();
+        found || runner.stopped_early()
+    };
+
+    if has_solution(constraint, &constraint.fixed_values) {
+        return None;
+    }
+
+    // Variants are removed first, as each needs the handlers to be rebuilt.
+    let mut reduced = constraint.clone();
+    for i in (0..constraint.variants.len()).rev() {
+        let mut candidate = reduced.clone();
+        candidate.variants.remove(i);
+        if !has_solution(&candidate, &candidate.fixed_values) {
+            reduced = candidate;
+        }
+    }
+
+    let mut remaining_values = reduced.fixed_values.clone();
+    let mut required_values = Vec::new();
+    while let Some(item) = remaining_values.pop() {
+        let fixed_values = [remaining_values.clone(), required_values.clone()].concat();
+        if has_solution(&reduced, &fixed_values) {
+            required_values.push(item);
+        }
+    }
+    required_values.sort_by_key(|&(cell, _)| cell);
+
+    Some(Diagnosis {
+        fixed_values: required_values,
+        variants: reduced.variants,
+        limit_reached,
+    })
+}
+
+// Each search only needs to find one solution, within the same limits.
+fn check_config(config: &Config) -> Config {
+    Config {
+        output_type: OutputType::Empty,
+        propagation_level: config.propagation_level,
+        tiered_propagation: config.tiered_propagation,
+        max_fish_size: config.max_fish_size,
+        time_limit: config.time_limit,
+        max_guesses: config.max_guesses,
+        cancellation: config.cancellation.clone(),
+        ..Config::default()
+    }
+}
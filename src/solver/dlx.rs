@@ -0,0 +1,538 @@
+use std::time::Instant;
+
+use crate::types::{CellIndex, CellValue, Constraint, FixedValues, ValueType, VariantConstraint};
+
+use super::engine::{Checkpoint, ProgressMetadata, Propagation, Runner};
+use super::handlers;
+use super::{Config, Counters, Output, OutputType};
+
+// The node for the root of the column list.
+const ROOT: usize = 0;
+
+// Whether the constraint can be solved as an exact cover problem. Every
+// constraint must be a house, which contains each value exactly once.
+pub fn check_supported(constraint: &Constraint) -> Result<(), String> {
+    let supported = !constraint.anti_king
+        && !constraint.argyle
+        && !constraint.kropki_negative
+        && !constraint.xv_negative
+        && !constraint.consecutive_negative
+        && constraint.propagators.is_empty()
+        && constraint.variants.iter().all(|variant| match variant {
+            VariantConstraint::Region { cells } => {
+                cells.len() == constraint.shape.num_values as usize
+            }
+            _ => false,
+        });
+    if supported {
+        Ok(())
+    } else {
+        Err(
+            "The dlx engine only supports constraints where every region contains \
+             each value (no other variants)."
+                .to_string(),
+        )
+    }
+}
+
+// Knuth's Algorithm X with dancing links. There is a row for each candidate
+// value of each cell, and a column for each cell and for each value in each
+// house. The nodes are stored in flat arrays, with the column headers first.
+pub struct DlxRunner {
+    num_values: usize,
+    left: Vec<u32>,
+    right: Vec<u32>,
+    up: Vec<u32>,
+    down: Vec<u32>,
+    // The column header for each node.
+    column: Vec<u32>,
+    // The candidate (cell * num_values + value) for each node.
+    candidate: Vec<u32>,
+    // The number of rows in each column.
+    size: Vec<u32>,
+    // The first node of the row for each candidate, if it is allowed.
+    candidate_rows: Vec<Option<u32>>,
+
+    // Rows selected from the fixed values or by propagation, which aren't
+    // backtracked over.
+    fixed_rows: Vec<usize>,
+    contradiction: bool,
+    // Rows selected by the search, and the fraction of the search space under
+    // each one.
+    search_rows: Vec<usize>,
+    progress_deltas: Vec<f64>,
+    started: bool,
+    // Whether the search continues by moving on from the top row of the stack
+    // (after a solution), rather than by choosing a new column.
+    resume_backtrack: bool,
+    finished: bool,
+
+    progress_metadata: ProgressMetadata,
+    counters: Counters,
+    config: Config,
+    deadline: Option<Instant>,
+}
+
+impl DlxRunner {
+    pub fn new(constraint: &Constraint, mut config: Config) -> DlxRunner {
+        let num_cells = constraint.shape.num_cells;
+        let num_values = constraint.shape.num_values as usize;
+        let houses = handlers::make_houses(constraint);
+        let num_columns = num_cells + houses.len() * num_values;
+
+        let mut cell_houses = vec![Vec::new(); num_cells];
+        for (h, house) in houses.iter().enumerate() {
+            for &cell in house {
+                cell_houses[cell].push(h);
+            }
+        }
+
+        let mut allowed = vec![true; num_cells * num_values];
+        for (cell, values) in &constraint.candidates {
+            let mut cell_allowed = vec![false; num_values];
+            for value in values {
+                cell_allowed[value.index() as usize] = true;
+            }
+            for (v, a) in cell_allowed.into_iter().enumerate() {
+                allowed[cell * num_values + v] &= a;
+            }
+        }
+
+        let progress_metadata = ProgressMetadata::new(&mut config);
+        let mut dlx = DlxRunner {
+            num_values,
+            left: Vec::new(),
+            right: Vec::new(),
+            up: Vec::new(),
+            down: Vec::new(),
+            column: Vec::new(),
+            candidate: Vec::new(),
+            size: vec![0; num_columns + 1],
+            candidate_rows: vec![None; num_cells * num_values],
+            fixed_rows: Vec::new(),
+            contradiction: false,
+            search_rows: Vec::new(),
+            progress_deltas: Vec::new(),
+            started: false,
+            resume_backtrack: false,
+            finished: false,
+            progress_metadata,
+            counters: Counters::default(),
+            deadline: config.time_limit.map(|limit| Instant::now() + limit),
+            config,
+        };
+
+        // The root and column headers, linked in a circle.
+        for node in 0..=num_columns {
+            dlx.left
+                .push(node.checked_sub(1).unwrap_or(num_columns) as u32);
+            dlx.right.push(((node + 1) % (num_columns + 1)) as u32);
+            dlx.up.push(node as u32);
+            dlx.down.push(node as u32);
+            dlx.column.push(node as u32);
+            dlx.candidate.push(u32::MAX);
+        }
+
+        for (cell, houses) in cell_houses.iter().enumerate() {
+            for v in 0..num_values {
+                let candidate = cell * num_values + v;
+                if !allowed[candidate] {
+                    continue;
+                }
+                let columns = std::iter::once(1 + cell)
+                    .chain(houses.iter().map(|&h| 1 + num_cells + h * num_values + v));
+                dlx.candidate_rows[candidate] = Some(dlx.left.len() as u32);
+                dlx.add_row(candidate, columns);
+            }
+        }
+
+        dlx.update_peak_memory();
+        dlx.reset_fixed_values(&constraint.fixed_values);
+        dlx
+    }
+
+    fn add_row(&mut self, candidate: usize, columns: impl Iterator<Item = usize>) {
+        let first = self.left.len();
+        for column in columns {
+            let node = self.left.len();
+            // Link at the bottom of the column.
+            let last = self.up[column] as usize;
+            self.up.push(last as u32);
+            self.down.push(column as u32);
+            self.down[last] = node as u32;
+            self.up[column] = node as u32;
+            // Link at the end of the row.
+            if node == first {
+                self.left.push(node as u32);
+                self.right.push(node as u32);
+            } else {
+                let last = self.left[first] as usize;
+                self.left.push(last as u32);
+                self.right.push(first as u32);
+                self.right[last] = node as u32;
+                self.left[first] = node as u32;
+            }
+
+            self.column.push(column as u32);
+            self.candidate.push(candidate as u32);
+            self.size[column] += 1;
+        }
+    }
+
+    // Remove the column, and every row which has a node in it.
+    fn cover(&mut self, column: usize) {
+        let (l, r) = (self.left[column], self.right[column]);
+        self.right[l as usize] = r;
+        self.left[r as usize] = l;
+
+        let mut i = self.down[column] as usize;
+        while i != column {
+            let mut j = self.right[i] as usize;
+            while j != i {
+                let (u, d) = (self.up[j], self.down[j]);
+                self.down[u as usize] = d;
+                self.up[d as usize] = u;
+                self.size[self.column[j] as usize] -= 1;
+                j = self.right[j] as usize;
+            }
+            i = self.down[i] as usize;
+        }
+        self.counters.constraints_processed += 1;
+    }
+
+    // Exactly undo `cover`.
+    fn uncover(&mut self, column: usize) {
+        let mut i = self.up[column] as usize;
+        while i != column {
+            let mut j = self.left[i] as usize;
+            while j != i {
+                self.size[self.column[j] as usize] += 1;
+                let (u, d) = (self.up[j], self.down[j]);
+                self.down[u as usize] = j as u32;
+                self.up[d as usize] = j as u32;
+                j = self.left[j] as usize;
+            }
+            i = self.up[i] as usize;
+        }
+
+        let (l, r) = (self.left[column], self.right[column]);
+        self.right[l as usize] = column as u32;
+        self.left[r as usize] = column as u32;
+    }
+
+    // Cover the other columns of a row, after its own column has been covered.
+    fn select(&mut self, node: usize) {
+        let mut j = self.right[node] as usize;
+        while j != node {
+            self.cover(self.column[j] as usize);
+            j = self.right[j] as usize;
+        }
+    }
+
+    fn unselect(&mut self, node: usize) {
+        let mut j = self.left[node] as usize;
+        while j != node {
+            self.uncover(self.column[j] as usize);
+            j = self.left[j] as usize;
+        }
+    }
+
+    // Whether the row is still in the matrix (none of its columns are covered).
+    fn is_live(&self, node: usize) -> bool {
+        let mut j = node;
+        loop {
+            let column = self.column[j] as usize;
+            if self.left[self.right[column] as usize] as usize != column {
+                return false;
+            }
+            j = self.right[j] as usize;
+            if j == node {
+                return true;
+            }
+        }
+    }
+
+    fn fix_row(&mut self, node: usize) {
+        self.cover(self.column[node] as usize);
+        self.select(node);
+        self.fixed_rows.push(node);
+    }
+
+    fn unwind_search(&mut self) {
+        while let Some(node) = self.search_rows.pop() {
+            self.unselect(node);
+            self.uncover(self.column[node] as usize);
+        }
+        self.progress_deltas.clear();
+    }
+
+    // The uncovered column with the fewest rows.
+    fn min_column(&self, only_cells: bool) -> Option<usize> {
+        let num_cells = self.candidate_rows.len() / self.num_values;
+        let mut best = None;
+        let mut best_size = u32::MAX;
+        let mut c = self.right[ROOT] as usize;
+        while c != ROOT {
+            if (!only_cells || c <= num_cells) && self.size[c] < best_size {
+                best = Some(c);
+                best_size = self.size[c];
+                if best_size == 0 {
+                    break;
+                }
+            }
+            c = self.right[c] as usize;
+        }
+        best
+    }
+
+    #[inline]
+    fn check_limits(&mut self) -> bool {
+        const TIME_CHECK_MASK: u64 = (1 << 8) - 1;
+
+        if let Some(max_guesses) = self.config.max_guesses {
+            if self.counters.guesses >= max_guesses {
+                self.counters.limit_reached = true;
+            }
+        }
+        if let Some(deadline) = self.deadline {
+            if self.counters.guesses & TIME_CHECK_MASK == 0 && Instant::now() >= deadline {
+                self.counters.limit_reached = true;
+            }
+        }
+        if let Some(token) = &self.config.cancellation {
+            if token.is_cancelled() {
+                self.counters.cancelled = true;
+            }
+        }
+        self.stopped_early()
+    }
+
+    // Search for the next solution, returning whether one was found. The
+    // search continues from the last solution if there was one.
+    fn run(&mut self) -> bool {
+        if self.finished || self.stopped_early() {
+            return false;
+        }
+
+        let mut backtrack = self.resume_backtrack;
+        if !self.started {
+            self.started = true;
+            self.progress_metadata.maybe_call(&self.counters);
+            if self.contradiction {
+                self.counters.progress_ratio = 1.0;
+                self.finished = true;
+                self.progress_metadata.maybe_call(&self.counters);
+                return false;
+            }
+        }
+
+        loop {
+            if backtrack {
+                let Some(node) = self.search_rows.pop() else {
+                    self.finished = true;
+                    self.progress_metadata.maybe_call(&self.counters);
+                    return false;
+                };
+                self.unselect(node);
+                let column = self.column[node] as usize;
+                let next = self.down[node] as usize;
+                if next == column {
+                    // All the rows in the column have been tried.
+                    self.uncover(column);
+                    self.progress_deltas.pop();
+                    continue;
+                }
+                if self.check_limits() {
+                    // Put the row back, so that the search moves on from it
+                    // when resumed.
+                    self.search_rows.push(node);
+                    self.select(node);
+                    self.resume_backtrack = true;
+                    self.progress_metadata.maybe_call(&self.counters);
+                    return false;
+                }
+                self.try_row(next);
+                backtrack = false;
+                continue;
+            }
+
+            let delta = self.progress_deltas.last().copied().unwrap_or(1.0);
+            let Some(column) = self.min_column(false) else {
+                // Every column is covered, so this is a solution.
+                self.counters.solutions += 1;
+                self.counters.progress_ratio += delta;
+                self.resume_backtrack = true;
+                self.progress_metadata.maybe_call(&self.counters);
+                return true;
+            };
+            let size = self.size[column];
+            if size == 0 {
+                self.counters.backtracks += 1;
+                self.counters.progress_ratio += delta;
+                backtrack = true;
+                continue;
+            }
+
+            if self.check_limits() {
+                self.resume_backtrack = false;
+                self.progress_metadata.maybe_call(&self.counters);
+                return false;
+            }
+            self.counters.cells_searched += 1;
+            self.cover(column);
+            self.progress_deltas.push(delta / size as f64);
+            self.try_row(self.down[column] as usize);
+        }
+    }
+
+    fn try_row(&mut self, node: usize) {
+        self.counters.values_tried += 1;
+        if self.size[self.column[node] as usize] > 1 {
+            self.counters.guesses += 1;
+        }
+        self.select(node);
+        self.search_rows.push(node);
+        self.progress_metadata
+            .maybe_call_thottled(self.counters.constraints_processed, &self.counters);
+    }
+
+    fn node_value(&self, node: usize) -> (CellIndex, CellValue) {
+        let candidate = self.candidate[node] as usize;
+        (
+            candidate / self.num_values,
+            CellValue::from_index((candidate % self.num_values) as ValueType),
+        )
+    }
+
+    // Select singles (columns with one row) until there are none left, then
+    // find the cell to branch on.
+    fn propagation_result(&mut self) -> Propagation {
+        loop {
+            if self.contradiction {
+                return Propagation::Contradiction;
+            }
+            match self.min_column(false) {
+                None => return Propagation::Solved,
+                Some(column) => match self.size[column] {
+                    0 => return Propagation::Contradiction,
+                    1 => self.fix_row(self.down[column] as usize),
+                    _ => break,
+                },
+            }
+        }
+
+        let Some(column) = self.min_column(true) else {
+            return Propagation::Contradiction;
+        };
+        let mut values = Vec::new();
+        let mut node = self.down[column] as usize;
+        while node != column {
+            values.push(self.node_value(node).1);
+            node = self.down[node] as usize;
+        }
+        Propagation::Branch(column - 1, values)
+    }
+
+    fn update_peak_memory(&mut self) {
+        let memory = super::vec_memory(&self.left)
+            + super::vec_memory(&self.right)
+            + super::vec_memory(&self.up)
+            + super::vec_memory(&self.down)
+            + super::vec_memory(&self.column)
+            + super::vec_memory(&self.candidate)
+            + super::vec_memory(&self.size)
+            + super::vec_memory(&self.candidate_rows);
+        self.counters.peak_memory = self.counters.peak_memory.max(memory as u64);
+    }
+}
+
+impl Runner for DlxRunner {
+    fn reset_fixed_values(&mut self, fixed_values: &FixedValues) {
+        self.unwind_search();
+        while let Some(node) = self.fixed_rows.pop() {
+            self.unselect(node);
+            self.uncover(self.column[node] as usize);
+        }
+
+        self.started = false;
+        self.resume_backtrack = false;
+        self.finished = false;
+        self.contradiction = false;
+        for (cell, value) in fixed_values {
+            let row = self.candidate_rows[cell * self.num_values + value.index() as usize];
+            match row {
+                Some(node) if self.is_live(node as usize) => self.fix_row(node as usize),
+                // The value isn't a candidate, or conflicts with another
+                // fixed value.
+                _ => {
+                    self.contradiction = true;
+                    break;
+                }
+            }
+        }
+
+        self.counters.progress_ratio = 0.0;
+        self.counters.solutions = 0;
+    }
+
+    fn next(&mut self) -> Option<Output> {
+        if !self.run() {
+            return None;
+        }
+        match self.config.output_type {
+            OutputType::Empty => Some(Output::Empty),
+            OutputType::Solution => {
+                let mut solution =
+                    vec![CellValue::from_index(0); self.candidate_rows.len() / self.num_values];
+                for &node in self.fixed_rows.iter().chain(&self.search_rows) {
+                    let (cell, value) = self.node_value(node);
+                    solution[cell] = value;
+                }
+                Some(Output::Solution(solution))
+            }
+            OutputType::Guesses => Some(Output::Guesses(
+                self.search_rows
+                    .iter()
+                    .map(|&node| self.node_value(node))
+                    .collect(),
+            )),
+        }
+    }
+
+    fn stopped_early(&self) -> bool {
+        self.counters.limit_reached || self.counters.cancelled
+    }
+
+    fn checkpoint(&self) -> Result<Checkpoint, String> {
+        Err("Checkpoints are not supported by the dlx engine.".to_string())
+    }
+
+    fn restore(&mut self, _checkpoint: &Checkpoint) -> Result<(), String> {
+        Err("Checkpoints are not supported by the dlx engine.".to_string())
+    }
+
+    fn propagate(&mut self) -> Propagation {
+        // Leave the search to start again from the fixed values.
+        self.unwind_search();
+        self.started = false;
+        self.resume_backtrack = false;
+        self.finished = false;
+        self.propagation_result()
+    }
+
+    fn assign(&mut self, cell: CellIndex, value: CellValue) -> Propagation {
+        match self.candidate_rows[cell * self.num_values + value.index() as usize] {
+            Some(node) if self.is_live(node as usize) => self.fix_row(node as usize),
+            _ => self.contradiction = true,
+        }
+        self.propagation_result()
+    }
+
+    fn counters(&self) -> Counters {
+        self.counters
+    }
+
+    fn partial_assignment(&self) -> FixedValues {
+        Vec::new()
+    }
+}
@@ -1,62 +1,224 @@
+use std::time::Duration;
+
 use rand::prelude::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
 
-use crate::types::{CellIndex, CellValue, Constraint, FixedValues, ValueType};
+use crate::types::{CellIndex, CellValue, Constraint, FixedValues, RngType, ValueType};
+use crate::value_set::{DynBitSet, IntBitSet, ValueSet};
 #[cfg(not(feature = "i64_value_set"))]
-use crate::value_set::RecValueSet;
-use crate::value_set::{IntBitSet, ValueSet};
+use crate::value_set::{RecValueSet, SimdValueSet};
 
-use super::cell_accumulator::CellAccumulator;
+use super::cell_accumulator::{CellAccumulator, CellContainer};
+use super::dlx::DlxRunner;
 use super::handlers;
-use super::{Config, Counters, Output, OutputType, ProgressCallback};
+use super::nogoods::NogoodStore;
+use super::progress::ProgressEstimator;
+#[cfg(feature = "sat")]
+use super::sat::SatRunner;
+use super::trail::TrailGrid;
+use super::{
+    CellOrdering, Config, Counters, EngineKind, HandlerDescription, HandlerProfile, Instant,
+    Output, OutputType, ProgressCallback, TraceEvent, ValueOrder,
+};
 
 pub trait Runner {
     fn reset_fixed_values(&mut self, fixed_values: &FixedValues);
 
     fn next(&mut self) -> Option<Output>;
+
+    // Whether the search stopped early because it reached a limit or was
+    // cancelled.
+    fn stopped_early(&self) -> bool;
+
+    fn checkpoint(&self) -> Result<Checkpoint, String>;
+
+    fn restore(&mut self, checkpoint: &Checkpoint) -> Result<(), String>;
+
+    // Enforce the constraints on the fixed values without guessing.
+    fn propagate(&mut self) -> Propagation;
+
+    // Fix a value after `propagate`, and enforce the constraints again.
+    fn assign(&mut self, cell: CellIndex, value: CellValue) -> Propagation;
+
+    fn counters(&self) -> Counters;
+
+    // The values fixed at the deepest point of the search so far, in the order
+    // they were fixed, if `config.record_partial_assignment` is set.
+    fn partial_assignment(&self) -> FixedValues;
 }
 
-pub const VALID_NUM_VALUE_RANGE: std::ops::RangeInclusive<u32> = 2..=512;
+pub enum Propagation {
+    Contradiction,
+    Solved,
+    // The cell with the fewest values, which the search can branch on.
+    Branch(CellIndex, Vec<CellValue>),
+}
+
+// A snapshot of the search state, taken between guesses.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    num_values: u32,
+    started: bool,
+    cell_order: Vec<CellIndex>,
+    rec_stack: Vec<usize>,
+    // The live frames of the grid stack. Each cell is stored as a bitmask of
+    // its values, split into 64-bit words.
+    grid_stack: Vec<Vec<u64>>,
+    backtrack_triggers: Vec<u32>,
+    progress_ratio_stack: Vec<f64>,
+    pub counters: Counters,
+}
+
+pub const VALID_NUM_VALUE_RANGE: std::ops::RangeInclusive<u32> = 2..=4096;
+// Grids with more values use DynBitSet.
+pub const MAX_FIXED_WIDTH_VALUES: u32 = 512;
 
 pub fn make_runner(constraint: &Constraint, config: Config) -> Box<dyn Runner> {
-    match constraint.shape.num_values {
-        #[cfg(not(feature = "i64_value_set"))]
-        2..=32 => Box::new(Engine::<IntBitSet<i32>>::new(constraint, config)),
-        #[cfg(not(feature = "i64_value_set"))]
-        33..=64 => Box::new(Engine::<IntBitSet<i64>>::new(constraint, config)),
-        #[cfg(feature = "i64_value_set")]
-        2..=64 => Box::new(Engine::<IntBitSet<i64>>::new(constraint, config)),
+    if config.engine == EngineKind::Dlx {
+        return Box::new(DlxRunner::new(constraint, config));
+    }
+    #[cfg(feature = "sat")]
+    if config.engine == EngineKind::Sat {
+        return Box::new(SatRunner::new(constraint, config));
+    }
+    match ValueSetKind::for_num_values(constraint.shape.num_values) {
         #[cfg(not(feature = "i64_value_set"))]
-        65..=128 => Box::new(Engine::<IntBitSet<i128>>::new(constraint, config)),
+        ValueSetKind::Int32 => Box::new(Engine::<IntBitSet<i32>>::new(constraint, config)),
+        ValueSetKind::Int64 => Box::new(Engine::<IntBitSet<i64>>::new(constraint, config)),
         #[cfg(not(feature = "i64_value_set"))]
-        129..=256 => Box::new(Engine::<RecValueSet<IntBitSet<i128>>>::new(
+        ValueSetKind::Int64Pair => Box::new(Engine::<RecValueSet<IntBitSet<i64>>>::new(
             constraint, config,
         )),
         #[cfg(not(feature = "i64_value_set"))]
-        257..=512 => Box::new(Engine::<RecValueSet<RecValueSet<IntBitSet<i128>>>>::new(
-            constraint, config,
-        )),
-        _ => panic!(
-            "Grid too large. num_values: {}",
-            constraint.shape.num_values
-        ),
+        ValueSetKind::Simd256 => Box::new(Engine::<SimdValueSet<4>>::new(constraint, config)),
+        #[cfg(not(feature = "i64_value_set"))]
+        ValueSetKind::Simd512 => Box::new(Engine::<SimdValueSet<8>>::new(constraint, config)),
+        ValueSetKind::Dynamic => Box::new(Engine::<DynBitSet>::new(constraint, config)),
+    }
+}
+
+// The value set which the propagation engine uses for each cell.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueSetKind {
+    #[cfg(not(feature = "i64_value_set"))]
+    Int32,
+    Int64,
+    #[cfg(not(feature = "i64_value_set"))]
+    Int64Pair,
+    #[cfg(not(feature = "i64_value_set"))]
+    Simd256,
+    #[cfg(not(feature = "i64_value_set"))]
+    Simd512,
+    Dynamic,
+}
+
+impl ValueSetKind {
+    // The narrowest set which holds `num_values`, except where a wider one was
+    // faster when counting solutions of empty grids: 16-bit integers are no
+    // faster than 32-bit ones, and a pair of 64-bit integers is about 20%
+    // faster than an i128.
+    pub fn for_num_values(num_values: u32) -> ValueSetKind {
+        match num_values {
+            #[cfg(not(feature = "i64_value_set"))]
+            0..=32 => ValueSetKind::Int32,
+            #[cfg(not(feature = "i64_value_set"))]
+            33..=64 => ValueSetKind::Int64,
+            #[cfg(feature = "i64_value_set")]
+            0..=64 => ValueSetKind::Int64,
+            #[cfg(not(feature = "i64_value_set"))]
+            65..=128 => ValueSetKind::Int64Pair,
+            #[cfg(not(feature = "i64_value_set"))]
+            129..=256 => ValueSetKind::Simd256,
+            #[cfg(not(feature = "i64_value_set"))]
+            257..=MAX_FIXED_WIDTH_VALUES => ValueSetKind::Simd512,
+            _ => ValueSetKind::Dynamic,
+        }
+    }
+}
+
+impl std::fmt::Display for ValueSetKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            #[cfg(not(feature = "i64_value_set"))]
+            ValueSetKind::Int32 => "32-bit integer",
+            ValueSetKind::Int64 => "64-bit integer",
+            #[cfg(not(feature = "i64_value_set"))]
+            ValueSetKind::Int64Pair => "pair of 64-bit integers",
+            #[cfg(not(feature = "i64_value_set"))]
+            ValueSetKind::Simd256 => "4 64-bit words (vectorized)",
+            #[cfg(not(feature = "i64_value_set"))]
+            ValueSetKind::Simd512 => "8 64-bit words (vectorized)",
+            ValueSetKind::Dynamic => "heap-allocated words",
+        };
+        write!(f, "{}", name)
     }
 }
 
+pub fn describe_handlers(constraint: &Constraint) -> Vec<HandlerDescription> {
+    // The handler structure doesn't depend on the value set, so just use one
+    // which is wide enough for any valid shape.
+    let handler_set = handlers::make_handlers::<DynBitSet>(constraint, &Config::default());
+    handler_set
+        .iter()
+        .map(|handler| HandlerDescription {
+            kind: handler.kind(),
+            cells: handler.cells().to_vec(),
+        })
+        .collect()
+}
+
 type Grid<VS> = Vec<VS>;
 
 struct Engine<VS: ValueSet> {
+    num_values: u32,
     started: bool,
     cell_order: Vec<CellIndex>,
     rec_stack: Vec<usize>,
-    grid_stack: Vec<Grid<VS>>,
-    full_cell: VS,
+    // The grid for the current frame. Each frame above the root is a level of
+    // the trail, which is unwound on backtrack.
+    grid: TrailGrid<VS>,
+    // The values allowed in each cell before any values are fixed.
+    initial_grid: Grid<VS>,
     handler_set: handlers::HandlerSet<VS>,
     cell_accumulator: CellAccumulator,
     backtrack_triggers: Vec<u32>,
+    // The number of times each handler has failed (plus one), for dom/wdeg.
+    handler_weights: Vec<u32>,
+    // With `Config::profile`, the index of each handler's kind in the profile.
+    handler_kinds: Vec<usize>,
+    nogoods: Option<NogoodStore>,
     progress_metadata: ProgressMetadata,
     progress_ratio_stack: Vec<f64>,
+    // Weights the progress by the sizes of the subtrees searched, when there
+    // is a progress or metrics callback.
+    progress_estimator: Option<ProgressEstimator>,
     counters: Counters,
     config: Config,
+    // Memory used by everything other than the grid (which grows).
+    base_memory: usize,
+    deadline: Option<Instant>,
+    next_checkpoint: Option<Instant>,
+    // Backtracks at which to restart the search.
+    next_restart: Option<u64>,
+    // Restarts since the fixed values were set.
+    num_restarts: u64,
+    // The grid after the initial propagation, which the search restarts from.
+    // The search removes values from the root frame, so it can't be used.
+    root_grid: Option<Grid<VS>>,
+    partial_assignment: FixedValues,
+    // With the `alloc-check` feature, whether to check that propagating during
+    // the search doesn't allocate. Wide value sets and custom propagators
+    // allocate, so they aren't checked.
+    #[cfg(feature = "alloc-check")]
+    check_allocations: bool,
+    // With `Config::batch_houses`, the handlers of the houses being enforced
+    // together.
+    #[cfg(feature = "batch-houses")]
+    house_batch: Vec<usize>,
+    // With the `logging` feature, the span the search's events are in.
+    #[cfg(feature = "logging")]
+    span: tracing::Span,
 }
 
 impl<VS: ValueSet> Engine<VS> {
@@ -64,54 +226,143 @@ impl<VS: ValueSet> Engine<VS> {
         assert!(constraint.shape.num_values <= VS::BITS as u32);
 
         let num_cells = constraint.shape.num_cells;
-        let handler_set = handlers::make_handlers(constraint);
-        let cell_accumulator = CellAccumulator::new(num_cells, &handler_set);
+        let handler_set = handlers::make_handlers(constraint, &config);
+        let cell_accumulator = CellAccumulator::new(num_cells, &handler_set, handler_set.tiers());
 
         let mut cell_order = (0..num_cells).collect::<Vec<_>>();
         if let Some(rng) = &mut config.search_randomizer {
             cell_order.shuffle(rng);
         };
 
-        let progress_metadata = ProgressMetadata::new(&mut config.progress_callback);
+        let progress_estimator = (config.progress_callback.is_some()
+            || config.metrics_callback.is_some())
+        .then(ProgressEstimator::default);
+        let progress_metadata = ProgressMetadata::new(&mut config);
 
         let mut new = Self {
+            num_values: constraint.shape.num_values,
             started: false,
             cell_order,
-            rec_stack: Vec::with_capacity(num_cells),
-            grid_stack: vec![vec![VS::empty(); num_cells]],
-            full_cell: VS::full(constraint.shape.num_values as ValueType),
-            handler_set,
+            // The stack has one entry for each guess, plus the next cell.
+            rec_stack: Vec::with_capacity(num_cells + 1),
+            grid: TrailGrid::new(num_cells, config.grid_strategy),
+            initial_grid: make_initial_grid(constraint),
             cell_accumulator,
             backtrack_triggers: vec![0; num_cells],
+            handler_weights: vec![1; handler_set.len()],
+            handler_kinds: if config.profile {
+                handler_set.iter().map(|h| h.kind_index()).collect()
+            } else {
+                Vec::new()
+            },
+            nogoods: config.nogoods.map(NogoodStore::new),
             progress_ratio_stack: vec![1.0; num_cells + 1],
-            counters: Counters::default(),
+            handler_set,
+            counters: Counters {
+                handler_profile: config.profile.then(HandlerProfile::default),
+                ..Counters::default()
+            },
             progress_metadata,
+            progress_estimator,
             config,
+            base_memory: 0,
+            deadline: None,
+            next_checkpoint: None,
+            next_restart: None,
+            num_restarts: 0,
+            root_grid: None,
+            partial_assignment: Vec::new(),
+            #[cfg(feature = "alloc-check")]
+            check_allocations: false,
+            #[cfg(feature = "batch-houses")]
+            house_batch: Vec::new(),
+            #[cfg(feature = "logging")]
+            span: tracing::debug_span!(
+                "search",
+                cells = num_cells,
+                values = constraint.shape.num_values
+            ),
         };
+        // Batches allocate while they are collected and searched.
+        #[cfg(feature = "alloc-check")]
+        {
+            new.check_allocations = VS::BITS as u32 <= MAX_FIXED_WIDTH_VALUES
+                && !new.config.batch_houses
+                && !new
+                    .handler_set
+                    .iter()
+                    .any(|h| matches!(h, handlers::ConstraintHandler::Custom(_)));
+        }
+        if new.config.profile || new.config.trace_callback.is_some() {
+            new.grid.count_removals();
+        }
+        new.grid.set_max_memory(new.max_grid_memory());
+        new.deadline = new.config.time_limit.map(|limit| Instant::now() + limit);
+        new.next_checkpoint = new
+            .config
+            .checkpoint_interval
+            .map(|interval| Instant::now() + interval);
+
+        new.base_memory = new.handler_set.memory_usage()
+            + new.cell_accumulator.memory_usage()
+            + super::vec_memory(&new.cell_order)
+            + super::vec_memory(&new.rec_stack)
+            + super::vec_memory(&new.backtrack_triggers)
+            + super::vec_memory(&new.handler_weights)
+            + super::vec_memory(&new.handler_kinds)
+            + super::vec_memory(&new.progress_ratio_stack);
+        new.update_peak_memory();
 
         new.reset_fixed_values(&constraint.fixed_values);
 
         new
     }
 
-    fn run(&mut self) -> Option<&Grid<VS>> {
+    fn run(&mut self) -> Option<&[VS]> {
         let mut new_cell_index = false;
         let mut progress_delta = 1.0;
         let num_cells = self.cell_order.len();
         let remember_guesses = self.config.output_type == OutputType::Guesses;
 
+        if self.stopped_early() {
+            return None;
+        }
+        #[cfg(feature = "logging")]
+        let _span = self.span.clone().entered();
+
         if !self.started {
+            // Report before starting, as the progress estimate treats a started
+            // search with nothing on the stack as finished.
+            self.report_progress();
             self.started = true;
 
-            self.progress_metadata.maybe_call(&self.counters);
-
             // Initialize by finding and running all handlers.
             for i in 0..num_cells {
                 self.cell_accumulator.add(i);
             }
-            if self.enforce_consistency().is_ok() {
+            let (constraints_processed, removals) =
+                (self.counters.constraints_processed, self.grid.removals());
+            let result = self.enforce_consistency();
+            self.trace(TraceEvent::Propagation {
+                depth: 0,
+                handlers: self.counters.constraints_processed - constraints_processed,
+                eliminations: self.grid.removals() - removals,
+                contradiction: result.is_err(),
+            });
+            #[cfg(feature = "logging")]
+            tracing::debug!(
+                handlers = self.handler_set.len(),
+                constraints_processed = self.counters.constraints_processed,
+                contradiction = result.is_err(),
+                "initial propagation finished"
+            );
+            if result.is_ok() {
                 // Only start the search if we successfully enforced constraints.
 
+                if self.config.restarts.is_some() {
+                    self.root_grid = Some(self.grid.to_vec());
+                }
+
                 // Handle the no guesses case - the initial enforce constraints round should have found everything.
                 let first_cell_index = if self.config.no_guesses {
                     if self.skip_fixed_cells(0) != num_cells {
@@ -126,11 +377,19 @@ impl<VS: ValueSet> Engine<VS> {
 
                 new_cell_index = true;
             }
-            self.progress_metadata.maybe_call(&self.counters);
+            self.report_progress();
         }
 
         while let Some(mut cell_index) = self.rec_stack.pop() {
             let grid_index = self.grid_index();
+            if self.grid.pop_to_level(grid_index) {
+                self.replay_decisions();
+            }
+            if !new_cell_index {
+                if let Some(estimator) = &mut self.progress_estimator {
+                    estimator.finish_guess(grid_index, self.counters.values_tried);
+                }
+            }
 
             // First time we've seen this cell (on this branch).
             if new_cell_index {
@@ -140,21 +399,36 @@ impl<VS: ValueSet> Engine<VS> {
                 // NOTE: We can't have zero values here, as they would have been
                 // rejected in the constraint propogation phase.
                 cell_index = self.skip_fixed_cells(cell_index);
+                if self.config.record_partial_assignment
+                    && cell_index > self.partial_assignment.len()
+                {
+                    self.record_partial_assignment(cell_index);
+                }
 
                 // We've reached the end, so output a solution!
                 if cell_index == num_cells {
+                    self.trace(TraceEvent::Solution { depth: grid_index });
+                    #[cfg(feature = "logging")]
+                    tracing::debug!(
+                        depth = grid_index,
+                        values_tried = self.counters.values_tried,
+                        "found a solution"
+                    );
                     self.counters.solutions += 1;
                     self.counters.progress_ratio += progress_delta;
-                    self.progress_metadata.maybe_call(&self.counters);
-                    return Some(&self.grid_stack[grid_index]);
+                    self.report_progress();
+                    return Some(&self.grid);
                 }
 
                 // Find the next cell to explore.
                 self.update_cell_order(cell_index);
 
                 // Update counters.
-                let count = self.grid_stack[grid_index][self.cell_order[cell_index]].count();
+                let count = self.grid[self.cell_order[cell_index]].count();
                 self.progress_ratio_stack[grid_index] = progress_delta / (count as f64);
+                if let Some(estimator) = &mut self.progress_estimator {
+                    estimator.start_cell(grid_index, count as u64);
+                }
                 self.counters.cells_searched += 1;
             }
             progress_delta = self.progress_ratio_stack[grid_index];
@@ -164,69 +438,343 @@ impl<VS: ValueSet> Engine<VS> {
 
             // We are trying a new value.
             self.counters.values_tried += 1;
+            let mut guess = None;
+
+            if remember_guesses || self.grid[cell].has_multiple() {
+                if self.check_limits() || self.check_paused() {
+                    // Leave the state as if we hadn't started on this cell, so
+                    // that the search can be resumed from a checkpoint.
+                    self.rec_stack.push(cell_index);
+                    #[cfg(feature = "logging")]
+                    tracing::debug!(
+                        limit_reached = self.counters.limit_reached,
+                        cancelled = self.counters.cancelled,
+                        guesses = self.counters.guesses,
+                        "search stopped early"
+                    );
+                    self.report_progress();
+                    return None;
+                }
+                self.maybe_checkpoint(cell_index);
+
+                if self.should_restart() {
+                    self.trace(TraceEvent::Restart);
+                    self.restart();
+                    new_cell_index = true;
+                    progress_delta = 1.0;
+                    continue;
+                }
 
-            if remember_guesses || self.grid_stack[grid_index][cell].has_multiple() {
                 // There are more values left, so push the current cell onto the
-                // stack and copy the grid to create a new stack frame.
+                // stack and start a new level of the trail for the new frame.
 
-                let v = self.grid_stack[grid_index][cell].pop().unwrap_or_default();
+                let v = self.choose_value(cell);
 
                 self.counters.guesses += 1;
-                self.progress_metadata
-                    .maybe_call_thottled(self.counters.constraints_processed, &self.counters);
+                if let Some(estimator) = &mut self.progress_estimator {
+                    estimator.guess(grid_index, self.counters.values_tried);
+                }
+                if self
+                    .progress_metadata
+                    .is_due(self.counters.constraints_processed)
+                {
+                    self.report_progress();
+                }
 
-                self.push_grid_onto_stack();
+                self.grid.push_level();
                 self.rec_stack.push(cell_index);
 
                 // Update the grid with the trial value.
-                self.grid_stack[grid_index + 1][cell] = VS::from_value(v);
+                self.grid.set_decision(cell, VS::from_value(v), true);
+                if ((self.base_memory + self.grid.memory_usage()) as u64)
+                    > self.counters.peak_memory
+                {
+                    self.update_peak_memory();
+                }
+                guess = Some(v);
+                self.trace(TraceEvent::Guess {
+                    depth: self.rec_stack.len(),
+                    cell,
+                    value: CellValue::from_index(v),
+                    progress: progress_delta,
+                });
+            } else if self.config.trace_callback.is_some() {
+                if let Some(v) = self.grid[cell].value() {
+                    self.trace(TraceEvent::LastValue {
+                        depth: self.rec_stack.len(),
+                        cell,
+                        value: CellValue::from_index(v),
+                        progress: progress_delta,
+                    });
+                }
             }
 
             // Propograte constraints.
+            let (constraints_processed, removals) =
+                (self.counters.constraints_processed, self.grid.removals());
             self.cell_accumulator.add(cell);
-            match self.enforce_consistency() {
+            #[cfg(feature = "alloc-check")]
+            let guard = self
+                .check_allocations
+                .then(super::alloc_check::NoAllocGuard::new);
+            let result = self.enforce_consistency_with_nogoods();
+            #[cfg(feature = "alloc-check")]
+            drop(guard);
+            self.trace(TraceEvent::Propagation {
+                depth: self.rec_stack.len(),
+                handlers: self.counters.constraints_processed - constraints_processed,
+                eliminations: self.grid.removals() - removals,
+                contradiction: result.is_err(),
+            });
+            match result {
                 Ok(()) => {
                     // Recurse to the new cell.
                     self.rec_stack.push(cell_index + 1);
                     new_cell_index = true;
                 }
-                Err(handlers::Contradition) => {
+                Err(handlers::Contradiction) => {
                     // Backtrack.
+                    self.trace(TraceEvent::Backtrack {
+                        depth: self.rec_stack.len(),
+                        cell,
+                    });
                     self.counters.progress_ratio += progress_delta;
                     self.record_backtrack(cell);
+                    self.maybe_record_nogood(guess);
                 }
             }
         }
 
+        #[cfg(feature = "logging")]
+        tracing::debug!(
+            solutions = self.counters.solutions,
+            guesses = self.counters.guesses,
+            backtracks = self.counters.backtracks,
+            "search finished"
+        );
         // Send the final set of progress counters.
-        self.progress_metadata.maybe_call(&self.counters);
+        self.report_progress();
 
         None
     }
 
+    // Check whether the search should stop, recording the reason in the
+    // counters.
+    #[inline]
+    fn check_limits(&mut self) -> bool {
+        // Only check the time occasionally, as guesses are frequent.
+        const TIME_CHECK_MASK: u64 = (1 << 8) - 1;
+
+        if let Some(max_guesses) = self.config.max_guesses {
+            if self.counters.guesses >= max_guesses {
+                self.counters.limit_reached = true;
+            }
+        }
+        if let Some(deadline) = self.deadline {
+            if self.counters.guesses & TIME_CHECK_MASK == 0 && Instant::now() >= deadline {
+                self.counters.limit_reached = true;
+            }
+        }
+        if let Some(token) = &self.config.cancellation {
+            if token.is_cancelled() {
+                self.counters.cancelled = true;
+            }
+        }
+        self.stopped_early()
+    }
+
+    // Send the counters to the progress callback, with the progress weighted
+    // by the sizes of the subtrees searched so far.
+    fn report_progress(&mut self) {
+        let counters = self.counters();
+        self.progress_metadata.maybe_call(&counters);
+    }
+
+    #[inline]
+    fn trace(&mut self, event: TraceEvent) {
+        super::maybe_call_callback(&mut self.config.trace_callback, &event);
+    }
+
+    #[inline]
+    fn check_paused(&self) -> bool {
+        self.config.pause.as_ref().is_some_and(|p| p.is_paused())
+    }
+
+    // The cells before `cell_index` in the cell order are all fixed.
+    fn record_partial_assignment(&mut self, cell_index: usize) {
+        self.partial_assignment = self.cell_order[..cell_index]
+            .iter()
+            .map(|&cell| (cell, CellValue::from_index(self.grid[cell].min().unwrap())))
+            .collect();
+    }
+
+    // Remove the next value to guess from the cell, and return it.
+    #[inline]
+    fn choose_value(&mut self, cell: CellIndex) -> ValueType {
+        let values = self.grid[cell].clone();
+        let v = match self.config.value_order {
+            ValueOrder::Min => values.min(),
+            ValueOrder::Max => values.max(),
+            ValueOrder::Random => {
+                let rng = self
+                    .config
+                    .search_randomizer
+                    .get_or_insert_with(|| RngType::seed_from_u64(0));
+                nth_value(&values, rng.gen_range(0..values.count().max(1)))
+            }
+            ValueOrder::LeastConstraining => {
+                let grid = &self.grid;
+                let peers = || {
+                    self.cell_accumulator
+                        .handlers(cell)
+                        .iter()
+                        .flat_map(|&h| self.handler_set[h].cells())
+                        .filter(|&&peer| peer != cell)
+                };
+                values.iter().min_by_key(|&v| {
+                    let v = VS::from_value(v);
+                    peers()
+                        .filter(|&&peer| !grid[peer].intersection(&v).is_empty())
+                        .count()
+                })
+            }
+        }
+        .unwrap_or_default();
+
+        self.grid
+            .set_decision(cell, values.without(&VS::from_value(v)), false);
+        v
+    }
+
+    // Rebuild the grid after the trail stopped recording levels, by setting
+    // the decisions again from the snapshot. The search propagated after each
+    // guess, and after each tried value left a cell with one value before it
+    // moved on to another cell, so the same is done here. This removes the
+    // same values as the first time, as the handlers remove everything they
+    // can from the values they are given.
+    fn replay_decisions(&mut self) {
+        for i in 0..self.grid.decisions().len() {
+            let decision = &self.grid.decisions()[i];
+            let (cell, values, guess) = (decision.cell, decision.values.clone(), decision.guess);
+            let moved_on = self
+                .grid
+                .decisions()
+                .get(i + 1)
+                .is_some_and(|next| next.cell != cell);
+            let propagate = guess || (moved_on && values.count() == 1);
+            self.grid.set(cell, values);
+            if propagate {
+                self.cell_accumulator.add(cell);
+                let result = self.enforce_consistency();
+                debug_assert!(result.is_ok(), "Replayed decisions should not fail");
+            }
+        }
+    }
+
+    fn max_grid_memory(&self) -> Option<usize> {
+        if self.config.nogoods.is_some() || self.config.early_exit_houses {
+            return None;
+        }
+        self.config.max_grid_memory
+    }
+
+    #[inline]
+    fn should_restart(&self) -> bool {
+        // Once there is a solution, we can't restart without finding it again.
+        self.root_grid.is_some()
+            && self.next_restart.is_some_and(|limit| {
+                self.counters.backtracks >= limit && self.counters.solutions == 0
+            })
+    }
+
+    // Start the search again from the root with a reshuffled cell order. The
+    // backtrack triggers are kept, so the new search still prefers the cells
+    // that caused trouble.
+    fn restart(&mut self) {
+        let rng = self
+            .config
+            .search_randomizer
+            .get_or_insert_with(|| RngType::seed_from_u64(0));
+        self.cell_order.shuffle(rng);
+
+        if let Some(root_grid) = &self.root_grid {
+            self.grid.reset(root_grid);
+        }
+        self.rec_stack.clear();
+        self.rec_stack.push(0);
+        self.counters.restarts += 1;
+        self.num_restarts += 1;
+        #[cfg(feature = "logging")]
+        tracing::debug!(
+            restarts = self.num_restarts,
+            backtracks = self.counters.backtracks,
+            "restarting the search"
+        );
+        self.counters.progress_ratio = 0.0;
+        if let Some(estimator) = &mut self.progress_estimator {
+            estimator.reset(self.counters.values_tried);
+        }
+        self.schedule_restart();
+    }
+
+    fn schedule_restart(&mut self) {
+        self.next_restart = self.config.restarts.map(|schedule| {
+            self.counters
+                .backtracks
+                .saturating_add(schedule.limit(self.num_restarts))
+        });
+    }
+
+    // Call the checkpoint callback if the checkpoint interval has passed.
+    #[inline]
+    fn maybe_checkpoint(&mut self, cell_index: usize) {
+        const TIME_CHECK_MASK: u64 = (1 << 8) - 1;
+
+        match self.next_checkpoint {
+            Some(next)
+                if self.counters.guesses & TIME_CHECK_MASK == 0 && Instant::now() >= next => {}
+            _ => return,
+        }
+
+        // The current cell has been popped, so put it back for the snapshot.
+        self.rec_stack.push(cell_index);
+        let checkpoint = self.checkpoint();
+        self.rec_stack.pop();
+
+        if let (Some(callback), Ok(checkpoint)) = (&mut self.config.checkpoint_callback, checkpoint)
+        {
+            callback(&checkpoint);
+        }
+        self.next_checkpoint = self
+            .config
+            .checkpoint_interval
+            .map(|interval| Instant::now() + interval);
+    }
+
     #[inline]
     fn grid_index(&self) -> usize {
         self.rec_stack.len()
     }
 
-    // Copy grid from self.grid_stack[grid_index] to self.grid_stack[grid_index+1].
-    fn push_grid_onto_stack(&mut self) {
-        let grid_index = self.grid_index();
-        if self.grid_stack.len() == grid_index + 1 {
-            // We've run out of space on the stack, so we need to push onto the
-            // end.
-            self.grid_stack.extend_from_within(grid_index..);
-        } else {
-            // Otherwise we copy over the existing elements.
-            let (grids_front, grids_back) = self.grid_stack.split_at_mut(grid_index + 1);
-            grids_back[0].copy_from_slice(&grids_front[grid_index]);
-        }
+    // The trail never shrinks its capacity, so the current usage is also the
+    // peak.
+    fn update_peak_memory(&mut self) {
+        let grid_memory = self.grid.memory_usage();
+        let nogood_memory = self.nogoods.as_ref().map_or(0, NogoodStore::memory_usage);
+        self.counters.peak_memory = self
+            .counters
+            .peak_memory
+            .max((self.base_memory + grid_memory + nogood_memory) as u64);
     }
 
     fn record_backtrack(&mut self, cell: CellIndex) {
         const BACKTRACK_DECAY_INTERVAL: u64 = 50;
         self.counters.backtracks += 1;
-        if 0 == self.counters.backtracks % BACKTRACK_DECAY_INTERVAL {
+        if self
+            .counters
+            .backtracks
+            .is_multiple_of(BACKTRACK_DECAY_INTERVAL)
+        {
             for bt in &mut self.backtrack_triggers {
                 *bt >>= 1;
             }
@@ -235,9 +783,8 @@ impl<VS: ValueSet> Engine<VS> {
     }
 
     fn skip_fixed_cells(&mut self, start_cell_index: usize) -> usize {
-        let grid_index = self.grid_index();
         let cell_order = &mut self.cell_order;
-        let grid = &mut self.grid_stack[grid_index];
+        let grid = &self.grid;
 
         let mut cell_index = start_cell_index;
         for i in start_cell_index..cell_order.len() {
@@ -255,21 +802,42 @@ impl<VS: ValueSet> Engine<VS> {
     // Find the best cell and bring it to the front. This means that it will
     // be processed next.
     fn update_cell_order(&mut self, cell_index: usize) {
-        let grid_index = self.grid_index();
         let cell_order = &mut self.cell_order;
-        let grid = &mut self.grid_stack[grid_index];
+        let grid = &self.grid;
+
+        let backtrack_triggers = &self.backtrack_triggers;
+        let handler_weights = &self.handler_weights;
+        let cell_accumulator = &self.cell_accumulator;
+        let ordering = self.config.cell_ordering;
 
         let (best_index, _) = cell_order
             .iter()
             .enumerate()
             .skip(cell_index)
-            .min_by_key(|(_, cell)| {
-                let count = grid[**cell].count() as u32;
-                let bt = self.backtrack_triggers[**cell];
-
-                #[allow(clippy::let_and_return)]
-                let score = if bt > 1 { count / bt } else { count };
-                score
+            .min_by_key(|(_, &cell)| {
+                let count = grid[cell].count() as u64;
+                match ordering {
+                    CellOrdering::MinValues => count,
+                    CellOrdering::BacktrackWeighted => {
+                        let bt = backtrack_triggers[cell] as u64;
+                        if bt > 1 {
+                            count / bt
+                        } else {
+                            count
+                        }
+                    }
+                    CellOrdering::DomWdeg => {
+                        // This counts all the cell's constraints, not just the
+                        // ones with other unfixed cells.
+                        let wdeg: u64 = cell_accumulator
+                            .handlers(cell)
+                            .iter()
+                            .map(|&h| handler_weights[h] as u64)
+                            .sum();
+                        (count << 32) / wdeg.max(1)
+                    }
+                    CellOrdering::Sequential => cell as u64,
+                }
             })
             .unwrap_or((0, &0));
 
@@ -277,44 +845,363 @@ impl<VS: ValueSet> Engine<VS> {
         cell_order.swap(best_index, cell_index);
     }
 
+    // Enforce the constraints on the root grid, and find the cell to branch on.
+    fn propagation_result(&mut self) -> Propagation {
+        if self.enforce_consistency().is_err() {
+            return Propagation::Contradiction;
+        }
+
+        let grid = &self.grid;
+        match (0..grid.len())
+            .filter(|&cell| grid[cell].has_multiple())
+            .min_by_key(|&cell| grid[cell].count())
+        {
+            None => Propagation::Solved,
+            Some(cell) => {
+                Propagation::Branch(cell, grid[cell].iter().map(CellValue::from_index).collect())
+            }
+        }
+    }
+
+    // Enforce the constraints, then the nogoods, until neither removes any
+    // more values.
+    fn enforce_consistency_with_nogoods(&mut self) -> handlers::Result {
+        loop {
+            self.enforce_consistency()?;
+
+            let Some(nogoods) = &mut self.nogoods else {
+                return Ok(());
+            };
+            match nogoods.propagate(&mut self.grid, &mut self.cell_accumulator) {
+                Ok(0) => return Ok(()),
+                Ok(num_removed) => self.counters.nogood_prunes += num_removed as u64,
+                Err(e) => {
+                    self.cell_accumulator.clear();
+                    self.counters.nogood_prunes += 1;
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    // Record the guesses on the stack as a nogood after a contradiction. The
+    // guesses are only known to lead to the contradiction while no solutions
+    // have been found: before that, every value removed by backtracking was
+    // removed because it had no solutions.
+    fn maybe_record_nogood(&mut self, guess: Option<ValueType>) {
+        let Some(nogoods) = &mut self.nogoods else {
+            return;
+        };
+        if self.counters.solutions > 0 || self.rec_stack.len() > nogoods.max_size() {
+            return;
+        }
+
+        let num_guesses = self.rec_stack.len();
+        let mut literals = Vec::with_capacity(num_guesses);
+        for (i, &cell_index) in self.rec_stack.iter().enumerate() {
+            let cell = self.cell_order[cell_index];
+            let value = match guess {
+                Some(v) if i + 1 == num_guesses => v,
+                // Earlier guesses are still fixed in the failed frame (unless
+                // the cell was emptied).
+                _ => match self.grid[cell].value() {
+                    Some(v) => v,
+                    None => return,
+                },
+            };
+            literals.push((cell, value));
+        }
+
+        nogoods.add(literals);
+        self.counters.nogoods_learned += 1;
+    }
+
     fn enforce_consistency(&mut self) -> handlers::Result {
-        let grid_index = self.grid_index();
-        let grid = &mut self.grid_stack[grid_index];
-        let cell_accumulator = &mut self.cell_accumulator;
+        while let Some(handler_index) = self.cell_accumulator.pop() {
+            #[cfg(feature = "batch-houses")]
+            if self.handler_set.can_batch(handler_index) {
+                self.enforce_house_batch(handler_index)?;
+                continue;
+            }
 
-        while let Some(handler_index) = cell_accumulator.pop() {
+            let grid = &mut self.grid;
+            let cell_accumulator = &mut self.cell_accumulator;
             cell_accumulator.hold(handler_index);
             self.counters.constraints_processed += 1;
-            self.handler_set
-                .run_handler(handler_index, grid, cell_accumulator)
-                .map_err(|e| {
-                    cell_accumulator.clear();
-                    e
-                })?;
+            let result = match &mut self.counters.handler_profile {
+                Some(profile) => {
+                    let stats = &mut profile.stats[self.handler_kinds[handler_index]];
+                    let (start, removals) = (Instant::now(), grid.removals());
+                    let result =
+                        self.handler_set
+                            .run_handler(handler_index, grid, cell_accumulator);
+                    stats.invocations += 1;
+                    stats.eliminations += grid.removals() - removals;
+                    stats.elapsed_secs += start.elapsed().as_secs_f64();
+                    result
+                }
+                None => self
+                    .handler_set
+                    .run_handler(handler_index, grid, cell_accumulator),
+            };
+            if let Err(e) = result {
+                cell_accumulator.clear();
+                self.handler_weights[handler_index] += 1;
+                return Err(e);
+            }
 
             cell_accumulator.clear_hold();
         }
 
         Ok(())
     }
+
+    // Enforce the house, along with the other houses waiting after it.
+    #[cfg(feature = "batch-houses")]
+    fn enforce_house_batch(&mut self, first_index: usize) -> handlers::Result {
+        let batch = &mut self.house_batch;
+        batch.clear();
+        batch.push(first_index);
+        while batch.len() < self.handler_set.max_batch_size() {
+            match self.cell_accumulator.peek() {
+                Some(index) if self.handler_set.can_batch(index) => {
+                    self.cell_accumulator.pop();
+                    batch.push(index);
+                }
+                _ => break,
+            }
+        }
+
+        self.counters.constraints_processed += batch.len() as u64;
+        let (start, removals) = (Instant::now(), self.grid.removals());
+        let result =
+            self.handler_set
+                .run_house_batch(batch, &mut self.grid, &mut self.cell_accumulator);
+        if let Some(profile) = &mut self.counters.handler_profile {
+            let stats = &mut profile.stats[self.handler_kinds[first_index]];
+            stats.invocations += batch.len() as u64;
+            stats.eliminations += self.grid.removals() - removals;
+            stats.elapsed_secs += start.elapsed().as_secs_f64();
+        }
+        if let Err(handler_index) = result {
+            self.cell_accumulator.clear();
+            self.handler_weights[handler_index] += 1;
+            return Err(handlers::Contradiction);
+        }
+        Ok(())
+    }
+}
+
+// The n-th smallest value in the set.
+#[inline]
+fn nth_value<VS: ValueSet>(values: &VS, n: usize) -> Option<ValueType> {
+    values.iter().nth(n)
+}
+
+fn make_initial_grid<VS: ValueSet>(constraint: &Constraint) -> Grid<VS> {
+    let mut grid =
+        vec![VS::full(constraint.shape.num_values as ValueType); constraint.shape.num_cells];
+    for (cell, values) in &constraint.candidates {
+        let mut allowed = VS::empty();
+        for value in values {
+            allowed.add_set(&VS::from_value(value.index()));
+        }
+        grid[*cell] = grid[*cell].intersection(&allowed);
+    }
+    grid
 }
 
 impl<VS: ValueSet> Runner for Engine<VS> {
     fn reset_fixed_values(&mut self, fixed_values: &FixedValues) {
         self.started = false;
         self.rec_stack.clear();
-        self.grid_stack[0].fill(self.full_cell);
+        self.root_grid = None;
+        self.grid.reset(&self.initial_grid);
         for (cell, value) in fixed_values {
-            self.grid_stack[0][*cell] = VS::from_value(value.index());
+            // A value which isn't a candidate leaves the cell empty, so the
+            // puzzle has no solutions.
+            let cell_values = self.grid[*cell].intersection(&VS::from_value(value.index()));
+            self.grid.set(*cell, cell_values);
         }
 
         // Both of these counters are confusing when aggregated.
         self.counters.progress_ratio = 0.0;
         self.counters.solutions = 0;
+        if let Some(estimator) = &mut self.progress_estimator {
+            estimator.reset(self.counters.values_tried);
+        }
+        self.num_restarts = 0;
+        self.partial_assignment.clear();
+        // Nogoods depend on the fixed values.
+        if let Some(nogoods) = &mut self.nogoods {
+            nogoods.clear();
+        }
+        self.schedule_restart();
+    }
+
+    fn stopped_early(&self) -> bool {
+        self.counters.limit_reached || self.counters.cancelled
+    }
+
+    fn checkpoint(&self) -> Result<Checkpoint, String> {
+        if self.grid.is_recomputing() {
+            return Err("The search can't be saved once it reaches the grid memory limit.".into());
+        }
+        let words_per_cell = (self.num_values as usize).div_ceil(64);
+        let to_words = |grid: &Vec<VS>| {
+            let mut words = vec![0; grid.len() * words_per_cell];
+            for (i, cell) in grid.iter().enumerate() {
+                for value in cell.iter() {
+                    let value = value as usize;
+                    words[i * words_per_cell + value / 64] |= 1 << (value % 64);
+                }
+            }
+            words
+        };
+
+        // Frames above the top of the recursion stack are no longer used.
+        let num_frames = self.rec_stack.len().max(1);
+        let mut frames = self.grid.level_snapshots();
+        frames.truncate(num_frames);
+
+        Ok(Checkpoint {
+            num_values: self.num_values,
+            started: self.started,
+            cell_order: self.cell_order.clone(),
+            rec_stack: self.rec_stack.clone(),
+            grid_stack: frames.iter().map(to_words).collect(),
+            backtrack_triggers: self.backtrack_triggers.clone(),
+            progress_ratio_stack: self.progress_ratio_stack.clone(),
+            counters: Counters {
+                limit_reached: false,
+                cancelled: false,
+                ..self.counters
+            },
+        })
+    }
+
+    fn restore(&mut self, checkpoint: &Checkpoint) -> Result<(), String> {
+        let num_cells = self.cell_order.len();
+        let words_per_cell = (self.num_values as usize).div_ceil(64);
+        let valid = checkpoint.num_values == self.num_values
+            && checkpoint.cell_order.len() == num_cells
+            && checkpoint.cell_order.iter().all(|&cell| cell < num_cells)
+            && checkpoint.rec_stack.iter().all(|&i| i <= num_cells)
+            && !checkpoint.grid_stack.is_empty()
+            && checkpoint.grid_stack.len() >= checkpoint.rec_stack.len()
+            && checkpoint
+                .grid_stack
+                .iter()
+                .all(|frame| frame.len() == num_cells * words_per_cell)
+            && checkpoint.backtrack_triggers.len() == num_cells
+            && checkpoint.progress_ratio_stack.len() == self.progress_ratio_stack.len();
+        if !valid {
+            return Err("Checkpoint does not match the puzzle.".to_string());
+        }
+
+        let from_words = |words: &[u64]| {
+            let mut cell = VS::empty();
+            for (i, word) in words.iter().enumerate() {
+                for bit in 0..64 {
+                    if word & (1 << bit) != 0 {
+                        cell.add_set(&VS::from_value((i * 64 + bit) as ValueType));
+                    }
+                }
+            }
+            cell
+        };
+
+        self.started = checkpoint.started;
+        self.cell_order.clone_from(&checkpoint.cell_order);
+        self.rec_stack.clone_from(&checkpoint.rec_stack);
+        // Replay the frames as levels of the trail. They must all be recorded,
+        // as there are no decisions to rebuild them from.
+        self.grid.set_max_memory(None);
+        for (i, frame) in checkpoint.grid_stack.iter().enumerate() {
+            let frame = frame
+                .chunks(words_per_cell)
+                .map(from_words)
+                .collect::<Vec<_>>();
+            if i == 0 {
+                self.grid.reset(&frame);
+                continue;
+            }
+            self.grid.push_level();
+            for (cell, values) in frame.iter().enumerate() {
+                if *values != self.grid[cell] {
+                    self.grid.set(cell, values.clone());
+                }
+            }
+        }
+        self.grid.set_max_memory(self.max_grid_memory());
+        self.backtrack_triggers
+            .clone_from(&checkpoint.backtrack_triggers);
+        self.progress_ratio_stack
+            .clone_from(&checkpoint.progress_ratio_stack);
+        // The profile is only kept if this search is profiled too.
+        self.counters = Counters {
+            handler_profile: self
+                .counters
+                .handler_profile
+                .map(|_| checkpoint.counters.handler_profile.unwrap_or_default()),
+            ..checkpoint.counters
+        };
+        // The root grid isn't saved, so a restored search can't restart.
+        self.root_grid = None;
+        if let Some(estimator) = &mut self.progress_estimator {
+            estimator.invalidate();
+        }
+        self.update_peak_memory();
+        #[cfg(feature = "logging")]
+        tracing::debug!(
+            depth = self.rec_stack.len(),
+            values_tried = self.counters.values_tried,
+            "restored a checkpoint"
+        );
+
+        Ok(())
+    }
+
+    fn propagate(&mut self) -> Propagation {
+        // Leave the search to start again from the fixed values.
+        self.started = false;
+        self.rec_stack.clear();
+
+        for i in 0..self.cell_order.len() {
+            self.cell_accumulator.add(i);
+        }
+        self.propagation_result()
+    }
+
+    fn assign(&mut self, cell: CellIndex, value: CellValue) -> Propagation {
+        let cell_values = self.grid[cell].intersection(&VS::from_value(value.index()));
+        self.grid.set(cell, cell_values);
+        self.cell_accumulator.add(cell);
+        self.propagation_result()
+    }
+
+    fn counters(&self) -> Counters {
+        let finished = self.started && self.rec_stack.is_empty();
+        let estimate = self
+            .progress_estimator
+            .as_ref()
+            .and_then(|e| e.estimate(self.rec_stack.len(), self.counters.values_tried, finished));
+        match estimate {
+            Some((ratio, lower, upper)) => Counters {
+                progress_ratio: ratio,
+                progress_bounds: Some((lower, upper)),
+                ..self.counters
+            },
+            None => self.counters,
+        }
+    }
+
+    fn partial_assignment(&self) -> FixedValues {
+        self.partial_assignment.clone()
     }
 
     fn next(&mut self) -> Option<Output> {
-        let grid_to_solution = |grid: &Grid<VS>| {
+        let grid_to_solution = |grid: &[VS]| {
             grid.iter()
                 .map(|vs| {
                     CellValue::from_index(
@@ -344,40 +1231,172 @@ impl<VS: ValueSet> Runner for Engine<VS> {
     }
 }
 
-struct ProgressMetadata {
+pub(super) struct ProgressMetadata {
     callback: Option<Box<ProgressCallback>>,
+    metrics: Option<MetricsMetadata>,
     frequency_mask: u64,
     next_check: u64,
+    start: Instant,
+    rates: RateEstimator,
+}
+
+// Sends snapshots of the counters to the metrics callback every interval. The
+// latest snapshot which wasn't sent is sent when the search is dropped, so the
+// last one has the final counters.
+struct MetricsMetadata {
+    callback: Box<ProgressCallback>,
+    interval: Duration,
+    next_call: Instant,
+    pending: Option<Counters>,
 }
 
 impl ProgressMetadata {
-    fn new(callback: &mut Option<Box<ProgressCallback>>) -> ProgressMetadata {
+    pub(super) fn new(config: &mut Config) -> ProgressMetadata {
         const LOG_UPDATE_FREQUENCY: u64 = 21;
         const UPDATE_FREQUENCY_MASK: u64 = (1 << LOG_UPDATE_FREQUENCY) - 1;
-        match callback {
-            None => ProgressMetadata {
-                callback: None,
-                frequency_mask: u64::MAX,
-                next_check: u64::MAX,
-            },
-            Some(_) => ProgressMetadata {
-                callback: callback.take(),
-                frequency_mask: UPDATE_FREQUENCY_MASK,
-                next_check: 0,
+        let metrics = config.metrics_callback.take().map(|callback| {
+            let interval = config.metrics_interval.unwrap_or(Duration::from_secs(1));
+            MetricsMetadata {
+                callback,
+                interval,
+                next_call: Instant::now() + interval,
+                pending: None,
+            }
+        });
+        let callback = config.progress_callback.take();
+        let enabled = callback.is_some() || metrics.is_some();
+        ProgressMetadata {
+            callback,
+            metrics,
+            frequency_mask: if enabled {
+                UPDATE_FREQUENCY_MASK
+            } else {
+                u64::MAX
             },
+            next_check: if enabled { 0 } else { u64::MAX },
+            start: Instant::now(),
+            rates: RateEstimator::default(),
+        }
+    }
+
+    #[inline]
+    pub(super) fn maybe_call_thottled(&mut self, progress_counter: u64, payload: &Counters) {
+        if self.is_due(progress_counter) {
+            self.maybe_call(payload);
         }
     }
 
+    // Whether the throttled callback should be called, for callers which
+    // build the counters only when it is.
     #[inline]
-    fn maybe_call_thottled(&mut self, progress_counter: u64, payload: &Counters) {
+    pub(super) fn is_due(&mut self, progress_counter: u64) -> bool {
         if progress_counter > self.next_check {
             self.next_check = progress_counter | self.frequency_mask;
-            self.maybe_call(payload);
+            return true;
         }
+        false
     }
 
     #[inline]
-    fn maybe_call(&mut self, payload: &Counters) {
-        super::maybe_call_callback(&mut self.callback, payload);
+    pub(super) fn maybe_call(&mut self, payload: &Counters) {
+        if self.callback.is_none() && self.metrics.is_none() {
+            return;
+        }
+
+        let elapsed_secs = self.start.elapsed().as_secs_f64();
+        self.rates.update(elapsed_secs, payload);
+        let counters = Counters {
+            elapsed_secs,
+            nodes_per_sec: self.rates.nodes_per_sec,
+            eta_secs: self.rates.eta_secs(payload.progress_ratio),
+            ..*payload
+        };
+        super::maybe_call_callback(&mut self.callback, &counters);
+
+        if let Some(metrics) = &mut self.metrics {
+            let now = Instant::now();
+            if now >= metrics.next_call {
+                (metrics.callback)(&counters);
+                metrics.next_call = now + metrics.interval;
+                metrics.pending = None;
+            } else {
+                metrics.pending = Some(counters);
+            }
+        }
+    }
+}
+
+impl Drop for ProgressMetadata {
+    fn drop(&mut self) {
+        if let Some(metrics) = &mut self.metrics {
+            if let Some(counters) = metrics.pending.take() {
+                (metrics.callback)(&counters);
+            }
+        }
+    }
+}
+
+// Smoothed estimates of the search rate and the rate of progress.
+#[derive(Default)]
+struct RateEstimator {
+    last_sample: Option<(f64, f64, u64, u64)>,
+    nodes_per_sec: f64,
+    progress_per_sec: f64,
+}
+
+impl RateEstimator {
+    // Weight given to the latest sample.
+    const SMOOTHING: f64 = 0.3;
+
+    fn update(&mut self, elapsed_secs: f64, counters: &Counters) {
+        let sample = (
+            elapsed_secs,
+            counters.progress_ratio,
+            counters.values_tried,
+            counters.restarts,
+        );
+        let Some((last_secs, last_ratio, last_nodes, last_restarts)) =
+            self.last_sample.replace(sample)
+        else {
+            return;
+        };
+
+        // The counters are reset when the solver is restarted. The weighted
+        // progress ratio can also go down without a restart, when the values
+        // left to try turn out to be larger than expected.
+        let ratio_reset =
+            counters.progress_ratio < last_ratio && counters.progress_bounds.is_none();
+        if ratio_reset || counters.restarts != last_restarts || counters.values_tried < last_nodes {
+            *self = RateEstimator {
+                last_sample: Some(sample),
+                ..RateEstimator::default()
+            };
+            return;
+        }
+
+        let dt = elapsed_secs - last_secs;
+        if dt <= 0.0 {
+            return;
+        }
+        let smooth = |rate: f64, sample: f64| {
+            if rate == 0.0 {
+                sample
+            } else {
+                Self::SMOOTHING * sample + (1.0 - Self::SMOOTHING) * rate
+            }
+        };
+        self.nodes_per_sec = smooth(
+            self.nodes_per_sec,
+            (counters.values_tried - last_nodes) as f64 / dt,
+        );
+        self.progress_per_sec = smooth(
+            self.progress_per_sec,
+            (counters.progress_ratio - last_ratio) / dt,
+        );
+    }
+
+    fn eta_secs(&self, progress_ratio: f64) -> Option<f64> {
+        (self.progress_per_sec > 0.0)
+            .then(|| (1.0 - progress_ratio).max(0.0) / self.progress_per_sec)
     }
 }
@@ -0,0 +1,134 @@
+use rand::Rng;
+use rand::SeedableRng;
+
+use crate::types::{Constraint, RngType};
+
+use super::engine::{self, Propagation};
+use super::{Config, EstimateProgressCallback, Instant};
+
+// The values are given as log10, as the counts for large grids don't fit in an
+// f64. A count of zero is negative infinity.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Estimate {
+    pub samples: u64,
+    // Mean of the sampled estimates of the number of solutions.
+    pub log10_solutions: f64,
+    pub log10_std_error: f64,
+    // Bounds of the 95% confidence interval.
+    pub log10_lower: f64,
+    pub log10_upper: f64,
+    // Samples which ended in a contradiction rather than a solution.
+    pub dead_ends: u64,
+    pub limit_reached: bool,
+    pub cancelled: bool,
+}
+
+// z-score for a 95% confidence interval.
+const CONFIDENCE_Z: f64 = 1.96;
+
+// Estimate the number of solutions with Knuth's method: follow random paths
+// down the search tree, and estimate the number of leaves as the inverse of the
+// probability of reaching each one. The mean of these estimates is unbiased.
+pub fn estimate(
+    constraint: &Constraint,
+    mut config: Config,
+    num_samples: u64,
+    mut progress_callback: Option<Box<EstimateProgressCallback>>,
+) -> Estimate {
+    let mut rng = config
+        .search_randomizer
+        .take()
+        .unwrap_or_else(RngType::from_entropy);
+    let deadline = config.time_limit.map(|limit| Instant::now() + limit);
+    let mut runner = engine::make_runner(constraint, Config::default());
+
+    let mut estimate = Estimate::default();
+    let mut sums = ScaledSums::default();
+
+    while estimate.samples < num_samples {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            estimate.limit_reached = true;
+        }
+        if let Some(token) = &config.cancellation {
+            estimate.cancelled = token.is_cancelled();
+        }
+        if estimate.limit_reached || estimate.cancelled {
+            break;
+        }
+
+        match sample_path(constraint, runner.as_mut(), &mut rng) {
+            Some(log_weight) => sums.add(log_weight),
+            None => estimate.dead_ends += 1,
+        }
+        estimate.samples += 1;
+        sums.update_estimate(&mut estimate);
+
+        super::maybe_call_callback(&mut progress_callback, &estimate);
+    }
+
+    estimate
+}
+
+// Sums of the samples and their squares, relative to the largest sample so far
+// so that they don't overflow.
+#[derive(Default)]
+struct ScaledSums {
+    // Natural log of the largest sample.
+    log_scale: f64,
+    sum: f64,
+    sum_squares: f64,
+}
+
+impl ScaledSums {
+    fn add(&mut self, log_weight: f64) {
+        if log_weight > self.log_scale {
+            let rescale = (self.log_scale - log_weight).exp();
+            self.sum *= rescale;
+            self.sum_squares *= rescale * rescale;
+            self.log_scale = log_weight;
+        }
+        let scaled = (log_weight - self.log_scale).exp();
+        self.sum += scaled;
+        self.sum_squares += scaled * scaled;
+    }
+
+    fn update_estimate(&self, estimate: &mut Estimate) {
+        let n = estimate.samples as f64;
+        let mean = self.sum / n;
+        let std_error = if estimate.samples > 1 {
+            let variance = (self.sum_squares - n * mean * mean).max(0.0) / (n - 1.0);
+            (variance / n).sqrt()
+        } else {
+            0.0
+        };
+
+        let to_log10 = |scaled: f64| (scaled.ln() + self.log_scale) / std::f64::consts::LN_10;
+        estimate.log10_solutions = to_log10(mean);
+        estimate.log10_std_error = to_log10(std_error);
+        estimate.log10_lower = to_log10((mean - CONFIDENCE_Z * std_error).max(0.0));
+        estimate.log10_upper = to_log10(mean + CONFIDENCE_Z * std_error);
+    }
+}
+
+// Follow one random path from the root, returning the natural log of the
+// product of the number of values at each branch (or None if the path reaches a
+// contradiction).
+fn sample_path(
+    constraint: &Constraint,
+    runner: &mut dyn engine::Runner,
+    rng: &mut RngType,
+) -> Option<f64> {
+    let mut log_weight = 0.0;
+    runner.reset_fixed_values(&constraint.fixed_values);
+    let mut propagation = runner.propagate();
+    loop {
+        match propagation {
+            Propagation::Contradiction => return None,
+            Propagation::Solved => return Some(log_weight),
+            Propagation::Branch(cell, values) => {
+                log_weight += (values.len() as f64).ln();
+                propagation = runner.assign(cell, values[rng.gen_range(0..values.len())]);
+            }
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use crate::types::{Constraint, FixedValues};
+
+use super::engine;
+use super::{CancellationToken, Config, Counters, Output, PauseToken};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SearchStatus {
+    Running,
+    Paused,
+    // The search found every solution, or was stopped.
+    Finished,
+}
+
+struct State {
+    status: SearchStatus,
+    counters: Counters,
+    partial_assignment: FixedValues,
+    // Outputs which haven't been taken yet.
+    outputs: Vec<Output>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+// A search running on its own thread, which can be paused and inspected while
+// it runs, e.g. to show the search state in a UI.
+pub struct SolverHandle {
+    shared: Arc<Shared>,
+    pause: PauseToken,
+    stop: CancellationToken,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl SolverHandle {
+    pub(super) fn new(constraint: &Constraint, config: Config) -> SolverHandle {
+        let stop = config.cancellation.clone().unwrap_or_default();
+        let pause = config.pause.clone().unwrap_or_default();
+        // The callbacks can't be sent to the search thread, so they aren't used.
+        let make_config = super::sendable_config(config);
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                status: SearchStatus::Running,
+                counters: Counters::default(),
+                partial_assignment: Vec::new(),
+                outputs: Vec::new(),
+            }),
+            changed: Condvar::new(),
+        });
+        let constraint = constraint.clone();
+        let thread = {
+            let (shared, pause, stop) = (shared.clone(), pause.clone(), stop.clone());
+            thread::spawn(move || {
+                let progress_shared = shared.clone();
+                let config = Config {
+                    progress_callback: Some(Box::new(move |counters: &Counters| {
+                        progress_shared.state.lock().unwrap().counters = *counters;
+                    })),
+                    cancellation: Some(stop.clone()),
+                    pause: Some(pause.clone()),
+                    record_partial_assignment: true,
+                    ..make_config()
+                };
+                run(&constraint, config, &shared, &pause, &stop);
+            })
+        };
+
+        SolverHandle {
+            shared,
+            pause,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn status(&self) -> SearchStatus {
+        self.shared.state.lock().unwrap().status
+    }
+
+    // The counters so far. These are updated as often as the progress callback
+    // would be called, and when the search pauses or finishes.
+    pub fn counters(&self) -> Counters {
+        self.shared.state.lock().unwrap().counters
+    }
+
+    // The values fixed at the deepest point of the search, as of the last pause
+    // (or the end of the search). Only the propagation engine records it.
+    pub fn partial_assignment(&self) -> FixedValues {
+        self.shared.state.lock().unwrap().partial_assignment.clone()
+    }
+
+    // The solutions (or other outputs) found since the last call.
+    pub fn take_outputs(&self) -> Vec<Output> {
+        std::mem::take(&mut self.shared.state.lock().unwrap().outputs)
+    }
+
+    // Pause the search, and wait until it has paused (or finished).
+    pub fn pause(&self) {
+        self.pause.pause();
+        self.wait_while(SearchStatus::Running);
+    }
+
+    pub fn resume(&self) {
+        self.pause.resume();
+        // Hold the lock so that the search thread can't miss the notification.
+        let _state = self.shared.state.lock().unwrap();
+        self.shared.changed.notify_all();
+    }
+
+    // Stop the search, keeping the outputs and counters so far.
+    pub fn stop(&self) {
+        self.stop.cancel();
+        self.resume();
+    }
+
+    // Wait for the search to finish, and return the final counters. A paused
+    // search must be resumed (or stopped) first.
+    pub fn join(mut self) -> Counters {
+        self.thread.take().unwrap().join().unwrap();
+        self.counters()
+    }
+
+    fn wait_while(&self, status: SearchStatus) {
+        let state = self.shared.state.lock().unwrap();
+        let _state = self
+            .shared
+            .changed
+            .wait_while(state, |state| state.status == status)
+            .unwrap();
+    }
+}
+
+impl Drop for SolverHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn run(
+    constraint: &Constraint,
+    config: Config,
+    shared: &Shared,
+    pause: &PauseToken,
+    stop: &CancellationToken,
+) {
+    let mut runner = engine::make_runner(constraint, config);
+    loop {
+        if let Some(output) = runner.next() {
+            if !matches!(output, Output::Empty) {
+                shared.state.lock().unwrap().outputs.push(output);
+            }
+            if !pause.is_paused() {
+                continue;
+            }
+        } else if !pause.is_paused() || runner.stopped_early() {
+            break;
+        }
+
+        let mut state = shared.state.lock().unwrap();
+        state.status = SearchStatus::Paused;
+        state.partial_assignment = runner.partial_assignment();
+        shared.changed.notify_all();
+        let mut state = shared
+            .changed
+            .wait_while(state, |_| pause.is_paused() && !stop.is_cancelled())
+            .unwrap();
+        state.status = SearchStatus::Running;
+    }
+
+    let mut state = shared.state.lock().unwrap();
+    state.status = SearchStatus::Finished;
+    state.partial_assignment = runner.partial_assignment();
+    shared.changed.notify_all();
+}
@@ -1,66 +1,350 @@
-use std::ops::Deref;
+use std::collections::HashSet;
+use std::iter::zip;
+use std::ops::{Deref, Range};
+use std::rc::Rc;
 
-use crate::types::{CellIndex, Constraint, Shape, ValueType};
+use crate::types::{
+    xv_negative_sums, CellIndex, CellValue, Constraint, KropkiKind, Shape, ValueType,
+    VariantConstraint,
+};
 use crate::value_set::ValueSet;
 
-use super::all_different::AllDifferentEnforcer;
+#[cfg(feature = "batch-houses")]
+use super::all_different::Edges;
+use super::all_different::{AllDifferentEnforcer, SccCache};
 use super::cell_accumulator::{CellAccumulator, CellContainer};
+use super::engine;
+use super::propagator::{GridDomains, Propagator};
+use super::trail::TrailGrid;
+use super::{Config, HandlerPriorities, PropagationLevel, HANDLER_KINDS};
 
-pub struct Contradition;
-pub type Result = std::result::Result<(), Contradition>;
+#[derive(Debug)]
+pub struct Contradiction;
+pub type Result = std::result::Result<(), Contradiction>;
 
 pub struct HouseHandler<VS> {
     cells: Vec<CellIndex>,
     all_values: VS,
     num_values: usize,
+    // Whether to run the full all-different algorithm, rather than just
+    // finding singles.
+    all_different: bool,
     candidate_matching: Vec<VS>,
+    // With `Config::incremental_scc`, the components found by the last
+    // all-different pass. Wider sets don't keep one, as the copy of the values
+    // would use too much memory.
+    scc_cache: Option<SccCache<VS>>,
+    // With `Config::gather_houses`, the values of the cells are copied here
+    // while the house is enforced, so that each pass reads them contiguously.
+    gather: bool,
+    values: Vec<VS>,
+    // With `Config::early_exit_houses`, the values after the last call, or
+    // empty if the last call failed.
+    last_values: Option<Vec<VS>>,
+}
+
+// With `Config::early_exit_houses`, the matching is skipped when at most this
+// many cells changed since the last call, unless a cell has at most
+// `FEW_CANDIDATES` values.
+const MAX_EARLY_EXIT_CHANGES: usize = 2;
+const FEW_CANDIDATES: usize = 2;
+
+enum Singles {
+    Solved,
+    Changed,
+    Unchanged,
 }
 
 impl<VS: ValueSet> HouseHandler<VS> {
-    pub fn new(cells: Vec<CellIndex>, shape: &Shape) -> Self {
+    pub fn new(cells: Vec<CellIndex>, shape: &Shape, all_different: bool, config: &Config) -> Self {
+        // Batches read the current values, as other houses change them.
+        let gather = config.gather_houses && !config.batch_houses;
+        let fixed_width = shape.num_values <= engine::MAX_FIXED_WIDTH_VALUES;
         Self {
+            values: if gather {
+                Vec::with_capacity(cells.len())
+            } else {
+                Vec::new()
+            },
+            gather,
             cells,
             num_values: shape.num_values as usize,
             all_values: VS::full(shape.num_values as ValueType),
-            candidate_matching: vec![VS::empty(); shape.num_values as usize],
+            all_different,
+            candidate_matching: if all_different {
+                vec![VS::empty(); shape.num_values as usize]
+            } else {
+                Vec::new()
+            },
+            scc_cache: (all_different && config.incremental_scc && fixed_width)
+                .then(SccCache::default),
+            last_values: (all_different && config.early_exit_houses && fixed_width).then(Vec::new),
         }
     }
 
     fn enforce_consistency(
         &mut self,
-        grid: &mut [VS],
+        grid: &mut TrailGrid<VS>,
+        cell_accumulator: &mut CellAccumulator,
+        all_diff_enforcer: &mut AllDifferentEnforcer<VS>,
+    ) -> Result {
+        let result = self.enforce_house(grid, cell_accumulator, all_diff_enforcer);
+        if self.last_values.is_some() {
+            self.save_values(grid, result.is_ok());
+        }
+        result
+    }
+
+    fn enforce_house(
+        &mut self,
+        grid: &mut TrailGrid<VS>,
         cell_accumulator: &mut CellAccumulator,
         all_diff_enforcer: &mut AllDifferentEnforcer<VS>,
     ) -> Result {
+        if !self.enforce_singles_first(grid, cell_accumulator)? {
+            return Ok(());
+        }
+        all_diff_enforcer.enforce_all_different(
+            grid,
+            &self.cells,
+            self.gather.then_some(&self.values[..]),
+            &mut self.candidate_matching,
+            self.scc_cache.as_mut(),
+            cell_accumulator,
+        )
+    }
+
+    // Find the singles, and return whether the all-different algorithm should
+    // be run as well.
+    fn enforce_singles_first(
+        &mut self,
+        grid: &mut TrailGrid<VS>,
+        cell_accumulator: &mut CellAccumulator,
+    ) -> std::result::Result<bool, Contradiction> {
+        if self.gather {
+            self.values.clear();
+            self.values
+                .extend(self.cells.iter().map(|&cell| grid[cell].clone()));
+        }
+
+        // When only a few cells changed since the last call, removing the values
+        // of the fixed cells is usually all that the matching would find.
+        let early_exit = self.few_cells_changed(grid);
+
+        // Singles are much cheaper to find than running the full all-different
+        // algorithm, and are often enough to fix the whole house.
+        loop {
+            match self.enforce_singles(grid, cell_accumulator)? {
+                Singles::Solved => return Ok(false),
+                // The all-different algorithm finds anything else.
+                _ if self.all_different && !early_exit => break,
+                Singles::Changed => continue,
+                Singles::Unchanged => break,
+            }
+        }
+        Ok(self.all_different && (!early_exit || self.has_few_candidates(grid)))
+    }
+
+    // With `Config::batch_houses`, the house is enforced in three steps, so
+    // that the all-different algorithm can be run for several houses at once.
+    // First the singles are found, and this returns whether the algorithm is
+    // needed.
+    #[cfg(feature = "batch-houses")]
+    fn start_batch(
+        &mut self,
+        grid: &mut TrailGrid<VS>,
+        cell_accumulator: &mut CellAccumulator,
+    ) -> std::result::Result<bool, Contradiction> {
+        let result = self.enforce_singles_first(grid, cell_accumulator);
+        if self.last_values.is_some() && !matches!(result, Ok(true)) {
+            self.save_values(grid, result.is_ok());
+        }
+        result
+    }
+
+    // Then the impossible values are found, without changing the grid. Other
+    // houses in the batch may have changed the cells since the first step, so
+    // the values are read again.
+    #[cfg(feature = "batch-houses")]
+    fn find_batch_edges(
+        &mut self,
+        grid: &[VS],
+        all_diff_enforcer: &mut AllDifferentEnforcer<VS>,
+    ) -> std::result::Result<Edges<VS>, Contradiction> {
+        let result = all_diff_enforcer.find_edges(
+            grid,
+            &self.cells,
+            None,
+            &mut self.candidate_matching,
+            self.scc_cache.as_mut(),
+        );
+        if self.last_values.is_some() && result.is_err() {
+            self.save_values(grid, false);
+        }
+        result
+    }
+
+    // Finally they are removed from the current values.
+    #[cfg(feature = "batch-houses")]
+    fn finish_batch(
+        &mut self,
+        grid: &mut TrailGrid<VS>,
+        all_diff_enforcer: &AllDifferentEnforcer<VS>,
+        edges: &Edges<VS>,
+        cell_accumulator: &mut CellAccumulator,
+    ) {
+        all_diff_enforcer.remove_edges(
+            grid,
+            &self.cells,
+            None,
+            edges,
+            self.scc_cache.as_mut(),
+            cell_accumulator,
+        );
+        if self.last_values.is_some() {
+            self.save_values(grid, true);
+        }
+    }
+
+    // Whether at most `MAX_EARLY_EXIT_CHANGES` cells changed since the last
+    // call, and only by removing values.
+    fn few_cells_changed(&self, grid: &[VS]) -> bool {
+        let Some(last_values) = &self.last_values else {
+            return false;
+        };
+        if last_values.len() != self.cells.len() {
+            return false;
+        }
+        let mut num_changed = 0;
+        for (i, old) in last_values.iter().enumerate() {
+            let v = self.value(grid, i);
+            if v != old {
+                if !v.without(old).is_empty() {
+                    return false;
+                }
+                num_changed += 1;
+                if num_changed > MAX_EARLY_EXIT_CHANGES {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    fn has_few_candidates(&self, grid: &[VS]) -> bool {
+        (0..self.cells.len()).any(|i| {
+            let v = self.value(grid, i);
+            v.has_multiple() && v.count() <= FEW_CANDIDATES
+        })
+    }
+
+    // Record the values for the next call. After a contradiction they are
+    // forgotten, so that the next call runs the matching.
+    fn save_values(&mut self, grid: &[VS], valid: bool) {
+        let mut last_values = self.last_values.take().unwrap_or_default();
+        last_values.clear();
+        if valid {
+            last_values.extend((0..self.cells.len()).map(|i| self.value(grid, i).clone()));
+        }
+        self.last_values = Some(last_values);
+    }
+
+    // The value of the `i`th cell of the house.
+    #[inline]
+    fn value<'a>(&'a self, grid: &'a [VS], i: usize) -> &'a VS {
+        if self.gather {
+            &self.values[i]
+        } else {
+            &grid[self.cells[i]]
+        }
+    }
+
+    // Remove the values of fixed cells from the other cells (naked singles),
+    // and fix values which only fit in one cell (hidden singles).
+    fn enforce_singles(
+        &mut self,
+        grid: &mut TrailGrid<VS>,
+        cell_accumulator: &mut CellAccumulator,
+    ) -> std::result::Result<Singles, Contradiction> {
         let mut all_values = VS::empty();
+        let mut repeated_values = VS::empty();
+        let mut fixed_values = VS::empty();
         // Counts the number of cells with only a single values.
         let mut num_fixed = 0;
 
-        for &cell in &self.cells {
-            let v = grid[cell];
-            all_values.add_set(&v);
+        for i in 0..self.cells.len() {
+            let v = self.value(grid, i);
+            repeated_values.add_set(&all_values.intersection(v));
+            all_values.add_set(v);
             // Assumes that no cells have zero values.
-            num_fixed += (!v.has_multiple()) as usize;
+            if !v.has_multiple() {
+                fixed_values.add_set(v);
+                num_fixed += 1;
+            }
         }
 
-        if all_values != self.all_values {
-            return Err(Contradition);
+        if all_values != self.all_values || fixed_values.count() != num_fixed {
+            return Err(Contradiction);
         }
         if num_fixed == self.num_values {
-            return Ok(());
+            return Ok(Singles::Solved);
         }
 
-        all_diff_enforcer.enforce_all_different(
-            grid,
-            &self.cells,
-            &mut self.candidate_matching,
-            cell_accumulator,
-        )
+        let naked_singles = repeated_values.intersection(&fixed_values);
+        let hidden_singles = all_values.without(&repeated_values).without(&fixed_values);
+        if naked_singles.is_empty() && hidden_singles.is_empty() {
+            return Ok(Singles::Unchanged);
+        }
+
+        for (i, &cell) in self.cells.iter().enumerate() {
+            let v = self.value(grid, i);
+            if !v.has_multiple() {
+                continue;
+            }
+            let hidden = v.intersection(&hidden_singles);
+            let is_hidden = !hidden.is_empty();
+            let new_v = if is_hidden {
+                hidden
+            } else {
+                v.without(&naked_singles)
+            };
+            if new_v.is_empty() || (is_hidden && new_v.has_multiple()) {
+                return Err(Contradiction);
+            }
+            if !new_v.has_multiple() {
+                fixed_values.add_set(&new_v);
+                num_fixed += 1;
+            }
+            if new_v != *v {
+                if self.gather {
+                    self.values[i] = new_v.clone();
+                }
+                grid.set(cell, new_v);
+                cell_accumulator.add(cell);
+            }
+        }
+
+        if num_fixed == self.num_values {
+            return if fixed_values == self.all_values {
+                Ok(Singles::Solved)
+            } else {
+                Err(Contradiction)
+            };
+        }
+        Ok(Singles::Changed)
     }
 
     fn cells(&self) -> &[CellIndex] {
         &self.cells
     }
+
+    fn memory_usage(&self) -> usize {
+        super::vec_memory(&self.cells)
+            + super::vec_memory(&self.candidate_matching)
+            + super::vec_memory(&self.values)
+            + self.scc_cache.as_ref().map_or(0, SccCache::memory_usage)
+            + self.last_values.as_ref().map_or(0, super::vec_memory)
+    }
 }
 
 pub struct SameValueHandler {
@@ -83,20 +367,18 @@ impl SameValueHandler {
 
     fn enforce_consistency<VS: ValueSet>(
         &self,
-        grid: &mut [VS],
+        grid: &mut TrailGrid<VS>,
         cell_accumulator: &mut CellAccumulator,
     ) -> Result {
         // Find the values in each cell set.
         let values0 = self
             .cells0
             .iter()
-            .map(|&c| grid[c])
-            .fold(VS::empty(), |a, b| a.union(&b));
+            .fold(VS::empty(), |a, &c| a.union(&grid[c]));
         let values1 = self
             .cells1
             .iter()
-            .map(|&c| grid[c])
-            .fold(VS::empty(), |a, b| a.union(&b));
+            .fold(VS::empty(), |a, &c| a.union(&grid[c]));
 
         if values0 == values1 {
             return Ok(());
@@ -106,8 +388,8 @@ impl SameValueHandler {
         let values = values0.intersection(&values1);
 
         // Check if we have enough values.
-        if (values.count() as usize) < self.cells0.len() {
-            return Err(Contradition);
+        if values.count() < self.cells0.len() {
+            return Err(Contradiction);
         }
 
         // Enforce the constrained value set.
@@ -122,7 +404,7 @@ impl SameValueHandler {
     }
 
     fn remove_extra_values<VS: ValueSet>(
-        grid: &mut [VS],
+        grid: &mut TrailGrid<VS>,
         allowed_values: &VS,
         cells: &[CellIndex],
         cell_accumulator: &mut CellAccumulator,
@@ -130,10 +412,10 @@ impl SameValueHandler {
         for &c0 in cells {
             let v = grid[c0].intersection(allowed_values);
             if v.is_empty() {
-                return Err(Contradition);
+                return Err(Contradiction);
             }
             if v != grid[c0] {
-                grid[c0] = v;
+                grid.set(c0, v);
                 cell_accumulator.add(c0);
             }
         }
@@ -143,151 +425,1784 @@ impl SameValueHandler {
     fn cells(&self) -> &[CellIndex] {
         &self.cells
     }
+
+    fn memory_usage(&self) -> usize {
+        super::vec_memory(&self.cells)
+            + super::vec_memory(&self.cells0)
+            + super::vec_memory(&self.cells1)
+    }
 }
 
-pub enum ConstraintHandler<VS> {
-    House(HouseHandler<VS>),
-    SameValue(SameValueHandler),
+// Enforces that the cells contain no repeated values, by removing fixed values
+// from the other cells. This is much cheaper than the full all-different
+// matching, so is used for small groups of cells (e.g. pairs).
+pub struct UniqueValuesHandler {
+    cells: Vec<CellIndex>,
 }
 
-impl<VS: ValueSet> CellContainer for ConstraintHandler<VS> {
+impl UniqueValuesHandler {
+    pub fn new(cells: Vec<CellIndex>) -> Self {
+        Self { cells }
+    }
+
+    fn enforce_consistency<VS: ValueSet>(
+        &self,
+        grid: &mut TrailGrid<VS>,
+        cell_accumulator: &mut CellAccumulator,
+    ) -> Result {
+        remove_fixed_values(grid, &self.cells, cell_accumulator)?;
+        Ok(())
+    }
+
     fn cells(&self) -> &[CellIndex] {
-        match self {
-            ConstraintHandler::House(h) => h.cells(),
-            ConstraintHandler::SameValue(h) => h.cells(),
-        }
+        &self.cells
+    }
+
+    fn memory_usage(&self) -> usize {
+        super::vec_memory(&self.cells)
     }
 }
 
-pub struct HandlerSet<VS: ValueSet> {
-    handlers: Vec<ConstraintHandler<VS>>,
-    all_diff_enforcer: AllDifferentEnforcer<VS>,
+// Enforces that the cells sum to a target, optionally with no repeated values.
+pub struct CageHandler {
+    cells: Vec<CellIndex>,
+    sum: i64,
+    unique: bool,
 }
 
-impl<VS: ValueSet> HandlerSet<VS> {
-    fn new(shape: &Shape) -> Self {
+impl CageHandler {
+    pub fn new(cells: Vec<CellIndex>, sum: u32, unique: bool) -> Self {
         Self {
-            handlers: Vec::new(),
-            all_diff_enforcer: AllDifferentEnforcer::new(shape.num_values),
+            cells,
+            sum: sum as i64,
+            unique,
         }
     }
 
-    pub fn run_handler(
-        &mut self,
-        index: usize,
-        grid: &mut [VS],
+    fn enforce_consistency<VS: ValueSet>(
+        &self,
+        grid: &mut TrailGrid<VS>,
         cell_accumulator: &mut CellAccumulator,
     ) -> Result {
-        match &mut self.handlers[index] {
-            ConstraintHandler::House(h) => {
-                h.enforce_consistency(grid, cell_accumulator, &mut self.all_diff_enforcer)
+        // Repeat until nothing changes, as each restriction can tighten the
+        // bounds of the others, and can fix cells whose values must then be
+        // removed from the rest of the cage.
+        let target = (self.sum, self.sum);
+        loop {
+            let mut changed = false;
+            if self.unique {
+                changed |= remove_fixed_values(grid, &self.cells, cell_accumulator)?;
+            }
+            changed |= restrict_sum(grid, &self.cells, target, cell_accumulator)?.0;
+            if !changed {
+                return Ok(());
             }
-            ConstraintHandler::SameValue(h) => h.enforce_consistency(grid, cell_accumulator),
         }
     }
-}
 
-impl<VS: ValueSet> Deref for HandlerSet<VS> {
-    type Target = [ConstraintHandler<VS>];
+    fn cells(&self) -> &[CellIndex] {
+        &self.cells
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.handlers
+    fn memory_usage(&self) -> usize {
+        super::vec_memory(&self.cells)
     }
 }
 
-fn make_houses(constraint: &Constraint) -> Vec<Vec<CellIndex>> {
-    let mut houses = Vec::new();
-    let shape = &constraint.shape;
-    let side_len = shape.side_len;
-    let box_size = shape.box_size;
+// Enforces that values strictly increase along the cells.
+pub struct ThermoHandler {
+    cells: Vec<CellIndex>,
+}
 
-    // Make rows.
-    for r in 0..side_len {
-        let f = |c| shape.make_cell_index(r, c);
-        houses.push((0..side_len).map(f).collect());
+impl ThermoHandler {
+    pub fn new(cells: Vec<CellIndex>) -> Self {
+        Self { cells }
     }
 
-    // Make columns.
-    for c in 0..side_len {
-        let f = |r| shape.make_cell_index(r, c);
-        houses.push((0..side_len).map(f).collect());
-    }
+    fn enforce_consistency<VS: ValueSet>(
+        &self,
+        grid: &mut TrailGrid<VS>,
+        cell_accumulator: &mut CellAccumulator,
+    ) -> Result {
+        // Push the minimum values up the thermometer.
+        let mut min = None;
+        for &cell in &self.cells {
+            if let Some(min) = min {
+                let allowed = grid[cell].values_above(min);
+                restrict_cell(grid, cell, &allowed, cell_accumulator)?;
+            }
+            min = Some(grid[cell].min().ok_or(Contradiction)?);
+        }
 
-    // Make boxes.
-    for b in 0..side_len {
-        let f = |i| {
-            let r = (b % box_size) * box_size + (i / box_size);
-            let c = (b / box_size) * box_size + (i % box_size);
-            shape.make_cell_index(r, c)
-        };
-        houses.push((0..side_len).map(f).collect());
+        // Then push the maximum values down.
+        let mut max = None;
+        for &cell in self.cells.iter().rev() {
+            if let Some(max) = max {
+                let allowed = grid[cell].values_below(max);
+                restrict_cell(grid, cell, &allowed, cell_accumulator)?;
+            }
+            max = Some(grid[cell].max().ok_or(Contradiction)?);
+        }
+
+        Ok(())
     }
 
-    if constraint.x_sudoku {
-        let f = |r| shape.make_cell_index(r, r);
-        houses.push((0..side_len).map(f).collect());
+    fn cells(&self) -> &[CellIndex] {
+        &self.cells
+    }
 
-        let f = |r| shape.make_cell_index(r, side_len - r - 1);
-        houses.push((0..side_len).map(f).collect());
+    fn memory_usage(&self) -> usize {
+        super::vec_memory(&self.cells)
     }
+}
 
-    houses
+// Enforces that the circle cell is equal to the sum of the arrow cells.
+pub struct ArrowHandler {
+    cells: Vec<CellIndex>,
 }
 
-fn array_intersection_size<T: PartialEq>(v0: &[T], v1: &[T]) -> usize {
-    v0.iter().filter(|e| v1.contains(e)).count()
+impl ArrowHandler {
+    // The circle is the first cell.
+    pub fn new(cells: Vec<CellIndex>) -> Self {
+        Self { cells }
+    }
+
+    fn enforce_consistency<VS: ValueSet>(
+        &self,
+        grid: &mut TrailGrid<VS>,
+        cell_accumulator: &mut CellAccumulator,
+    ) -> Result {
+        let (&circle, arrow) = self.cells.split_first().unwrap();
+
+        loop {
+            let circle_bounds = display_bounds(&grid[circle]).ok_or(Contradiction)?;
+            let (mut changed, (min_sum, max_sum)) =
+                restrict_sum(grid, arrow, circle_bounds, cell_accumulator)?;
+            let allowed = in_display_range(&grid[circle], min_sum, max_sum);
+            changed |= restrict_cell(grid, circle, &allowed, cell_accumulator)?;
+            if !changed {
+                return Ok(());
+            }
+        }
+    }
+
+    fn cells(&self) -> &[CellIndex] {
+        &self.cells
+    }
+
+    fn memory_usage(&self) -> usize {
+        super::vec_memory(&self.cells)
+    }
 }
 
-fn array_difference<T: PartialEq + Copy>(v0: &[T], v1: &[T]) -> Vec<T> {
-    v0.iter().filter(|e| !v1.contains(e)).copied().collect()
+// Enforces that the cells contain a run of consecutive values in any order.
+pub struct RenbanHandler {
+    cells: Vec<CellIndex>,
+    num_values: ValueType,
 }
 
-fn make_house_intersections<VS>(
-    houses: &[Vec<CellIndex>],
-    shape: &Shape,
-) -> Vec<ConstraintHandler<VS>> {
-    let box_size = shape.box_size as usize;
+impl RenbanHandler {
+    pub fn new(cells: Vec<CellIndex>, shape: &Shape) -> Self {
+        Self {
+            cells,
+            num_values: shape.num_values as ValueType,
+        }
+    }
 
-    let mut handlers = Vec::new();
+    fn enforce_consistency<VS: ValueSet>(
+        &self,
+        grid: &mut TrailGrid<VS>,
+        cell_accumulator: &mut CellAccumulator,
+    ) -> Result {
+        // Find every window of consecutive values which each cell can take a
+        // value from. Cells are restricted to the union of these windows.
+        // A window is identified by its smallest value, and a cell has a value
+        // in the window starting at `start` if it has `start + i` for some `i`
+        // less than the length.
+        // Repeat until nothing changes, as restricting the windows can fix
+        // cells whose values must then be removed from the other cells.
+        let len = self.cells.len() as i32;
+        let num_starts = (self.num_values as i32 - len + 1).max(0);
+        loop {
+            let mut changed = remove_fixed_values(grid, &self.cells, cell_accumulator)?;
+
+            let mut starts = VS::full(num_starts as ValueType);
+            for &cell in &self.cells {
+                let cell_starts =
+                    (0..len).fold(VS::empty(), |a, i| a.union(&grid[cell].shifted(-i)));
+                starts = starts.intersection(&cell_starts);
+            }
+            let allowed = (0..len).fold(VS::empty(), |a, i| a.union(&starts.shifted(i)));
 
-    for (i, h0) in houses.iter().enumerate() {
-        for h1 in houses.iter().skip(i + 1) {
-            if array_intersection_size(h0, h1) == box_size {
-                let handler =
-                    SameValueHandler::new(array_difference(h0, h1), array_difference(h1, h0));
-                handlers.push(ConstraintHandler::SameValue(handler));
+            for &cell in &self.cells {
+                changed |= restrict_cell(grid, cell, &allowed, cell_accumulator)?;
+            }
+            if !changed {
+                return Ok(());
             }
         }
     }
 
-    handlers
+    fn cells(&self) -> &[CellIndex] {
+        &self.cells
+    }
+
+    fn memory_usage(&self) -> usize {
+        super::vec_memory(&self.cells)
+    }
 }
 
-pub fn make_handlers<VS: ValueSet>(constraint: &Constraint) -> HandlerSet<VS> {
-    const MAX_SIZE_FOR_INTERSECTIONS: u32 = 100;
+// Enforces that pairs of cells have the same value, by keeping their candidates
+// identical.
+pub struct CloneHandler {
+    cells: Vec<CellIndex>,
+}
 
-    let shape = &constraint.shape;
+impl CloneHandler {
+    // The first half of the cells are paired with the second half.
+    pub fn new(cells: Vec<CellIndex>) -> Self {
+        Self { cells }
+    }
 
-    let mut handler_set = HandlerSet::new(shape);
+    fn enforce_consistency<VS: ValueSet>(
+        &self,
+        grid: &mut TrailGrid<VS>,
+        cell_accumulator: &mut CellAccumulator,
+    ) -> Result {
+        let (cells0, cells1) = self.cells.split_at(self.cells.len() / 2);
+        for (&cell0, &cell1) in cells0.iter().zip(cells1) {
+            let values = grid[cell0].intersection(&grid[cell1]);
+            restrict_cell(grid, cell0, &values, cell_accumulator)?;
+            restrict_cell(grid, cell1, &values, cell_accumulator)?;
+        }
+        Ok(())
+    }
 
-    let houses = make_houses(constraint);
+    fn cells(&self) -> &[CellIndex] {
+        &self.cells
+    }
 
-    // Don't create intersections if the grid is too large.
-    // The process below is quadratic, so it gets really slow. In addition,
-    // the only grids people create of this size are trivially solvable without
-    // backtracking.
-    let mut intersection_handlers = if shape.num_values <= MAX_SIZE_FOR_INTERSECTIONS {
-        make_house_intersections(&houses, shape)
-    } else {
-        Vec::new()
-    };
+    fn memory_usage(&self) -> usize {
+        super::vec_memory(&self.cells)
+    }
+}
 
-    for house in houses {
-        let handler = ConstraintHandler::House(HouseHandler::new(house, shape));
-        handler_set.handlers.push(handler);
+// Restricts the cells to only even or only odd values. Candidates only ever
+// shrink, so this only does any work the first time it is run.
+pub struct ParityHandler<VS> {
+    cells: Vec<CellIndex>,
+    allowed: VS,
+}
+
+impl<VS: ValueSet> ParityHandler<VS> {
+    pub fn new(cells: Vec<CellIndex>, even: bool, shape: &Shape) -> Self {
+        // Value indexes are offset by one from the display values.
+        let first = if even { 1 } else { 0 };
+        let allowed = (first..shape.num_values as ValueType)
+            .step_by(2)
+            .fold(VS::empty(), |a, v| a.union(&VS::from_value(v)));
+        Self { cells, allowed }
+    }
+
+    fn enforce_consistency(
+        &self,
+        grid: &mut TrailGrid<VS>,
+        cell_accumulator: &mut CellAccumulator,
+    ) -> Result {
+        for &cell in &self.cells {
+            restrict_cell(grid, cell, &self.allowed, cell_accumulator)?;
+        }
+        Ok(())
+    }
+
+    fn cells(&self) -> &[CellIndex] {
+        &self.cells
+    }
+
+    fn memory_usage(&self) -> usize {
+        super::vec_memory(&self.cells)
+    }
+}
+
+// Enforces that each value appears in the cells at least as many times as it is
+// listed.
+pub struct QuadrupleHandler {
+    cells: Vec<CellIndex>,
+    // Sorted, so that repeated values are adjacent.
+    values: Vec<ValueType>,
+}
+
+impl QuadrupleHandler {
+    pub fn new(cells: Vec<CellIndex>, values: &[CellValue]) -> Self {
+        let mut values = values.iter().map(|v| v.index()).collect::<Vec<_>>();
+        values.sort_unstable();
+        Self { cells, values }
+    }
+
+    fn enforce_consistency<VS: ValueSet>(
+        &self,
+        grid: &mut TrailGrid<VS>,
+        cell_accumulator: &mut CellAccumulator,
+    ) -> Result {
+        for repeats in self.values.chunk_by(|a, b| a == b) {
+            let value = VS::from_value(repeats[0]);
+            let num_possible = self
+                .cells
+                .iter()
+                .filter(|&&cell| !grid[cell].intersection(&value).is_empty())
+                .count();
+            if num_possible < repeats.len() {
+                return Err(Contradiction);
+            }
+            // The value must go in every cell which can take it.
+            if num_possible == repeats.len() {
+                for &cell in &self.cells {
+                    if !grid[cell].intersection(&value).is_empty() {
+                        restrict_cell(grid, cell, &value, cell_accumulator)?;
+                    }
+                }
+            }
+        }
+
+        // If every cell is accounted for, then cells can only take listed
+        // values.
+        if self.values.len() == self.cells.len() {
+            let allowed = self
+                .values
+                .iter()
+                .fold(VS::empty(), |a, &v| a.union(&VS::from_value(v)));
+            for &cell in &self.cells {
+                restrict_cell(grid, cell, &allowed, cell_accumulator)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn cells(&self) -> &[CellIndex] {
+        &self.cells
+    }
+
+    fn memory_usage(&self) -> usize {
+        super::vec_memory(&self.cells) + super::vec_memory(&self.values)
+    }
+}
+
+// Enforces that a value appears exactly `count` times in the cells.
+pub struct ValueCountHandler {
+    cells: Vec<CellIndex>,
+    value: ValueType,
+    count: usize,
+}
+
+impl ValueCountHandler {
+    pub fn new(cells: Vec<CellIndex>, value: CellValue, count: u32) -> Self {
+        Self {
+            cells,
+            value: value.index(),
+            count: count as usize,
+        }
+    }
+
+    fn enforce_consistency<VS: ValueSet>(
+        &self,
+        grid: &mut TrailGrid<VS>,
+        cell_accumulator: &mut CellAccumulator,
+    ) -> Result {
+        let value = VS::from_value(self.value);
+
+        let mut num_fixed = 0;
+        let mut num_possible = 0;
+        for &cell in &self.cells {
+            if !grid[cell].intersection(&value).is_empty() {
+                num_possible += 1;
+                num_fixed += (!grid[cell].has_multiple()) as usize;
+            }
+        }
+        if num_fixed > self.count || num_possible < self.count {
+            return Err(Contradiction);
+        }
+
+        if num_fixed == self.count {
+            // The value can't appear anywhere else.
+            for &cell in &self.cells {
+                if grid[cell].has_multiple() {
+                    let allowed = grid[cell].without(&value);
+                    restrict_cell(grid, cell, &allowed, cell_accumulator)?;
+                }
+            }
+        } else if num_possible == self.count {
+            // The value must appear everywhere it can.
+            for &cell in &self.cells {
+                if !grid[cell].intersection(&value).is_empty() {
+                    restrict_cell(grid, cell, &value, cell_accumulator)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn cells(&self) -> &[CellIndex] {
+        &self.cells
+    }
+
+    fn memory_usage(&self) -> usize {
+        super::vec_memory(&self.cells)
+    }
+}
+
+// Finds fish patterns (X-wings, swordfish, ...): if a value only fits in the
+// same N columns in each of N rows, then those rows take the value in each of
+// the columns, so it can be removed from the rest of the columns. The same
+// applies with rows and columns swapped.
+pub struct FishHandler<VS> {
+    cells: Vec<CellIndex>,
+    side_len: usize,
+    max_size: usize,
+    // For each base line (row or column), the cross lines where the value fits.
+    positions: Vec<VS>,
+    // The base lines which are candidates for a fish.
+    base_lines: Vec<usize>,
+    chosen: Vec<usize>,
+}
+
+impl<VS: ValueSet> FishHandler<VS> {
+    pub fn new(shape: &Shape, max_size: usize) -> Self {
+        let side_len = shape.side_len as usize;
+        Self {
+            cells: (0..shape.num_cells).collect(),
+            side_len,
+            max_size,
+            positions: vec![VS::empty(); side_len],
+            base_lines: Vec::with_capacity(side_len),
+            chosen: Vec::with_capacity(max_size),
+        }
+    }
+
+    fn enforce_consistency(
+        &mut self,
+        grid: &mut TrailGrid<VS>,
+        cell_accumulator: &mut CellAccumulator,
+    ) -> Result {
+        let side_len = self.side_len;
+        for value in 0..side_len as ValueType {
+            let value = VS::from_value(value);
+            let row_major = |base: usize, cross: usize| base * side_len + cross;
+            let column_major = |base: usize, cross: usize| cross * side_len + base;
+            self.find_fish(grid, cell_accumulator, &value, row_major)?;
+            self.find_fish(grid, cell_accumulator, &value, column_major)?;
+        }
+        Ok(())
+    }
+
+    // Find fish with base lines along `cell_index`'s first argument.
+    fn find_fish<F: Fn(usize, usize) -> CellIndex>(
+        &mut self,
+        grid: &mut TrailGrid<VS>,
+        cell_accumulator: &mut CellAccumulator,
+        value: &VS,
+        cell_index: F,
+    ) -> Result {
+        self.base_lines.clear();
+        for base in 0..self.side_len {
+            let mut positions = VS::empty();
+            for cross in 0..self.side_len {
+                if !grid[cell_index(base, cross)].intersection(value).is_empty() {
+                    positions.add_set(&VS::from_value(cross as ValueType));
+                }
+            }
+            // Lines where the value is fixed can't add anything which the
+            // houses don't already find.
+            if (2..=self.max_size).contains(&positions.count()) {
+                self.base_lines.push(base);
+            }
+            self.positions[base] = positions;
+        }
+
+        self.chosen.clear();
+        self.search_fish(grid, cell_accumulator, value, &cell_index, 0, VS::empty())
+    }
+
+    fn search_fish<F: Fn(usize, usize) -> CellIndex>(
+        &mut self,
+        grid: &mut TrailGrid<VS>,
+        cell_accumulator: &mut CellAccumulator,
+        value: &VS,
+        cell_index: &F,
+        start: usize,
+        cover: VS,
+    ) -> Result {
+        for i in start..self.base_lines.len() {
+            let base = self.base_lines[i];
+            let new_cover = cover.union(&self.positions[base]);
+            if new_cover.count() > self.max_size {
+                continue;
+            }
+            self.chosen.push(base);
+
+            if self.chosen.len() > 1 && new_cover.count() <= self.chosen.len() {
+                if new_cover.count() < self.chosen.len() {
+                    return Err(Contradiction);
+                }
+                self.remove_from_cover(grid, cell_accumulator, value, cell_index, &new_cover)?;
+            }
+            if self.chosen.len() < self.max_size {
+                self.search_fish(grid, cell_accumulator, value, cell_index, i + 1, new_cover)?;
+            }
+
+            self.chosen.pop();
+        }
+        Ok(())
+    }
+
+    // Remove the value from the cover lines, other than in the chosen base
+    // lines.
+    fn remove_from_cover<F: Fn(usize, usize) -> CellIndex>(
+        &mut self,
+        grid: &mut TrailGrid<VS>,
+        cell_accumulator: &mut CellAccumulator,
+        value: &VS,
+        cell_index: &F,
+        cover: &VS,
+    ) -> Result {
+        for base in 0..self.side_len {
+            if self.chosen.contains(&base) || self.positions[base].intersection(cover).is_empty() {
+                continue;
+            }
+            for cross in self.positions[base].intersection(cover).iter() {
+                let cell = cell_index(base, cross as usize);
+                let allowed = grid[cell].without(value);
+                restrict_cell(grid, cell, &allowed, cell_accumulator)?;
+            }
+            self.positions[base].remove_set(cover);
+        }
+        Ok(())
+    }
+
+    fn cells(&self) -> &[CellIndex] {
+        &self.cells
+    }
+
+    fn memory_usage(&self) -> usize {
+        super::vec_memory(&self.cells)
+            + super::vec_memory(&self.positions)
+            + super::vec_memory(&self.base_lines)
+            + super::vec_memory(&self.chosen)
+    }
+}
+
+// Enforces that the segments of the line within each box all have the same
+// sum.
+pub struct RegionSumHandler {
+    cells: Vec<CellIndex>,
+    // Ranges of `cells` for each segment.
+    segments: Vec<Range<usize>>,
+    num_values: ValueType,
+}
+
+impl RegionSumHandler {
+    pub fn new(cells: Vec<CellIndex>, shape: &Shape) -> Self {
+        Self {
+            segments: region_sum_segments(&cells, shape),
+            cells,
+            num_values: shape.num_values as ValueType,
+        }
+    }
+
+    fn enforce_consistency<VS: ValueSet>(
+        &self,
+        grid: &mut TrailGrid<VS>,
+        cell_accumulator: &mut CellAccumulator,
+    ) -> Result {
+        if self.segments.len() < 2 {
+            return Ok(());
+        }
+
+        // Restrict each segment to the sums which are possible for all the
+        // others, until neither the cells nor the shared range of sums change.
+        // A later segment can narrow the range without removing a value, so
+        // the earlier ones are checked again against it. Start from a range
+        // which contains every possible sum.
+        let mut target = (0, self.num_values as i64 * self.cells.len() as i64);
+        loop {
+            let old_target = target;
+            let mut changed = false;
+            for segment in &self.segments {
+                let (segment_changed, (min_sum, max_sum)) =
+                    restrict_sum(grid, &self.cells[segment.clone()], target, cell_accumulator)?;
+                changed |= segment_changed;
+                target = (target.0.max(min_sum), target.1.min(max_sum));
+            }
+            if !changed && target == old_target {
+                return Ok(());
+            }
+        }
+    }
+
+    fn cells(&self) -> &[CellIndex] {
+        &self.cells
+    }
+
+    fn memory_usage(&self) -> usize {
+        super::vec_memory(&self.cells) + super::vec_memory(&self.segments)
+    }
+}
+
+// Enforces that every window of `classes.len()` consecutive cells contains one
+// value from each class.
+// Cells which are a window length apart must be in the same class, so the line
+// is split into groups of cells which each take a different class.
+pub struct ClassLineHandler<VS> {
+    cells: Vec<CellIndex>,
+    classes: Vec<VS>,
+}
+
+// The classes available to a group are tracked as a bitmask.
+pub const MAX_LINE_CLASSES: usize = u64::BITS as usize;
+
+impl<VS: ValueSet> ClassLineHandler<VS> {
+    pub fn new(cells: Vec<CellIndex>, classes: Vec<VS>) -> Self {
+        assert!(classes.len() <= MAX_LINE_CLASSES);
+        Self { cells, classes }
+    }
+
+    fn enforce_consistency(
+        &self,
+        grid: &mut TrailGrid<VS>,
+        cell_accumulator: &mut CellAccumulator,
+    ) -> Result {
+        let num_classes = self.classes.len();
+        let num_groups = num_classes.min(self.cells.len());
+        let group = |g: usize| self.cells.iter().skip(g).step_by(num_classes);
+
+        // Find the classes which every cell in each group can take.
+        let mut group_classes = [0u64; MAX_LINE_CLASSES];
+        for (g, mask) in group_classes.iter_mut().enumerate().take(num_groups) {
+            for (i, class) in self.classes.iter().enumerate() {
+                if group(g).all(|&cell| !grid[cell].intersection(class).is_empty()) {
+                    *mask |= 1 << i;
+                }
+            }
+        }
+        let group_classes = &mut group_classes[..num_groups];
+
+        // Each group takes a different class.
+        loop {
+            let mut changed = false;
+            for g in 0..num_groups {
+                let mask = group_classes[g];
+                if mask == 0 {
+                    return Err(Contradiction);
+                }
+                if mask.count_ones() == 1 {
+                    for (other, other_mask) in group_classes.iter_mut().enumerate() {
+                        if other != g && *other_mask & mask != 0 {
+                            *other_mask &= !mask;
+                            changed = true;
+                        }
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        for (g, &mask) in group_classes.iter().enumerate() {
+            let allowed = self
+                .classes
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| mask & (1 << i) != 0)
+                .fold(VS::empty(), |a, (_, class)| a.union(class));
+            for &cell in group(g) {
+                restrict_cell(grid, cell, &allowed, cell_accumulator)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn cells(&self) -> &[CellIndex] {
+        &self.cells
+    }
+
+    fn memory_usage(&self) -> usize {
+        super::vec_memory(&self.cells) + super::vec_memory(&self.classes)
+    }
+}
+
+// The allowed value pairs of a relation between two cells.
+pub enum BinaryRelation<VS> {
+    // The compatible values of each value in either direction.
+    Table { forward: Vec<VS>, backward: Vec<VS> },
+    // The first value is less than the second.
+    LessThan,
+    // The values differ by at least this much.
+    MinDifference(ValueType),
+    // The values differ by exactly one.
+    Consecutive,
+}
+
+impl<VS: ValueSet> BinaryRelation<VS> {
+    // `pred` is called with display values.
+    pub fn new<F: Fn(i64, i64) -> bool>(shape: &Shape, pred: F) -> Self {
+        let num_values = shape.num_values as ValueType;
+        let mut forward = vec![VS::empty(); num_values as usize];
+        let mut backward = vec![VS::empty(); num_values as usize];
+        for i in 0..num_values {
+            for j in 0..num_values {
+                if pred(i as i64 + 1, j as i64 + 1) {
+                    forward[i as usize].add_set(&VS::from_value(j));
+                    backward[j as usize].add_set(&VS::from_value(i));
+                }
+            }
+        }
+        Self::Table { forward, backward }
+    }
+
+    // The values of `other` which are compatible with some value in `values`.
+    // `forward` is whether `values` belong to the first cell.
+    fn supported_values(&self, values: &VS, other: &VS, forward: bool) -> VS {
+        match self {
+            Self::Table {
+                forward: f,
+                backward: b,
+            } => {
+                let table = if forward { f } else { b };
+                values
+                    .iter()
+                    .fold(VS::empty(), |a, v| a.union(&table[v as usize]))
+            }
+            Self::LessThan if forward => match values.min() {
+                Some(min) => other.values_above(min),
+                None => VS::empty(),
+            },
+            Self::LessThan => match values.max() {
+                Some(max) => other.values_below(max),
+                None => VS::empty(),
+            },
+            Self::MinDifference(difference) => {
+                let (Some(min), Some(max)) = (values.min(), values.max()) else {
+                    return VS::empty();
+                };
+                // Values at most max - difference, or at least min + difference.
+                let below = match (max + 1).checked_sub(*difference) {
+                    Some(v) => other.values_below(v),
+                    None => VS::empty(),
+                };
+                let above = match min.checked_add(*difference) {
+                    Some(0) => other.clone(),
+                    Some(v) => other.values_above(v - 1),
+                    None => VS::empty(),
+                };
+                below.union(&above)
+            }
+            Self::Consecutive => other.intersection(&values.shifted(1).union(&values.shifted(-1))),
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        match self {
+            Self::Table { forward, backward } => {
+                super::vec_memory(forward) + super::vec_memory(backward)
+            }
+            _ => 0,
+        }
+    }
+}
+
+// Enforces a relation between a pair of cells.
+pub struct BinaryConstraintHandler<VS> {
+    cells: [CellIndex; 2],
+    relation: Rc<BinaryRelation<VS>>,
+}
+
+impl<VS: ValueSet> BinaryConstraintHandler<VS> {
+    pub fn new(cell0: CellIndex, cell1: CellIndex, relation: Rc<BinaryRelation<VS>>) -> Self {
+        Self {
+            cells: [cell0, cell1],
+            relation,
+        }
+    }
+
+    fn enforce_consistency(
+        &self,
+        grid: &mut TrailGrid<VS>,
+        cell_accumulator: &mut CellAccumulator,
+    ) -> Result {
+        let [cell0, cell1] = self.cells;
+
+        let allowed1 = self
+            .relation
+            .supported_values(&grid[cell0], &grid[cell1], true);
+        restrict_cell(grid, cell1, &allowed1, cell_accumulator)?;
+        let allowed0 = self
+            .relation
+            .supported_values(&grid[cell1], &grid[cell0], false);
+        restrict_cell(grid, cell0, &allowed0, cell_accumulator)?;
+
+        Ok(())
+    }
+
+    fn cells(&self) -> &[CellIndex] {
+        &self.cells
+    }
+}
+
+// Restrict each cell to the values which allow the sum of the cells to lie
+// within `target`, given the range of values in the other cells.
+// Returns whether any cell changed, and the range of possible sums before the
+// restriction.
+fn restrict_sum<VS: ValueSet>(
+    grid: &mut TrailGrid<VS>,
+    cells: &[CellIndex],
+    target: (i64, i64),
+    cell_accumulator: &mut CellAccumulator,
+) -> std::result::Result<(bool, (i64, i64)), Contradiction> {
+    let (min_target, max_target) = target;
+
+    let mut min_sum = 0;
+    let mut max_sum = 0;
+    for &cell in cells {
+        let (min, max) = display_bounds(&grid[cell]).ok_or(Contradiction)?;
+        min_sum += min;
+        max_sum += max;
+    }
+    if max_target < min_sum || min_target > max_sum {
+        return Err(Contradiction);
+    }
+
+    let mut changed = false;
+    for &cell in cells {
+        let (min, max) = display_bounds(&grid[cell]).ok_or(Contradiction)?;
+        let allowed = in_display_range(
+            &grid[cell],
+            min_target - (max_sum - max),
+            max_target - (min_sum - min),
+        );
+        changed |= restrict_cell(grid, cell, &allowed, cell_accumulator)?;
+    }
+
+    Ok((changed, (min_sum, max_sum)))
+}
+
+// Remove the values of fixed cells from the other cells, so that no values are
+// repeated. Returns whether any cell changed.
+fn remove_fixed_values<VS: ValueSet>(
+    grid: &mut TrailGrid<VS>,
+    cells: &[CellIndex],
+    cell_accumulator: &mut CellAccumulator,
+) -> std::result::Result<bool, Contradiction> {
+    let mut fixed_values = VS::empty();
+    let mut num_fixed = 0;
+    for &cell in cells {
+        if !grid[cell].has_multiple() {
+            fixed_values.add_set(&grid[cell]);
+            num_fixed += 1;
+        }
+    }
+    if fixed_values.count() != num_fixed {
+        return Err(Contradiction);
+    }
+    let mut changed = false;
+    if num_fixed > 0 {
+        for &cell in cells {
+            if grid[cell].has_multiple() {
+                let allowed = grid[cell].without(&fixed_values);
+                changed |= restrict_cell(grid, cell, &allowed, cell_accumulator)?;
+            }
+        }
+    }
+    Ok(changed)
+}
+
+// Intersect a cell with `allowed`, returning whether the cell changed.
+fn restrict_cell<VS: ValueSet>(
+    grid: &mut TrailGrid<VS>,
+    cell: CellIndex,
+    allowed: &VS,
+    cell_accumulator: &mut CellAccumulator,
+) -> std::result::Result<bool, Contradiction> {
+    let v = grid[cell].intersection(allowed);
+    if v.is_empty() {
+        return Err(Contradiction);
+    }
+    if v == grid[cell] {
+        return Ok(false);
+    }
+    grid.set(cell, v);
+    cell_accumulator.add(cell);
+    Ok(true)
+}
+
+// The min and max display values of a cell.
+fn display_bounds<VS: ValueSet>(values: &VS) -> Option<(i64, i64)> {
+    Some((values.min()? as i64 + 1, values.max()? as i64 + 1))
+}
+
+// The values with display values in the range [min, max].
+fn in_display_range<VS: ValueSet>(values: &VS, min: i64, max: i64) -> VS {
+    // The display value v has index v - 1, so these are the indexes above
+    // min - 2 and below max.
+    let below = match ValueType::try_from(max) {
+        Ok(max) => values.values_below(max),
+        Err(_) if max < 0 => return VS::empty(),
+        Err(_) => values.clone(),
+    };
+    match ValueType::try_from(min - 2) {
+        Ok(min) => below.values_above(min),
+        Err(_) if min < 2 => below,
+        Err(_) => VS::empty(),
+    }
+}
+
+pub enum ConstraintHandler<VS> {
+    House(HouseHandler<VS>),
+    SameValue(SameValueHandler),
+    UniqueValues(UniqueValuesHandler),
+    Cage(CageHandler),
+    Thermo(ThermoHandler),
+    Arrow(ArrowHandler),
+    Renban(RenbanHandler),
+    Clone(CloneHandler),
+    Parity(ParityHandler<VS>),
+    ClassLine(ClassLineHandler<VS>),
+    RegionSum(RegionSumHandler),
+    Quadruple(QuadrupleHandler),
+    ValueCount(ValueCountHandler),
+    Binary(BinaryConstraintHandler<VS>),
+    Fish(FishHandler<VS>),
+    // Handlers defined outside the solver.
+    Custom(Box<dyn Propagator>),
+}
+
+impl<VS> ConstraintHandler<VS> {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ConstraintHandler::House(_) => "house",
+            ConstraintHandler::SameValue(_) => "same-value",
+            ConstraintHandler::UniqueValues(_) => "unique-values",
+            ConstraintHandler::Cage(_) => "cage",
+            ConstraintHandler::Thermo(_) => "thermo",
+            ConstraintHandler::Arrow(_) => "arrow",
+            ConstraintHandler::Renban(_) => "renban",
+            ConstraintHandler::Clone(_) => "clone",
+            ConstraintHandler::Parity(_) => "parity",
+            ConstraintHandler::ClassLine(_) => "class-line",
+            ConstraintHandler::RegionSum(_) => "region-sum",
+            ConstraintHandler::Quadruple(_) => "quadruple",
+            ConstraintHandler::ValueCount(_) => "value-count",
+            ConstraintHandler::Binary(_) => "binary",
+            ConstraintHandler::Fish(_) => "fish",
+            ConstraintHandler::Custom(h) => h.kind(),
+        }
+    }
+
+    // The index of the kind in `HANDLER_KINDS`, or one past the end for custom
+    // propagators.
+    pub fn kind_index(&self) -> usize {
+        match self {
+            ConstraintHandler::Custom(_) => HANDLER_KINDS.len(),
+            _ => HANDLER_KINDS
+                .iter()
+                .position(|&kind| kind == self.kind())
+                .unwrap(),
+        }
+    }
+}
+
+impl<VS: ValueSet> CellContainer for ConstraintHandler<VS> {
+    fn cells(&self) -> &[CellIndex] {
+        match self {
+            ConstraintHandler::House(h) => h.cells(),
+            ConstraintHandler::SameValue(h) => h.cells(),
+            ConstraintHandler::UniqueValues(h) => h.cells(),
+            ConstraintHandler::Cage(h) => h.cells(),
+            ConstraintHandler::Thermo(h) => h.cells(),
+            ConstraintHandler::Arrow(h) => h.cells(),
+            ConstraintHandler::Renban(h) => h.cells(),
+            ConstraintHandler::Clone(h) => h.cells(),
+            ConstraintHandler::Parity(h) => h.cells(),
+            ConstraintHandler::ClassLine(h) => h.cells(),
+            ConstraintHandler::RegionSum(h) => h.cells(),
+            ConstraintHandler::Quadruple(h) => h.cells(),
+            ConstraintHandler::ValueCount(h) => h.cells(),
+            ConstraintHandler::Binary(h) => h.cells(),
+            ConstraintHandler::Fish(h) => h.cells(),
+            ConstraintHandler::Custom(h) => h.cells(),
+        }
+    }
+}
+
+pub struct HandlerSet<VS: ValueSet> {
+    handlers: Vec<ConstraintHandler<VS>>,
+    // The propagation tier of each handler. Handlers in lower tiers are run to
+    // a fixed point before any in higher tiers.
+    tiers: Vec<usize>,
+    all_diff_enforcer: AllDifferentEnforcer<VS>,
+    // With `Config::batch_houses`, an enforcer for each house in a batch.
+    #[cfg(feature = "batch-houses")]
+    batch_enforcers: Vec<AllDifferentEnforcer<VS>>,
+    relations: Vec<Rc<BinaryRelation<VS>>>,
+    num_values: u32,
+}
+
+// With `Config::batch_houses`, this many houses are searched at once for each
+// thread.
+#[cfg(feature = "batch-houses")]
+const HOUSES_PER_THREAD: usize = 4;
+
+impl<VS: ValueSet> HandlerSet<VS> {
+    fn new(shape: &Shape) -> Self {
+        Self {
+            handlers: Vec::new(),
+            tiers: Vec::new(),
+            all_diff_enforcer: AllDifferentEnforcer::new(shape.num_values),
+            #[cfg(feature = "batch-houses")]
+            batch_enforcers: Vec::new(),
+            relations: Vec::new(),
+            num_values: shape.num_values,
+        }
+    }
+
+    pub fn run_handler(
+        &mut self,
+        index: usize,
+        grid: &mut TrailGrid<VS>,
+        cell_accumulator: &mut CellAccumulator,
+    ) -> Result {
+        let result = match &mut self.handlers[index] {
+            ConstraintHandler::House(h) => {
+                h.enforce_consistency(grid, cell_accumulator, &mut self.all_diff_enforcer)
+            }
+            ConstraintHandler::SameValue(h) => h.enforce_consistency(grid, cell_accumulator),
+            ConstraintHandler::UniqueValues(h) => h.enforce_consistency(grid, cell_accumulator),
+            ConstraintHandler::Cage(h) => h.enforce_consistency(grid, cell_accumulator),
+            ConstraintHandler::Thermo(h) => h.enforce_consistency(grid, cell_accumulator),
+            ConstraintHandler::Arrow(h) => h.enforce_consistency(grid, cell_accumulator),
+            ConstraintHandler::Renban(h) => h.enforce_consistency(grid, cell_accumulator),
+            ConstraintHandler::Clone(h) => h.enforce_consistency(grid, cell_accumulator),
+            ConstraintHandler::Parity(h) => h.enforce_consistency(grid, cell_accumulator),
+            ConstraintHandler::ClassLine(h) => h.enforce_consistency(grid, cell_accumulator),
+            ConstraintHandler::RegionSum(h) => h.enforce_consistency(grid, cell_accumulator),
+            ConstraintHandler::Quadruple(h) => h.enforce_consistency(grid, cell_accumulator),
+            ConstraintHandler::ValueCount(h) => h.enforce_consistency(grid, cell_accumulator),
+            ConstraintHandler::Binary(h) => h.enforce_consistency(grid, cell_accumulator),
+            ConstraintHandler::Fish(h) => h.enforce_consistency(grid, cell_accumulator),
+            ConstraintHandler::Custom(h) => h.propagate(&mut GridDomains {
+                grid,
+                cell_accumulator,
+                num_values: self.num_values,
+            }),
+        };
+        #[cfg(feature = "logging")]
+        if result.is_err() {
+            tracing::trace!(
+                handler = index,
+                kind = self.handlers[index].kind(),
+                "handler found a contradiction"
+            );
+        }
+        result
+    }
+
+    #[cfg(feature = "batch-houses")]
+    fn enable_batches(&mut self) {
+        let batch_size = rayon::current_num_threads() * HOUSES_PER_THREAD;
+        self.batch_enforcers = (0..batch_size)
+            .map(|_| AllDifferentEnforcer::new(self.num_values))
+            .collect();
+    }
+
+    #[cfg(feature = "batch-houses")]
+    pub fn max_batch_size(&self) -> usize {
+        self.batch_enforcers.len()
+    }
+
+    // Whether the handler can be run in a batch by `run_house_batch`.
+    #[cfg(feature = "batch-houses")]
+    pub fn can_batch(&self, index: usize) -> bool {
+        !self.batch_enforcers.is_empty()
+            && matches!(&self.handlers[index], ConstraintHandler::House(h) if h.all_different)
+    }
+
+    // Enforce the houses, running the all-different algorithm for all of them
+    // in parallel on the same grid. After a contradiction, returns the index of
+    // the handler which found it.
+    #[cfg(feature = "batch-houses")]
+    pub fn run_house_batch(
+        &mut self,
+        batch: &[usize],
+        grid: &mut TrailGrid<VS>,
+        cell_accumulator: &mut CellAccumulator,
+    ) -> std::result::Result<(), usize> {
+        use rayon::prelude::*;
+
+        // The singles change the grid, so they are found one house at a time.
+        let mut searched = Vec::with_capacity(batch.len());
+        for &index in batch {
+            let ConstraintHandler::House(house) = &mut self.handlers[index] else {
+                unreachable!("Only houses are batched");
+            };
+            cell_accumulator.hold(index);
+            let result = house.start_batch(grid, cell_accumulator);
+            cell_accumulator.clear_hold();
+            match result {
+                Ok(true) => searched.push(index),
+                Ok(false) => {}
+                Err(Contradiction) => return Err(index),
+            }
+        }
+        searched.sort_unstable();
+
+        // Split the handlers to borrow each house separately.
+        let mut houses = Vec::with_capacity(searched.len());
+        let mut rest = &mut self.handlers[..];
+        let mut start = 0;
+        for &index in &searched {
+            let (handler, tail) = std::mem::take(&mut rest)[index - start..]
+                .split_first_mut()
+                .unwrap();
+            if let ConstraintHandler::House(house) = handler {
+                houses.push((index, house));
+            }
+            (rest, start) = (tail, index + 1);
+        }
+        // Handing the houses to the thread pool costs more than searching one.
+        let values: &[VS] = grid;
+        let find_edges = |((_, house), enforcer): (&mut (usize, &mut HouseHandler<VS>), _)| {
+            house.find_batch_edges(values, enforcer)
+        };
+        let results = if houses.len() > 1 && rayon::current_num_threads() > 1 {
+            houses
+                .par_iter_mut()
+                .zip(self.batch_enforcers.par_iter_mut())
+                .map(find_edges)
+                .collect::<Vec<_>>()
+        } else {
+            zip(houses.iter_mut(), self.batch_enforcers.iter_mut())
+                .map(find_edges)
+                .collect::<Vec<_>>()
+        };
+
+        // The other houses were searched without the values removed by each
+        // house, so they are queued again if it changes their cells.
+        for (((index, house), enforcer), result) in zip(zip(houses, &self.batch_enforcers), results)
+        {
+            let edges = result.map_err(|_| index)?;
+            cell_accumulator.hold(index);
+            house.finish_batch(grid, enforcer, &edges, cell_accumulator);
+            cell_accumulator.clear_hold();
+        }
+        Ok(())
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        let handlers_memory = self
+            .handlers
+            .iter()
+            .map(|handler| match handler {
+                ConstraintHandler::House(h) => h.memory_usage(),
+                ConstraintHandler::SameValue(h) => h.memory_usage(),
+                ConstraintHandler::UniqueValues(h) => h.memory_usage(),
+                ConstraintHandler::Cage(h) => h.memory_usage(),
+                ConstraintHandler::Thermo(h) => h.memory_usage(),
+                ConstraintHandler::Arrow(h) => h.memory_usage(),
+                ConstraintHandler::Renban(h) => h.memory_usage(),
+                ConstraintHandler::Clone(h) => h.memory_usage(),
+                ConstraintHandler::Parity(h) => h.memory_usage(),
+                ConstraintHandler::ClassLine(h) => h.memory_usage(),
+                ConstraintHandler::RegionSum(h) => h.memory_usage(),
+                ConstraintHandler::Quadruple(h) => h.memory_usage(),
+                ConstraintHandler::ValueCount(h) => h.memory_usage(),
+                ConstraintHandler::Fish(h) => h.memory_usage(),
+                // Relations are shared, so are counted separately.
+                ConstraintHandler::Binary(_) => 0,
+                // Not known.
+                ConstraintHandler::Custom(_) => 0,
+            })
+            .sum::<usize>();
+        let relations_memory = self
+            .relations
+            .iter()
+            .map(|r| r.memory_usage())
+            .sum::<usize>();
+        #[cfg(feature = "batch-houses")]
+        let batch_memory = self
+            .batch_enforcers
+            .iter()
+            .map(AllDifferentEnforcer::memory_usage)
+            .sum::<usize>();
+        #[cfg(not(feature = "batch-houses"))]
+        let batch_memory = 0;
+        super::vec_memory(&self.handlers)
+            + super::vec_memory(&self.tiers)
+            + handlers_memory
+            + relations_memory
+            + self.all_diff_enforcer.memory_usage()
+            + batch_memory
+    }
+
+    pub fn tiers(&self) -> &[usize] {
+        &self.tiers
+    }
+
+    fn push(&mut self, handler: ConstraintHandler<VS>) {
+        self.push_with_tier(handler, 0);
+    }
+
+    fn push_with_tier(&mut self, handler: ConstraintHandler<VS>, tier: usize) {
+        self.handlers.push(handler);
+        self.tiers.push(tier);
+    }
+
+    // Order the handlers within each tier by priority. The tiers are renumbered
+    // so that there are no unused ones.
+    fn apply_priorities(&mut self, priorities: &HandlerPriorities) {
+        let keys = zip(&self.handlers, &self.tiers)
+            .map(|(handler, &tier)| (tier, priorities.priority(handler.kind())))
+            .collect::<Vec<_>>();
+        let mut sorted_keys = keys.clone();
+        sorted_keys.sort_unstable();
+        sorted_keys.dedup();
+        for (tier, key) in zip(&mut self.tiers, &keys) {
+            *tier = sorted_keys.binary_search(key).unwrap();
+        }
+    }
+
+    fn add_relation(&mut self, relation: BinaryRelation<VS>) -> Rc<BinaryRelation<VS>> {
+        let relation = Rc::new(relation);
+        self.relations.push(relation.clone());
+        relation
+    }
+
+    // Add a handler for the relation between each pair of orthogonally adjacent
+    // cells which is not marked by one of the variant constraints.
+    fn add_negative_handlers<F: Fn(&VariantConstraint) -> bool>(
+        &mut self,
+        constraint: &Constraint,
+        is_marked: F,
+        relation: &Rc<BinaryRelation<VS>>,
+    ) {
+        let marked_pairs = constraint
+            .variants
+            .iter()
+            .filter(|v| is_marked(v))
+            .map(|v| ordered_pair(v.cells()[0], v.cells()[1]))
+            .collect::<HashSet<_>>();
+        for (cell0, cell1) in make_adjacent_pairs(&constraint.shape) {
+            if !marked_pairs.contains(&(cell0, cell1)) {
+                let handler = BinaryConstraintHandler::new(cell0, cell1, relation.clone());
+                self.push(ConstraintHandler::Binary(handler));
+            }
+        }
+    }
+
+    // Add a handler for the relation between each consecutive pair of cells.
+    fn add_line_handlers(&mut self, cells: &[CellIndex], relation: &Rc<BinaryRelation<VS>>) {
+        for pair in cells.windows(2) {
+            let handler = BinaryConstraintHandler::new(pair[0], pair[1], relation.clone());
+            self.push(ConstraintHandler::Binary(handler));
+        }
+    }
+}
+
+impl<VS: ValueSet> Deref for HandlerSet<VS> {
+    type Target = [ConstraintHandler<VS>];
+
+    fn deref(&self) -> &Self::Target {
+        &self.handlers
+    }
+}
+
+pub(super) fn make_houses(constraint: &Constraint) -> Vec<Vec<CellIndex>> {
+    let mut houses = Vec::new();
+    let shape = &constraint.shape;
+    let side_len = shape.side_len;
+    let box_size = shape.box_size;
+
+    // Make rows.
+    for r in 0..side_len {
+        let f = |c| shape.make_cell_index(r, c);
+        houses.push((0..side_len).map(f).collect());
+    }
+
+    // Make columns.
+    for c in 0..side_len {
+        let f = |r| shape.make_cell_index(r, c);
+        houses.push((0..side_len).map(f).collect());
+    }
+
+    // Make boxes.
+    if let Some(box_size) = box_size {
+        for b in 0..side_len {
+            let f = |i| {
+                let r = (b % box_size) * box_size + (i / box_size);
+                let c = (b / box_size) * box_size + (i % box_size);
+                shape.make_cell_index(r, c)
+            };
+            houses.push((0..side_len).map(f).collect());
+        }
+    }
+
+    if constraint.x_sudoku || constraint.main_diagonal {
+        let f = |r| shape.make_cell_index(r, r);
+        houses.push((0..side_len).map(f).collect());
+    }
+
+    if constraint.x_sudoku || constraint.anti_diagonal {
+        let f = |r| shape.make_cell_index(r, side_len - r - 1);
+        houses.push((0..side_len).map(f).collect());
+    }
+
+    if let (true, Some(box_size)) = (constraint.windoku, box_size) {
+        // Windows are separated from each other and the edge of the grid by a
+        // single row/column. This gives 4 windows for 9x9 grids.
+        let offsets = (0..box_size.saturating_sub(1))
+            .map(|i| 1 + i * (box_size + 1))
+            .collect::<Vec<_>>();
+        for &r0 in &offsets {
+            for &c0 in &offsets {
+                let f = |i| shape.make_cell_index(r0 + i / box_size, c0 + i % box_size);
+                houses.push((0..side_len).map(f).collect());
+            }
+        }
+    }
+
+    // Regions which contain every value are treated like any other house.
+    for variant in &constraint.variants {
+        if let VariantConstraint::Region { cells } = variant {
+            if cells.len() == side_len as usize {
+                houses.push(cells.clone());
+            }
+        }
+    }
+
+    houses
+}
+
+// Split the values into low, medium and high bands of (close to) equal size.
+pub(super) fn make_entropic_bands<VS: ValueSet>(shape: &Shape) -> Vec<VS> {
+    const NUM_BANDS: usize = 3;
+    let num_values = shape.num_values as usize;
+    let mut bands = vec![VS::empty(); NUM_BANDS];
+    for v in 0..num_values {
+        bands[v * NUM_BANDS / num_values].add_set(&VS::from_value(v as ValueType));
+    }
+    bands
+}
+
+// The values in each residue class mod `modulus`.
+pub(super) fn make_residue_classes<VS: ValueSet>(modulus: u32, shape: &Shape) -> Vec<VS> {
+    let mut classes = vec![VS::empty(); modulus as usize];
+    for v in 0..shape.num_values {
+        // Value indexes are offset by one from the display values.
+        classes[((v + 1) % modulus) as usize].add_set(&VS::from_value(v as ValueType));
+    }
+    classes
+}
+
+// Ranges of the cells of a region sum line in each box. A new segment starts
+// whenever the line crosses into a different box.
+pub(super) fn region_sum_segments(cells: &[CellIndex], shape: &Shape) -> Vec<Range<usize>> {
+    let box_size = shape.box_size.unwrap_or(shape.side_len) as usize;
+    let side_len = shape.side_len as usize;
+    let box_of = |cell: CellIndex| (cell / side_len / box_size, cell % side_len / box_size);
+
+    let mut segments = Vec::new();
+    let mut start = 0;
+    for i in 1..=cells.len() {
+        if i == cells.len() || box_of(cells[i]) != box_of(cells[start]) {
+            segments.push(start..i);
+            start = i;
+        }
+    }
+    segments
+}
+
+pub(super) fn kropki_relation(kind: KropkiKind, a: i64, b: i64) -> bool {
+    match kind {
+        KropkiKind::White => (a - b).abs() == 1,
+        KropkiKind::Black => a == 2 * b || b == 2 * a,
+    }
+}
+
+pub(super) fn ordered_pair(cell0: CellIndex, cell1: CellIndex) -> (CellIndex, CellIndex) {
+    (cell0.min(cell1), cell0.max(cell1))
+}
+
+// All pairs of orthogonally adjacent cells, with the lower index first.
+pub(super) fn make_adjacent_pairs(shape: &Shape) -> Vec<(CellIndex, CellIndex)> {
+    let side_len = shape.side_len;
+    let mut pairs = Vec::new();
+    for r in 0..side_len {
+        for c in 0..side_len {
+            let cell = shape.make_cell_index(r, c);
+            if c + 1 < side_len {
+                pairs.push((cell, shape.make_cell_index(r, c + 1)));
+            }
+            if r + 1 < side_len {
+                pairs.push((cell, shape.make_cell_index(r + 1, c)));
+            }
+        }
+    }
+    pairs
+}
+
+// Diagonals offset from the main diagonals by 1, box_size + 1, etc. These are
+// the 8 argyle lines on a 9x9 grid.
+pub(super) fn make_argyle_lines(shape: &Shape) -> Vec<Vec<CellIndex>> {
+    let side_len = shape.side_len;
+    let box_size = match shape.box_size {
+        Some(box_size) => box_size,
+        None => return Vec::new(),
+    };
+
+    let mut lines = Vec::new();
+    for offset in (0..box_size.saturating_sub(1)).map(|i| 1 + i * box_size) {
+        let len = side_len - offset;
+        let f = |i| shape.make_cell_index(i, i + offset);
+        lines.push((0..len).map(f).collect());
+        let f = |i| shape.make_cell_index(i + offset, i);
+        lines.push((0..len).map(f).collect());
+        let f = |i| shape.make_cell_index(i, side_len - 1 - offset - i);
+        lines.push((0..len).map(f).collect());
+        let f = |i| shape.make_cell_index(i + offset, side_len - 1 - i);
+        lines.push((0..len).map(f).collect());
+    }
+
+    lines
+}
+
+// Pairs of diagonally touching cells. Pairs within the same box are skipped, as
+// they are already handled by the box constraint.
+pub(super) fn make_anti_king_pairs(shape: &Shape) -> Vec<Vec<CellIndex>> {
+    let side_len = shape.side_len;
+    let same_box = |r0: u32, c0: u32, r1: u32, c1: u32| match shape.box_size {
+        Some(box_size) => r0 / box_size == r1 / box_size && c0 / box_size == c1 / box_size,
+        None => false,
+    };
+
+    let mut pairs = Vec::new();
+    for r in 0..side_len - 1 {
+        for c in 0..side_len {
+            if c > 0 && !same_box(r, c, r + 1, c - 1) {
+                pairs.push(vec![
+                    shape.make_cell_index(r, c),
+                    shape.make_cell_index(r + 1, c - 1),
+                ]);
+            }
+            if c < side_len - 1 && !same_box(r, c, r + 1, c + 1) {
+                pairs.push(vec![
+                    shape.make_cell_index(r, c),
+                    shape.make_cell_index(r + 1, c + 1),
+                ]);
+            }
+        }
+    }
+
+    pairs
+}
+
+// Handlers for pairs of houses which overlap in a line of a box (pointing pairs
+// and box-line reduction). The values in the rest of one house must be the same
+// as the values in the rest of the other.
+fn make_house_intersections<VS>(
+    houses: &[Vec<CellIndex>],
+    shape: &Shape,
+) -> Vec<ConstraintHandler<VS>> {
+    let mut handlers = Vec::new();
+
+    // Latin squares have no boxes to intersect with.
+    let box_size = match shape.box_size {
+        Some(box_size) => box_size as usize,
+        None => return handlers,
+    };
+    let side_len = shape.side_len as usize;
+
+    let mut in_house = vec![false; shape.num_cells];
+    let mut add_intersection = |h0: &[CellIndex], h1: &[CellIndex]| {
+        for &cell in h1 {
+            in_house[cell] = true;
+        }
+        let cells0 = h0
+            .iter()
+            .filter(|&&cell| !in_house[cell])
+            .copied()
+            .collect::<Vec<_>>();
+        for &cell in h1 {
+            in_house[cell] = false;
+        }
+        if h0.len() - cells0.len() != box_size {
+            return;
+        }
+
+        for &cell in h0 {
+            in_house[cell] = true;
+        }
+        let cells1 = h1
+            .iter()
+            .filter(|&&cell| !in_house[cell])
+            .copied()
+            .collect::<Vec<_>>();
+        for &cell in h0 {
+            in_house[cell] = false;
+        }
+
+        let handler = SameValueHandler::new(cells0, cells1);
+        handlers.push(ConstraintHandler::SameValue(handler));
+    };
+
+    // The rows, columns and boxes are the first houses, so only the boxes which
+    // cross each row and column need to be checked.
+    let (rows, rest) = houses.split_at(side_len);
+    let (columns, rest) = rest.split_at(side_len);
+    let (boxes, others) = rest.split_at(side_len);
+    for (r, row) in rows.iter().enumerate() {
+        for k in 0..box_size {
+            add_intersection(row, &boxes[r / box_size + k * box_size]);
+        }
+    }
+    for (c, column) in columns.iter().enumerate() {
+        for k in 0..box_size {
+            add_intersection(column, &boxes[(c / box_size) * box_size + k]);
+        }
+    }
+
+    // Other houses (diagonals, windows and regions) can overlap anything.
+    for (i, h1) in others.iter().enumerate() {
+        for h0 in &houses[..3 * side_len + i] {
+            add_intersection(h0, h1);
+        }
+    }
+
+    handlers
+}
+
+pub fn make_handlers<VS: ValueSet>(constraint: &Constraint, config: &Config) -> HandlerSet<VS> {
+    const MAX_SIZE_FOR_UNTIERED_INTERSECTIONS: u32 = 100;
+
+    let level = config.propagation_level;
+    let tiered = config.tiered_propagation;
+
+    let shape = &constraint.shape;
+
+    let mut handler_set = HandlerSet::new(shape);
+
+    let houses = make_houses(constraint);
+
+    let intersection_handlers = if level != PropagationLevel::Singles {
+        make_house_intersections(&houses, shape)
+    } else {
+        Vec::new()
+    };
+
+    // Without tiers, every handler is in the same tier.
+    let tier = |level: PropagationLevel| if tiered { level as usize } else { 0 };
+
+    for house in houses {
+        let all_different = level == PropagationLevel::Full;
+        if all_different && tiered {
+            // Find the singles separately, so that the all-different algorithm
+            // is only run once the cheaper handlers have nothing left to do.
+            let handler = HouseHandler::new(house.clone(), shape, false, config);
+            handler_set.push(ConstraintHandler::House(handler));
+        }
+        let handler = HouseHandler::new(house, shape, all_different, config);
+        let house_tier = if all_different { tier(level) } else { 0 };
+        handler_set.push_with_tier(ConstraintHandler::House(handler), house_tier);
+    }
+
+    // Intersections make large grids (which are usually solved without
+    // guessing) several times slower when they are run as often as the houses,
+    // so without tiers they are only run on large grids once the houses have
+    // nothing left to remove.
+    let intersection_tier = if !tiered && shape.num_values > MAX_SIZE_FOR_UNTIERED_INTERSECTIONS {
+        1
+    } else {
+        tier(PropagationLevel::Intersections)
+    };
+    for handler in intersection_handlers {
+        handler_set.push_with_tier(handler, intersection_tier);
+    }
+
+    if constraint.anti_king {
+        for cells in make_anti_king_pairs(shape) {
+            let handler = ConstraintHandler::UniqueValues(UniqueValuesHandler::new(cells));
+            handler_set.push(handler);
+        }
+    }
+
+    // Inequalities all share the same relation, as there can be many of them.
+    let mut less_than = None;
+    if constraint.argyle {
+        for cells in make_argyle_lines(shape) {
+            let handler = ConstraintHandler::UniqueValues(UniqueValuesHandler::new(cells));
+            handler_set.push(handler);
+        }
+    }
+
+    for variant in &constraint.variants {
+        match variant {
+            VariantConstraint::Cage {
+                cells,
+                sum,
+                allow_repeats,
+            } => handler_set.push(ConstraintHandler::Cage(CageHandler::new(
+                cells.clone(),
+                *sum,
+                !allow_repeats,
+            ))),
+            VariantConstraint::Thermo { cells } => {
+                handler_set.push(ConstraintHandler::Thermo(ThermoHandler::new(cells.clone())))
+            }
+            VariantConstraint::Arrow { cells } => {
+                handler_set.push(ConstraintHandler::Arrow(ArrowHandler::new(cells.clone())))
+            }
+            VariantConstraint::Renban { cells } => handler_set.push(ConstraintHandler::Renban(
+                RenbanHandler::new(cells.clone(), shape),
+            )),
+            VariantConstraint::Whisper { cells, difference } => {
+                let relation = handler_set.add_relation(BinaryRelation::MinDifference(
+                    ValueType::try_from(*difference).unwrap_or(ValueType::MAX),
+                ));
+                handler_set.add_line_handlers(cells, &relation);
+            }
+            VariantConstraint::Kropki { cells, kind } => {
+                let relation = handler_set.add_relation(match kind {
+                    KropkiKind::White => BinaryRelation::Consecutive,
+                    KropkiKind::Black => {
+                        BinaryRelation::new(shape, |a, b| kropki_relation(*kind, a, b))
+                    }
+                });
+                handler_set.add_line_handlers(cells, &relation);
+            }
+            VariantConstraint::Consecutive { cells } => {
+                let relation = handler_set.add_relation(BinaryRelation::Consecutive);
+                handler_set.add_line_handlers(cells, &relation);
+            }
+            VariantConstraint::Xv { cells, sum } => {
+                let sum = *sum as i64;
+                let relation =
+                    handler_set.add_relation(BinaryRelation::new(shape, |a, b| a + b == sum));
+                handler_set.add_line_handlers(cells, &relation);
+            }
+            VariantConstraint::CloneRegion { cells } => {
+                handler_set.push(ConstraintHandler::Clone(CloneHandler::new(cells.clone())))
+            }
+            VariantConstraint::Entropic { cells } => {
+                handler_set.push(ConstraintHandler::ClassLine(ClassLineHandler::new(
+                    cells.clone(),
+                    make_entropic_bands(shape),
+                )))
+            }
+            VariantConstraint::Modular { cells, modulus } => {
+                handler_set.push(ConstraintHandler::ClassLine(ClassLineHandler::new(
+                    cells.clone(),
+                    make_residue_classes(*modulus, shape),
+                )))
+            }
+            VariantConstraint::RegionSum { cells } => handler_set.push(
+                ConstraintHandler::RegionSum(RegionSumHandler::new(cells.clone(), shape)),
+            ),
+            VariantConstraint::Quadruple { cells, values } => handler_set.push(
+                ConstraintHandler::Quadruple(QuadrupleHandler::new(cells.clone(), values)),
+            ),
+            VariantConstraint::ValueCount {
+                cells,
+                value,
+                count,
+            } => handler_set.push(ConstraintHandler::ValueCount(ValueCountHandler::new(
+                cells.clone(),
+                *value,
+                *count,
+            ))),
+            VariantConstraint::Region { cells } => {
+                // Full regions were added with the houses.
+                if cells.len() < shape.num_values as usize {
+                    handler_set.push(ConstraintHandler::UniqueValues(UniqueValuesHandler::new(
+                        cells.clone(),
+                    )))
+                }
+            }
+            VariantConstraint::Inequality { cells } => {
+                let relation = less_than
+                    .get_or_insert_with(|| handler_set.add_relation(BinaryRelation::LessThan))
+                    .clone();
+                handler_set.add_line_handlers(cells, &relation);
+            }
+            VariantConstraint::Parity { cells, even } => handler_set.push(
+                ConstraintHandler::Parity(ParityHandler::new(cells.clone(), *even, shape)),
+            ),
+        }
+    }
+
+    if constraint.kropki_negative {
+        let relation = handler_set.add_relation(BinaryRelation::new(shape, |a, b| {
+            !kropki_relation(KropkiKind::White, a, b) && !kropki_relation(KropkiKind::Black, a, b)
+        }));
+        let is_marked = |v: &VariantConstraint| matches!(v, VariantConstraint::Kropki { .. });
+        handler_set.add_negative_handlers(constraint, is_marked, &relation);
+    }
+
+    if constraint.consecutive_negative {
+        let relation = handler_set.add_relation(BinaryRelation::new(shape, |a, b| {
+            !kropki_relation(KropkiKind::White, a, b)
+        }));
+        let is_marked = |v: &VariantConstraint| {
+            matches!(
+                v,
+                VariantConstraint::Consecutive { .. }
+                    | VariantConstraint::Kropki {
+                        kind: KropkiKind::White,
+                        ..
+                    }
+            )
+        };
+        handler_set.add_negative_handlers(constraint, is_marked, &relation);
+    }
+
+    if constraint.xv_negative {
+        let sums = xv_negative_sums(constraint);
+        let relation = handler_set.add_relation(BinaryRelation::new(shape, |a, b| {
+            !sums.contains(&((a + b) as u32))
+        }));
+        let is_marked = |v: &VariantConstraint| matches!(v, VariantConstraint::Xv { .. });
+        handler_set.add_negative_handlers(constraint, is_marked, &relation);
+    }
+
+    if let Some(max_size) = config.max_fish_size {
+        // Fish are the most expensive to find, so they are in their own tier.
+        let handler = FishHandler::new(shape, max_size);
+        let fish_tier = if tiered {
+            PropagationLevel::Full as usize + 1
+        } else {
+            0
+        };
+        handler_set.push_with_tier(ConstraintHandler::Fish(handler), fish_tier);
+    }
+
+    for propagator in &constraint.propagators {
+        handler_set.push(ConstraintHandler::Custom(propagator.make(shape)));
+    }
+
+    if let Some(priorities) = &config.handler_priorities {
+        handler_set.apply_priorities(priorities);
+    }
+    #[cfg(feature = "batch-houses")]
+    if config.batch_houses {
+        handler_set.enable_batches();
     }
 
-    handler_set.handlers.append(&mut intersection_handlers);
+    #[cfg(feature = "logging")]
+    tracing::debug!(
+        handlers = handler_set.handlers.len(),
+        relations = handler_set.relations.len(),
+        tiers = handler_set.tiers.iter().max().map_or(0, |&tier| tier + 1),
+        "made handlers"
+    );
 
     handler_set
 }
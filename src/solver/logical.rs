@@ -0,0 +1,657 @@
+use crate::types::{CellIndex, CellValue, Constraint, FixedValues, Solution, ValueType};
+use crate::value_set::{DynBitSet, SimdValueSet, ValueSet};
+
+use super::engine::MAX_FIXED_WIDTH_VALUES;
+use super::handlers;
+
+// Wide enough for any valid shape. Logical solving is for puzzles which people
+// solve by hand, so the speed of smaller sets doesn't matter.
+
+const MAX_TUPLE_SIZE: usize = 4;
+const MAX_FISH_SIZE: usize = 4;
+
+// Human-style techniques, from simplest to hardest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Technique {
+    // A cell with only one value left.
+    NakedSingle,
+    // A value which only fits in one cell of a house.
+    HiddenSingle,
+    // A value which only fits in the intersection of two houses, so it can be
+    // removed from the rest of the other house.
+    LockedCandidates,
+    // N cells of a house with only N values between them.
+    NakedTuple,
+    // N values which only fit in the same N cells of a house.
+    HiddenTuple,
+    // N rows where a value only fits in the same N columns (or the reverse).
+    Fish,
+}
+
+impl Technique {
+    pub fn name(&self, size: usize) -> String {
+        const TUPLES: [&str; 3] = ["pair", "triple", "quad"];
+        const FISH: [&str; 3] = ["X-wing", "swordfish", "jellyfish"];
+        match self {
+            Technique::NakedSingle => "naked single".to_string(),
+            Technique::HiddenSingle => "hidden single".to_string(),
+            Technique::LockedCandidates => "locked candidates".to_string(),
+            Technique::NakedTuple => format!("naked {}", TUPLES[size - 2]),
+            Technique::HiddenTuple => format!("hidden {}", TUPLES[size - 2]),
+            Technique::Fish => FISH[size - 2].to_string(),
+        }
+    }
+}
+
+// A single step of a logical solve.
+#[derive(Clone, Debug)]
+pub struct Deduction {
+    pub technique: Technique,
+    // The number of cells or values in a tuple, or lines in a fish.
+    pub size: usize,
+    // The houses which justify the deduction, e.g. "box 2".
+    pub houses: Vec<String>,
+    // Cells which were fixed to a value.
+    pub placements: FixedValues,
+    // Values which were removed from cells.
+    pub eliminations: FixedValues,
+}
+
+pub struct Explanation {
+    pub deductions: Vec<Deduction>,
+    // The values fixed by the deductions (including the givens).
+    pub fixed_values: FixedValues,
+    // The solution, if the techniques were enough to solve the puzzle.
+    pub solution: Option<Solution>,
+    // The deductions showed that the puzzle has no solution.
+    pub contradiction: bool,
+}
+
+pub enum LogicalStep {
+    Deduction(Deduction),
+    Solved(Solution),
+    // None of the techniques can make any progress.
+    Stuck,
+    Contradiction,
+}
+
+// Solve the puzzle using only the techniques, recording each deduction in the
+// order it was made. Only the houses are used (not other variant constraints).
+pub fn explain(constraint: &Constraint) -> Explanation {
+    if is_wide(constraint) {
+        explain_with::<DynBitSet>(constraint)
+    } else {
+        explain_with::<SimdValueSet<8>>(constraint)
+    }
+}
+
+fn explain_with<VS: ValueSet>(constraint: &Constraint) -> Explanation {
+    let mut solver = LogicalSolver::<VS>::new(constraint, &constraint.fixed_values);
+    let mut deductions = Vec::new();
+    let mut solution = None;
+    let mut contradiction = false;
+    loop {
+        match solver.next_step() {
+            LogicalStep::Deduction(deduction) => deductions.push(deduction),
+            LogicalStep::Solved(s) => {
+                solution = Some(s);
+                break;
+            }
+            LogicalStep::Stuck => break,
+            LogicalStep::Contradiction => {
+                contradiction = true;
+                break;
+            }
+        }
+    }
+
+    Explanation {
+        deductions,
+        fixed_values: solver.fixed_values(),
+        solution,
+        contradiction,
+    }
+}
+
+// The simplest deduction which can be made from the givens and the values
+// filled in so far, without revealing the rest of the solution. Values which
+// can't be placed give a contradiction.
+pub fn next_hint(constraint: &Constraint, partial_fill: &FixedValues) -> LogicalStep {
+    let fixed_values = [constraint.fixed_values.as_slice(), partial_fill].concat();
+    if is_wide(constraint) {
+        LogicalSolver::<DynBitSet>::new(constraint, &fixed_values).next_step()
+    } else {
+        LogicalSolver::<SimdValueSet<8>>::new(constraint, &fixed_values).next_step()
+    }
+}
+
+fn is_wide(constraint: &Constraint) -> bool {
+    constraint.shape.num_values > MAX_FIXED_WIDTH_VALUES
+}
+
+pub struct LogicalSolver<VS: ValueSet> {
+    side_len: usize,
+    all_values: VS,
+    grid: Vec<VS>,
+    placed: Vec<bool>,
+    houses: Vec<Vec<CellIndex>>,
+    house_names: Vec<String>,
+    cell_houses: Vec<Vec<usize>>,
+    // Pairs of houses which share more than one cell, with the shared cells.
+    intersections: Vec<(usize, usize, Vec<CellIndex>)>,
+    // Cells which may be naked singles.
+    pending_singles: Vec<CellIndex>,
+    contradiction: bool,
+}
+
+impl<VS: ValueSet> LogicalSolver<VS> {
+    pub fn new(constraint: &Constraint, fixed_values: &FixedValues) -> LogicalSolver<VS> {
+        let shape = &constraint.shape;
+        let houses = handlers::make_houses(constraint);
+        let mut cell_houses = vec![Vec::new(); shape.num_cells];
+        for (i, house) in houses.iter().enumerate() {
+            for &cell in house {
+                cell_houses[cell].push(i);
+            }
+        }
+
+        let all_values = VS::full(shape.num_values as ValueType);
+        let mut grid = vec![all_values.clone(); shape.num_cells];
+        for (cell, values) in &constraint.candidates {
+            grid[*cell] = values
+                .iter()
+                .map(|v| VS::from_value(v.index()))
+                .fold(VS::empty(), |a, b| a.union(&b));
+        }
+
+        let mut solver = LogicalSolver {
+            side_len: shape.side_len as usize,
+            all_values,
+            grid,
+            placed: vec![false; shape.num_cells],
+            intersections: make_intersections(&houses, &cell_houses),
+            house_names: make_house_names(constraint, &houses),
+            houses,
+            cell_houses,
+            pending_singles: Vec::new(),
+            contradiction: false,
+        };
+
+        for &(cell, value) in fixed_values {
+            let values = solver.grid[cell].intersection(&VS::from_value(value.index()));
+            if values.is_empty() {
+                solver.contradiction = true;
+            } else {
+                solver.place(cell, value.index());
+            }
+        }
+        for cell in 0..shape.num_cells {
+            match solver.grid[cell].count() {
+                0 => solver.contradiction = true,
+                1 if !solver.placed[cell] => solver.pending_singles.push(cell),
+                _ => {}
+            }
+        }
+        // Singles are found in cell order.
+        solver.pending_singles.reverse();
+
+        solver
+    }
+
+    // The values which have been placed so far.
+    pub fn fixed_values(&self) -> FixedValues {
+        (0..self.grid.len())
+            .filter(|&cell| self.placed[cell])
+            .map(|cell| (cell, CellValue::from_index(self.grid[cell].min().unwrap())))
+            .collect()
+    }
+
+    // Make the next deduction, using the simplest technique which finds one.
+    pub fn next_step(&mut self) -> LogicalStep {
+        if !self.contradiction && self.placed.iter().all(|&p| p) {
+            let solution = self
+                .grid
+                .iter()
+                .map(|v| CellValue::from_index(v.min().unwrap()))
+                .collect();
+            return LogicalStep::Solved(solution);
+        }
+
+        let deduction = self
+            .find_naked_single()
+            .or_else(|| self.find_hidden_single())
+            .or_else(|| self.find_locked_candidates())
+            .or_else(|| {
+                (2..=MAX_TUPLE_SIZE).find_map(|size| {
+                    self.find_naked_tuple(size)
+                        .or_else(|| self.find_hidden_tuple(size))
+                })
+            })
+            .or_else(|| (2..=MAX_FISH_SIZE).find_map(|size| self.find_fish(size)));
+
+        if self.contradiction {
+            return LogicalStep::Contradiction;
+        }
+        match deduction {
+            Some(deduction) => {
+                for &(cell, value) in &deduction.placements {
+                    self.place(cell, value.index());
+                }
+                for &(cell, value) in &deduction.eliminations {
+                    self.eliminate(cell, value.index());
+                }
+                LogicalStep::Deduction(deduction)
+            }
+            None => LogicalStep::Stuck,
+        }
+    }
+
+    fn place(&mut self, cell: CellIndex, value: ValueType) {
+        let value_set = VS::from_value(value);
+        self.grid[cell] = value_set.clone();
+        self.placed[cell] = true;
+        for i in 0..self.cell_houses[cell].len() {
+            let house = self.cell_houses[cell][i];
+            for j in 0..self.houses[house].len() {
+                let other = self.houses[house][j];
+                if other != cell && !self.grid[other].intersection(&value_set).is_empty() {
+                    if self.placed[other] {
+                        self.contradiction = true;
+                    } else {
+                        self.eliminate(other, value);
+                    }
+                }
+            }
+        }
+    }
+
+    fn eliminate(&mut self, cell: CellIndex, value: ValueType) {
+        self.grid[cell].remove_set(&VS::from_value(value));
+        match self.grid[cell].count() {
+            0 => self.contradiction = true,
+            1 => self.pending_singles.push(cell),
+            _ => {}
+        }
+    }
+
+    fn find_naked_single(&mut self) -> Option<Deduction> {
+        while let Some(cell) = self.pending_singles.pop() {
+            if self.placed[cell] {
+                continue;
+            }
+            if let Some(value) = self.grid[cell].value() {
+                return Some(Deduction {
+                    technique: Technique::NakedSingle,
+                    size: 1,
+                    houses: Vec::new(),
+                    placements: vec![(cell, CellValue::from_index(value))],
+                    eliminations: Vec::new(),
+                });
+            }
+        }
+        None
+    }
+
+    fn find_hidden_single(&mut self) -> Option<Deduction> {
+        for (i, house) in self.houses.iter().enumerate() {
+            let mut placed_values = VS::empty();
+            let mut at_least_once = VS::empty();
+            let mut at_least_twice = VS::empty();
+            for &cell in house {
+                let v = &self.grid[cell];
+                if self.placed[cell] {
+                    placed_values.add_set(v);
+                } else {
+                    at_least_twice.add_set(&at_least_once.intersection(v));
+                    at_least_once.add_set(v);
+                }
+            }
+            if at_least_once.union(&placed_values) != self.all_values {
+                self.contradiction = true;
+                return None;
+            }
+
+            let hidden_singles = at_least_once.without(&at_least_twice);
+            if let Some(value) = hidden_singles.min() {
+                let value_set = VS::from_value(value);
+                let &cell = house
+                    .iter()
+                    .find(|&&cell| !self.grid[cell].intersection(&value_set).is_empty())
+                    .unwrap();
+                return Some(Deduction {
+                    technique: Technique::HiddenSingle,
+                    size: 1,
+                    houses: vec![self.house_names[i].clone()],
+                    placements: vec![(cell, CellValue::from_index(value))],
+                    eliminations: Vec::new(),
+                });
+            }
+        }
+        None
+    }
+
+    // The values of the unplaced cells.
+    fn unplaced_values<'a, I: IntoIterator<Item = &'a CellIndex>>(&self, cells: I) -> VS {
+        cells
+            .into_iter()
+            .filter(|&&cell| !self.placed[cell])
+            .fold(VS::empty(), |a, &cell| a.union(&self.grid[cell]))
+    }
+
+    fn find_locked_candidates(&self) -> Option<Deduction> {
+        for (h0, h1, common) in &self.intersections {
+            let inside = self.unplaced_values(common);
+            let outside0 =
+                self.unplaced_values(self.houses[*h0].iter().filter(|c| !common.contains(c)));
+            let outside1 =
+                self.unplaced_values(self.houses[*h1].iter().filter(|c| !common.contains(c)));
+
+            // A value which is only inside the intersection in one house can't
+            // be in the rest of the other house.
+            for (from, to, outside_from, outside_to) in [
+                (h0, h1, &outside0, &outside1),
+                (h1, h0, &outside1, &outside0),
+            ] {
+                let locked = inside.without(outside_from).intersection(outside_to);
+                if let Some(value) = locked.min() {
+                    let value_set = VS::from_value(value);
+                    let eliminations = self.houses[*to]
+                        .iter()
+                        .filter(|&&cell| {
+                            !self.placed[cell]
+                                && !common.contains(&cell)
+                                && !self.grid[cell].intersection(&value_set).is_empty()
+                        })
+                        .map(|&cell| (cell, CellValue::from_index(value)))
+                        .collect();
+                    return Some(Deduction {
+                        technique: Technique::LockedCandidates,
+                        size: common.len(),
+                        houses: vec![
+                            self.house_names[*from].clone(),
+                            self.house_names[*to].clone(),
+                        ],
+                        placements: Vec::new(),
+                        eliminations,
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    fn find_naked_tuple(&self, size: usize) -> Option<Deduction> {
+        for (i, house) in self.houses.iter().enumerate() {
+            let cells = house
+                .iter()
+                .copied()
+                .filter(|&cell| !self.placed[cell] && self.grid[cell].count() <= size)
+                .collect::<Vec<_>>();
+            let sets = cells
+                .iter()
+                .map(|&cell| self.grid[cell].clone())
+                .collect::<Vec<_>>();
+            let mut chosen = Vec::new();
+            let mut found = None;
+            find_tuple(
+                &sets,
+                size,
+                0,
+                VS::empty(),
+                &mut chosen,
+                &mut |chosen, values| {
+                    let tuple_cells = chosen.iter().map(|&j| cells[j]).collect::<Vec<_>>();
+                    let eliminations = house
+                        .iter()
+                        .filter(|&cell| !self.placed[*cell] && !tuple_cells.contains(cell))
+                        .flat_map(|&cell| {
+                            self.grid[cell]
+                                .intersection(values)
+                                .iter()
+                                .map(move |v| (cell, CellValue::from_index(v)))
+                        })
+                        .collect::<Vec<_>>();
+                    if eliminations.is_empty() {
+                        return false;
+                    }
+                    found = Some(eliminations);
+                    true
+                },
+            );
+            if let Some(eliminations) = found {
+                return Some(Deduction {
+                    technique: Technique::NakedTuple,
+                    size,
+                    houses: vec![self.house_names[i].clone()],
+                    placements: Vec::new(),
+                    eliminations,
+                });
+            }
+        }
+        None
+    }
+
+    fn find_hidden_tuple(&self, size: usize) -> Option<Deduction> {
+        for (i, house) in self.houses.iter().enumerate() {
+            // The positions of each value in the house.
+            let mut positions = vec![VS::empty(); self.side_len];
+            for (j, &cell) in house.iter().enumerate() {
+                if self.placed[cell] {
+                    continue;
+                }
+                for v in self.grid[cell].iter() {
+                    positions[v as usize].add_set(&VS::from_value(j as ValueType));
+                }
+            }
+            let values = (0..self.side_len)
+                .filter(|&v| (1..=size).contains(&positions[v].count()))
+                .collect::<Vec<_>>();
+            let sets = values
+                .iter()
+                .map(|&v| positions[v].clone())
+                .collect::<Vec<_>>();
+
+            let mut chosen = Vec::new();
+            let mut found = None;
+            find_tuple(
+                &sets,
+                size,
+                0,
+                VS::empty(),
+                &mut chosen,
+                &mut |chosen, cells| {
+                    let tuple_values = chosen
+                        .iter()
+                        .map(|&j| VS::from_value(values[j] as ValueType))
+                        .fold(VS::empty(), |a, b| a.union(&b));
+                    let eliminations = cells
+                        .iter()
+                        .map(|j| house[j as usize])
+                        .flat_map(|cell| {
+                            self.grid[cell]
+                                .without(&tuple_values)
+                                .iter()
+                                .map(move |v| (cell, CellValue::from_index(v)))
+                        })
+                        .collect::<Vec<_>>();
+                    if eliminations.is_empty() {
+                        return false;
+                    }
+                    found = Some(eliminations);
+                    true
+                },
+            );
+            if let Some(eliminations) = found {
+                return Some(Deduction {
+                    technique: Technique::HiddenTuple,
+                    size,
+                    houses: vec![self.house_names[i].clone()],
+                    placements: Vec::new(),
+                    eliminations,
+                });
+            }
+        }
+        None
+    }
+
+    fn find_fish(&self, size: usize) -> Option<Deduction> {
+        let n = self.side_len;
+        // Rows are the first houses, followed by the columns.
+        for (base_offset, cover_offset) in [(0, n), (n, 0)] {
+            for value in 0..n as ValueType {
+                let value_set = VS::from_value(value);
+                let mut lines = Vec::new();
+                let mut sets = Vec::new();
+                for base in 0..n {
+                    let mut positions = VS::empty();
+                    for (cross, &cell) in self.houses[base_offset + base].iter().enumerate() {
+                        if !self.placed[cell]
+                            && !self.grid[cell].intersection(&value_set).is_empty()
+                        {
+                            positions.add_set(&VS::from_value(cross as ValueType));
+                        }
+                    }
+                    if (2..=size).contains(&positions.count()) {
+                        lines.push(base);
+                        sets.push(positions);
+                    }
+                }
+
+                let mut chosen = Vec::new();
+                let mut found = None;
+                find_tuple(
+                    &sets,
+                    size,
+                    0,
+                    VS::empty(),
+                    &mut chosen,
+                    &mut |chosen, cover| {
+                        let base_lines = chosen.iter().map(|&j| lines[j]).collect::<Vec<_>>();
+                        let cover_lines = cover.iter().map(|c| c as usize).collect::<Vec<_>>();
+                        let eliminations = cover_lines
+                            .iter()
+                            .flat_map(|&c| self.houses[cover_offset + c].iter().enumerate())
+                            .filter(|&(base, &cell)| {
+                                !base_lines.contains(&base)
+                                    && !self.placed[cell]
+                                    && !self.grid[cell].intersection(&value_set).is_empty()
+                            })
+                            .map(|(_, &cell)| (cell, CellValue::from_index(value)))
+                            .collect::<Vec<_>>();
+                        if eliminations.is_empty() {
+                            return false;
+                        }
+                        let houses = base_lines
+                            .iter()
+                            .map(|&b| base_offset + b)
+                            .chain(cover_lines.iter().map(|&c| cover_offset + c))
+                            .map(|h| self.house_names[h].clone())
+                            .collect();
+                        found = Some((houses, eliminations));
+                        true
+                    },
+                );
+                if let Some((houses, eliminations)) = found {
+                    return Some(Deduction {
+                        technique: Technique::Fish,
+                        size,
+                        houses,
+                        placements: Vec::new(),
+                        eliminations,
+                    });
+                }
+            }
+        }
+        None
+    }
+}
+
+// Search for `size` of the sets whose union has `size` elements, calling `f`
+// with the chosen indexes and the union until it returns true.
+fn find_tuple<VS: ValueSet, F: FnMut(&[usize], &VS) -> bool>(
+    sets: &[VS],
+    size: usize,
+    start: usize,
+    union: VS,
+    chosen: &mut Vec<usize>,
+    f: &mut F,
+) -> bool {
+    for i in start..sets.len() {
+        let new_union = union.union(&sets[i]);
+        if new_union.count() > size {
+            continue;
+        }
+        chosen.push(i);
+        let found = if chosen.len() == size {
+            f(chosen, &new_union)
+        } else {
+            find_tuple(sets, size, i + 1, new_union, chosen, f)
+        };
+        chosen.pop();
+        if found {
+            return true;
+        }
+    }
+    false
+}
+
+// Pairs of houses which share more than one cell.
+fn make_intersections(
+    houses: &[Vec<CellIndex>],
+    cell_houses: &[Vec<usize>],
+) -> Vec<(usize, usize, Vec<CellIndex>)> {
+    let mut intersections = Vec::new();
+    let mut common = vec![Vec::new(); houses.len()];
+    for (i, house) in houses.iter().enumerate() {
+        for &cell in house {
+            for &j in &cell_houses[cell] {
+                if j > i {
+                    common[j].push(cell);
+                }
+            }
+        }
+        for (j, cells) in common.iter_mut().enumerate() {
+            if cells.len() > 1 {
+                intersections.push((i, j, std::mem::take(cells)));
+            } else {
+                cells.clear();
+            }
+        }
+    }
+    intersections
+}
+
+// Names for the houses, in the order they are made by `make_houses`.
+pub(super) fn make_house_names(constraint: &Constraint, houses: &[Vec<CellIndex>]) -> Vec<String> {
+    let shape = &constraint.shape;
+    let side_len = shape.side_len as usize;
+
+    let mut names = Vec::new();
+    names.extend((1..=side_len).map(|r| format!("row {}", r)));
+    names.extend((1..=side_len).map(|c| format!("column {}", c)));
+    if let Some(box_size) = shape.box_size {
+        // Boxes are numbered across the rows of boxes, like the cells.
+        let box_size = box_size as usize;
+        names.extend(
+            houses[names.len()..names.len() + side_len]
+                .iter()
+                .map(|house| {
+                    let (r, c) = (house[0] / side_len, house[0] % side_len);
+                    format!("box {}", (r / box_size) * box_size + c / box_size + 1)
+                }),
+        );
+    }
+    if constraint.x_sudoku || constraint.main_diagonal {
+        names.push("the main diagonal".to_string());
+    }
+    if constraint.x_sudoku || constraint.anti_diagonal {
+        names.push("the anti-diagonal".to_string());
+    }
+    if let (true, Some(box_size)) = (constraint.windoku, shape.box_size) {
+        let num_windows = (box_size.saturating_sub(1) * box_size.saturating_sub(1)) as usize;
+        names.extend((1..=num_windows).map(|w| format!("window {}", w)));
+    }
+    let num_named = names.len();
+    names.extend((1..=houses.len() - num_named).map(|i| format!("region {}", i)));
+    names
+}
@@ -1,10 +1,16 @@
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
 
-use crate::types::{Constraint, FixedValues};
+use rand::prelude::SliceRandom;
+
+use crate::types::{Constraint, FixedValues, RngType};
 
 use super::engine;
-use super::{Config, Counters, MinimizerCounters, MinimizerProgressCallback, OutputType};
+use super::rating::{self, Difficulty};
+use super::{Config, Counters, MinimizerCounters, MinimizerProgressCallback, OutputType, Symmetry};
 
 pub fn make(
     constraint: &Constraint,
@@ -14,6 +20,15 @@ pub fn make(
     let minimizer_counters = Rc::new(RefCell::new(MinimizerCounters::default()));
     let progress_callback = Rc::new(RefCell::new(progress_callback));
 
+    // Groups with a locked cell are never removed.
+    let (required_groups, remaining_groups): (Vec<_>, Vec<_>) =
+        symmetric_groups(constraint, config.symmetry)
+            .into_iter()
+            .partition(|group| {
+                group
+                    .iter()
+                    .any(|(cell, _)| constraint.locked_cells.contains(cell))
+            });
     config.output_type = OutputType::Empty;
     {
         let minimizer_counters = minimizer_counters.clone();
@@ -29,9 +44,15 @@ pub fn make(
     }
 
     Box::new(Minimizer {
+        target_clues: config.target_clues,
+        max_removals: config.max_removals,
+        target_difficulty: config
+            .target_difficulty
+            .map(|difficulty| (difficulty, constraint.clone())),
+        difficulty_reached: false,
         runner: engine::make_runner(constraint, config),
-        remaining_values: constraint.fixed_values.clone(),
-        required_values: Vec::new(),
+        remaining_groups,
+        required_values: required_groups.concat(),
         progress_callback,
         counters: minimizer_counters,
     })
@@ -39,45 +60,344 @@ pub fn make(
 
 struct Minimizer {
     runner: Box<dyn engine::Runner>,
-    remaining_values: FixedValues,
+    // The values which haven't been tried yet, in the groups which are removed
+    // together. The last group is tried next.
+    remaining_groups: Vec<FixedValues>,
     required_values: FixedValues,
+    target_clues: Option<usize>,
+    max_removals: Option<u64>,
+    // The puzzle is rated against the target with the rest of the constraint.
+    target_difficulty: Option<(Difficulty, Constraint)>,
+    difficulty_reached: bool,
     progress_callback: Rc<RefCell<Option<Box<MinimizerProgressCallback>>>>,
     counters: Rc<RefCell<MinimizerCounters>>,
 }
 
+impl Minimizer {
+    // Rate the puzzle if there is a target difficulty, and whether it is above
+    // the target.
+    fn too_hard(&mut self, fixed_values: &FixedValues) -> bool {
+        let Some((target, constraint)) = &self.target_difficulty else {
+            return false;
+        };
+        let puzzle = Constraint {
+            fixed_values: fixed_values.clone(),
+            ..constraint.clone()
+        };
+        let difficulty = rating::rate(&puzzle, Config::default()).difficulty();
+        self.difficulty_reached = difficulty == *target;
+        difficulty > *target
+    }
+
+    fn target_reached(&self) -> bool {
+        let num_clues =
+            self.remaining_groups.iter().map(Vec::len).sum::<usize>() + self.required_values.len();
+        let cells_removed = self.counters.borrow().cells_removed;
+        self.target_clues.is_some_and(|target| num_clues <= target)
+            || self.max_removals.is_some_and(|max| cells_removed >= max)
+            || self.difficulty_reached
+    }
+}
+
 impl Iterator for Minimizer {
     type Item = FixedValues;
 
     fn next(&mut self) -> Option<Self::Item> {
         let fixed_values = loop {
+            let target_reached = self.target_reached();
+            self.counters.borrow_mut().target_reached = target_reached;
             super::maybe_call_callback(
                 &mut self.progress_callback.borrow_mut().as_mut(),
                 &self.counters.borrow(),
             );
+            if target_reached {
+                #[cfg(feature = "logging")]
+                tracing::debug!(
+                    cells_removed = self.counters.borrow().cells_removed,
+                    "minimizer reached its target"
+                );
+                return None;
+            }
 
-            let item = self.remaining_values.pop()?;
-            let fixed_values =
-                [self.remaining_values.clone(), self.required_values.clone()].concat();
+            let group = self.remaining_groups.pop()?;
+            let fixed_values = self
+                .remaining_groups
+                .iter()
+                .flatten()
+                .chain(&self.required_values)
+                .copied()
+                .collect::<FixedValues>();
 
             self.runner.reset_fixed_values(&fixed_values);
 
-            self.counters.borrow_mut().cells_tried += 1;
-
-            if self.runner.next().is_none() {
-                // No solutions, this is usually because it aborted early due to
-                // the no_guesses requirement - so keep the value.
-                // If this puzzle was already inconsistent, then we don't care.
-                self.required_values.push(item);
-            } else if self.runner.next().is_none() {
-                // One solution, return it!
-                self.counters.borrow_mut().cells_removed += 1;
-                break fixed_values;
-            } else {
-                // Multiple solutions - this was required.
-                self.required_values.push(item);
+            self.counters.borrow_mut().cells_tried += group.len() as u64;
+
+            let num_solutions = (0..2)
+                .map(|_| self.runner.next())
+                .take_while(Option::is_some)
+                .count();
+            if self.runner.stopped_early() {
+                // We can't tell if the solution is unique, so stop here.
+                #[cfg(feature = "logging")]
+                tracing::debug!("minimizer stopped early");
+                return None;
+            }
+
+            match num_solutions {
+                0 => {
+                    // No solutions, this is usually because it aborted early due to
+                    // the no_guesses requirement - so keep the value.
+                    // If this puzzle was already inconsistent, then we don't care.
+                    #[cfg(feature = "logging")]
+                    tracing::trace!(givens = group.len(), "kept givens without a solution");
+                    self.required_values.extend(group);
+                }
+                1 if self.too_hard(&fixed_values) => {
+                    // Removing more values rarely makes a puzzle easier.
+                    #[cfg(feature = "logging")]
+                    tracing::trace!(
+                        givens = group.len(),
+                        "kept givens, as the puzzle was too hard"
+                    );
+                    self.required_values.extend(group);
+                }
+                1 => {
+                    // One solution, return it!
+                    #[cfg(feature = "logging")]
+                    tracing::debug!(
+                        givens = group.len(),
+                        clues = fixed_values.len(),
+                        "removed givens"
+                    );
+                    self.counters.borrow_mut().cells_removed += group.len() as u64;
+                    break fixed_values;
+                }
+                _ => {
+                    // Multiple solutions - this was required.
+                    #[cfg(feature = "logging")]
+                    tracing::trace!(givens = group.len(), "kept givens with multiple solutions");
+                    self.required_values.extend(group);
+                }
             }
         };
 
         Some(fixed_values)
     }
 }
+
+// Whether a puzzle is minimal: it has a unique solution, and removing any one
+// of its givens gives more solutions.
+pub struct Minimality {
+    // The givens are only checked if the solution is unique.
+    pub unique: bool,
+    // The givens which can each be removed on their own while keeping the
+    // solution unique.
+    pub removable: FixedValues,
+    pub counters: Counters,
+}
+
+impl Minimality {
+    pub fn is_minimal(&self) -> bool {
+        self.unique && self.removable.is_empty()
+    }
+}
+
+// Try removing each given in turn, as the minimizer does, without removing any.
+pub fn check_minimal(constraint: &Constraint, mut config: Config) -> Minimality {
+    config.output_type = OutputType::Empty;
+    let mut runner = engine::make_runner(constraint, config);
+    let mut num_solutions = |fixed_values: &FixedValues| {
+        runner.reset_fixed_values(fixed_values);
+        let num_solutions = (0..2)
+            .map(|_| runner.next())
+            .take_while(Option::is_some)
+            .count();
+        (num_solutions, runner.stopped_early())
+    };
+
+    let fixed_values = &constraint.fixed_values;
+    let (solutions, stopped_early) = num_solutions(fixed_values);
+    let unique = solutions == 1 && !stopped_early;
+    let mut removable = Vec::new();
+    if unique {
+        for (i, &item) in fixed_values.iter().enumerate() {
+            let others = [&fixed_values[..i], &fixed_values[i + 1..]].concat();
+            match num_solutions(&others) {
+                // We can't tell if the solution is unique, so stop here.
+                (_, true) => break,
+                (1, false) => removable.push(item),
+                _ => {}
+            }
+        }
+    }
+
+    Minimality {
+        unique,
+        removable,
+        counters: runner.counters(),
+    }
+}
+
+// A message from a worker running minimizer passes.
+struct PassMessage {
+    pass: usize,
+    counters: MinimizerCounters,
+    // The smallest puzzle found by the pass, once it has finished. None if no
+    // value could be removed.
+    result: Option<Option<FixedValues>>,
+}
+
+// Run `passes` minimizer passes, each over a different random order of the
+// fixed values, and return the puzzle with the fewest clues, preferring passes
+// which reached the target (None if no value could be removed). The passes are run on up to `num_threads` threads, each
+// with its own config from `make_config`.
+pub fn best_of(
+    constraint: &Constraint,
+    make_config: &(dyn Fn() -> Config + Sync),
+    passes: usize,
+    num_threads: usize,
+    rng: &mut RngType,
+    mut progress_callback: Option<Box<MinimizerProgressCallback>>,
+) -> Option<FixedValues> {
+    // The orders are chosen up front, so that the result doesn't depend on the
+    // number of threads.
+    let pass_constraints = (0..passes)
+        .map(|_| {
+            let mut constraint = constraint.clone();
+            constraint.fixed_values.shuffle(rng);
+            constraint
+        })
+        .collect::<Vec<_>>();
+
+    let next_pass = AtomicUsize::new(0);
+    // The first pass to reach the target, as later passes can't do better. They
+    // aren't started once it is known, and are ignored if they already were, so
+    // that the result doesn't depend on which passes finished first.
+    let first_done = AtomicUsize::new(usize::MAX);
+    let (sender, receiver) = mpsc::channel();
+
+    let mut pass_counters = vec![MinimizerCounters::default(); passes];
+    let mut pass_results = vec![None; passes];
+    thread::scope(|scope| {
+        for _ in 0..num_threads.clamp(1, passes.max(1)) {
+            let sender = sender.clone();
+            let (pass_constraints, next_pass, first_done) =
+                (&pass_constraints, &next_pass, &first_done);
+            scope.spawn(move || loop {
+                let pass = next_pass.fetch_add(1, Ordering::Relaxed);
+                if pass > first_done.load(Ordering::Relaxed) {
+                    break;
+                }
+                let Some(pass_constraint) = pass_constraints.get(pass) else {
+                    break;
+                };
+                #[cfg(feature = "logging")]
+                let _span = tracing::debug_span!("minimizer_pass", pass).entered();
+                let last_counters = Rc::new(RefCell::new(MinimizerCounters::default()));
+                let progress_callback = {
+                    let (sender, last_counters) = (sender.clone(), last_counters.clone());
+                    Box::new(move |counters: &MinimizerCounters| {
+                        *last_counters.borrow_mut() = *counters;
+                        let _ = sender.send(PassMessage {
+                            pass,
+                            counters: *counters,
+                            result: None,
+                        });
+                    })
+                };
+                let result = make(pass_constraint, make_config(), Some(progress_callback)).last();
+                let counters = *last_counters.borrow();
+                let message = PassMessage {
+                    pass,
+                    counters,
+                    result: Some(result),
+                };
+                if sender.send(message).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        for message in receiver {
+            pass_counters[message.pass] = message.counters;
+            if let Some(result) = message.result {
+                if message.counters.target_reached {
+                    first_done.fetch_min(message.pass, Ordering::Relaxed);
+                }
+                pass_results[message.pass] = result;
+            }
+            super::maybe_call_callback(&mut progress_callback, &total_counters(&pass_counters));
+        }
+    });
+
+    let num_passes = first_done.into_inner().saturating_add(1).min(passes);
+    super::maybe_call_callback(
+        &mut progress_callback,
+        &total_counters(&pass_counters[..num_passes]),
+    );
+    // Passes which reached the target come first, then the fewest clues.
+    pass_results
+        .into_iter()
+        .take(num_passes)
+        .enumerate()
+        .filter_map(|(pass, result)| {
+            let fixed_values = result?;
+            let key = (
+                !pass_counters[pass].target_reached,
+                fixed_values.len(),
+                pass,
+            );
+            Some((key, fixed_values))
+        })
+        .min_by_key(|(key, _)| *key)
+        .map(|(_, fixed_values)| fixed_values)
+}
+
+// The cells tried and solver work summed over the passes, with the removals
+// from the best pass.
+fn total_counters(pass_counters: &[MinimizerCounters]) -> MinimizerCounters {
+    let mut total = MinimizerCounters::default();
+    for counters in pass_counters {
+        total.cells_tried += counters.cells_tried;
+        total.cells_removed = total.cells_removed.max(counters.cells_removed);
+        total.target_reached |= counters.target_reached;
+
+        let (t, c) = (&mut total.solver_counters, &counters.solver_counters);
+        t.solutions += c.solutions;
+        t.guesses += c.guesses;
+        t.constraints_processed += c.constraints_processed;
+        t.values_tried += c.values_tried;
+        t.cells_searched += c.cells_searched;
+        t.backtracks += c.backtracks;
+        t.peak_memory = t.peak_memory.max(c.peak_memory);
+        t.elapsed_secs = t.elapsed_secs.max(c.elapsed_secs);
+        t.limit_reached |= c.limit_reached;
+        t.cancelled |= c.cancelled;
+    }
+    total
+}
+
+// Group the fixed values with their symmetric images, in the order that each
+// group first appears. Cells whose images aren't fixed are in smaller groups.
+fn symmetric_groups(constraint: &Constraint, symmetry: Symmetry) -> Vec<FixedValues> {
+    let shape = &constraint.shape;
+    let mut group_index = vec![None; shape.num_cells];
+    let mut groups: Vec<FixedValues> = Vec::new();
+    for &(cell, value) in &constraint.fixed_values {
+        let mut orbit = cell;
+        let index = loop {
+            if let Some(index) = group_index[orbit] {
+                break index;
+            }
+            orbit = symmetry.image(shape, orbit);
+            if orbit == cell {
+                groups.push(Vec::new());
+                break groups.len() - 1;
+            }
+        };
+        group_index[cell] = Some(index);
+        groups[index].push((cell, value));
+    }
+    groups
+}
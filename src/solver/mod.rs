@@ -1,25 +1,309 @@
 pub mod all_different;
+#[cfg(feature = "alloc-check")]
+mod alloc_check;
+#[cfg(feature = "sat")]
+mod cdcl;
 mod cell_accumulator;
+mod diagnoser;
+mod dlx;
 mod engine;
+mod estimator;
+mod handle;
 mod handlers;
+mod logical;
 mod minimizer;
+mod nogoods;
+mod parallel;
+mod progress;
+mod propagator;
+mod rating;
+#[cfg(feature = "sat")]
+mod sat;
+mod stream;
+mod trail;
+mod verifier;
 
-use crate::types::{Constraint, FixedValues, RngType, Solution};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+// std::time::Instant panics in browsers.
+#[cfg(not(feature = "wasm"))]
+use std::time::Instant;
+#[cfg(feature = "wasm")]
+use web_time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use crate::types::{CellIndex, CellValue, Constraint, FixedValues, RngType, Shape, Solution};
+
+pub use diagnoser::{diagnose, Diagnosis};
+pub use handle::{SearchStatus, SolverHandle};
+pub use handlers::Contradiction;
+pub use logical::{explain, next_hint, Deduction, Explanation, LogicalStep, Technique};
+pub use minimizer::Minimality;
+pub use propagator::{CustomPropagator, Domains, Propagator, PropagatorFactory};
+pub use rating::{rate, Difficulty, Rating};
+pub use stream::SolutionStream;
+pub use verifier::{verify, Violation};
 
 pub const VALID_NUM_VALUE_RANGE: std::ops::RangeInclusive<u32> = engine::VALID_NUM_VALUE_RANGE;
+pub const MAX_LINE_CLASSES: usize = handlers::MAX_LINE_CLASSES;
 
 pub type ProgressCallback = dyn FnMut(&Counters);
 pub type MinimizerProgressCallback = dyn FnMut(&MinimizerCounters);
+pub type CheckpointCallback = dyn FnMut(&Checkpoint);
+pub type EstimateProgressCallback = dyn FnMut(&Estimate);
+pub type TraceCallback = dyn FnMut(&TraceEvent);
+
+pub use engine::{Checkpoint, ValueSetKind};
+pub use estimator::Estimate;
 
 #[derive(Default)]
 pub struct Config {
     pub no_guesses: bool,
+    // The minimizer removes the cells of each symmetric group together.
+    pub symmetry: Symmetry,
+    // The minimizer stops once the puzzle has at most `target_clues` values,
+    // or `max_removals` values have been removed.
+    pub target_clues: Option<usize>,
+    pub max_removals: Option<u64>,
+    // The minimizer rates the puzzle after each removal, keeps values whose
+    // removal would make it harder than this, and stops once it is this hard.
+    pub target_difficulty: Option<Difficulty>,
     pub progress_callback: Option<Box<ProgressCallback>>,
     pub search_randomizer: Option<RngType>,
     pub output_type: OutputType,
+    pub cell_ordering: CellOrdering,
+    pub value_order: ValueOrder,
+    pub grid_strategy: GridStrategy,
+    // Stop saving the grid for each guess once it takes this many bytes, and
+    // rebuild the deeper grids by propagating the guesses again from the last
+    // saved grid. This is ignored with `nogoods` or `early_exit_houses`, as
+    // they can remove different values when propagating again.
+    pub max_grid_memory: Option<usize>,
+    // Copy the values of each house into one place before enforcing it, and
+    // write back the changes. This reads fewer scattered cells on large grids.
+    pub gather_houses: bool,
+    // Keep the components found by the all-different algorithm for each house,
+    // and only search again the components whose cells changed since.
+    pub incremental_scc: bool,
+    // Skip the all-different algorithm for a house when only a few of its cells
+    // changed since the last time, and none of the others is nearly fixed.
+    // This finds less, but is much cheaper.
+    pub early_exit_houses: bool,
+    // Run the all-different algorithm for the waiting houses in batches, with
+    // the houses in each batch searched in parallel. Only with the
+    // `batch-houses` feature.
+    pub batch_houses: bool,
+    // How much work is done to remove values from the houses. With
+    // `tiered_propagation`, the cheaper levels are run to a fixed point before
+    // each more expensive level.
+    pub propagation_level: PropagationLevel,
+    pub tiered_propagation: bool,
+    // Run the waiting handlers in order of priority, so that cheap handlers
+    // remove what they can before the expensive ones run.
+    pub handler_priorities: Option<HandlerPriorities>,
+    // Track the work done by each kind of handler in
+    // `Counters::handler_profile`. Only the propagation engine tracks it.
+    pub profile: bool,
+    // Give each thread of `count_parallel` a fixed share of the subproblems,
+    // rather than the next one waiting, so that the counters don't depend on
+    // the timing of the threads.
+    pub deterministic: bool,
+    // Also find fish patterns (X-wings, swordfish, ...) across the rows and
+    // columns, with up to this many lines.
+    pub max_fish_size: Option<usize>,
+    // The search algorithm. Only the fixed values, candidates, output type and
+    // limits apply to the dlx engine.
+    pub engine: EngineKind,
+    // Learn from contradictions found while guessing.
+    pub nogoods: Option<NogoodConfig>,
+    // Stop the search once this much time has passed, or this many guesses
+    // have been made.
+    pub time_limit: Option<Duration>,
+    pub max_guesses: Option<u64>,
+    // Stop the search once the token is cancelled.
+    pub cancellation: Option<CancellationToken>,
+    // Return from `next` without finishing while the token is paused. Calling
+    // `next` again continues the search. The dlx and sat engines only pause
+    // between solutions.
+    pub pause: Option<PauseToken>,
+    // Keep the deepest partial assignment that the search reaches. Only the
+    // propagation engine records it.
+    pub record_partial_assignment: bool,
+    // Restart the search with a new cell order when it is stuck, until the first
+    // solution is found.
+    pub restarts: Option<RestartSchedule>,
+    // Called with a checkpoint of the search state every `checkpoint_interval`.
+    pub checkpoint_callback: Option<Box<CheckpointCallback>>,
+    pub checkpoint_interval: Option<Duration>,
+    // Called with each guess, propagation and backtrack of the search. Only
+    // the propagation engine traces the search.
+    pub trace_callback: Option<Box<TraceCallback>>,
+    // Called with a snapshot of the counters every `metrics_interval` (a
+    // second by default), and with the last counters when the search is
+    // dropped, whether or not there is a progress callback.
+    pub metrics_callback: Option<Box<ProgressCallback>>,
+    pub metrics_interval: Option<Duration>,
 }
 
-#[derive(Copy, Clone, Debug, Default)]
+// Move the config to another thread. The callbacks can't be sent, so they are
+// left out, and the config is rebuilt without them by the returned function.
+fn sendable_config(config: Config) -> impl FnOnce() -> Config + Send {
+    let Config {
+        no_guesses,
+        symmetry,
+        target_clues,
+        max_removals,
+        target_difficulty,
+        progress_callback: _,
+        search_randomizer,
+        output_type,
+        cell_ordering,
+        value_order,
+        grid_strategy,
+        max_grid_memory,
+        gather_houses,
+        incremental_scc,
+        early_exit_houses,
+        batch_houses,
+        propagation_level,
+        tiered_propagation,
+        handler_priorities,
+        profile,
+        deterministic,
+        max_fish_size,
+        engine,
+        nogoods,
+        time_limit,
+        max_guesses,
+        cancellation,
+        pause,
+        record_partial_assignment,
+        restarts,
+        checkpoint_callback: _,
+        checkpoint_interval: _,
+        trace_callback: _,
+        metrics_callback: _,
+        metrics_interval: _,
+    } = config;
+    move || Config {
+        no_guesses,
+        symmetry,
+        target_clues,
+        max_removals,
+        target_difficulty,
+        search_randomizer,
+        output_type,
+        cell_ordering,
+        value_order,
+        grid_strategy,
+        max_grid_memory,
+        gather_houses,
+        incremental_scc,
+        early_exit_houses,
+        batch_houses,
+        propagation_level,
+        tiered_propagation,
+        handler_priorities,
+        profile,
+        deterministic,
+        max_fish_size,
+        engine,
+        nogoods,
+        time_limit,
+        max_guesses,
+        cancellation,
+        pause,
+        record_partial_assignment,
+        restarts,
+        ..Config::default()
+    }
+}
+
+// How many backtracks are allowed before each restart.
+#[derive(Clone, Copy, Debug)]
+pub enum RestartSchedule {
+    // The given multiple of the Luby sequence (1, 1, 2, 1, 1, 2, 4, 1, ...).
+    Luby(u64),
+    // Starting at the given number, growing by the factor after each restart.
+    Geometric(u64, f64),
+}
+
+impl RestartSchedule {
+    // The number of backtracks allowed after `restarts` restarts.
+    pub fn limit(&self, restarts: u64) -> u64 {
+        match *self {
+            RestartSchedule::Luby(scale) => scale.saturating_mul(luby(restarts + 1)),
+            // `powi` can round differently on each platform, so the limit is
+            // multiplied out instead.
+            RestartSchedule::Geometric(base, factor) => {
+                let mut limit = base as f64;
+                for _ in 0..restarts {
+                    if limit >= u64::MAX as f64 {
+                        break;
+                    }
+                    limit *= factor;
+                }
+                limit as u64
+            }
+        }
+    }
+}
+
+// The i-th term (1-indexed) of the Luby sequence.
+fn luby(mut i: u64) -> u64 {
+    loop {
+        // Find k such that 2^(k-1) <= i < 2^k.
+        let k = 64 - i.leading_zeros();
+        if i == (1 << k) - 1 {
+            return 1 << (k - 1);
+        }
+        i -= (1 << (k - 1)) - 1;
+    }
+}
+
+// Allows a search to be stopped from another thread.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Allows a search to be paused from another thread.
+#[derive(Clone, Debug, Default)]
+pub struct PauseToken(Arc<AtomicBool>);
+
+impl PauseToken {
+    pub fn new() -> PauseToken {
+        PauseToken::default()
+    }
+
+    pub fn pause(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Counters {
     pub solutions: u64,
     pub guesses: u64,
@@ -27,16 +311,275 @@ pub struct Counters {
     pub values_tried: u64,
     pub cells_searched: u64,
     pub backtracks: u64,
+    pub restarts: u64,
+    pub nogoods_learned: u64,
+    // Values removed by nogoods, or contradictions found by them.
+    pub nogood_prunes: u64,
+    // The share of the search which is done. The propagation engine weights
+    // this by the sizes of the subtrees searched so far, when there is a
+    // progress callback, and other engines by the number of values of each
+    // cell.
     pub progress_ratio: f64,
+    // With the weighted progress ratio, its pessimistic and optimistic bounds,
+    // from the largest and smallest subtrees searched at each depth.
+    #[serde(default)]
+    pub progress_bounds: Option<(f64, f64)>,
+    // Peak bytes used by the major solver structures (grid stack, handlers,
+    // cell accumulator).
+    pub peak_memory: u64,
+    // Wall-clock timing, which is only tracked when there is a progress
+    // callback.
+    pub elapsed_secs: f64,
+    // Search nodes (values tried) per second.
+    pub nodes_per_sec: f64,
+    // Estimated time remaining, from the recent rate of change of the progress
+    // ratio.
+    pub eta_secs: Option<f64>,
+    // The search was stopped early because it reached a limit in the config.
+    pub limit_reached: bool,
+    // The search was stopped early by the cancellation token.
+    pub cancelled: bool,
+    // With `Config::profile`, the work done by each kind of handler.
+    #[serde(default)]
+    pub handler_profile: Option<HandlerProfile>,
+}
+
+impl Counters {
+    // Clear the timings and memory use, which differ between runs and
+    // platforms, leaving only the counts of the work done.
+    pub fn clear_measurements(&mut self) {
+        self.peak_memory = 0;
+        self.elapsed_secs = 0.0;
+        self.nodes_per_sec = 0.0;
+        self.eta_secs = None;
+        if let Some(profile) = &mut self.handler_profile {
+            profile.clear_times();
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct HandlerStats {
+    pub invocations: u64,
+    // Values removed from cells.
+    pub eliminations: u64,
+    pub elapsed_secs: f64,
+}
+
+// The work done by each kind of handler, in the order of `HANDLER_KINDS`, with
+// all custom propagators together at the end.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct HandlerProfile {
+    pub stats: [HandlerStats; HANDLER_KINDS.len() + 1],
+}
+
+impl HandlerProfile {
+    pub fn kinds(&self) -> impl Iterator<Item = (&'static str, &HandlerStats)> {
+        HANDLER_KINDS.into_iter().chain(["custom"]).zip(&self.stats)
+    }
+
+    pub fn clear_times(&mut self) {
+        for stats in &mut self.stats {
+            stats.elapsed_secs = 0.0;
+        }
+    }
+
+    pub fn add(&mut self, other: &HandlerProfile) {
+        for (stats, other) in self.stats.iter_mut().zip(&other.stats) {
+            stats.invocations += other.invocations;
+            stats.eliminations += other.eliminations;
+            stats.elapsed_secs += other.elapsed_secs;
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, Default)]
 pub struct MinimizerCounters {
     pub cells_tried: u64,
     pub cells_removed: u64,
+    // The minimizer stopped at `target_clues`, `max_removals` or
+    // `target_difficulty`.
+    pub target_reached: bool,
     pub solver_counters: Counters,
 }
 
+// The pattern of clues kept by the minimizer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Symmetry {
+    #[default]
+    None,
+    // The same after rotating the grid by 180 degrees.
+    Rotational,
+    // The same after reflecting the grid left to right.
+    Mirror,
+    // The same after reflecting the grid in the main diagonal.
+    Diagonal,
+}
+
+impl Symmetry {
+    // The cell which `cell` maps to.
+    pub fn image(&self, shape: &Shape, cell: CellIndex) -> CellIndex {
+        let side_len = shape.side_len;
+        let (row, col) = (cell as u32 / side_len, cell as u32 % side_len);
+        match self {
+            Symmetry::None => cell,
+            Symmetry::Rotational => shape.make_cell_index(side_len - 1 - row, side_len - 1 - col),
+            Symmetry::Mirror => shape.make_cell_index(row, side_len - 1 - col),
+            Symmetry::Diagonal => shape.make_cell_index(col, row),
+        }
+    }
+}
+
+// How the next cell to guess is chosen.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CellOrdering {
+    // The cell with the fewest values.
+    MinValues,
+    // The fewest values, divided by the number of recent backtracks caused by
+    // the cell.
+    #[default]
+    BacktrackWeighted,
+    // The fewest values, divided by the total weight of the cell's constraints,
+    // where a constraint's weight is the number of times it failed.
+    DomWdeg,
+    // The first cell (in row-major order) which isn't fixed.
+    Sequential,
+}
+
+// Which search algorithm to use.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EngineKind {
+    // Constraint propagation with backtracking, which supports every
+    // constraint.
+    #[default]
+    Propagation,
+    // Dancing links (Knuth's Algorithm X), which only supports constraints
+    // where every region contains each value exactly once.
+    Dlx,
+    // Encodes the constraints as clauses for a CDCL SAT solver.
+    #[cfg(feature = "sat")]
+    Sat,
+}
+
+// Check that the engine supports the constraint.
+pub fn check_engine(constraint: &Constraint, engine: EngineKind) -> Result<(), String> {
+    match engine {
+        EngineKind::Propagation => Ok(()),
+        EngineKind::Dlx => dlx::check_supported(constraint),
+        #[cfg(feature = "sat")]
+        EngineKind::Sat => sat::check_supported(constraint),
+    }
+}
+
+// How the grid is saved for each guess, so that it can be restored on
+// backtrack.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GridStrategy {
+    // Record the old value of each cell as it changes.
+    #[default]
+    Trail,
+    // Copy each block of cells the first time it changes. This copies more
+    // values, but records fewer changes.
+    CopyOnWrite,
+}
+
+// How values are removed from the houses (rows, columns, boxes and other
+// regions containing every value). Variant constraints are always enforced.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PropagationLevel {
+    // Naked and hidden singles.
+    Singles = 0,
+    // Singles, and values removed by the intersections between houses (pointing
+    // pairs and box-line reduction).
+    Intersections = 1,
+    // Generalized arc consistency, using the all-different algorithm.
+    #[default]
+    Full = 2,
+}
+
+// The kinds of handlers built into the solver. Custom propagators have their
+// own kinds.
+pub const HANDLER_KINDS: [&str; 15] = [
+    "house",
+    "same-value",
+    "unique-values",
+    "cage",
+    "thermo",
+    "arrow",
+    "renban",
+    "clone",
+    "parity",
+    "class-line",
+    "region-sum",
+    "quadruple",
+    "value-count",
+    "binary",
+    "fish",
+];
+
+// The priority of each kind of handler (see `HANDLER_KINDS`). Handlers with a
+// lower priority are run until nothing changes before any with a higher
+// priority, within each level of `tiered_propagation`.
+#[derive(Clone, Debug, Default)]
+pub struct HandlerPriorities {
+    overrides: Vec<(String, usize)>,
+}
+
+impl HandlerPriorities {
+    // Set the priority of a kind of handler, which can also be the kind of a
+    // custom propagator.
+    pub fn set(&mut self, kind: &str, priority: usize) {
+        self.overrides.retain(|(k, _)| k != kind);
+        self.overrides.push((kind.to_string(), priority));
+    }
+
+    pub fn priority(&self, kind: &str) -> usize {
+        if let Some((_, priority)) = self.overrides.iter().find(|(k, _)| k == kind) {
+            return *priority;
+        }
+        // By default, from the cheapest to the most expensive to run.
+        match kind {
+            "same-value" | "binary" | "parity" | "clone" => 0,
+            "house" => 2,
+            "fish" => 3,
+            _ => 1,
+        }
+    }
+}
+
+// Limits on the nogoods that are learnt. A nogood is a set of guesses which
+// led to a contradiction, and it is used to prune the search when the same
+// values are seen again (e.g. in another branch, or after a restart).
+#[derive(Clone, Copy, Debug)]
+pub struct NogoodConfig {
+    // The largest nogood to keep, in cells.
+    pub max_size: usize,
+    // The most nogoods to keep. The least useful half is discarded when full.
+    pub capacity: usize,
+}
+
+impl Default for NogoodConfig {
+    fn default() -> Self {
+        NogoodConfig {
+            max_size: 4,
+            capacity: 1000,
+        }
+    }
+}
+
+// Which value of a cell is guessed first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ValueOrder {
+    #[default]
+    Min,
+    Max,
+    // A random value, chosen with `search_randomizer`.
+    Random,
+    // The value which appears in the fewest cells that share a constraint with
+    // the cell, so that it removes the fewest options.
+    LeastConstraining,
+}
+
 #[derive(Default, PartialEq)]
 pub enum OutputType {
     #[default]
@@ -51,9 +594,82 @@ pub enum Output {
     Empty,
 }
 
+// A step of the search, passed to `Config::trace_callback`. The depth is the
+// number of guesses on the search stack when the step was taken, including a
+// new guess. The progress of a value is the share of the search space below
+// it, giving each value of a cell the same share.
+#[derive(Clone, Copy, Debug)]
+pub enum TraceEvent {
+    Guess {
+        depth: usize,
+        cell: CellIndex,
+        value: CellValue,
+        progress: f64,
+    },
+    // The other values of the cell had no solutions, so the last one is tried
+    // without a new guess.
+    LastValue {
+        depth: usize,
+        cell: CellIndex,
+        value: CellValue,
+        progress: f64,
+    },
+    // The constraints were enforced at the start, or after a guess or last
+    // value, by running this many handlers, which removed this many values.
+    Propagation {
+        depth: usize,
+        handlers: u64,
+        eliminations: u64,
+        contradiction: bool,
+    },
+    // The value guessed in the cell had no solutions.
+    Backtrack {
+        depth: usize,
+        cell: CellIndex,
+    },
+    Solution {
+        depth: usize,
+    },
+    Restart,
+}
+
+// The cells which a single constraint handler acts on.
+#[derive(Clone, Debug)]
+pub struct HandlerDescription {
+    pub kind: &'static str,
+    pub cells: Vec<CellIndex>,
+}
+
 pub struct Solutions {
     runner: Box<dyn engine::Runner>,
 }
+impl Solutions {
+    // Whether the search was stopped by a limit or cancellation, rather than
+    // finding all solutions.
+    pub fn stopped_early(&self) -> bool {
+        self.runner.stopped_early()
+    }
+
+    // The current search state, which can be restored to continue the search
+    // from the same point.
+    pub fn checkpoint(&self) -> Result<Checkpoint, String> {
+        self.runner.checkpoint()
+    }
+
+    pub fn restore(&mut self, checkpoint: &Checkpoint) -> Result<(), String> {
+        self.runner.restore(checkpoint)
+    }
+
+    // The values fixed at the deepest point of the search so far, if
+    // `record_partial_assignment` is set.
+    pub fn partial_assignment(&self) -> FixedValues {
+        self.runner.partial_assignment()
+    }
+
+    pub fn counters(&self) -> Counters {
+        self.runner.counters()
+    }
+}
 impl Iterator for Solutions {
     type Item = Output;
 
@@ -63,9 +679,42 @@ impl Iterator for Solutions {
 }
 
 pub fn solution_iter(constraint: &Constraint, config: Config) -> Solutions {
-    return Solutions {
+    Solutions {
         runner: engine::make_runner(constraint, config),
-    };
+    }
+}
+
+// Find solutions on a background thread, buffering up to `buffer_size` of
+// them until they are taken from the stream.
+pub fn solution_stream(
+    constraint: &Constraint,
+    config: Config,
+    buffer_size: usize,
+) -> SolutionStream {
+    SolutionStream::new(constraint, config, buffer_size)
+}
+
+// Start a search on a background thread, which can be paused and inspected
+// through the handle.
+pub fn spawn_search(constraint: &Constraint, config: Config) -> SolverHandle {
+    SolverHandle::new(constraint, config)
+}
+
+// Count the solutions using multiple threads. The search is split the same
+// way for any number of threads.
+pub fn count_parallel(constraint: &Constraint, config: Config, num_threads: usize) -> Counters {
+    parallel::count(constraint, config, num_threads.max(1))
+}
+
+// Estimate the number of solutions from `num_samples` random paths through the
+// search tree. `config.search_randomizer` is used to choose the paths.
+pub fn estimate(
+    constraint: &Constraint,
+    config: Config,
+    num_samples: u64,
+    progress_callback: Option<Box<EstimateProgressCallback>>,
+) -> Estimate {
+    estimator::estimate(constraint, config, num_samples, progress_callback)
 }
 
 pub fn minimize(
@@ -76,6 +725,43 @@ pub fn minimize(
     minimizer::make(constraint, config, progress_callback)
 }
 
+// Check whether the puzzle is minimal, and list the givens which could be
+// removed if not.
+pub fn check_minimal(constraint: &Constraint, config: Config) -> Minimality {
+    minimizer::check_minimal(constraint, config)
+}
+
+// Run `passes` minimizer passes over different random orders of the fixed
+// values, on up to `num_threads` threads, and keep the puzzle with the fewest
+// clues which reached the target (if any). Returns None if no value could be
+// removed.
+pub fn minimize_best_of(
+    constraint: &Constraint,
+    make_config: &(dyn Fn() -> Config + Sync),
+    passes: usize,
+    num_threads: usize,
+    rng: &mut RngType,
+    progress_callback: Option<Box<MinimizerProgressCallback>>,
+) -> Option<FixedValues> {
+    minimizer::best_of(
+        constraint,
+        make_config,
+        passes,
+        num_threads,
+        rng,
+        progress_callback,
+    )
+}
+
+pub fn describe_handlers(constraint: &Constraint) -> Vec<HandlerDescription> {
+    engine::describe_handlers(constraint)
+}
+
+// Approximate heap size of a vector's buffer.
+fn vec_memory<T>(v: &Vec<T>) -> usize {
+    v.capacity() * std::mem::size_of::<T>()
+}
+
 fn maybe_call_callback<A, F: FnMut(A)>(f: &mut Option<F>, arg: A) {
     if let Some(f) = f {
         (f)(arg);
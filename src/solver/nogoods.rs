@@ -0,0 +1,111 @@
+use crate::types::{CellIndex, ValueType};
+use crate::value_set::ValueSet;
+
+use super::cell_accumulator::CellAccumulator;
+use super::handlers;
+use super::trail::TrailGrid;
+use super::NogoodConfig;
+
+// A set of cell values which can't all hold in any solution.
+struct Nogood {
+    literals: Vec<(CellIndex, ValueType)>,
+    // The number of times the nogood has pruned the search, used to decide
+    // which nogoods to keep.
+    hits: u32,
+}
+
+pub struct NogoodStore {
+    nogoods: Vec<Nogood>,
+    config: NogoodConfig,
+}
+
+impl NogoodStore {
+    pub fn new(config: NogoodConfig) -> NogoodStore {
+        NogoodStore {
+            nogoods: Vec::new(),
+            config,
+        }
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.config.max_size
+    }
+
+    pub fn clear(&mut self) {
+        self.nogoods.clear();
+    }
+
+    pub fn add(&mut self, literals: Vec<(CellIndex, ValueType)>) {
+        if literals.is_empty() || literals.len() > self.config.max_size {
+            return;
+        }
+        if self.nogoods.len() >= self.config.capacity {
+            self.evict();
+        }
+        self.nogoods.push(Nogood { literals, hits: 0 });
+    }
+
+    // Keep the half of the nogoods which have pruned the most, and halve their
+    // hits so that old successes fade.
+    fn evict(&mut self) {
+        self.nogoods
+            .sort_by_key(|nogood| std::cmp::Reverse(nogood.hits));
+        self.nogoods.truncate(self.config.capacity / 2);
+        for nogood in &mut self.nogoods {
+            nogood.hits /= 2;
+        }
+    }
+
+    // Remove values which would complete a nogood. Returns the number of
+    // values removed, or an error if a nogood already holds.
+    pub fn propagate<VS: ValueSet>(
+        &mut self,
+        grid: &mut TrailGrid<VS>,
+        cell_accumulator: &mut CellAccumulator,
+    ) -> Result<usize, handlers::Contradiction> {
+        let mut num_removed = 0;
+        for nogood in &mut self.nogoods {
+            let mut unfixed = None;
+            let mut num_unfixed = 0;
+            let mut excluded = false;
+            for &(cell, value) in &nogood.literals {
+                let values = &grid[cell];
+                if values.intersection(&VS::from_value(value)).is_empty() {
+                    excluded = true;
+                    break;
+                }
+                if values.has_multiple() {
+                    num_unfixed += 1;
+                    unfixed = Some((cell, value));
+                }
+            }
+            if excluded {
+                continue;
+            }
+
+            match (num_unfixed, unfixed) {
+                (0, _) => {
+                    nogood.hits += 1;
+                    return Err(handlers::Contradiction);
+                }
+                (1, Some((cell, value))) => {
+                    nogood.hits += 1;
+                    grid.set(cell, grid[cell].without(&VS::from_value(value)));
+                    cell_accumulator.add(cell);
+                    num_removed += 1;
+                }
+                _ => {}
+            }
+        }
+        Ok(num_removed)
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        super::vec_memory(&self.nogoods)
+            + self
+                .nogoods
+                .iter()
+                .map(|nogood| super::vec_memory(&nogood.literals))
+                .sum::<usize>()
+    }
+}
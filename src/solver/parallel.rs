@@ -0,0 +1,215 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use crate::types::{Constraint, FixedValues};
+
+use super::engine::{self, ProgressMetadata, Propagation};
+use super::{CancellationToken, Config, Counters, HandlerProfile, Instant, OutputType};
+
+// The search is split into at least this many subproblems before counting.
+// This doesn't depend on the number of threads, so the same subproblems are
+// searched regardless of how many threads there are.
+const MIN_SUBPROBLEMS: usize = 1024;
+
+// How often to check for cancellation and report progress.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+struct Subproblem {
+    fixed_values: FixedValues,
+    // The fraction of the search space covered by this subproblem.
+    weight: f64,
+}
+
+// The result of counting one subproblem.
+struct Message {
+    thread: usize,
+    subproblem: usize,
+    solutions: u64,
+    weight: f64,
+    // The total counters for the thread so far.
+    counters: Counters,
+}
+
+pub fn count(constraint: &Constraint, mut config: Config, num_threads: usize) -> Counters {
+    let mut progress_metadata = ProgressMetadata::new(&mut config);
+    let mut counters = Counters::default();
+
+    let mut splitter = engine::make_runner(constraint, Config::default());
+    let subproblems = split(constraint, splitter.as_mut(), &mut counters);
+    let split_counters = splitter.counters();
+    let split_ratio = counters.progress_ratio;
+    progress_metadata.maybe_call(&counters);
+
+    // Workers stop when this is cancelled, either by the caller or because a
+    // limit was reached.
+    let stop = CancellationToken::new();
+    let next_subproblem = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+
+    let mut thread_counters = vec![Counters::default(); num_threads];
+    let mut finished = vec![false; subproblems.len()];
+    let deterministic = config.deterministic;
+    thread::scope(|scope| {
+        for thread in 0..num_threads {
+            let sender = sender.clone();
+            let (time_limit, profile, stop) = (config.time_limit, config.profile, stop.clone());
+            let (subproblems, next_subproblem) = (&subproblems, &next_subproblem);
+            scope.spawn(move || {
+                let worker_config = Config {
+                    output_type: OutputType::Empty,
+                    time_limit,
+                    profile,
+                    cancellation: Some(stop),
+                    ..Config::default()
+                };
+                let mut runner = engine::make_runner(constraint, worker_config);
+                // The runner keeps what it learned about the cells from one
+                // subproblem to the next, so its counters depend on which
+                // subproblems it searched.
+                let mut own_subproblems = (thread..subproblems.len()).step_by(num_threads);
+                let mut next_index = || {
+                    if deterministic {
+                        own_subproblems.next()
+                    } else {
+                        Some(next_subproblem.fetch_add(1, Ordering::Relaxed))
+                    }
+                };
+                while let Some((index, subproblem)) =
+                    next_index().and_then(|i| Some((i, subproblems.get(i)?)))
+                {
+                    runner.reset_fixed_values(&subproblem.fixed_values);
+                    let solutions = std::iter::from_fn(|| runner.next()).count() as u64;
+                    let message = Message {
+                        thread,
+                        subproblem: index,
+                        solutions,
+                        weight: subproblem.weight,
+                        counters: runner.counters(),
+                    };
+                    if sender.send(message).is_err() || runner.stopped_early() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        let mut last_report = Instant::now();
+        loop {
+            match receiver.recv_timeout(POLL_INTERVAL) {
+                Ok(message) => {
+                    counters.solutions += message.solutions;
+                    // Subproblems which were stopped early aren't finished.
+                    if !message.counters.limit_reached && !message.counters.cancelled {
+                        counters.progress_ratio += message.weight;
+                        finished[message.subproblem] = true;
+                    }
+                    counters.limit_reached |= message.counters.limit_reached;
+                    thread_counters[message.thread] = message.counters;
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+
+            add_search_counters(&mut counters, &split_counters, &thread_counters);
+            if let Some(max_guesses) = config.max_guesses {
+                counters.limit_reached |= counters.guesses >= max_guesses;
+            }
+            if let Some(token) = &config.cancellation {
+                counters.cancelled |= token.is_cancelled();
+            }
+            if counters.limit_reached || counters.cancelled {
+                stop.cancel();
+            }
+            if last_report.elapsed() >= POLL_INTERVAL {
+                last_report = Instant::now();
+                progress_metadata.maybe_call(&counters);
+            }
+        }
+    });
+
+    // The weights are added again in order, as the sum of floats depends on the
+    // order the subproblems finished in.
+    counters.progress_ratio = split_ratio
+        + subproblems
+            .iter()
+            .zip(&finished)
+            .filter(|(_, &finished)| finished)
+            .map(|(subproblem, _)| subproblem.weight)
+            .sum::<f64>();
+    // Send the final set of progress counters.
+    progress_metadata.maybe_call(&counters);
+    counters
+}
+
+// Split the search into subproblems by branching on the cell with the fewest
+// values, breadth first. Solutions found while splitting are added to the
+// counters.
+fn split(
+    constraint: &Constraint,
+    runner: &mut dyn engine::Runner,
+    counters: &mut Counters,
+) -> Vec<Subproblem> {
+    let mut queue = VecDeque::from([Subproblem {
+        fixed_values: constraint.fixed_values.clone(),
+        weight: 1.0,
+    }]);
+
+    while queue.len() < MIN_SUBPROBLEMS {
+        let Some(subproblem) = queue.pop_front() else {
+            break;
+        };
+
+        runner.reset_fixed_values(&subproblem.fixed_values);
+        match runner.propagate() {
+            Propagation::Contradiction => {
+                counters.progress_ratio += subproblem.weight;
+            }
+            Propagation::Solved => {
+                counters.solutions += 1;
+                counters.progress_ratio += subproblem.weight;
+            }
+            Propagation::Branch(cell, values) => {
+                let weight = subproblem.weight / values.len() as f64;
+                for value in values {
+                    let mut fixed_values = subproblem.fixed_values.clone();
+                    fixed_values.push((cell, value));
+                    queue.push_back(Subproblem {
+                        fixed_values,
+                        weight,
+                    });
+                }
+            }
+        }
+    }
+
+    queue.into()
+}
+
+// Sum the search counters over the splitter and all the threads.
+fn add_search_counters(
+    counters: &mut Counters,
+    split_counters: &Counters,
+    thread_counters: &[Counters],
+) {
+    let total =
+        |f: fn(&Counters) -> u64| f(split_counters) + thread_counters.iter().map(f).sum::<u64>();
+    counters.guesses = total(|c| c.guesses);
+    counters.constraints_processed = total(|c| c.constraints_processed);
+    counters.values_tried = total(|c| c.values_tried);
+    counters.cells_searched = total(|c| c.cells_searched);
+    counters.backtracks = total(|c| c.backtracks);
+    counters.peak_memory = total(|c| c.peak_memory);
+    if thread_counters.iter().any(|c| c.handler_profile.is_some()) {
+        let mut profile = HandlerProfile::default();
+        for c in thread_counters {
+            if let Some(thread_profile) = &c.handler_profile {
+                profile.add(thread_profile);
+            }
+        }
+        counters.handler_profile = Some(profile);
+    }
+}
@@ -0,0 +1,123 @@
+// Estimates how far through the search the propagation engine is, from the
+// sizes of the subtrees searched so far.
+//
+// `Counters::progress_ratio` is otherwise the share of the search space which
+// has been searched, giving each value of a cell the same share. When the first
+// value of a cell has most of the work below it, the ratio stays near 0 until
+// that value is finished, then jumps. Instead, each value still to be tried is
+// assumed to take as long as the guesses already finished at the same depth,
+// and the progress is the share of the estimated total work which is done.
+#[derive(Default)]
+pub struct ProgressEstimator {
+    // The cell being searched at each depth, for depths up to the deepest
+    // reached.
+    levels: Vec<Level>,
+    // The values tried before the search (or the last restart) started.
+    start: u64,
+    // A restored checkpoint doesn't record the levels, so there is no estimate
+    // until the search is reset.
+    valid: bool,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Level {
+    // The values of the cell left to try, including the last value, which is
+    // tried without a guess.
+    untried: u64,
+    // The values tried when the current guess at this depth was made.
+    guess_start: Option<u64>,
+    // The sizes, in values tried, of the subtrees below the guesses finished
+    // at this depth, for any cell.
+    finished: u64,
+    total_size: u64,
+    min_size: u64,
+    max_size: u64,
+}
+
+impl ProgressEstimator {
+    pub fn reset(&mut self, values_tried: u64) {
+        self.levels.clear();
+        self.start = values_tried;
+        self.valid = true;
+    }
+
+    pub fn invalidate(&mut self) {
+        self.valid = false;
+    }
+
+    // A new cell with `count` values is searched at `depth`.
+    #[inline]
+    pub fn start_cell(&mut self, depth: usize, count: u64) {
+        if depth >= self.levels.len() {
+            self.levels.resize(depth + 1, Level::default());
+        }
+        let level = &mut self.levels[depth];
+        level.untried = count;
+        level.guess_start = None;
+    }
+
+    #[inline]
+    pub fn guess(&mut self, depth: usize, values_tried: u64) {
+        if let Some(level) = self.levels.get_mut(depth) {
+            level.untried = level.untried.saturating_sub(1);
+            level.guess_start = Some(values_tried);
+        }
+    }
+
+    // The search returned to `depth`, after searching below its last guess.
+    #[inline]
+    pub fn finish_guess(&mut self, depth: usize, values_tried: u64) {
+        let Some(level) = self.levels.get_mut(depth) else {
+            return;
+        };
+        let Some(start) = level.guess_start.take() else {
+            return;
+        };
+        let size = values_tried - start;
+        level.min_size = if level.finished == 0 {
+            size
+        } else {
+            level.min_size.min(size)
+        };
+        level.max_size = level.max_size.max(size);
+        level.total_size += size;
+        level.finished += 1;
+    }
+
+    // The estimated progress, with its pessimistic and optimistic bounds, when
+    // the cells at the first `depth` levels are being searched. Values which
+    // are left to try take the mean, largest and smallest size of the
+    // finished guesses at their depth, and at least as long as the current
+    // guess for the pessimistic bound. There is no estimate before the first
+    // guess, unless the search has already finished.
+    pub fn estimate(
+        &self,
+        depth: usize,
+        values_tried: u64,
+        finished: bool,
+    ) -> Option<(f64, f64, f64)> {
+        if !self.valid || depth > self.levels.len() || (depth == 0 && !finished) {
+            return None;
+        }
+        let done = values_tried.saturating_sub(self.start).max(1) as f64;
+        let (mut expected, mut pessimistic, mut optimistic) = (0.0, 0.0, 0.0);
+        for level in &self.levels[..depth] {
+            let untried = level.untried as f64;
+            let current = level.guess_start.map_or(0, |start| values_tried - start);
+            if level.finished > 0 {
+                expected += untried * level.total_size as f64 / level.finished as f64;
+                pessimistic += untried * level.max_size.max(current) as f64;
+                optimistic += untried * level.min_size.max(1) as f64;
+            } else {
+                expected += untried * current.max(1) as f64;
+                pessimistic += untried * current.max(1) as f64;
+                optimistic += untried;
+            }
+        }
+        Some((
+            done / (done + expected),
+            done / (done + pessimistic),
+            done / (done + optimistic),
+        ))
+    }
+}
@@ -0,0 +1,129 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::types::{CellIndex, CellValue, Shape};
+use crate::value_set::ValueSet;
+
+use super::cell_accumulator::CellAccumulator;
+use super::handlers::{self, Contradiction};
+use super::trail::TrailGrid;
+
+// A constraint handler which can be defined outside the solver. It is run
+// whenever the values of any of its cells change, and removes values which
+// can't be part of a solution.
+pub trait Propagator {
+    // The cells which the constraint depends on.
+    fn cells(&self) -> &[CellIndex];
+
+    // Remove values from the cells, returning an error if there is no
+    // solution. This must not depend on anything other than the values of
+    // the cells, as the solver backtracks by restoring the values.
+    fn propagate(&mut self, domains: &mut dyn Domains) -> handlers::Result;
+
+    // A short name, used when describing the handlers.
+    fn kind(&self) -> &'static str {
+        "custom"
+    }
+}
+
+// The values which are still possible for each cell.
+pub trait Domains {
+    fn num_values(&self) -> u32;
+
+    fn values(&self, cell: CellIndex) -> Vec<CellValue>;
+
+    fn contains(&self, cell: CellIndex, value: CellValue) -> bool;
+
+    fn count(&self, cell: CellIndex) -> usize;
+
+    // Remove any values which aren't in `allowed`, returning an error if there
+    // are none left.
+    fn restrict(&mut self, cell: CellIndex, allowed: &[CellValue]) -> handlers::Result;
+
+    // Remove the value, returning an error if there are none left.
+    fn remove(&mut self, cell: CellIndex, value: CellValue) -> handlers::Result;
+}
+
+pub type PropagatorFactory = dyn Fn(&Shape) -> Box<dyn Propagator> + Send + Sync;
+
+// Makes a propagator for each search. Searches may run in parallel, so the
+// propagators themselves aren't shared.
+#[derive(Clone)]
+pub struct CustomPropagator {
+    make: Arc<PropagatorFactory>,
+}
+
+impl CustomPropagator {
+    pub fn new<F>(make: F) -> CustomPropagator
+    where
+        F: Fn(&Shape) -> Box<dyn Propagator> + Send + Sync + 'static,
+    {
+        CustomPropagator {
+            make: Arc::new(make),
+        }
+    }
+
+    pub(super) fn make(&self, shape: &Shape) -> Box<dyn Propagator> {
+        (self.make)(shape)
+    }
+}
+
+impl fmt::Debug for CustomPropagator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CustomPropagator")
+    }
+}
+
+// The grid as seen by a propagator.
+pub(super) struct GridDomains<'a, VS> {
+    pub grid: &'a mut TrailGrid<VS>,
+    pub cell_accumulator: &'a mut CellAccumulator,
+    pub num_values: u32,
+}
+
+impl<VS: ValueSet> GridDomains<'_, VS> {
+    fn set(&mut self, cell: CellIndex, values: VS) -> handlers::Result {
+        if values.is_empty() {
+            return Err(Contradiction);
+        }
+        if values != self.grid[cell] {
+            self.grid.set(cell, values);
+            self.cell_accumulator.add(cell);
+        }
+        Ok(())
+    }
+}
+
+impl<VS: ValueSet> Domains for GridDomains<'_, VS> {
+    fn num_values(&self) -> u32 {
+        self.num_values
+    }
+
+    fn values(&self, cell: CellIndex) -> Vec<CellValue> {
+        self.grid[cell].iter().map(CellValue::from_index).collect()
+    }
+
+    fn contains(&self, cell: CellIndex, value: CellValue) -> bool {
+        !self.grid[cell]
+            .intersection(&VS::from_value(value.index()))
+            .is_empty()
+    }
+
+    fn count(&self, cell: CellIndex) -> usize {
+        self.grid[cell].count()
+    }
+
+    fn restrict(&mut self, cell: CellIndex, allowed: &[CellValue]) -> handlers::Result {
+        let allowed = allowed
+            .iter()
+            .fold(VS::empty(), |a, v| a.union(&VS::from_value(v.index())));
+        self.set(cell, self.grid[cell].intersection(&allowed))
+    }
+
+    fn remove(&mut self, cell: CellIndex, value: CellValue) -> handlers::Result {
+        self.set(
+            cell,
+            self.grid[cell].without(&VS::from_value(value.index())),
+        )
+    }
+}
@@ -0,0 +1,111 @@
+use crate::types::Constraint;
+
+use super::engine;
+use super::logical::{self, Technique};
+use super::{Config, Counters, OutputType};
+
+// Puzzles which need search are rated above every technique.
+const SEARCH_SCORE: f64 = 20.0;
+
+// Bands of the score, as used by puzzle collections.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Difficulty {
+    // Singles only.
+    Easy,
+    // Locked candidates, pairs and hidden pairs.
+    Medium,
+    // Larger tuples and fish.
+    Hard,
+    // Needs search.
+    Expert,
+}
+
+impl Difficulty {
+    pub fn from_score(score: f64) -> Difficulty {
+        match score {
+            s if s < 3.0 => Difficulty::Easy,
+            s if s < 6.0 => Difficulty::Medium,
+            s if s < SEARCH_SCORE => Difficulty::Hard,
+            _ => Difficulty::Expert,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Difficulty::Easy => "easy",
+            Difficulty::Medium => "medium",
+            Difficulty::Hard => "hard",
+            Difficulty::Expert => "expert",
+        }
+    }
+}
+
+pub struct Rating {
+    // The hardest technique used, and its size (e.g. 3 for a naked triple).
+    pub hardest_technique: Option<(Technique, usize)>,
+    // Whether the techniques were enough to solve the puzzle.
+    pub solved_by_techniques: bool,
+    // The search for the first solution, if the techniques weren't enough.
+    pub search_counters: Option<Counters>,
+    // Higher is harder. The same puzzle always gets the same score.
+    pub score: f64,
+}
+
+impl Rating {
+    pub fn difficulty(&self) -> Difficulty {
+        Difficulty::from_score(self.score)
+    }
+}
+
+// Grade a puzzle by the hardest technique needed to solve it, or by how much
+// searching is needed if the techniques aren't enough.
+pub fn rate(constraint: &Constraint, mut config: Config) -> Rating {
+    let explanation = logical::explain(constraint);
+    let hardest_technique = explanation
+        .deductions
+        .iter()
+        .map(|d| (d.technique, d.size))
+        .max_by_key(|&(technique, size)| technique_score(technique, size));
+
+    if explanation.solution.is_some() {
+        return Rating {
+            hardest_technique,
+            solved_by_techniques: true,
+            search_counters: None,
+            score: hardest_technique.map_or(0.0, |(t, s)| technique_score(t, s) as f64),
+        };
+    }
+
+    // Random choices would make the score change between runs.
+    config.search_randomizer = None;
+    config.restarts = None;
+    config.output_type = OutputType::Empty;
+    let mut runner = engine::make_runner(constraint, config);
+    runner.next();
+    let counters = runner.counters();
+
+    // Each guess or backtrack roughly doubles the work for a person, and
+    // each extra constraint processed per cell adds a little more.
+    let search_effort = (1 + counters.guesses + counters.backtracks) as f64;
+    let cells = constraint.shape.num_cells as f64;
+    let propagation_effort = counters.constraints_processed as f64 / cells;
+    Rating {
+        hardest_technique,
+        solved_by_techniques: false,
+        search_counters: Some(counters),
+        score: SEARCH_SCORE + search_effort.log2() + (1.0 + propagation_effort).log10(),
+    }
+}
+
+// The techniques in order of difficulty, from 1 for a naked single.
+fn technique_score(technique: Technique, size: usize) -> u32 {
+    let size = size as u32;
+    match technique {
+        Technique::NakedSingle => 1,
+        Technique::HiddenSingle => 2,
+        Technique::LockedCandidates => 3,
+        Technique::NakedTuple => 2 * size,
+        Technique::HiddenTuple => 2 * size + 1,
+        Technique::Fish => 8 + size,
+    }
+}
@@ -0,0 +1,615 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::types::{
+    xv_negative_sums, CellIndex, CellValue, Constraint, FixedValues, KropkiKind, ValueType,
+    VariantConstraint,
+};
+use crate::value_set::{DynBitSet, ValueSet};
+
+use super::cdcl::{Lit, SatSolver, Var};
+use super::engine::{Checkpoint, ProgressMetadata, Propagation, Runner};
+use super::handlers;
+use super::{Config, Counters, Instant, Output, OutputType};
+
+// Wide enough for the classes of any valid shape.
+type ClassSet = DynBitSet;
+
+// Cell lists with at most this many literals use the pairwise at-most-one
+// encoding, and longer lists use the sequential encoding.
+const MAX_PAIRWISE_LITS: usize = 8;
+
+// Encodes the constraints as clauses. The variable `cell * num_values + value`
+// is true when the cell has the value, and any further variables are auxiliary.
+struct Encoder {
+    num_values: usize,
+    num_vars: usize,
+    clauses: Vec<Vec<Lit>>,
+}
+
+impl Encoder {
+    fn lit(&self, cell: CellIndex, value: usize) -> Lit {
+        Lit::pos((cell * self.num_values + value) as Var)
+    }
+
+    fn new_var(&mut self) -> Var {
+        self.num_vars += 1;
+        (self.num_vars - 1) as Var
+    }
+
+    fn exactly_one(&mut self, lits: &[Lit]) {
+        self.clauses.push(lits.to_vec());
+        self.at_most_one(lits);
+    }
+
+    fn at_most_one(&mut self, lits: &[Lit]) {
+        if lits.len() <= MAX_PAIRWISE_LITS {
+            for (i, &a) in lits.iter().enumerate() {
+                for &b in &lits[i + 1..] {
+                    self.clauses.push(vec![!a, !b]);
+                }
+            }
+            return;
+        }
+
+        // Sequential encoding: `seen[i]` is true if any of the first i+1
+        // literals is true.
+        let seen = (0..lits.len() - 1)
+            .map(|_| self.new_var())
+            .collect::<Vec<_>>();
+        for (i, &lit) in lits.iter().enumerate() {
+            if i + 1 < lits.len() {
+                self.clauses.push(vec![!lit, Lit::pos(seen[i])]);
+            }
+            if i > 0 {
+                self.clauses.push(vec![!lit, Lit::neg(seen[i - 1])]);
+                if i + 1 < lits.len() {
+                    self.clauses
+                        .push(vec![Lit::neg(seen[i - 1]), Lit::pos(seen[i])]);
+                }
+            }
+        }
+    }
+
+    // Each value appears at most once in the cells.
+    fn unique_values(&mut self, cells: &[CellIndex]) {
+        for v in 0..self.num_values {
+            let lits = cells.iter().map(|&c| self.lit(c, v)).collect::<Vec<_>>();
+            self.at_most_one(&lits);
+        }
+    }
+
+    // `pred` is called with display values.
+    fn relation<F: Fn(i64, i64) -> bool>(&mut self, cell0: CellIndex, cell1: CellIndex, pred: F) {
+        for a in 0..self.num_values {
+            for b in 0..self.num_values {
+                if !pred(a as i64 + 1, b as i64 + 1) {
+                    self.clauses
+                        .push(vec![!self.lit(cell0, a), !self.lit(cell1, b)]);
+                }
+            }
+        }
+    }
+
+    // Enforce the relation between adjacent cells without a marker.
+    fn negative_relation<M, F>(&mut self, constraint: &Constraint, is_marked: M, pred: F)
+    where
+        M: Fn(&VariantConstraint) -> bool,
+        F: Fn(i64, i64) -> bool,
+    {
+        let marked_pairs = constraint
+            .variants
+            .iter()
+            .filter(|v| is_marked(v))
+            .map(|v| handlers::ordered_pair(v.cells()[0], v.cells()[1]))
+            .collect::<HashSet<_>>();
+        for (cell0, cell1) in handlers::make_adjacent_pairs(&constraint.shape) {
+            if !marked_pairs.contains(&(cell0, cell1)) {
+                self.relation(cell0, cell1, &pred);
+            }
+        }
+    }
+
+    fn line_relation<F: Fn(i64, i64) -> bool>(&mut self, cells: &[CellIndex], pred: F) {
+        for pair in cells.windows(2) {
+            self.relation(pair[0], pair[1], &pred);
+        }
+    }
+
+    // The sum of `weight(i, value)` over the cells is a total allowed by
+    // `allowed`. Partial sums are tracked with a variable for each reachable
+    // total after each cell, which is implied by the previous total and the
+    // cell's value.
+    fn sum<W, A>(&mut self, cells: &[CellIndex], weight: W, allowed: A)
+    where
+        W: Fn(usize, usize) -> i64,
+        A: Fn(i64) -> bool,
+    {
+        // The totals so far, and the variable for each (None for the empty sum,
+        // which is always true).
+        let mut totals: Vec<(i64, Option<Var>)> = vec![(0, None)];
+        for (i, &cell) in cells.iter().enumerate() {
+            let mut next: HashMap<i64, Var> = HashMap::new();
+            for (total, var) in totals {
+                for v in 0..self.num_values {
+                    let next_total = total + weight(i, v);
+                    let next_var = match next.get(&next_total) {
+                        Some(&var) => var,
+                        None => {
+                            let var = self.new_var();
+                            next.insert(next_total, var);
+                            var
+                        }
+                    };
+                    let mut clause = vec![!self.lit(cell, v), Lit::pos(next_var)];
+                    if let Some(var) = var {
+                        clause.push(Lit::neg(var));
+                    }
+                    self.clauses.push(clause);
+                }
+            }
+            totals = next
+                .into_iter()
+                .map(|(total, var)| (total, Some(var)))
+                .collect();
+            totals.sort_unstable();
+        }
+
+        for (total, var) in totals {
+            if !allowed(total) {
+                match var {
+                    Some(var) => self.clauses.push(vec![Lit::neg(var)]),
+                    // The empty sum is all there is.
+                    None => self.clauses.push(Vec::new()),
+                }
+            }
+        }
+    }
+
+    // The cells are in distinct classes wherever they are less than the number
+    // of classes apart.
+    fn class_line(&mut self, cells: &[CellIndex], classes: &[ClassSet]) {
+        let class_of = |v: usize| {
+            classes.iter().position(|class| {
+                !class
+                    .intersection(&ClassSet::from_value(v as ValueType))
+                    .is_empty()
+            })
+        };
+        for (i, &cell0) in cells.iter().enumerate() {
+            for &cell1 in cells.iter().skip(i + 1).take(classes.len() - 1) {
+                for a in 0..self.num_values {
+                    for b in 0..self.num_values {
+                        if class_of(a).is_some() && class_of(a) == class_of(b) {
+                            self.clauses
+                                .push(vec![!self.lit(cell0, a), !self.lit(cell1, b)]);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn check_supported(constraint: &Constraint) -> Result<(), String> {
+    if constraint.propagators.is_empty() {
+        Ok(())
+    } else {
+        Err("The sat engine doesn't support custom propagators.".to_string())
+    }
+}
+
+// Encode the constraint as CNF, returning the number of variables and the
+// clauses. The fixed values aren't included.
+fn encode(constraint: &Constraint) -> (usize, Vec<Vec<Lit>>) {
+    let shape = &constraint.shape;
+    let num_values = shape.num_values as usize;
+    let mut encoder = Encoder {
+        num_values,
+        num_vars: shape.num_cells * num_values,
+        clauses: Vec::new(),
+    };
+
+    // Each cell has exactly one of its candidate values.
+    for cell in 0..shape.num_cells {
+        let lits = (0..num_values)
+            .map(|v| encoder.lit(cell, v))
+            .collect::<Vec<_>>();
+        encoder.exactly_one(&lits);
+    }
+    for (cell, values) in &constraint.candidates {
+        for v in 0..num_values {
+            if !values.iter().any(|value| value.index() as usize == v) {
+                encoder.clauses.push(vec![!encoder.lit(*cell, v)]);
+            }
+        }
+    }
+
+    // Each house contains every value exactly once.
+    for house in handlers::make_houses(constraint) {
+        for v in 0..num_values {
+            let lits = house.iter().map(|&c| encoder.lit(c, v)).collect::<Vec<_>>();
+            encoder.exactly_one(&lits);
+        }
+    }
+
+    if constraint.anti_king {
+        for cells in handlers::make_anti_king_pairs(shape) {
+            encoder.unique_values(&cells);
+        }
+    }
+    if constraint.argyle {
+        for cells in handlers::make_argyle_lines(shape) {
+            encoder.unique_values(&cells);
+        }
+    }
+
+    let display = |v: usize| v as i64 + 1;
+    for variant in &constraint.variants {
+        match variant {
+            VariantConstraint::Cage {
+                cells,
+                sum,
+                allow_repeats,
+            } => {
+                if !allow_repeats {
+                    encoder.unique_values(cells);
+                }
+                encoder.sum(cells, |_, v| display(v), |total| total == *sum as i64);
+            }
+            VariantConstraint::Thermo { cells } => encoder.line_relation(cells, |a, b| a < b),
+            VariantConstraint::Arrow { cells } => encoder.sum(
+                cells,
+                // The circle is subtracted from the sum of the arrow.
+                |i, v| if i == 0 { -display(v) } else { display(v) },
+                |total| total == 0,
+            ),
+            VariantConstraint::Renban { cells } => {
+                encoder.unique_values(cells);
+                let len = cells.len() as i64;
+                for (i, &cell0) in cells.iter().enumerate() {
+                    for &cell1 in &cells[i + 1..] {
+                        encoder.relation(cell0, cell1, |a, b| (a - b).abs() < len);
+                    }
+                }
+            }
+            VariantConstraint::Whisper { cells, difference } => {
+                let difference = *difference as i64;
+                encoder.line_relation(cells, |a, b| (a - b).abs() >= difference)
+            }
+            VariantConstraint::Kropki { cells, kind } => {
+                encoder.line_relation(cells, |a, b| handlers::kropki_relation(*kind, a, b))
+            }
+            VariantConstraint::Consecutive { cells } => encoder.line_relation(cells, |a, b| {
+                handlers::kropki_relation(KropkiKind::White, a, b)
+            }),
+            VariantConstraint::Xv { cells, sum } => {
+                let sum = *sum as i64;
+                encoder.line_relation(cells, |a, b| a + b == sum)
+            }
+            VariantConstraint::CloneRegion { cells } => {
+                let (cells0, cells1) = cells.split_at(cells.len() / 2);
+                for (&cell0, &cell1) in cells0.iter().zip(cells1) {
+                    encoder.relation(cell0, cell1, |a, b| a == b);
+                }
+            }
+            VariantConstraint::Entropic { cells } => {
+                encoder.class_line(cells, &handlers::make_entropic_bands(shape))
+            }
+            VariantConstraint::Modular { cells, modulus } => {
+                encoder.class_line(cells, &handlers::make_residue_classes(*modulus, shape))
+            }
+            VariantConstraint::RegionSum { cells } => {
+                let segments = handlers::region_sum_segments(cells, shape);
+                for segment in segments.iter().skip(1) {
+                    // This segment has the same sum as the first.
+                    let pair = cells[segments[0].clone()]
+                        .iter()
+                        .chain(&cells[segment.clone()])
+                        .copied()
+                        .collect::<Vec<_>>();
+                    let first_len = segments[0].len();
+                    encoder.sum(
+                        &pair,
+                        |i, v| {
+                            if i < first_len {
+                                display(v)
+                            } else {
+                                -display(v)
+                            }
+                        },
+                        |total| total == 0,
+                    );
+                }
+            }
+            VariantConstraint::Quadruple { cells, values } => {
+                let mut values = values
+                    .iter()
+                    .map(|v| v.index() as usize)
+                    .collect::<Vec<_>>();
+                values.sort_unstable();
+                for chunk in values.chunk_by(|a, b| a == b) {
+                    let (value, count) = (chunk[0], chunk.len() as i64);
+                    encoder.sum(cells, |_, v| (v == value) as i64, |total| total >= count);
+                }
+            }
+            VariantConstraint::ValueCount {
+                cells,
+                value,
+                count,
+            } => {
+                let value = value.index() as usize;
+                encoder.sum(
+                    cells,
+                    |_, v| (v == value) as i64,
+                    |total| total == *count as i64,
+                );
+            }
+            VariantConstraint::Region { cells } => {
+                // Full regions were added with the houses.
+                if cells.len() < num_values {
+                    encoder.unique_values(cells);
+                }
+            }
+            VariantConstraint::Inequality { cells } => encoder.line_relation(cells, |a, b| a < b),
+            VariantConstraint::Parity { cells, even } => {
+                for &cell in cells {
+                    for v in 0..num_values {
+                        if (display(v) % 2 == 0) != *even {
+                            encoder.clauses.push(vec![!encoder.lit(cell, v)]);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    if constraint.kropki_negative {
+        let is_marked = |v: &VariantConstraint| matches!(v, VariantConstraint::Kropki { .. });
+        encoder.negative_relation(constraint, is_marked, |a, b| {
+            !handlers::kropki_relation(KropkiKind::White, a, b)
+                && !handlers::kropki_relation(KropkiKind::Black, a, b)
+        });
+    }
+
+    if constraint.consecutive_negative {
+        let is_marked = |v: &VariantConstraint| {
+            matches!(
+                v,
+                VariantConstraint::Consecutive { .. }
+                    | VariantConstraint::Kropki {
+                        kind: KropkiKind::White,
+                        ..
+                    }
+            )
+        };
+        encoder.negative_relation(constraint, is_marked, |a, b| {
+            !handlers::kropki_relation(KropkiKind::White, a, b)
+        });
+    }
+
+    if constraint.xv_negative {
+        let sums = xv_negative_sums(constraint);
+        let is_marked = |v: &VariantConstraint| matches!(v, VariantConstraint::Xv { .. });
+        encoder.negative_relation(constraint, is_marked, |a, b| {
+            !sums.contains(&((a + b) as u32))
+        });
+    }
+
+    (encoder.num_vars, encoder.clauses)
+}
+
+// Finds solutions with a SAT solver, blocking each solution once it is found so
+// that the next search finds a different one. This is much slower than the
+// other engines for counting, but is an independent check of uniqueness.
+pub struct SatRunner {
+    num_cells: usize,
+    num_values: usize,
+    num_vars: usize,
+    clauses: Vec<Vec<Lit>>,
+    solver: SatSolver,
+    finished: bool,
+    // Solver stats from before the last reset.
+    base_counters: Counters,
+    progress_metadata: ProgressMetadata,
+    counters: Counters,
+    config: Config,
+    deadline: Option<Instant>,
+}
+
+impl SatRunner {
+    pub fn new(constraint: &Constraint, mut config: Config) -> SatRunner {
+        let (num_vars, clauses) = encode(constraint);
+        let progress_metadata = ProgressMetadata::new(&mut config);
+        let mut runner = SatRunner {
+            num_cells: constraint.shape.num_cells,
+            num_values: constraint.shape.num_values as usize,
+            num_vars,
+            clauses,
+            solver: SatSolver::new(0),
+            finished: false,
+            base_counters: Counters::default(),
+            progress_metadata,
+            counters: Counters::default(),
+            deadline: config.time_limit.map(|limit| Instant::now() + limit),
+            config,
+        };
+        runner.reset_fixed_values(&constraint.fixed_values);
+        runner
+    }
+
+    fn lit(&self, cell: CellIndex, value: usize) -> Lit {
+        Lit::pos((cell * self.num_values + value) as Var)
+    }
+
+    // The values which the solver hasn't ruled out at the root.
+    fn cell_values(&self, cell: CellIndex) -> Vec<CellValue> {
+        (0..self.num_values)
+            .filter(|&v| self.solver.value(self.lit(cell, v).var()) != Some(false))
+            .map(|v| CellValue::from_index(v as ValueType))
+            .collect()
+    }
+
+    fn update_counters(&mut self) {
+        let stats = self.solver.stats;
+        self.counters.guesses = self.base_counters.guesses + stats.decisions;
+        self.counters.values_tried = self.base_counters.values_tried + stats.decisions;
+        self.counters.cells_searched = self.base_counters.cells_searched + stats.decisions;
+        self.counters.backtracks = self.base_counters.backtracks + stats.conflicts;
+        self.counters.restarts = self.base_counters.restarts + stats.restarts;
+        self.counters.constraints_processed =
+            self.base_counters.constraints_processed + stats.propagations;
+    }
+
+    fn propagation_result(&mut self) -> Propagation {
+        if !self.solver.propagate_root() {
+            return Propagation::Contradiction;
+        }
+        let mut branch: Option<(CellIndex, Vec<CellValue>)> = None;
+        for cell in 0..self.num_cells {
+            let values = self.cell_values(cell);
+            match values.len() {
+                0 => return Propagation::Contradiction,
+                1 => {}
+                n => {
+                    if branch.as_ref().is_none_or(|(_, best)| n < best.len()) {
+                        branch = Some((cell, values));
+                    }
+                }
+            }
+        }
+        match branch {
+            None => Propagation::Solved,
+            Some((cell, values)) => Propagation::Branch(cell, values),
+        }
+    }
+}
+
+impl Runner for SatRunner {
+    fn reset_fixed_values(&mut self, fixed_values: &FixedValues) {
+        self.update_counters();
+        self.base_counters = self.counters;
+
+        self.solver = SatSolver::new(self.num_vars);
+        for clause in &self.clauses {
+            self.solver.add_clause(clause);
+        }
+        for (cell, value) in fixed_values {
+            let lit = self.lit(*cell, value.index() as usize);
+            self.solver.add_clause(&[lit]);
+        }
+        self.finished = false;
+
+        self.counters.progress_ratio = 0.0;
+        self.counters.solutions = 0;
+        self.counters.peak_memory = self.counters.peak_memory.max(
+            (super::vec_memory(&self.clauses)
+                + self.clauses.iter().map(super::vec_memory).sum::<usize>()) as u64,
+        );
+    }
+
+    fn next(&mut self) -> Option<Output> {
+        if self.finished || self.stopped_early() {
+            return None;
+        }
+        self.progress_metadata.maybe_call(&self.counters);
+
+        const TIME_CHECK_MASK: u64 = (1 << 8) - 1;
+        let (config, deadline, counters) = (&self.config, self.deadline, &mut self.counters);
+        let progress_metadata = &mut self.progress_metadata;
+        let base_counters = self.base_counters;
+        let result = self.solver.solve(&mut |stats| {
+            let guesses = base_counters.guesses + stats.decisions;
+            if config.max_guesses.is_some_and(|max| guesses >= max) {
+                counters.limit_reached = true;
+            }
+            if let Some(deadline) = deadline {
+                if stats.decisions & TIME_CHECK_MASK == 0 && Instant::now() >= deadline {
+                    counters.limit_reached = true;
+                }
+            }
+            if config
+                .cancellation
+                .as_ref()
+                .is_some_and(|t| t.is_cancelled())
+            {
+                counters.cancelled = true;
+            }
+            counters.guesses = guesses;
+            counters.backtracks = base_counters.backtracks + stats.conflicts;
+            progress_metadata.maybe_call_thottled(stats.propagations, counters);
+            counters.limit_reached || counters.cancelled
+        });
+        self.update_counters();
+
+        let output = match result {
+            Some(true) => {
+                let solution = (0..self.num_cells)
+                    .map(|cell| {
+                        let v = (0..self.num_values)
+                            .find(|&v| self.solver.value(self.lit(cell, v).var()) == Some(true))
+                            .expect("Cell without a value in the model");
+                        CellValue::from_index(v as ValueType)
+                    })
+                    .collect::<Vec<_>>();
+
+                // Block this solution, so that the next search finds another.
+                let blocking = solution
+                    .iter()
+                    .enumerate()
+                    .map(|(cell, value)| !self.lit(cell, value.index() as usize))
+                    .collect::<Vec<_>>();
+                self.solver.add_clause(&blocking);
+
+                self.counters.solutions += 1;
+                Some(match self.config.output_type {
+                    OutputType::Empty => Output::Empty,
+                    OutputType::Solution => Output::Solution(solution),
+                    // The solver doesn't guess cell values, so the whole
+                    // solution is given.
+                    OutputType::Guesses => {
+                        Output::Guesses(solution.into_iter().enumerate().collect())
+                    }
+                })
+            }
+            Some(false) => {
+                self.finished = true;
+                self.counters.progress_ratio = 1.0;
+                None
+            }
+            None => None,
+        };
+        self.progress_metadata.maybe_call(&self.counters);
+        output
+    }
+
+    fn stopped_early(&self) -> bool {
+        self.counters.limit_reached || self.counters.cancelled
+    }
+
+    fn checkpoint(&self) -> Result<Checkpoint, String> {
+        Err("Checkpoints are not supported by the sat engine.".to_string())
+    }
+
+    fn restore(&mut self, _checkpoint: &Checkpoint) -> Result<(), String> {
+        Err("Checkpoints are not supported by the sat engine.".to_string())
+    }
+
+    fn propagate(&mut self) -> Propagation {
+        self.finished = false;
+        self.propagation_result()
+    }
+
+    fn assign(&mut self, cell: CellIndex, value: CellValue) -> Propagation {
+        let lit = self.lit(cell, value.index() as usize);
+        self.solver.add_clause(&[lit]);
+        self.propagation_result()
+    }
+
+    fn counters(&self) -> Counters {
+        self.counters
+    }
+
+    fn partial_assignment(&self) -> FixedValues {
+        Vec::new()
+    }
+}
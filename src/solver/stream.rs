@@ -0,0 +1,92 @@
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::types::Constraint;
+
+use super::engine;
+use super::{CancellationToken, Config, Counters, Output};
+
+// Solutions from a search running on its own thread. The search stops when
+// `buffer_size` solutions are waiting to be taken, and continues as they are
+// taken, so the caller can process them at its own pace.
+pub struct SolutionStream {
+    receiver: Option<mpsc::Receiver<Output>>,
+    stop: CancellationToken,
+    counters: Arc<Mutex<Counters>>,
+    handle: Option<thread::JoinHandle<Counters>>,
+}
+
+impl SolutionStream {
+    pub(super) fn new(constraint: &Constraint, config: Config, buffer_size: usize) -> Self {
+        // The config's token is used if there is one, so that the caller can
+        // also stop the search.
+        let stop = config.cancellation.clone().unwrap_or_default();
+        // The callbacks can't be sent to the search thread, so they aren't used.
+        // Progress is available from `counters` instead.
+        let make_config = super::sendable_config(config);
+        let counters = Arc::new(Mutex::new(Counters::default()));
+        let (sender, receiver) = mpsc::sync_channel(buffer_size);
+        let constraint = constraint.clone();
+        let handle = {
+            let (stop, counters) = (stop.clone(), counters.clone());
+            thread::spawn(move || {
+                let config = Config {
+                    progress_callback: Some(Box::new(move |c: &Counters| {
+                        *counters.lock().unwrap() = *c;
+                    })),
+                    cancellation: Some(stop),
+                    ..make_config()
+                };
+                let mut runner = engine::make_runner(&constraint, config);
+                while let Some(output) = runner.next() {
+                    // The stream was dropped or finished while the search was
+                    // waiting for space in the buffer.
+                    if sender.send(output).is_err() {
+                        return Counters {
+                            cancelled: true,
+                            ..runner.counters()
+                        };
+                    }
+                }
+                runner.counters()
+            })
+        };
+
+        SolutionStream {
+            receiver: Some(receiver),
+            stop,
+            counters,
+            handle: Some(handle),
+        }
+    }
+
+    // The counters so far. These are updated as often as the progress callback
+    // would be called.
+    pub fn counters(&self) -> Counters {
+        *self.counters.lock().unwrap()
+    }
+
+    // Stop the search if it is still running, and return the final counters.
+    pub fn finish(mut self) -> Counters {
+        self.stop.cancel();
+        // Let the search continue if it is waiting for space in the buffer.
+        self.receiver = None;
+        self.handle.take().unwrap().join().unwrap()
+    }
+}
+
+impl Iterator for SolutionStream {
+    type Item = Output;
+
+    fn next(&mut self) -> Option<Output> {
+        self.receiver.as_ref()?.recv().ok()
+    }
+}
+
+impl Drop for SolutionStream {
+    fn drop(&mut self) {
+        // The search thread stops by itself once the receiver is dropped.
+        self.stop.cancel();
+    }
+}
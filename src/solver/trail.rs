@@ -0,0 +1,342 @@
+use std::ops::Deref;
+
+use crate::types::CellIndex;
+use crate::value_set::ValueSet;
+
+use super::GridStrategy;
+
+// Cells are copied in blocks of this size (as a power of 2) by the
+// copy-on-write strategy.
+const BLOCK_SHIFT: usize = 4;
+
+// The cell values for the search, with a trail of the changes made at each
+// level so that they can be undone when backtracking. This avoids copying the
+// whole grid for every guess.
+//
+// Reads go through `Deref`, but all writes must use `set` so that they are
+// recorded.
+pub struct TrailGrid<VS> {
+    values: Vec<VS>,
+    strategy: GridStrategy,
+    // Trail: the previous value of each cell changed since the first level.
+    trail: Vec<(CellIndex, VS)>,
+    // Copy-on-write: each block copied since the first level, with the start
+    // of its copy in `saved`.
+    blocks: Vec<(usize, usize)>,
+    saved: Vec<VS>,
+    // The start of each level in `trail` and `blocks`.
+    levels: Vec<(usize, usize)>,
+    // The level stamp at which each cell (or block) was last recorded, so that
+    // it is only recorded once per level.
+    stamps: Vec<u64>,
+    stamp: u64,
+    // The number of values removed by `set`, if they are being counted.
+    removals: Option<u64>,
+    // Once recording another level would take the trail over `max_memory`,
+    // the values at that level are kept in `snapshot`, and deeper levels are
+    // no longer recorded. Only the cells changed since the snapshot and the
+    // engine's decisions are kept, and the deeper levels are rebuilt by
+    // replaying the decisions.
+    max_memory: Option<usize>,
+    snapshot_level: Option<usize>,
+    snapshot: Vec<VS>,
+    changed: Vec<CellIndex>,
+    is_changed: Vec<bool>,
+    decisions: Vec<Decision<VS>>,
+}
+
+// A value set by the engine rather than by propagation, after the trail stopped
+// recording.
+pub struct Decision<VS> {
+    // The level the value was set in.
+    pub level: usize,
+    pub cell: CellIndex,
+    pub values: VS,
+    // Whether this is a guess, rather than the removal of a guess which was
+    // tried.
+    pub guess: bool,
+}
+
+impl<VS: ValueSet> TrailGrid<VS> {
+    pub fn new(num_cells: usize, strategy: GridStrategy) -> TrailGrid<VS> {
+        TrailGrid {
+            values: vec![VS::empty(); num_cells],
+            strategy,
+            trail: Vec::new(),
+            blocks: Vec::new(),
+            saved: Vec::new(),
+            // There is at most one level for each cell.
+            levels: Vec::with_capacity(num_cells),
+            stamps: vec![0; num_cells],
+            stamp: 1,
+            removals: None,
+            max_memory: None,
+            snapshot_level: None,
+            snapshot: Vec::new(),
+            changed: Vec::new(),
+            is_changed: Vec::new(),
+            decisions: Vec::new(),
+        }
+    }
+
+    // Stop recording levels once the trail would use more than `max_memory`
+    // bytes.
+    pub fn set_max_memory(&mut self, max_memory: Option<usize>) {
+        self.max_memory = max_memory;
+    }
+
+    // Whether deeper levels are being rebuilt by replaying decisions, so that
+    // the trail can't give the values at each level.
+    pub fn is_recomputing(&self) -> bool {
+        self.snapshot_level.is_some()
+    }
+
+    // The decisions to replay after `pop_to_level` returns true, in order.
+    pub fn decisions(&self) -> &[Decision<VS>] {
+        &self.decisions
+    }
+
+    // Start counting the values removed by `set`.
+    pub fn count_removals(&mut self) {
+        self.removals.get_or_insert(0);
+    }
+
+    pub fn removals(&self) -> u64 {
+        self.removals.unwrap_or(0)
+    }
+
+    #[inline]
+    pub fn set(&mut self, cell: CellIndex, value: VS) {
+        if let Some(snapshot_level) = self.snapshot_level {
+            if self.levels.len() > snapshot_level {
+                if !self.is_changed[cell] {
+                    self.changed.push(cell);
+                    self.is_changed[cell] = true;
+                }
+            } else {
+                self.snapshot[cell] = value.clone();
+                self.record(cell);
+            }
+        } else {
+            self.record(cell);
+        }
+        if let Some(removals) = &mut self.removals {
+            *removals += self.values[cell].count().saturating_sub(value.count()) as u64;
+        }
+        self.values[cell] = value;
+    }
+
+    // Set a value chosen by the engine, which is replayed if the level is
+    // rebuilt.
+    #[inline]
+    pub fn set_decision(&mut self, cell: CellIndex, values: VS, guess: bool) {
+        let level = self.levels.len();
+        if self
+            .snapshot_level
+            .is_some_and(|snapshot_level| level > snapshot_level)
+        {
+            self.decisions.push(Decision {
+                level,
+                cell,
+                values: values.clone(),
+                guess,
+            });
+        }
+        self.set(cell, values);
+    }
+
+    #[inline]
+    fn record(&mut self, cell: CellIndex) {
+        // Nothing below the first level is ever undone.
+        if !self.levels.is_empty() {
+            match self.strategy {
+                GridStrategy::Trail => {
+                    if self.stamps[cell] != self.stamp {
+                        self.trail.push((cell, self.values[cell].clone()));
+                        self.stamps[cell] = self.stamp;
+                    }
+                }
+                GridStrategy::CopyOnWrite => {
+                    let block = cell >> BLOCK_SHIFT;
+                    if self.stamps[block] != self.stamp {
+                        self.blocks.push((block, self.saved.len()));
+                        self.saved
+                            .extend_from_slice(&self.values[block_range(block, self.values.len())]);
+                        self.stamps[block] = self.stamp;
+                    }
+                }
+            }
+        }
+    }
+
+    // Replace all the values, and forget the trail.
+    pub fn reset(&mut self, values: &[VS]) {
+        self.values.clone_from_slice(values);
+        self.trail.clear();
+        self.blocks.clear();
+        self.saved.clear();
+        self.levels.clear();
+        self.stamp += 1;
+        self.stop_recomputing();
+    }
+
+    // Start a new level. Changes after this are undone by `pop_to_level`.
+    #[inline]
+    pub fn push_level(&mut self) {
+        // Each cell (or block) is recorded at most once per level, so this is
+        // enough room for the whole level. It only allocates when the search
+        // goes deeper than it has before, and not while propagating.
+        let num_cells = self.values.len();
+        let (trail, blocks, saved) = match self.strategy {
+            GridStrategy::Trail => (num_cells, 0, 0),
+            GridStrategy::CopyOnWrite => (0, num_cells.div_ceil(1 << BLOCK_SHIFT), num_cells),
+        };
+        if self.snapshot_level.is_none() {
+            let over_limit = self.max_memory.is_some_and(|max_memory| {
+                self.memory_usage()
+                    + growth(&self.trail, trail)
+                    + growth(&self.blocks, blocks)
+                    + growth(&self.saved, saved)
+                    > max_memory
+            });
+            if over_limit {
+                self.start_recomputing();
+            } else {
+                self.trail.reserve(trail);
+                self.blocks.reserve(blocks);
+                self.saved.reserve(saved);
+            }
+        }
+        self.levels.push((self.trail.len(), self.blocks.len()));
+        self.stamp += 1;
+    }
+
+    fn start_recomputing(&mut self) {
+        let num_cells = self.values.len();
+        self.snapshot_level = Some(self.levels.len());
+        self.snapshot.clone_from(&self.values);
+        self.changed.reserve(num_cells);
+        self.is_changed.resize(num_cells, false);
+    }
+
+    fn stop_recomputing(&mut self) {
+        self.snapshot_level = None;
+        self.changed.clear();
+        self.is_changed.fill(false);
+        self.decisions.clear();
+    }
+
+    // Undo all the changes made in levels above `level`. Returns true if the
+    // decisions must then be replayed to rebuild the level.
+    #[inline]
+    pub fn pop_to_level(&mut self, level: usize) -> bool {
+        if self.levels.len() <= level {
+            return false;
+        }
+        if let Some(snapshot_level) = self.snapshot_level {
+            for &cell in &self.changed {
+                self.values[cell] = self.snapshot[cell].clone();
+                self.is_changed[cell] = false;
+            }
+            self.changed.clear();
+            if level >= snapshot_level {
+                self.levels.truncate(level);
+                // The decisions are in order of level.
+                let end = self.decisions.partition_point(|d| d.level <= level);
+                self.decisions.truncate(end);
+                return !self.decisions.is_empty();
+            }
+            self.stop_recomputing();
+        }
+        let (trail_start, blocks_start) = self.levels[level];
+        undo(
+            &mut self.values,
+            &self.trail[trail_start..],
+            &self.blocks[blocks_start..],
+            &self.saved,
+        );
+        self.trail.truncate(trail_start);
+        if let Some(&(_, saved_start)) = self.blocks.get(blocks_start) {
+            self.saved.truncate(saved_start);
+        }
+        self.blocks.truncate(blocks_start);
+        self.levels.truncate(level);
+        // Cells changed before the popped levels need to be recorded again.
+        self.stamp += 1;
+        false
+    }
+
+    // The values as they were at each level, from the first to the current.
+    pub fn level_snapshots(&self) -> Vec<Vec<VS>> {
+        let mut snapshots = vec![self.values.clone()];
+        let (mut trail_end, mut blocks_end) = (self.trail.len(), self.blocks.len());
+        for &(trail_start, blocks_start) in self.levels.iter().rev() {
+            let mut values = snapshots.last().unwrap().clone();
+            undo(
+                &mut values,
+                &self.trail[trail_start..trail_end],
+                &self.blocks[blocks_start..blocks_end],
+                &self.saved,
+            );
+            snapshots.push(values);
+            (trail_end, blocks_end) = (trail_start, blocks_start);
+        }
+        snapshots.reverse();
+        snapshots
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        super::vec_memory(&self.values)
+            + super::vec_memory(&self.trail)
+            + super::vec_memory(&self.blocks)
+            + super::vec_memory(&self.saved)
+            + super::vec_memory(&self.levels)
+            + super::vec_memory(&self.stamps)
+            + super::vec_memory(&self.snapshot)
+            + super::vec_memory(&self.changed)
+            + super::vec_memory(&self.is_changed)
+            + super::vec_memory(&self.decisions)
+    }
+}
+
+impl<VS> Deref for TrailGrid<VS> {
+    type Target = [VS];
+
+    #[inline]
+    fn deref(&self) -> &[VS] {
+        &self.values
+    }
+}
+
+// About how many bytes `reserve` would add to the vector, as it at least
+// doubles the capacity when it grows.
+fn growth<T>(v: &Vec<T>, additional: usize) -> usize {
+    let needed = v.len() + additional;
+    if needed <= v.capacity() {
+        return 0;
+    }
+    (needed.max(2 * v.capacity()) - v.capacity()) * std::mem::size_of::<T>()
+}
+
+#[inline]
+fn block_range(block: usize, num_cells: usize) -> std::ops::Range<usize> {
+    let start = block << BLOCK_SHIFT;
+    start..num_cells.min(start + (1 << BLOCK_SHIFT))
+}
+
+// Restore the recorded values, latest first.
+fn undo<VS: Clone>(
+    values: &mut [VS],
+    trail: &[(CellIndex, VS)],
+    blocks: &[(usize, usize)],
+    saved: &[VS],
+) {
+    for (cell, value) in trail.iter().rev() {
+        values[*cell] = value.clone();
+    }
+    for &(block, saved_start) in blocks.iter().rev() {
+        let range = block_range(block, values.len());
+        let saved_end = saved_start + range.len();
+        values[range].clone_from_slice(&saved[saved_start..saved_end]);
+    }
+}
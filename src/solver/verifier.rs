@@ -0,0 +1,118 @@
+use crate::types::{CellIndex, Constraint, ValueType};
+use crate::value_set::{DynBitSet, SimdValueSet, ValueSet};
+
+use super::cell_accumulator::{CellAccumulator, CellContainer};
+use super::engine::MAX_FIXED_WIDTH_VALUES;
+use super::handlers;
+use super::logical;
+use super::trail::TrailGrid;
+use super::{Config, GridStrategy, PropagationLevel};
+
+// A constraint which the filled values break.
+#[derive(Clone, Debug)]
+pub struct Violation {
+    pub kind: &'static str,
+    // The name of the house, e.g. "row 3", if the constraint is a house.
+    pub house: Option<String>,
+    // The cells responsible, e.g. the cells with the same value in a house.
+    pub cells: Vec<CellIndex>,
+}
+
+// Check the fixed values against every constraint. Each constraint is checked
+// on its own, so the grid may be partly filled: a constraint is only violated
+// if no values for its empty cells could satisfy it.
+pub fn verify(constraint: &Constraint) -> Vec<Violation> {
+    if constraint.shape.num_values > MAX_FIXED_WIDTH_VALUES {
+        verify_with::<DynBitSet>(constraint)
+    } else {
+        verify_with::<SimdValueSet<8>>(constraint)
+    }
+}
+
+fn verify_with<VS: ValueSet>(constraint: &Constraint) -> Vec<Violation> {
+    let shape = &constraint.shape;
+    let num_values = shape.num_values as ValueType;
+    let mut violations = Vec::new();
+
+    let mut initial_grid = vec![VS::full(num_values); shape.num_cells];
+    for (cell, values) in &constraint.candidates {
+        initial_grid[*cell] = values
+            .iter()
+            .map(|v| VS::from_value(v.index()))
+            .fold(VS::empty(), |a, b| a.union(&b));
+    }
+    let mut filled = vec![None; shape.num_cells];
+    for &(cell, value) in &constraint.fixed_values {
+        filled[cell] = Some(value.index());
+        let value_set = initial_grid[cell].intersection(&VS::from_value(value.index()));
+        if value_set.is_empty() {
+            violations.push(Violation {
+                kind: "candidates",
+                house: None,
+                cells: vec![cell],
+            });
+        }
+        initial_grid[cell] = VS::from_value(value.index());
+    }
+
+    // Only the singles are needed to find repeated values, and the houses are
+    // then the first handlers, in the same order as `make_houses`.
+    let config = Config {
+        propagation_level: PropagationLevel::Singles,
+        ..Config::default()
+    };
+    let mut handler_set = handlers::make_handlers::<VS>(constraint, &config);
+    let houses = handlers::make_houses(constraint);
+    let house_names = logical::make_house_names(constraint, &houses);
+
+    let mut grid = TrailGrid::new(shape.num_cells, GridStrategy::Trail);
+    let mut cell_accumulator =
+        CellAccumulator::new(shape.num_cells, &handler_set, handler_set.tiers());
+    for index in 0..handler_set.len() {
+        grid.reset(&initial_grid);
+        cell_accumulator.clear();
+        if handler_set
+            .run_handler(index, &mut grid, &mut cell_accumulator)
+            .is_ok()
+        {
+            continue;
+        }
+
+        let handler = &handler_set[index];
+        let handler_cells = handler.cells();
+        let filled_cells = handler_cells
+            .iter()
+            .copied()
+            .filter(|&cell| filled[cell].is_some())
+            .collect::<Vec<_>>();
+        let (house, cells) = if index < houses.len() {
+            // Point to the repeated values if there are any.
+            let repeated = filled_cells
+                .iter()
+                .copied()
+                .filter(|&cell| {
+                    filled_cells
+                        .iter()
+                        .any(|&other| other != cell && filled[other] == filled[cell])
+                })
+                .collect::<Vec<_>>();
+            let cells = if repeated.is_empty() {
+                handler_cells.to_vec()
+            } else {
+                repeated
+            };
+            (Some(house_names[index].clone()), cells)
+        } else if filled_cells.is_empty() {
+            (None, handler_cells.to_vec())
+        } else {
+            (None, filled_cells)
+        };
+        violations.push(Violation {
+            kind: handler.kind(),
+            house,
+            cells,
+        });
+    }
+
+    violations
+}
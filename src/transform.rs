@@ -0,0 +1,210 @@
+// Transformations which turn a puzzle into an equivalent one, with the same
+// number of solutions (transformed in the same way). These are useful for
+// making variants of a puzzle, and for checking that the solver gives the same
+// results for each.
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use crate::types::{CellIndex, CellValue, Constraint, Shape, ValueType};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Transform {
+    // Rotate the grid 90 degrees clockwise.
+    Rotate,
+    // Reflect the grid left to right.
+    ReflectHorizontal,
+    // Reflect the grid top to bottom.
+    ReflectVertical,
+    // Reflect the grid in its main diagonal.
+    Transpose,
+    // Each value v is replaced by `labels[v - 1]`.
+    Relabel(Vec<ValueType>),
+    // The band (or stack) moved to each position, counted from 0.
+    PermuteBands(Vec<usize>),
+    PermuteStacks(Vec<usize>),
+}
+
+impl Transform {
+    // Parse a transformation such as "rotate" or "relabel=2,1,3,4". Without a
+    // list, relabel and the permutations are chosen at random.
+    pub fn parse<R: Rng>(spec: &str, shape: &Shape, rng: &mut R) -> Result<Transform, String> {
+        let (name, list) = match spec.split_once('=') {
+            Some((name, list)) => (name, Some(list)),
+            None => (spec, None),
+        };
+        let numbers = |list: &str| {
+            list.split(',')
+                .map(|n| n.trim().parse::<usize>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("Invalid number in {}: {}", spec, e))
+        };
+        // Permutations are written counting from 1.
+        let permutation = |len: usize, rng: &mut R| -> Result<Vec<usize>, String> {
+            match list {
+                Some(list) => Ok(numbers(list)?.iter().map(|n| n.wrapping_sub(1)).collect()),
+                None => Ok(random_permutation(len, rng)),
+            }
+        };
+
+        let num_bands = shape.side_len as usize / band_size(shape);
+        let transform = match name {
+            "rotate" => Transform::Rotate,
+            "reflect-horizontal" => Transform::ReflectHorizontal,
+            "reflect-vertical" => Transform::ReflectVertical,
+            "transpose" => Transform::Transpose,
+            "relabel" => Transform::Relabel(
+                permutation(shape.num_values as usize, rng)?
+                    .into_iter()
+                    .map(|i| i.wrapping_add(1) as ValueType)
+                    .collect(),
+            ),
+            "permute-bands" => Transform::PermuteBands(permutation(num_bands, rng)?),
+            "permute-stacks" => Transform::PermuteStacks(permutation(num_bands, rng)?),
+            _ => return Err(format!("Unknown transformation: {}", name)),
+        };
+        if list.is_some()
+            && !matches!(
+                transform,
+                Transform::Relabel(_) | Transform::PermuteBands(_) | Transform::PermuteStacks(_)
+            )
+        {
+            return Err(format!("{} doesn't take a list.", name));
+        }
+        transform.check(shape)?;
+        Ok(transform)
+    }
+
+    // A random transformation which keeps the grid's orientation (except for
+    // transposing), relabeling the values and permuting the bands and stacks.
+    pub fn random<R: Rng>(shape: &Shape, rng: &mut R) -> Vec<Transform> {
+        let num_bands = shape.side_len as usize / band_size(shape);
+        let mut transforms = Vec::new();
+        if rng.gen_bool(0.5) {
+            transforms.push(Transform::Transpose);
+        }
+        transforms.push(Transform::Relabel(
+            random_permutation(shape.num_values as usize, rng)
+                .into_iter()
+                .map(|i| i as ValueType + 1)
+                .collect(),
+        ));
+        transforms.push(Transform::PermuteBands(random_permutation(num_bands, rng)));
+        transforms.push(Transform::PermuteStacks(random_permutation(num_bands, rng)));
+        transforms
+    }
+
+    pub fn apply(&self, constraint: &Constraint) -> Result<Constraint, String> {
+        if crate::canonical::has_variants(constraint) {
+            return Err(
+                "Transformations are only supported for puzzles without variant constraints."
+                    .to_string(),
+            );
+        }
+        let shape = &constraint.shape;
+        self.check(shape)?;
+
+        let cell = |cell: CellIndex| self.map_cell(shape, cell);
+        let value = |value: CellValue| match self {
+            Transform::Relabel(labels) => {
+                CellValue::from_display_value(labels[value.index() as usize])
+            }
+            _ => value,
+        };
+
+        let mut fixed_values = constraint
+            .fixed_values
+            .iter()
+            .map(|&(c, v)| (cell(c), value(v)))
+            .collect::<Vec<_>>();
+        fixed_values.sort_by_key(|&(c, _)| c);
+        let mut candidates = constraint
+            .candidates
+            .iter()
+            .map(|(c, values)| (cell(*c), values.iter().map(|&v| value(v)).collect()))
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|(c, _)| *c);
+        let mut locked_cells = constraint
+            .locked_cells
+            .iter()
+            .map(|&c| cell(c))
+            .collect::<Vec<_>>();
+        locked_cells.sort();
+
+        Ok(Constraint {
+            fixed_values,
+            candidates,
+            locked_cells,
+            ..constraint.clone()
+        })
+    }
+
+    // Relabeling must use each value once, and the permutations each band (or
+    // stack) once.
+    fn check(&self, shape: &Shape) -> Result<(), String> {
+        let num_bands = shape.side_len as usize / band_size(shape);
+        let (items, len, what) = match self {
+            Transform::Relabel(labels) => (
+                labels
+                    .iter()
+                    .map(|&v| (v as usize).wrapping_sub(1))
+                    .collect(),
+                shape.num_values as usize,
+                "values",
+            ),
+            Transform::PermuteBands(order) => (order.clone(), num_bands, "bands"),
+            Transform::PermuteStacks(order) => (order.clone(), num_bands, "stacks"),
+            _ => return Ok(()),
+        };
+        let mut sorted = items;
+        sorted.sort();
+        if sorted != (0..len).collect::<Vec<_>>() {
+            return Err(format!(
+                "The {} must be a permutation of 1 to {}.",
+                what, len
+            ));
+        }
+        Ok(())
+    }
+
+    // The cell which `cell` moves to.
+    fn map_cell(&self, shape: &Shape, cell: CellIndex) -> CellIndex {
+        let n = shape.side_len as usize;
+        let band_size = band_size(shape);
+        let (row, col) = (cell / n, cell % n);
+        // The position which each band (or stack) moves to.
+        let position = |order: &[usize], line: usize| {
+            let band = order.iter().position(|&b| b == line / band_size).unwrap();
+            band * band_size + line % band_size
+        };
+        let (row, col) = match self {
+            Transform::Rotate => (col, n - 1 - row),
+            Transform::ReflectHorizontal => (row, n - 1 - col),
+            Transform::ReflectVertical => (n - 1 - row, col),
+            Transform::Transpose => (col, row),
+            Transform::Relabel(_) => (row, col),
+            Transform::PermuteBands(order) => (position(order, row), col),
+            Transform::PermuteStacks(order) => (row, position(order, col)),
+        };
+        row * n + col
+    }
+}
+
+// Apply each transformation in turn.
+pub fn apply_all(constraint: &Constraint, transforms: &[Transform]) -> Result<Constraint, String> {
+    let mut constraint = constraint.clone();
+    for transform in transforms {
+        constraint = transform.apply(&constraint)?;
+    }
+    Ok(constraint)
+}
+
+// Latin squares have no boxes, so the whole grid is one band.
+fn band_size(shape: &Shape) -> usize {
+    shape.box_size.unwrap_or(shape.side_len) as usize
+}
+
+fn random_permutation<R: Rng>(len: usize, rng: &mut R) -> Vec<usize> {
+    let mut permutation = (0..len).collect::<Vec<_>>();
+    permutation.shuffle(rng);
+    permutation
+}
@@ -1,10 +1,21 @@
 use std::fmt;
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::solver::CustomPropagator;
+
 pub type CellIndex = usize;
 pub type ValueType = u16;
 pub type RngType = rand::rngs::StdRng;
 
+// Values are serialized as they are displayed, i.e. counting from 1.
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(into = "ValueType", try_from = "ValueType")
+)]
 pub struct CellValue(ValueType);
 
 impl CellValue {
@@ -31,9 +42,30 @@ impl fmt::Display for CellValue {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[cfg(feature = "serde")]
+impl From<CellValue> for ValueType {
+    fn from(value: CellValue) -> ValueType {
+        value.display_value()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<ValueType> for CellValue {
+    type Error = String;
+
+    fn try_from(display_value: ValueType) -> Result<CellValue, String> {
+        if display_value == 0 {
+            return Err("Values start from 1".to_string());
+        }
+        Ok(CellValue::from_display_value(display_value))
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Shape {
-    pub box_size: u32,
+    // None for latin squares, which have no boxes.
+    pub box_size: Option<u32>,
     pub num_values: u32,
     pub num_cells: usize,
     pub side_len: u32,
@@ -43,13 +75,22 @@ impl Shape {
     pub fn new(dim: u32) -> Shape {
         let num_values = dim * dim;
         Shape {
-            box_size: dim,
+            box_size: Some(dim),
             num_values,
             num_cells: (num_values * num_values).try_into().unwrap(),
             side_len: num_values,
         }
     }
 
+    pub fn new_latin_square(side_len: u32) -> Shape {
+        Shape {
+            box_size: None,
+            num_values: side_len,
+            num_cells: (side_len * side_len).try_into().unwrap(),
+            side_len,
+        }
+    }
+
     pub fn make_cell_index(&self, row: u32, col: u32) -> CellIndex {
         ((row * self.side_len) + col).try_into().unwrap()
     }
@@ -57,10 +98,399 @@ impl Shape {
 
 pub type Solution = Vec<CellValue>;
 pub type FixedValues = Vec<(CellIndex, CellValue)>;
+// Cells which are restricted to a set of candidate values.
+pub type Candidates = Vec<(CellIndex, Vec<CellValue>)>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum KropkiKind {
+    // The values are consecutive.
+    White,
+    // One value is double the other.
+    Black,
+}
 
+// The default sums for X and V markers. These are scaled so that they split the
+// values in the same way as on a 9x9 grid.
+pub fn default_xv_sums(shape: &Shape) -> (u32, u32) {
+    (shape.num_values + 1, shape.num_values.div_ceil(2))
+}
+
+// The sums which pairs without an XV marker can't have with `xv_negative`: the
+// default X and V sums, and the sums of any markers with a custom sum.
+pub fn xv_negative_sums(constraint: &Constraint) -> Vec<u32> {
+    let (x_sum, v_sum) = default_xv_sums(&constraint.shape);
+    let mut sums = vec![x_sum, v_sum];
+    sums.extend(constraint.variants.iter().filter_map(|v| match v {
+        VariantConstraint::Xv { sum, .. } => Some(*sum),
+        _ => None,
+    }));
+    sums.sort_unstable();
+    sums.dedup();
+    sums
+}
+
+// Constraints in addition to the standard sudoku rules.
 #[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(tag = "type", rename_all = "snake_case")
+)]
+pub enum VariantConstraint {
+    // The cells must sum to `sum`. Unless `allow_repeats` is set, they contain
+    // no repeated values.
+    Cage {
+        cells: Vec<CellIndex>,
+        sum: u32,
+        allow_repeats: bool,
+    },
+    // Values strictly increase along the cells, starting from the bulb.
+    Thermo {
+        cells: Vec<CellIndex>,
+    },
+    // The first cell (the circle) equals the sum of the remaining cells.
+    Arrow {
+        cells: Vec<CellIndex>,
+    },
+    // The cells contain a run of consecutive values in any order.
+    Renban {
+        cells: Vec<CellIndex>,
+    },
+    // Adjacent cells along the line differ by at least `difference`.
+    Whisper {
+        cells: Vec<CellIndex>,
+        difference: u32,
+    },
+    // A kropki dot between a pair of cells.
+    Kropki {
+        cells: Vec<CellIndex>,
+        kind: KropkiKind,
+    },
+    // A consecutive marker between a pair of cells.
+    Consecutive {
+        cells: Vec<CellIndex>,
+    },
+    // An X or V marker between a pair of cells, which must sum to `sum`.
+    Xv {
+        cells: Vec<CellIndex>,
+        sum: u32,
+    },
+    // Two regions of the same size, given as the cells of the first region
+    // followed by the corresponding cells of the second. Corresponding cells
+    // have the same value.
+    CloneRegion {
+        cells: Vec<CellIndex>,
+    },
+    // Every three consecutive cells contain one low, one medium and one high
+    // value.
+    Entropic {
+        cells: Vec<CellIndex>,
+    },
+    // Every `modulus` consecutive cells contain one value from each residue
+    // class mod `modulus`.
+    Modular {
+        cells: Vec<CellIndex>,
+        modulus: u32,
+    },
+    // The segments of the line within each box all have the same sum.
+    RegionSum {
+        cells: Vec<CellIndex>,
+    },
+    // The values (which may be repeated) appear in the 2x2 square of cells.
+    Quadruple {
+        cells: Vec<CellIndex>,
+        values: Vec<CellValue>,
+    },
+    // `value` appears exactly `count` times in the cells.
+    ValueCount {
+        cells: Vec<CellIndex>,
+        value: CellValue,
+        count: u32,
+    },
+    // The cells contain no repeated values.
+    Region {
+        cells: Vec<CellIndex>,
+    },
+    // The first cell is less than the second.
+    Inequality {
+        cells: Vec<CellIndex>,
+    },
+    // The cells contain only even (or only odd) values.
+    Parity {
+        cells: Vec<CellIndex>,
+        even: bool,
+    },
+}
+
+impl VariantConstraint {
+    // A short name for the kind of constraint, e.g. "cage".
+    pub fn name(&self) -> &'static str {
+        match self {
+            VariantConstraint::Cage { .. } => "cage",
+            VariantConstraint::Thermo { .. } => "thermo",
+            VariantConstraint::Arrow { .. } => "arrow",
+            VariantConstraint::Renban { .. } => "renban",
+            VariantConstraint::Whisper { .. } => "whisper",
+            VariantConstraint::Kropki {
+                kind: KropkiKind::White,
+                ..
+            } => "white",
+            VariantConstraint::Kropki {
+                kind: KropkiKind::Black,
+                ..
+            } => "black",
+            VariantConstraint::Xv { .. } => "xv",
+            VariantConstraint::Consecutive { .. } => "consecutive",
+            VariantConstraint::CloneRegion { .. } => "clone",
+            VariantConstraint::Parity { even: true, .. } => "even",
+            VariantConstraint::Parity { even: false, .. } => "odd",
+            VariantConstraint::Inequality { .. } => "inequality",
+            VariantConstraint::Region { .. } => "region",
+            VariantConstraint::Entropic { .. } => "entropic",
+            VariantConstraint::Modular { .. } => "modular",
+            VariantConstraint::RegionSum { .. } => "region-sum",
+            VariantConstraint::Quadruple { .. } => "quad",
+            VariantConstraint::ValueCount { .. } => "count",
+        }
+    }
+
+    pub fn cells(&self) -> &[CellIndex] {
+        match self {
+            VariantConstraint::Cage { cells, .. } => cells,
+            VariantConstraint::Thermo { cells } => cells,
+            VariantConstraint::Arrow { cells } => cells,
+            VariantConstraint::Renban { cells } => cells,
+            VariantConstraint::Whisper { cells, .. } => cells,
+            VariantConstraint::Kropki { cells, .. } => cells,
+            VariantConstraint::Xv { cells, .. } => cells,
+            VariantConstraint::Consecutive { cells } => cells,
+            VariantConstraint::CloneRegion { cells } => cells,
+            VariantConstraint::Parity { cells, .. } => cells,
+            VariantConstraint::Inequality { cells } => cells,
+            VariantConstraint::Region { cells } => cells,
+            VariantConstraint::Entropic { cells } => cells,
+            VariantConstraint::Modular { cells, .. } => cells,
+            VariantConstraint::RegionSum { cells } => cells,
+            VariantConstraint::Quadruple { cells, .. } => cells,
+            VariantConstraint::ValueCount { cells, .. } => cells,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Constraint {
     pub shape: Shape,
     pub fixed_values: FixedValues,
+    pub candidates: Candidates,
+    // Cells whose givens the minimizer never removes, e.g. to keep a pattern of
+    // clues. When generating, the cells keep the values of the solution.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub locked_cells: Vec<CellIndex>,
     pub x_sudoku: bool,
+    // Only one of the diagonals of X-Sudoku contains no repeated values.
+    pub main_diagonal: bool,
+    pub anti_diagonal: bool,
+    // Diagonals offset from the main diagonals contain no repeated values.
+    pub argyle: bool,
+    // Cells which touch diagonally must have different values.
+    pub anti_king: bool,
+    // Extra box-shaped regions offset inside the grid.
+    pub windoku: bool,
+    // Orthogonally adjacent cells without a kropki dot can't have consecutive
+    // values or values in a 1:2 ratio.
+    pub kropki_negative: bool,
+    // Orthogonally adjacent cells without an XV marker can't sum to the X or V
+    // sums.
+    pub xv_negative: bool,
+    // Orthogonally adjacent cells without a consecutive marker (or white kropki
+    // dot) can't have consecutive values.
+    pub consecutive_negative: bool,
+    pub variants: Vec<VariantConstraint>,
+    // Constraints with handlers defined outside the solver. These can't be
+    // written to puzzle files, and are skipped when serializing.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub propagators: Vec<CustomPropagator>,
+}
+
+impl Constraint {
+    pub fn new(shape: Shape, fixed_values: FixedValues) -> Constraint {
+        Constraint {
+            shape,
+            fixed_values,
+            candidates: Candidates::new(),
+            locked_cells: Vec::new(),
+            x_sudoku: false,
+            main_diagonal: false,
+            anti_diagonal: false,
+            argyle: false,
+            anti_king: false,
+            windoku: false,
+            kropki_negative: false,
+            xv_negative: false,
+            consecutive_negative: false,
+            variants: Vec::new(),
+            propagators: Vec::new(),
+        }
+    }
+}
+
+// Builds a constraint in code, without going through the text formats. Rows
+// and columns are counted from 0, and values from 1 (as they are displayed).
+pub struct ConstraintBuilder {
+    constraint: Constraint,
+    // Checked when the constraint is built.
+    fixed: Vec<(u32, u32, ValueType)>,
+    candidates: Vec<(u32, u32, Vec<ValueType>)>,
+    locked: Vec<(u32, u32)>,
+}
+
+impl ConstraintBuilder {
+    pub fn new(shape: Shape) -> ConstraintBuilder {
+        ConstraintBuilder {
+            constraint: Constraint::new(shape, FixedValues::new()),
+            fixed: Vec::new(),
+            candidates: Vec::new(),
+            locked: Vec::new(),
+        }
+    }
+
+    // The index of a cell, for the constraints which take a list of cells.
+    pub fn cell(&self, row: u32, col: u32) -> CellIndex {
+        self.constraint.shape.make_cell_index(row, col)
+    }
+
+    pub fn fixed(mut self, row: u32, col: u32, value: ValueType) -> Self {
+        self.fixed.push((row, col, value));
+        self
+    }
+
+    pub fn candidates(mut self, row: u32, col: u32, values: &[ValueType]) -> Self {
+        self.candidates.push((row, col, values.to_vec()));
+        self
+    }
+
+    pub fn locked(mut self, row: u32, col: u32) -> Self {
+        self.locked.push((row, col));
+        self
+    }
+
+    pub fn x_sudoku(mut self, enabled: bool) -> Self {
+        self.constraint.x_sudoku = enabled;
+        self
+    }
+
+    pub fn main_diagonal(mut self, enabled: bool) -> Self {
+        self.constraint.main_diagonal = enabled;
+        self
+    }
+
+    pub fn anti_diagonal(mut self, enabled: bool) -> Self {
+        self.constraint.anti_diagonal = enabled;
+        self
+    }
+
+    pub fn argyle(mut self, enabled: bool) -> Self {
+        self.constraint.argyle = enabled;
+        self
+    }
+
+    pub fn anti_king(mut self, enabled: bool) -> Self {
+        self.constraint.anti_king = enabled;
+        self
+    }
+
+    pub fn windoku(mut self, enabled: bool) -> Self {
+        self.constraint.windoku = enabled;
+        self
+    }
+
+    pub fn kropki_negative(mut self, enabled: bool) -> Self {
+        self.constraint.kropki_negative = enabled;
+        self
+    }
+
+    pub fn xv_negative(mut self, enabled: bool) -> Self {
+        self.constraint.xv_negative = enabled;
+        self
+    }
+
+    pub fn consecutive_negative(mut self, enabled: bool) -> Self {
+        self.constraint.consecutive_negative = enabled;
+        self
+    }
+
+    pub fn region(self, cells: Vec<CellIndex>) -> Self {
+        self.variant(VariantConstraint::Region { cells })
+    }
+
+    pub fn cage(self, cells: Vec<CellIndex>, sum: u32) -> Self {
+        self.variant(VariantConstraint::Cage {
+            cells,
+            sum,
+            allow_repeats: false,
+        })
+    }
+
+    pub fn thermo(self, cells: Vec<CellIndex>) -> Self {
+        self.variant(VariantConstraint::Thermo { cells })
+    }
+
+    pub fn arrow(self, cells: Vec<CellIndex>) -> Self {
+        self.variant(VariantConstraint::Arrow { cells })
+    }
+
+    // Any other variant constraint.
+    pub fn variant(mut self, variant: VariantConstraint) -> Self {
+        self.constraint.variants.push(variant);
+        self
+    }
+
+    pub fn propagator(mut self, propagator: CustomPropagator) -> Self {
+        self.constraint.propagators.push(propagator);
+        self
+    }
+
+    // Check that the cells and values fit in the grid.
+    pub fn build(self) -> Result<Constraint, String> {
+        let mut constraint = self.constraint;
+        let shape = constraint.shape;
+        let cell = |row: u32, col: u32| {
+            if row >= shape.side_len || col >= shape.side_len {
+                return Err(format!("Cell ({}, {}) is outside the grid.", row, col));
+            }
+            Ok(shape.make_cell_index(row, col))
+        };
+        let value = |value: ValueType| {
+            if value == 0 || value as u32 > shape.num_values {
+                return Err(format!(
+                    "Value {} is not between 1 and {}.",
+                    value, shape.num_values
+                ));
+            }
+            Ok(CellValue::from_display_value(value))
+        };
+
+        for (row, col, v) in self.fixed {
+            constraint.fixed_values.push((cell(row, col)?, value(v)?));
+        }
+        for (row, col, values) in self.candidates {
+            let values = values.into_iter().map(value).collect::<Result<_, _>>()?;
+            constraint.candidates.push((cell(row, col)?, values));
+        }
+        for (row, col) in self.locked {
+            constraint.locked_cells.push(cell(row, col)?);
+        }
+        for variant in &constraint.variants {
+            if let Some(cell) = variant.cells().iter().find(|&&c| c >= shape.num_cells) {
+                return Err(format!("Cell {} is outside the grid.", cell));
+            }
+        }
+        Ok(constraint)
+    }
 }
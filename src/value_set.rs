@@ -5,7 +5,8 @@ use std::{fmt, mem, ops};
 
 use crate::types::ValueType;
 
-pub trait ValueSet: Copy + Eq + fmt::Debug {
+// Value sets are `Send + Sync` so that houses can be searched in parallel.
+pub trait ValueSet: Clone + Eq + fmt::Debug + Send + Sync {
     const BITS: ValueType = (mem::size_of::<Self>() as ValueType) * (u8::BITS as ValueType);
 
     fn from_value(value: ValueType) -> Self;
@@ -25,6 +26,8 @@ pub trait ValueSet: Copy + Eq + fmt::Debug {
 
     fn min(&self) -> Option<ValueType>;
 
+    fn max(&self) -> Option<ValueType>;
+
     // Return the value if it is unique, otherwise None.
     // To get a value more efficiently without checking the count, use min().
     #[inline]
@@ -45,6 +48,28 @@ pub trait ValueSet: Copy + Eq + fmt::Debug {
 
     fn without(&self, other: &Self) -> Self;
 
+    // The values less than `value`.
+    #[inline]
+    fn values_below(&self, value: ValueType) -> Self {
+        if value >= Self::BITS {
+            return self.clone();
+        }
+        self.intersection(&Self::full(value))
+    }
+
+    // The values greater than `value`.
+    #[inline]
+    fn values_above(&self, value: ValueType) -> Self {
+        if value >= Self::BITS - 1 {
+            return Self::empty();
+        }
+        self.without(&Self::full(value + 1))
+    }
+
+    // Each value increased by `k` (or decreased if `k` is negative). Values
+    // which would fall outside the set are dropped.
+    fn shifted(&self, k: i32) -> Self;
+
     #[inline]
     fn pop(&mut self) -> Option<ValueType> {
         let value = self.min()?;
@@ -52,18 +77,46 @@ pub trait ValueSet: Copy + Eq + fmt::Debug {
         Some(value)
     }
 
+    // The values in increasing order.
+    #[inline]
+    fn iter(&self) -> Values<Self> {
+        Values(self.clone())
+    }
+
     fn values(&self) -> Vec<ValueType> {
-        let mut values = Vec::new();
+        self.iter().collect()
+    }
+}
 
-        let mut copy = *self;
-        while let Some(value) = copy.pop() {
-            values.push(value);
-        }
+// An iterator over the values in a set, from smallest to largest.
+pub struct Values<VS>(VS);
+
+impl<VS: ValueSet> Iterator for Values<VS> {
+    type Item = ValueType;
+
+    #[inline]
+    fn next(&mut self) -> Option<ValueType> {
+        self.0.pop()
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let count = self.0.count();
+        (count, Some(count))
+    }
+}
 
-        values
+impl<VS: ValueSet> DoubleEndedIterator for Values<VS> {
+    #[inline]
+    fn next_back(&mut self) -> Option<ValueType> {
+        let value = self.0.max()?;
+        self.0.remove_set(&VS::from_value(value));
+        Some(value)
     }
 }
 
+impl<VS: ValueSet> ExactSizeIterator for Values<VS> {}
+
 pub struct IntBitSet<T>(T);
 
 impl<T> ValueSet for IntBitSet<T>
@@ -75,7 +128,9 @@ where
         + ops::BitAndAssign
         + ops::BitOr<Output = T>
         + ops::BitOrAssign
-        + num::traits::WrappingSub,
+        + num::traits::WrappingSub
+        + Send
+        + Sync,
 {
     #[inline]
     fn from_value(value: ValueType) -> Self {
@@ -120,6 +175,15 @@ where
         }
     }
 
+    #[inline]
+    fn max(&self) -> Option<ValueType> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(Self::BITS - 1 - self.0.leading_zeros() as ValueType)
+        }
+    }
+
     #[inline]
     fn remove_set(&mut self, other: &Self) {
         self.0 &= !other.0
@@ -144,6 +208,18 @@ where
     fn without(&self, other: &Self) -> Self {
         Self(self.0 & !other.0)
     }
+
+    #[inline]
+    fn shifted(&self, k: i32) -> Self {
+        let shift = k.unsigned_abs();
+        if shift >= Self::BITS as u32 {
+            Self::empty()
+        } else if k >= 0 {
+            Self(self.0.unsigned_shl(shift))
+        } else {
+            Self(self.0.unsigned_shr(shift))
+        }
+    }
 }
 
 impl<T: Copy> Copy for IntBitSet<T> {}
@@ -169,7 +245,9 @@ where
         + ops::BitAnd<Output = T>
         + ops::BitOrAssign
         + ops::BitOr<Output = T>
-        + num::traits::WrappingSub,
+        + num::traits::WrappingSub
+        + Send
+        + Sync,
 {
     fn from_iter<I: IntoIterator<Item = ValueType>>(iter: I) -> Self {
         iter.into_iter()
@@ -178,6 +256,27 @@ where
     }
 }
 
+impl<T> IntoIterator for IntBitSet<T>
+where
+    T: num::PrimInt
+        + ops::Shl<ValueType, Output = T>
+        + ops::Neg<Output = T>
+        + ops::BitAndAssign
+        + ops::BitAnd<Output = T>
+        + ops::BitOrAssign
+        + ops::BitOr<Output = T>
+        + num::traits::WrappingSub
+        + Send
+        + Sync,
+{
+    type Item = ValueType;
+    type IntoIter = Values<Self>;
+
+    fn into_iter(self) -> Values<Self> {
+        Values(self)
+    }
+}
+
 impl<T> fmt::Debug for IntBitSet<T>
 where
     T: num::PrimInt
@@ -187,7 +286,9 @@ where
         + ops::BitAnd<Output = T>
         + ops::BitOrAssign
         + ops::BitOr<Output = T>
-        + num::traits::WrappingSub,
+        + num::traits::WrappingSub
+        + Send
+        + Sync,
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self.values())
@@ -245,6 +346,14 @@ impl<T: ValueSet> ValueSet for RecValueSet<T> {
             .or_else(|| self.0.min().map(|v| v + T::BITS as ValueType))
     }
 
+    #[inline]
+    fn max(&self) -> Option<ValueType> {
+        self.0
+            .max()
+            .map(|v| v + T::BITS as ValueType)
+            .or_else(|| self.1.max())
+    }
+
     #[inline]
     fn remove_set(&mut self, other: &Self) {
         self.0.remove_set(&other.0);
@@ -271,12 +380,29 @@ impl<T: ValueSet> ValueSet for RecValueSet<T> {
     fn without(&self, other: &Self) -> Self {
         Self(self.0.without(&other.0), self.1.without(&other.1))
     }
+
+    #[inline]
+    fn shifted(&self, k: i32) -> Self {
+        // Values cross between the halves when shifted by less than a half.
+        let half = T::BITS as i32;
+        if k >= 0 {
+            Self(
+                self.0.shifted(k).union(&self.1.shifted(k - half)),
+                self.1.shifted(k),
+            )
+        } else {
+            Self(
+                self.0.shifted(k),
+                self.1.shifted(k).union(&self.0.shifted(k + half)),
+            )
+        }
+    }
 }
 
 impl<T: Copy> Copy for RecValueSet<T> {}
-impl<T: Copy> Clone for RecValueSet<T> {
+impl<T: Clone> Clone for RecValueSet<T> {
     fn clone(&self) -> Self {
-        *self
+        Self(self.0.clone(), self.1.clone())
     }
 }
 
@@ -298,8 +424,331 @@ where
     }
 }
 
+impl<T: ValueSet> IntoIterator for RecValueSet<T> {
+    type Item = ValueType;
+    type IntoIter = Values<Self>;
+
+    fn into_iter(self) -> Values<Self> {
+        Values(self)
+    }
+}
+
 impl<T: ValueSet> fmt::Debug for RecValueSet<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self.values())
     }
 }
+
+// A set of up to 64 * N values, stored as N words. Each operation is a simple
+// loop over the words, which the compiler turns into vector instructions, so
+// this is faster than nesting `RecValueSet`s for 256 or 512 values.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SimdValueSet<const N: usize>([u64; N]);
+
+impl<const N: usize> ValueSet for SimdValueSet<N> {
+    #[inline]
+    fn from_value(value: ValueType) -> Self {
+        let word = value as usize / 64;
+        Self(std::array::from_fn(|i| {
+            ((i == word) as u64) << (value % 64)
+        }))
+    }
+
+    #[inline]
+    fn full(num_values: ValueType) -> Self {
+        let mut words = [0; N];
+        for (i, word) in words.iter_mut().enumerate() {
+            let bits = (num_values as usize).saturating_sub(i * 64).min(64);
+            *word = if bits == 64 { !0 } else { (1 << bits) - 1 };
+        }
+        Self(words)
+    }
+
+    #[inline]
+    fn empty() -> Self {
+        Self([0; N])
+    }
+
+    #[inline]
+    fn count(&self) -> usize {
+        self.0.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.0.iter().fold(0, |a, w| a | w) == 0
+    }
+
+    #[inline]
+    fn has_multiple(&self) -> bool {
+        // Either one word has multiple values, or multiple words have values.
+        let mut multiple = 0;
+        let mut non_empty = 0;
+        for w in &self.0 {
+            multiple |= w & w.wrapping_sub(1);
+            non_empty += (*w != 0) as usize;
+        }
+        multiple != 0 || non_empty > 1
+    }
+
+    #[inline]
+    fn min(&self) -> Option<ValueType> {
+        for (i, w) in self.0.iter().enumerate() {
+            if *w != 0 {
+                return Some((i * 64) as ValueType + w.trailing_zeros() as ValueType);
+            }
+        }
+        None
+    }
+
+    #[inline]
+    fn max(&self) -> Option<ValueType> {
+        for (i, w) in self.0.iter().enumerate().rev() {
+            if *w != 0 {
+                return Some((i * 64 + 63) as ValueType - w.leading_zeros() as ValueType);
+            }
+        }
+        None
+    }
+
+    #[inline]
+    fn remove_set(&mut self, other: &Self) {
+        for (a, b) in self.0.iter_mut().zip(&other.0) {
+            *a &= !b;
+        }
+    }
+
+    #[inline]
+    fn add_set(&mut self, other: &Self) {
+        for (a, b) in self.0.iter_mut().zip(&other.0) {
+            *a |= b;
+        }
+    }
+
+    #[inline]
+    fn intersection(&self, other: &Self) -> Self {
+        Self(std::array::from_fn(|i| self.0[i] & other.0[i]))
+    }
+
+    #[inline]
+    fn union(&self, other: &Self) -> Self {
+        Self(std::array::from_fn(|i| self.0[i] | other.0[i]))
+    }
+
+    #[inline]
+    fn without(&self, other: &Self) -> Self {
+        Self(std::array::from_fn(|i| self.0[i] & !other.0[i]))
+    }
+
+    #[inline]
+    fn shifted(&self, k: i32) -> Self {
+        Self(std::array::from_fn(|i| shifted_word(&self.0, i, k)))
+    }
+}
+
+// Word `i` of the bits in `words` shifted up by `k` (or down if `k` is
+// negative).
+#[inline]
+fn shifted_word(words: &[u64], i: usize, k: i32) -> u64 {
+    let word = |j: i64| {
+        usize::try_from(j)
+            .ok()
+            .and_then(|j| words.get(j))
+            .copied()
+            .unwrap_or(0)
+    };
+    let j = i as i64 - k.div_euclid(64) as i64;
+    let bits = k.rem_euclid(64) as u32;
+    if bits == 0 {
+        word(j)
+    } else {
+        word(j) << bits | word(j - 1) >> (64 - bits)
+    }
+}
+
+impl<const N: usize> FromIterator<ValueType> for SimdValueSet<N> {
+    fn from_iter<I: IntoIterator<Item = ValueType>>(iter: I) -> Self {
+        iter.into_iter()
+            .map(Self::from_value)
+            .fold(Self::empty(), |a, b| a.union(&b))
+    }
+}
+
+impl<const N: usize> IntoIterator for SimdValueSet<N> {
+    type Item = ValueType;
+    type IntoIter = Values<Self>;
+
+    fn into_iter(self) -> Values<Self> {
+        Values(self)
+    }
+}
+
+impl<const N: usize> fmt::Debug for SimdValueSet<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.values())
+    }
+}
+
+// A set of any number of values, for grids too large for the fixed-width sets.
+// Sets only store words up to their largest value, so sets with different
+// numbers of words can be combined. This needs an allocation for each set, so
+// it is much slower than the fixed-width sets.
+#[derive(Clone)]
+pub struct DynBitSet(Box<[u64]>);
+
+impl DynBitSet {
+    // The words without any trailing zero words.
+    fn trimmed(&self) -> &[u64] {
+        let len = self.0.iter().rposition(|&w| w != 0).map_or(0, |i| i + 1);
+        &self.0[..len]
+    }
+
+    fn word(&self, i: usize) -> u64 {
+        self.0.get(i).copied().unwrap_or(0)
+    }
+}
+
+impl ValueSet for DynBitSet {
+    // Any value which fits in a ValueType.
+    const BITS: ValueType = ValueType::MAX;
+
+    fn from_value(value: ValueType) -> Self {
+        let mut words = vec![0; value as usize / 64 + 1];
+        words[value as usize / 64] = 1 << (value % 64);
+        Self(words.into_boxed_slice())
+    }
+
+    fn full(num_values: ValueType) -> Self {
+        let num_values = num_values as usize;
+        let mut words = vec![!0; num_values.div_ceil(64)];
+        if !num_values.is_multiple_of(64) {
+            *words.last_mut().unwrap() = (1 << (num_values % 64)) - 1;
+        }
+        Self(words.into_boxed_slice())
+    }
+
+    fn empty() -> Self {
+        Self(Box::new([]))
+    }
+
+    fn count(&self) -> usize {
+        self.0.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.iter().all(|&w| w == 0)
+    }
+
+    fn has_multiple(&self) -> bool {
+        let mut words = self.0.iter().filter(|&&w| w != 0);
+        match (words.next(), words.next()) {
+            (Some(w), None) => w & w.wrapping_sub(1) != 0,
+            (Some(_), Some(_)) => true,
+            _ => false,
+        }
+    }
+
+    fn min(&self) -> Option<ValueType> {
+        let i = self.0.iter().position(|&w| w != 0)?;
+        Some((i * 64) as ValueType + self.0[i].trailing_zeros() as ValueType)
+    }
+
+    fn max(&self) -> Option<ValueType> {
+        let i = self.0.iter().rposition(|&w| w != 0)?;
+        Some((i * 64 + 63) as ValueType - self.0[i].leading_zeros() as ValueType)
+    }
+
+    fn remove_set(&mut self, other: &Self) {
+        for (a, b) in self.0.iter_mut().zip(other.0.iter()) {
+            *a &= !b;
+        }
+    }
+
+    fn add_set(&mut self, other: &Self) {
+        if other.0.len() > self.0.len() {
+            *self = self.union(other);
+        } else {
+            for (a, b) in self.0.iter_mut().zip(other.0.iter()) {
+                *a |= b;
+            }
+        }
+    }
+
+    fn intersection(&self, other: &Self) -> Self {
+        let len = self.0.len().min(other.0.len());
+        Self((0..len).map(|i| self.0[i] & other.0[i]).collect())
+    }
+
+    fn union(&self, other: &Self) -> Self {
+        let len = self.0.len().max(other.0.len());
+        Self((0..len).map(|i| self.word(i) | other.word(i)).collect())
+    }
+
+    fn without(&self, other: &Self) -> Self {
+        Self(
+            (0..self.0.len())
+                .map(|i| self.0[i] & !other.word(i))
+                .collect(),
+        )
+    }
+
+    fn values_below(&self, value: ValueType) -> Self {
+        let value = value as usize;
+        let mut words = self.0[..self.0.len().min(value.div_ceil(64))].to_vec();
+        if let Some(last) = words.get_mut(value / 64) {
+            *last &= (1 << (value % 64)) - 1;
+        }
+        Self(words.into_boxed_slice())
+    }
+
+    fn values_above(&self, value: ValueType) -> Self {
+        let value = value as usize + 1;
+        let mut words = self.0.to_vec();
+        for word in words.iter_mut().take(value / 64) {
+            *word = 0;
+        }
+        if let Some(word) = words.get_mut(value / 64) {
+            *word &= !((1 << (value % 64)) - 1);
+        }
+        Self(words.into_boxed_slice())
+    }
+
+    fn shifted(&self, k: i32) -> Self {
+        let bits = (self.0.len() as i64 * 64 + k as i64).max(0) as usize;
+        Self(
+            (0..bits.div_ceil(64))
+                .map(|i| shifted_word(&self.0, i, k))
+                .collect(),
+        )
+    }
+}
+
+impl PartialEq for DynBitSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.trimmed() == other.trimmed()
+    }
+}
+impl Eq for DynBitSet {}
+
+impl FromIterator<ValueType> for DynBitSet {
+    fn from_iter<I: IntoIterator<Item = ValueType>>(iter: I) -> Self {
+        iter.into_iter()
+            .map(Self::from_value)
+            .fold(Self::empty(), |a, b| a.union(&b))
+    }
+}
+
+impl IntoIterator for DynBitSet {
+    type Item = ValueType;
+    type IntoIter = Values<Self>;
+
+    fn into_iter(self) -> Values<Self> {
+        Values(self)
+    }
+}
+
+impl fmt::Debug for DynBitSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.values())
+    }
+}
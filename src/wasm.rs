@@ -0,0 +1,115 @@
+// Bindings for running the solver from JavaScript, e.g. in a browser-based
+// puzzle setter. Puzzles are given in any of the text formats, and results are
+// returned as plain objects with the same fields as the `--json` output.
+//
+// The solver runs on the calling thread, so long searches should be run in a
+// web worker.
+use std::cell::Cell;
+use std::rc::Rc;
+
+use wasm_bindgen::prelude::*;
+
+use crate::io::{output, parser};
+use crate::solver;
+use crate::types::Constraint;
+
+// Find the first solution. Returns `{ solution, counters }`, where the
+// solution is null if there is none.
+//
+// `on_progress` is called with the counters every so often during the search.
+#[wasm_bindgen]
+pub fn solve(puzzle: &str, on_progress: Option<js_sys::Function>) -> Result<JsValue, JsError> {
+    let constraint = parse(puzzle)?;
+    let last_counters = Rc::new(Cell::new(solver::Counters::default()));
+    let config = solver::Config {
+        progress_callback: Some(progress_callback(on_progress, last_counters.clone())),
+        ..solver::Config::default()
+    };
+
+    let solution = match solver::solution_iter(&constraint, config).next() {
+        Some(solver::Output::Solution(solution)) => Some(solution),
+        _ => None,
+    };
+    Ok(to_js(serde_json::json!({
+        "solution": solution.map(|s| s.iter().map(|v| v.display_value()).collect::<Vec<_>>()),
+        "counters": output::counters_json(&last_counters.get()),
+    })))
+}
+
+// Count the solutions. Returns the final counters.
+#[wasm_bindgen]
+pub fn count(puzzle: &str, on_progress: Option<js_sys::Function>) -> Result<JsValue, JsError> {
+    let constraint = parse(puzzle)?;
+    let last_counters = Rc::new(Cell::new(solver::Counters::default()));
+    let config = solver::Config {
+        output_type: solver::OutputType::Empty,
+        progress_callback: Some(progress_callback(on_progress, last_counters.clone())),
+        ..solver::Config::default()
+    };
+
+    solver::solution_iter(&constraint, config).for_each(drop);
+    Ok(to_js(output::counters_json(&last_counters.get())))
+}
+
+// Remove givens while the puzzle still has a unique solution. Returns
+// `{ puzzle, counters }`, where the puzzle has the values of each cell, with
+// null for empty cells. The givens are returned unchanged if none could be
+// removed, e.g. if the input doesn't have a unique solution.
+//
+// `on_progress` is called with the minimizer counters after each cell is
+// tried.
+#[wasm_bindgen]
+pub fn minimize(puzzle: &str, on_progress: Option<js_sys::Function>) -> Result<JsValue, JsError> {
+    let constraint = parse(puzzle)?;
+    let last_counters = Rc::new(Cell::new(solver::MinimizerCounters::default()));
+    let callback_counters = last_counters.clone();
+    let progress_callback = Box::new(move |counters: &solver::MinimizerCounters| {
+        callback_counters.set(*counters);
+        if let Some(f) = &on_progress {
+            let _ = f.call1(
+                &JsValue::NULL,
+                &to_js(output::minimizer_counters_json(counters)),
+            );
+        }
+    });
+
+    let fixed_values = solver::minimize(
+        &constraint,
+        solver::Config::default(),
+        Some(progress_callback),
+    )
+    .last()
+    .unwrap_or_else(|| constraint.fixed_values.clone());
+    let mut grid = vec![None; constraint.shape.num_cells];
+    for (cell, value) in fixed_values {
+        grid[cell] = Some(value.display_value());
+    }
+    Ok(to_js(serde_json::json!({
+        "puzzle": grid,
+        "counters": output::minimizer_counters_json(&last_counters.get()),
+    })))
+}
+
+fn parse(puzzle: &str) -> Result<Constraint, JsError> {
+    parser::parse_text(puzzle, false).map_err(|e| JsError::new(&e.to_string()))
+}
+
+// Keeps the last counters (the engine always sends the final counters), and
+// passes them on to the JavaScript callback.
+fn progress_callback(
+    on_progress: Option<js_sys::Function>,
+    last_counters: Rc<Cell<solver::Counters>>,
+) -> Box<solver::ProgressCallback> {
+    Box::new(move |counters: &solver::Counters| {
+        last_counters.set(*counters);
+        if let Some(f) = &on_progress {
+            // Errors thrown by the callback are ignored, as the search can't be
+            // unwound from here.
+            let _ = f.call1(&JsValue::NULL, &to_js(output::counters_json(counters)));
+        }
+    })
+}
+
+fn to_js(json: serde_json::Value) -> JsValue {
+    js_sys::JSON::parse(&json.to_string()).unwrap()
+}
@@ -0,0 +1,257 @@
+// Checks the solution counts of variant constraints on small grids. The
+// propagation engine's count is compared with a brute force count: the
+// solutions of the grid without its variants which the verifier accepts. The
+// other engines are checked too, when they support the puzzle.
+
+use large_sudoku_solver::io::parser;
+use large_sudoku_solver::solver::{self, Config, EngineKind, Output};
+use large_sudoku_solver::types::{Constraint, Solution};
+
+fn parse(puzzle: &str) -> Constraint {
+    parser::parse_text(puzzle, false).unwrap()
+}
+
+fn solutions(constraint: &Constraint, engine: EngineKind) -> Vec<Solution> {
+    let config = Config {
+        engine,
+        ..Config::default()
+    };
+    solver::solution_iter(constraint, config)
+        .filter_map(|output| match output {
+            Output::Solution(solution) => Some(solution),
+            _ => None,
+        })
+        .collect()
+}
+
+fn is_valid(constraint: &Constraint, solution: &Solution) -> bool {
+    let mut filled = constraint.clone();
+    filled.fixed_values = solution.iter().copied().enumerate().collect();
+    solver::verify(&filled).is_empty()
+}
+
+fn brute_force_count(constraint: &Constraint) -> usize {
+    let base = Constraint::new(constraint.shape, constraint.fixed_values.clone());
+    solutions(&base, EngineKind::Propagation)
+        .iter()
+        .filter(|solution| is_valid(constraint, solution))
+        .count()
+}
+
+// Check that every engine finds `expected` solutions, and that they are all
+// valid.
+fn assert_count(puzzle: &str, expected: usize) {
+    let constraint = parse(puzzle);
+    assert_eq!(brute_force_count(&constraint), expected, "brute force");
+
+    let found = solutions(&constraint, EngineKind::Propagation);
+    assert!(found.iter().all(|solution| is_valid(&constraint, solution)));
+    assert_eq!(found.len(), expected, "propagation");
+
+    if solver::check_engine(&constraint, EngineKind::Dlx).is_ok() {
+        assert_eq!(
+            solutions(&constraint, EngineKind::Dlx).len(),
+            expected,
+            "dlx"
+        );
+    }
+    #[cfg(feature = "sat")]
+    assert_eq!(
+        solutions(&constraint, EngineKind::Sat).len(),
+        expected,
+        "sat"
+    );
+}
+
+// A marker line for each pair of orthogonally adjacent cells of `solution` (a
+// 4x4 grid in short format) whose values have `relation`, so that the solution
+// has no unmarked pairs with the relation.
+fn markers(solution: &str, name: &str, relation: impl Fn(u32, u32) -> bool) -> String {
+    let value = |r: usize, c: usize| solution.as_bytes()[r * 4 + c] as u32 - b'0' as u32;
+    let mut lines = String::new();
+    for r in 0..4 {
+        for c in 0..4 {
+            for (r1, c1) in [(r, c + 1), (r + 1, c)] {
+                if r1 < 4 && c1 < 4 && relation(value(r, c), value(r1, c1)) {
+                    lines += &format!("{}: R{}C{} R{}C{}\n", name, r + 1, c + 1, r1 + 1, c1 + 1);
+                }
+            }
+        }
+    }
+    lines
+}
+
+const SOLUTION: &str = "1234341221434321";
+
+#[test]
+fn no_variants() {
+    assert_count("4x4", 288);
+}
+
+#[test]
+fn cage() {
+    assert_count("4x4\ncage: 6 R1C1 R1C2 R2C3", 0);
+    assert_count("4x4\ncage: 8 R1C3 R1C2 R2C2", 36);
+    assert_count("4x4\ncage: 3 R1C1 R1C2", 48);
+}
+
+#[test]
+fn sum_with_repeats() {
+    assert_count("4x4\nsum: 2 R1C1 R3C2", 36);
+    assert_count("4x4\nsum: 6 R1C1 R2C3 R3C2", 48);
+    assert_count("4x4\nsum: 9 R1C1 R2C3 R3C2 R4C4", 24);
+}
+
+#[test]
+fn killer_only() {
+    assert_count(
+        "killer-only
+        cage: 3 R1C1 R1C2
+        cage: 7 R1C3 R1C4
+        cage: 7 R2C1 R2C2
+        cage: 3 R2C3 R2C4
+        cage: 3 R3C1 R3C2
+        cage: 7 R3C3 R3C4
+        cage: 7 R4C1 R4C2
+        cage: 3 R4C3 R4C4",
+        16,
+    );
+    // The cells outside a unique cage are covered by a cage with repeats.
+    assert_count(
+        "killer-only
+        cage: 6 R1C1 R1C2 R2C3
+        sum: 34 R1C3 R1C4 R2C1 R2C2 R2C4 R3C1 R3C2 R3C3 R3C4 R4C1 R4C2 R4C3 R4C4",
+        0,
+    );
+    assert_count(
+        "killer-only
+        cage: 8 R1C3 R1C2 R2C2
+        sum: 32 R1C1 R1C4 R2C1 R2C3 R2C4 R3C1 R3C2 R3C3 R3C4 R4C1 R4C2 R4C3 R4C4",
+        36,
+    );
+}
+
+#[test]
+fn renban() {
+    assert_count("4x4\nrenban: R2C2 R1C2 R1C3", 72);
+    assert_count("4x4\nrenban: R1C1 R2C2 R3C3 R4C4", 48);
+}
+
+#[test]
+fn xv_negative_with_only_x_markers() {
+    // Pairs without a marker can't sum to 5 either, even though there are no V
+    // markers. Without that there would be 4 solutions.
+    assert_count(
+        "...24....2....8.....8....296.9.5.24.3.....95.4.2......9.4867...76..348..83..92...
+        XV-Negative
+        x: R1C1 R1C2
+        x: R5C5 R6C5",
+        1,
+    );
+}
+
+#[test]
+fn thermo() {
+    assert_count("4x4\nthermo: R1C1 R2C2 R3C3", 24);
+    assert_count("4x4\nthermo: R4C1 R3C2 R2C3 R1C4", 2);
+}
+
+#[test]
+fn arrow() {
+    assert_count("4x4\narrow: R1C1 R2C2 R3C3", 36);
+    assert_count("4x4\narrow: R4C4 R1C1 R2C3", 24);
+}
+
+#[test]
+fn whisper() {
+    assert_count("4x4\nwhisper: R1C1 R1C2 R2C3", 72);
+    assert_count("4x4\nwhisper: 1 R1C1 R2C2 R3C3", 216);
+}
+
+#[test]
+fn kropki_and_consecutive() {
+    assert_count("4x4\nwhite: R1C1 R1C2\nblack: R2C1 R2C2", 48);
+    assert_count("4x4\nconsecutive: R1C1 R2C1", 144);
+}
+
+#[test]
+fn line_classes() {
+    assert_count("4x4\nentropic: R1C1 R2C2 R3C1 R4C2", 40);
+    assert_count("4x4\nmodular: 2 R1C1 R2C2 R3C3 R4C4", 64);
+}
+
+#[test]
+fn region_sum() {
+    assert_count("4x4\nregion-sum: R1C2 R1C3 R2C3", 24);
+}
+
+#[test]
+fn quadruple() {
+    assert_count("4x4\nquad: R2C2 1 2", 220);
+}
+
+#[test]
+fn value_count() {
+    assert_count("4x4\ncount: 1 1 R1C1 R2C2 R3C3 R4C4", 144);
+}
+
+#[test]
+fn extra_region() {
+    assert_count("4x4\nregion: R1C1 R2C3 R3C2 R4C4", 24);
+}
+
+#[test]
+fn inequality() {
+    assert_count("4x4\ninequality: R1C1 < R2C3", 72);
+}
+
+#[test]
+fn parity() {
+    assert_count("4x4\neven: R1C1 R2C3\nodd: R3C2", 36);
+}
+
+#[test]
+fn clone_region() {
+    assert_count("4x4\nclone: R1C1 R1C2 = R3C3 R3C4", 48);
+}
+
+#[test]
+fn global_flags() {
+    assert_count("4x4\nX-Sudoku", 48);
+    assert_count("4x4\nwindoku", 168);
+    assert_count("4x4\nargyle", 24);
+    assert_count(
+        "1.34.67..9..28..4686.....3....52....54...1...6...7.5.....8.9...4..7326.....64...2
+        anti-king",
+        4,
+    );
+}
+
+#[test]
+fn kropki_negative() {
+    let white = markers(SOLUTION, "white", |a, b| a.abs_diff(b) == 1);
+    let black = markers(SOLUTION, "black", |a, b| {
+        a.abs_diff(b) != 1 && (a == 2 * b || b == 2 * a)
+    });
+    assert_count(&format!("4x4\nKropki-Negative\n{}{}", white, black), 1);
+}
+
+#[test]
+fn consecutive_negative() {
+    let consecutive = markers(SOLUTION, "consecutive", |a, b| a.abs_diff(b) == 1);
+    assert_count(&format!("4x4\nConsecutive-Negative\n{}", consecutive), 2);
+}
+
+#[test]
+fn xv_negative() {
+    let x = markers(SOLUTION, "x", |a, b| a + b == 5);
+    assert_count(&format!("4x4\nXV-Negative\n{}", x), 8);
+    // A custom sum is forbidden as well as the default sums.
+    assert_count(
+        "...87....4....2.....6....849.7.3.81.8.....93.3.2......6.1793...79..486..54..26...
+        XV-Negative
+        v: R1C1 R2C1
+        x: 7 R4C4 R4C5",
+        2,
+    );
+}